@@ -0,0 +1,176 @@
+use chrono::{DateTime, Duration, Local, NaiveDate, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use crate::config::manager::load_timezone;
+
+/// Zone that decides where a "day" begins and ends for history queries and
+/// displays. The ring's own clock always runs in UTC.
+#[derive(Clone, Copy, Debug)]
+pub enum Calendar {
+    /// The operating system's local zone.
+    System,
+    /// An IANA zone from `config.toml`, e.g. `Europe/Berlin`.
+    Named(Tz),
+}
+
+impl Calendar {
+    /// The zone configured in `config.toml`, or the system zone.
+    pub fn load() -> Self {
+        load_timezone().map(Self::Named).unwrap_or(Self::System)
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Self::System => "system local time".to_string(),
+            Self::Named(tz) => tz.name().to_string(),
+        }
+    }
+
+    pub fn today(&self) -> NaiveDate {
+        self.date_of(Utc::now())
+    }
+
+    /// Local date an instant falls on.
+    pub fn date_of(&self, instant: DateTime<Utc>) -> NaiveDate {
+        match self {
+            Self::System => instant.with_timezone(&Local).date_naive(),
+            Self::Named(tz) => instant.with_timezone(tz).date_naive(),
+        }
+    }
+
+    pub fn format(&self, instant: DateTime<Utc>, fmt: &str) -> String {
+        match self {
+            Self::System => instant.with_timezone(&Local).format(fmt).to_string(),
+            Self::Named(tz) => instant.with_timezone(tz).format(fmt).to_string(),
+        }
+    }
+
+    /// How far the zone's wall clock is ahead of UTC at `instant`.
+    pub fn utc_offset(&self, instant: DateTime<Utc>) -> Duration {
+        let seconds = match self {
+            Self::System => instant.with_timezone(&Local).offset().fix(),
+            Self::Named(tz) => instant.with_timezone(tz).offset().fix(),
+        }
+        .local_minus_utc();
+        Duration::seconds(seconds as i64)
+    }
+
+    /// Start of a local day. Across DST changes days are 23 or 25 hours
+    /// long, and where midnight itself is skipped the day starts at the
+    /// first time that exists.
+    pub fn day_start(&self, date: NaiveDate) -> DateTime<Utc> {
+        match self {
+            Self::System => first_instant(&Local, date),
+            Self::Named(tz) => first_instant(tz, date),
+        }
+    }
+
+    /// `[start, end)` of a local day as UTC instants.
+    pub fn day_range(&self, date: NaiveDate) -> (DateTime<Utc>, DateTime<Utc>) {
+        let next = date.succ_opt().unwrap_or(date);
+        (self.day_start(date), self.day_start(next))
+    }
+
+    /// Whether a local day is exactly one of the ring's days, so ring-day
+    /// totals can be compared with it.
+    pub fn is_ring_day(&self, date: NaiveDate) -> bool {
+        self.day_range(date).0 == date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc()
+            && self.ring_days(date) == [date]
+    }
+
+    /// The ring's (UTC) days that overlap a local day, oldest first.
+    pub fn ring_days(&self, date: NaiveDate) -> Vec<NaiveDate> {
+        let (start, end) = self.day_range(date);
+        let last = (end - Duration::seconds(1)).date_naive();
+        start
+            .date_naive()
+            .iter_days()
+            .take_while(|day| *day <= last)
+            .collect()
+    }
+}
+
+/// The ring's current day; its clock runs in UTC, and its history is kept
+/// per UTC day.
+pub fn ring_today() -> NaiveDate {
+    Utc::now().date_naive()
+}
+
+fn first_instant<Tz: TimeZone>(tz: &Tz, date: NaiveDate) -> DateTime<Utc> {
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap_or_default();
+    (0..=24 * 60)
+        .map(|minutes| midnight + Duration::minutes(minutes))
+        .find_map(|naive| tz.from_local_datetime(&naive).earliest())
+        .map(|dt| dt.with_timezone(&Utc))
+        .unwrap_or_else(|| midnight.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    fn hours(range: (DateTime<Utc>, DateTime<Utc>)) -> i64 {
+        (range.1 - range.0).num_hours()
+    }
+
+    #[test]
+    fn spring_forward_day_is_23_hours() {
+        let berlin = Calendar::Named(chrono_tz::Europe::Berlin);
+        let range = berlin.day_range(date(2024, 3, 31));
+        assert_eq!(range.0, utc(2024, 3, 30, 23, 0));
+        assert_eq!(range.1, utc(2024, 3, 31, 22, 0));
+        assert_eq!(hours(range), 23);
+        assert_eq!(hours(berlin.day_range(date(2024, 3, 30))), 24);
+    }
+
+    #[test]
+    fn fall_back_day_is_25_hours() {
+        let berlin = Calendar::Named(chrono_tz::Europe::Berlin);
+        let range = berlin.day_range(date(2024, 10, 27));
+        assert_eq!(range.0, utc(2024, 10, 26, 22, 0));
+        assert_eq!(range.1, utc(2024, 10, 27, 23, 0));
+        assert_eq!(hours(range), 25);
+        assert_eq!(
+            berlin.ring_days(date(2024, 10, 27)),
+            [date(2024, 10, 26), date(2024, 10, 27)]
+        );
+    }
+
+    #[test]
+    fn skipped_midnight_starts_the_day_at_the_first_existing_time() {
+        // Chile moves its clocks from 00:00 to 01:00 (UTC-4 to UTC-3).
+        let santiago = Calendar::Named(chrono_tz::America::Santiago);
+        let start = santiago.day_start(date(2024, 9, 8));
+        assert_eq!(start, utc(2024, 9, 8, 4, 0));
+        assert_eq!(santiago.format(start, "%H:%M"), "01:00");
+        assert_eq!(hours(santiago.day_range(date(2024, 9, 8))), 23);
+        assert_eq!(santiago.date_of(start), date(2024, 9, 8));
+    }
+
+    #[test]
+    fn local_day_east_of_utc_spans_two_ring_days() {
+        let tokyo = Calendar::Named(chrono_tz::Asia::Tokyo);
+        let day = date(2024, 3, 10);
+        assert_eq!(tokyo.day_range(day).0, utc(2024, 3, 9, 15, 0));
+        assert_eq!(tokyo.ring_days(day), [date(2024, 3, 9), day]);
+        assert!(!tokyo.is_ring_day(day));
+        assert_eq!(tokyo.date_of(utc(2024, 3, 9, 15, 0)), day);
+        assert_eq!(tokyo.date_of(utc(2024, 3, 9, 14, 59)), date(2024, 3, 9));
+    }
+
+    #[test]
+    fn utc_days_are_ring_days() {
+        let utc_calendar = Calendar::Named(chrono_tz::UTC);
+        let day = date(2024, 3, 10);
+        assert_eq!(utc_calendar.ring_days(day), [day]);
+        assert!(utc_calendar.is_ring_day(day));
+    }
+}
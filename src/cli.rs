@@ -1,11 +1,25 @@
-use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+use chrono::NaiveDate;
+use clap::{Args, Parser, Subcommand};
+
+use crate::export::ExportMetric;
+use crate::export::influx::Target;
+use crate::protocol::workout::DEFAULT_MAX_HEART_RATE;
+
+use output::OutputFormat;
 
 pub mod commands;
+pub mod output;
 
 #[derive(Parser)]
 #[command(name = "colmi_client")]
 #[command(about = "A CLI tool for interacting with Colmi Bluetooth devices")]
 pub struct Cli {
+    /// Output format. Machine formats report errors on stderr as JSON.
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -33,22 +47,83 @@ pub enum Commands {
     Steps {
         #[arg(long, default_value_t = 1)]
         days: u32,
+        /// Only fetch today's totals (single request, no 15-minute details).
+        #[arg(long)]
+        today: bool,
     },
     Sleep,
     Spo2,
+    /// Print live step, calorie and distance updates until Ctrl-C.
+    LiveSteps,
+    /// Stream every metric the ring supports over realtime, one after the
+    /// other, and print the median of the first few readings of each.
+    Sample,
     /// Stream live readings for a few seconds.
     Realtime {
-        /// Reading type: hr, spo2 or hrv.
+        /// Reading types, comma-separated: hr, spo2, hrv (e.g. hr,spo2,hrv).
         #[arg(long, default_value = "hr")]
         r#type: String,
+        /// Measure one type at a time, switching every N seconds, instead of
+        /// all together.
+        #[arg(long)]
+        cycle: Option<u64>,
         /// Stream duration in seconds (values appear after ~30s warm-up).
         #[arg(long, default_value_t = 60)]
         seconds: u64,
+        /// Keep monitoring until Ctrl-C, with keepalive and auto-reconnect.
+        #[arg(long)]
+        continuous: bool,
+        /// Also write readings as InfluxDB line protocol: `-` for stdout
+        /// (text output then goes to stderr), a file, or an HTTP write URL.
+        #[arg(long)]
+        influx: Option<Target>,
+        /// API token for HTTP pushes.
+        #[arg(long, requires = "influx")]
+        influx_token: Option<String>,
+    },
+    /// Run a workout session with live heart rate, steps and calories.
+    Workout {
+        /// Sport type: walk, run, hike, cycle or other.
+        #[arg(long, default_value = "run")]
+        sport: String,
+        /// Max heart rate used for the zone boundaries.
+        #[arg(long, default_value_t = DEFAULT_MAX_HEART_RATE)]
+        max_hr: u8,
+    },
+    /// Download everything new since the last sync into the local store.
+    Sync,
+    /// Print the JSON Schema of the measurement data model.
+    Schema,
+    /// Write history from the local store to files.
+    Export {
+        #[command(subcommand)]
+        command: ExportCommands,
+    },
+    /// Bring history from other apps into the local store.
+    Import {
+        #[command(subcommand)]
+        command: ImportCommands,
     },
     Settings {
         #[command(subcommand)]
         command: SettingsCommands,
     },
+    /// Keep rings connected and publish their state until Ctrl-C. Without a
+    /// subcommand, serves a local HTTP JSON API.
+    #[command(args_conflicts_with_subcommands = true)]
+    Serve {
+        #[command(subcommand)]
+        command: Option<ServeCommands>,
+        /// Address to serve the API on.
+        #[arg(long, default_value = "127.0.0.1:8787")]
+        listen: String,
+        /// Rings to serve, by Bluetooth address or name; repeat for more
+        /// than one (default: ask for one).
+        #[arg(long)]
+        device: Vec<String>,
+        #[command(flatten)]
+        schedule: ScheduleArgs,
+    },
     Tui,
 }
 
@@ -64,3 +139,183 @@ pub enum SettingsCommands {
         interval: Option<u8>,
     },
 }
+
+#[derive(Subcommand)]
+pub enum ServeCommands {
+    /// Prometheus `/metrics` endpoint with battery, latest readings, today's
+    /// activity, sync and connection state.
+    Metrics {
+        /// Address to serve on.
+        #[arg(long, default_value = "127.0.0.1:9464")]
+        listen: String,
+        /// Ring to serve, by Bluetooth address or name (default: ask).
+        #[arg(long)]
+        device: Option<String>,
+        #[command(flatten)]
+        schedule: ScheduleArgs,
+    },
+    /// Publish to an MQTT broker, with Home Assistant discovery and command
+    /// topics. Topics are keyed by each ring's address.
+    Mqtt {
+        #[command(flatten)]
+        broker: BrokerArgs,
+        /// Rings to publish, by Bluetooth address or name; repeat for more
+        /// than one (default: ask for one).
+        #[arg(long)]
+        device: Vec<String>,
+        #[command(flatten)]
+        schedule: ScheduleArgs,
+    },
+}
+
+/// Which ring and local days an export of stored history covers.
+#[derive(Args)]
+pub struct HistoryArgs {
+    /// First local day to export (default: a week before --to).
+    #[arg(long)]
+    pub from: Option<NaiveDate>,
+    /// Last local day to export (default: today).
+    #[arg(long)]
+    pub to: Option<NaiveDate>,
+    /// Ring to export, by Bluetooth address (default: the only one stored).
+    #[arg(long)]
+    pub device: Option<String>,
+}
+
+/// Where and under which topics the MQTT bridge publishes.
+#[derive(Args)]
+pub struct BrokerArgs {
+    /// Broker as host:port.
+    #[arg(long, default_value = "localhost:1883")]
+    pub broker: String,
+    #[arg(long)]
+    pub username: Option<String>,
+    #[arg(long, requires = "username")]
+    pub password: Option<String>,
+    /// Root of the ring's state and command topics.
+    #[arg(long, default_value = "colmi")]
+    pub prefix: String,
+    /// Root Home Assistant watches for discovery messages.
+    #[arg(long, default_value = "homeassistant")]
+    pub discovery_prefix: String,
+}
+
+/// How often a daemon polls the ring.
+#[derive(Args)]
+pub struct ScheduleArgs {
+    /// Seconds between battery and activity polls.
+    #[arg(long, default_value_t = 60)]
+    pub interval: u64,
+    /// Seconds between realtime samples of HR, SpO2 and HRV (0: never).
+    #[arg(long, default_value_t = 900)]
+    pub sample_interval: u64,
+    /// Seconds between history syncs into the local store (0: never).
+    #[arg(long, default_value_t = 3600)]
+    pub sync_interval: u64,
+}
+
+#[derive(Subcommand)]
+pub enum ImportCommands {
+    /// Colmi samples from a Gadgetbridge database export. Samples already
+    /// in the local store are skipped.
+    Gadgetbridge {
+        /// The exported `Gadgetbridge` database file.
+        path: PathBuf,
+        /// Only import this ring (Bluetooth address; default: all).
+        #[arg(long)]
+        device: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ExportCommands {
+    /// One CSV file per metric, with times in the configured timezone.
+    Csv {
+        /// First local day to export (default: a week before --to).
+        #[arg(long)]
+        from: Option<NaiveDate>,
+        /// Last local day to export (default: today).
+        #[arg(long)]
+        to: Option<NaiveDate>,
+        /// Metrics to export, comma-separated (default: all).
+        #[arg(long, value_enum, value_delimiter = ',')]
+        metric: Vec<ExportMetric>,
+        /// Directory to write the files to.
+        #[arg(long, default_value = ".")]
+        dir: PathBuf,
+    },
+    /// HL7 FHIR R4 bundle of Observations from the local store.
+    Fhir {
+        #[command(flatten)]
+        history: HistoryArgs,
+        /// Patient id on the receiving FHIR server, used as the subject.
+        #[arg(long)]
+        patient: Option<String>,
+        #[arg(long, default_value = "colmi-fhir.json")]
+        out: PathBuf,
+    },
+    /// Open mHealth data points (heart-rate, step-count, sleep-episode,
+    /// oxygen-saturation) from the local store, as one JSON array.
+    Omh {
+        #[command(flatten)]
+        history: HistoryArgs,
+        /// User id to put in each data point's header.
+        #[arg(long)]
+        user: Option<String>,
+        #[arg(long, default_value = "colmi-omh.json")]
+        out: PathBuf,
+    },
+    /// EDF+ hypnogram per stored night, with the sleep stages as
+    /// annotations in 30-second records.
+    Edf {
+        #[command(flatten)]
+        history: HistoryArgs,
+        /// Add logged heart rate as a signal (-1 where nothing was logged).
+        #[arg(long)]
+        hr: bool,
+        /// Add hourly SpO2 as a signal (-1 where nothing was logged).
+        #[arg(long)]
+        spo2: bool,
+        /// Directory to write the files to.
+        #[arg(long, default_value = ".")]
+        dir: PathBuf,
+    },
+    /// iCalendar file with the stored sleep, workouts and realtime sessions
+    /// as events.
+    Ics {
+        #[command(flatten)]
+        history: HistoryArgs,
+        #[arg(long, default_value = "colmi.ics")]
+        out: PathBuf,
+    },
+    /// InfluxDB line protocol for the stored history and realtime readings.
+    Influx {
+        #[command(flatten)]
+        history: HistoryArgs,
+        /// `-` for stdout (text output then goes to stderr), a file, or an
+        /// HTTP write URL to push to.
+        #[arg(long, default_value = "colmi.lp")]
+        out: Target,
+        /// API token for HTTP pushes.
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Stored history into a Gadgetbridge database export, for rings paired
+    /// there. Samples Gadgetbridge already has are skipped.
+    Gadgetbridge {
+        /// The exported `Gadgetbridge` database file; it is modified in place.
+        path: PathBuf,
+        /// Only export this ring (Bluetooth address; default: all).
+        #[arg(long)]
+        device: Option<String>,
+    },
+    /// Garmin FIT monitoring file of the stored history, plus one activity
+    /// file per stored workout and realtime session.
+    Fit {
+        #[command(flatten)]
+        history: HistoryArgs,
+        /// Directory to write the files to.
+        #[arg(long, default_value = ".")]
+        dir: PathBuf,
+    },
+}
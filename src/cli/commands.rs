@@ -1,297 +1,405 @@
 use inquire::Confirm;
 
+use std::io::Write;
+use std::path::Path;
 use std::time::Duration;
 
-use chrono::{Datelike, TimeZone, Utc};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use chrono::{DateTime, NaiveDate, Utc};
 
 use crate::bluetooth::scanner;
+use crate::calendar::Calendar;
+use crate::cli::output::{
+    ActionRecord, DeviceRecord, ExportRecord, HeartRateSettingsRecord, ImportRecord, InfoRecord,
+    Output, SyncRecord, WorkoutRecord,
+};
+use crate::cli::{BrokerArgs, HistoryArgs, ScheduleArgs};
+use crate::config::manager::load_device_features;
 use crate::devices::manager::DeviceManager;
-use crate::devices::models::Device;
-use crate::error::ScanError;
-use crate::protocol::bigdata::{OxygenData, SleepData, sleep_phase_label};
+use crate::devices::models::{Device, address_key};
+use crate::error::{CliError, GadgetbridgeError, ScanError, ServeError, StoreError};
+use crate::export::{self, ExportMetric, History, edf, fhir, fit, ics, influx, omh};
+use crate::model::{self, Measurement, Series};
+use crate::protocol::bigdata::OxygenData;
 use crate::protocol::hr::HeartRateResult;
-use crate::protocol::realtime::{ReadingType, RealtimeReading};
+use crate::protocol::realtime::{
+    MonitorEvent, ReadingType, RealtimeReading, RealtimeSession, SampleProgress, SessionMode,
+};
 use crate::protocol::settings::HeartRateLogSettings;
-use crate::protocol::steps::StepsResult;
+use crate::protocol::steps::{DailyTotals, LiveActivity, StepsResult, detail_shortfall};
+use crate::protocol::workout::{
+    SportType, WorkoutAction, WorkoutEvent, WorkoutSession, WorkoutSummary,
+};
+use crate::serve::mqtt::{self, BridgeEvent};
+use crate::serve::{self, PollEvent, Poller, Schedule, SharedState};
+use crate::store::database::{DeviceInfo, Store};
+use crate::store::gadgetbridge::{Gadgetbridge, Samples};
+use crate::store::sync::{MetricSync, SYNC_LOOKBACK_DAYS, SyncMetric, sync_metric};
 use crate::tui;
 
-pub async fn scan(filter_colmi: bool) {
+pub async fn scan(output: &Output, filter_colmi: bool) {
     match filter_devices(filter_colmi).await {
         Ok(devices) => {
-            println!("Found {} device(s):", devices.len());
+            output.text(format!("Found {} device(s):", devices.len()));
             for (i, device) in devices.iter().enumerate() {
-                println!("  {}. {}", i + 1, device.display_name());
+                output.text(format!("  {}. {}", i + 1, device.display_name()));
+                output.record(&DeviceRecord {
+                    name: device.name().to_string(),
+                    address: device.id().to_string(),
+                });
             }
         }
-        Err(err) => println!("{err}"),
+        Err(err) => output.error(&err),
     }
 }
 
-pub async fn connect(filter_colmi: bool) {
+pub async fn connect(output: &Output, filter_colmi: bool) {
     match filter_devices(filter_colmi).await {
         Ok(devices) => {
-            println!("Found {} device(s):", devices.len());
+            output.text(format!("Found {} device(s):", devices.len()));
 
-            if let Some(selected_device) = tui::select_device(devices) {
+            if let Some(selected_device) = choose_device(output, devices) {
                 match DeviceManager::connect_and_setup(&selected_device).await {
                     Ok(conn) => {
-                        println!("Connected and configured device: {selected_device}");
+                        output.text(format!(
+                            "Connected and configured device: {selected_device}"
+                        ));
+                        output.record(&ActionRecord {
+                            device: selected_device.id().to_string(),
+                            action: "connect",
+                            status: "done",
+                        });
                         let _ = &conn;
                     }
                     Err(err) => {
-                        println!("{err}");
+                        output.error(&err);
                     }
                 };
             }
         }
-        Err(err) => println!("{err}"),
+        Err(err) => output.error(&err),
     }
 }
 
-pub async fn battery() {
+pub async fn battery(output: &Output) {
     match filter_devices(true).await {
         Ok(devices) => {
-            println!("Found {} device(s):", devices.len());
+            output.text(format!("Found {} device(s):", devices.len()));
 
-            if let Some(selected_device) = tui::select_device(devices) {
+            if let Some(selected_device) = choose_device(output, devices) {
                 match DeviceManager::connect_and_setup(&selected_device).await {
                     Ok(conn) => match DeviceManager::get_battery_level(&conn).await {
-                        Ok(response) => println!("{response}"),
-                        Err(err) => println!("{err}"),
+                        Ok(response) => {
+                            output.text(&response);
+                            output.records(&Measurement::battery(
+                                selected_device.id(),
+                                Utc::now(),
+                                &response,
+                            ));
+                            if let Some(mut store) = open_store(output) {
+                                warn_if_unsaved(
+                                    output,
+                                    store.save_battery(selected_device.id(), Utc::now(), &response),
+                                );
+                            }
+                        }
+                        Err(err) => output.error(&err),
                     },
-                    Err(err) => println!("{err}"),
+                    Err(err) => output.error(&err),
                 }
             }
         }
-        Err(err) => println!("{err}"),
+        Err(err) => output.error(&err),
     }
 }
 
-pub async fn info() {
+pub async fn info(output: &Output) {
     match filter_devices(true).await {
         Ok(devices) => {
-            println!("Found {} device(s):", devices.len());
+            output.text(format!("Found {} device(s):", devices.len()));
 
-            if let Some(selected_device) = tui::select_device(devices) {
+            if let Some(selected_device) = choose_device(output, devices) {
                 match DeviceManager::connect_and_setup(&selected_device).await {
                     Ok(conn) => match DeviceManager::get_device_info(&conn).await {
                         Ok((firmware, hardware, manufacturer)) => {
-                            println!("Manufacturer: {manufacturer}");
-                            println!("Firmware:     {firmware}");
-                            println!("Hardware:     {hardware}");
+                            output.text(format!("Manufacturer: {manufacturer}"));
+                            output.text(format!("Firmware:     {firmware}"));
+                            output.text(format!("Hardware:     {hardware}"));
+                            output.record(&InfoRecord {
+                                device: selected_device.id().to_string(),
+                                manufacturer,
+                                firmware,
+                                hardware,
+                            });
                         }
-                        Err(err) => println!("{err}"),
+                        Err(err) => output.error(&err),
                     },
-                    Err(err) => println!("{err}"),
+                    Err(err) => output.error(&err),
                 }
             }
         }
-        Err(err) => println!("{err}"),
+        Err(err) => output.error(&err),
     }
 }
 
-pub async fn blink() {
+pub async fn blink(output: &Output) {
     match filter_devices(true).await {
         Ok(devices) => {
-            println!("Found {} device(s):", devices.len());
+            output.text(format!("Found {} device(s):", devices.len()));
 
-            if let Some(selected_device) = tui::select_device(devices) {
+            if let Some(selected_device) = choose_device(output, devices) {
                 match DeviceManager::connect_and_setup(&selected_device).await {
                     Ok(conn) => match DeviceManager::blink(&conn).await {
-                        Ok(_) => (),
-                        Err(err) => println!("{err}"),
+                        Ok(_) => {
+                            output.text("Ring is blinking");
+                            output.record(&ActionRecord {
+                                device: selected_device.id().to_string(),
+                                action: "blink",
+                                status: "done",
+                            });
+                        }
+                        Err(err) => output.error(&err),
                     },
-                    Err(err) => println!("{err}"),
+                    Err(err) => output.error(&err),
                 }
             }
         }
-        Err(err) => println!("{err}"),
+        Err(err) => output.error(&err),
     }
 }
 
-pub async fn hr(days: u32) {
+pub async fn hr(output: &Output, days: u32) {
     match filter_devices(true).await {
         Ok(devices) => {
-            println!("Found {} device(s):", devices.len());
+            output.text(format!("Found {} device(s):", devices.len()));
 
-            if let Some(selected_device) = tui::select_device(devices) {
+            if let Some(selected_device) = choose_device(output, devices) {
                 match DeviceManager::connect_and_setup(&selected_device).await {
                     Ok(conn) => {
+                        let calendar = Calendar::load();
+                        output.text(format!("Days in {}", calendar.name()));
+                        let mut store = open_store(output);
                         for day_offset in 0..days {
-                            let day = Utc::now() - chrono::Duration::days(day_offset as i64);
-                            let midnight = Utc
-                                .with_ymd_and_hms(day.year(), day.month(), day.day(), 0, 0, 0)
-                                .single()
-                                .unwrap_or(day);
+                            let date = calendar.today() - chrono::Duration::days(day_offset as i64);
 
-                            match DeviceManager::get_heart_rate_log(
-                                &conn,
-                                midnight.timestamp() as u32,
-                            )
-                            .await
-                            {
+                            match DeviceManager::get_day_heart_rate(&conn, &calendar, date).await {
                                 Ok(HeartRateResult::Log(log)) => {
-                                    let readings: Vec<u8> = log
-                                        .heart_rates
-                                        .iter()
-                                        .copied()
-                                        .filter(|&r| r > 0)
-                                        .collect();
+                                    if let Some(store) = &mut store {
+                                        warn_if_unsaved(
+                                            output,
+                                            store.save_heart_rate_log(selected_device.id(), &log),
+                                        );
+                                    }
+                                    output.records(
+                                        &Series::heart_rate(selected_device.id(), &log)
+                                            .measurements(),
+                                    );
+                                    let readings: Vec<u8> =
+                                        log.readings().map(|(_, bpm)| bpm).collect();
                                     if readings.is_empty() {
-                                        println!("{}: no readings", midnight.format("%Y-%m-%d"));
+                                        output.text(format!("{date}: no readings"));
                                     } else {
                                         let avg = readings.iter().map(|&r| r as u32).sum::<u32>()
                                             / readings.len() as u32;
                                         let min = readings.iter().min().unwrap();
                                         let max = readings.iter().max().unwrap();
-                                        println!(
+                                        output.text(format!(
                                             "{}: {} readings, avg {} bpm ({} - {}), interval {}m",
-                                            midnight.format("%Y-%m-%d"),
+                                            calendar.date_of(log.day_start),
                                             readings.len(),
                                             avg,
                                             min,
                                             max,
-                                            log.range
-                                        );
+                                            log.interval.num_minutes()
+                                        ));
                                     }
                                 }
                                 Ok(HeartRateResult::NoData) => {
-                                    println!("{}: no data", midnight.format("%Y-%m-%d"));
+                                    output.text(format!("{date}: no data"));
                                 }
                                 Err(err) => {
-                                    println!("{err}");
+                                    output.error(&err);
                                 }
                             }
                         }
                     }
-                    Err(err) => println!("{err}"),
+                    Err(err) => output.error(&err),
                 }
             }
         }
-        Err(err) => println!("{err}"),
+        Err(err) => output.error(&err),
     }
 }
 
-pub async fn steps(days: u32) {
+pub async fn steps(output: &Output, days: u32, today: bool) {
     match filter_devices(true).await {
         Ok(devices) => {
-            println!("Found {} device(s):", devices.len());
+            output.text(format!("Found {} device(s):", devices.len()));
 
-            if let Some(selected_device) = tui::select_device(devices) {
+            if let Some(selected_device) = choose_device(output, devices) {
                 match DeviceManager::connect_and_setup(&selected_device).await {
+                    Ok(conn) if today => match DeviceManager::get_today_totals(&conn).await {
+                        Ok(totals) => {
+                            output.text(format!(
+                                "Today: {} steps, {:.0} kcal, {} m",
+                                totals.steps, totals.calories, totals.distance
+                            ));
+                            output.records(&Measurement::daily_totals(
+                                selected_device.id(),
+                                Utc::now(),
+                                &totals,
+                            ));
+                        }
+                        Err(err) => output.error(&err),
+                    },
                     Ok(conn) => {
+                        let totals = DeviceManager::get_today_totals(&conn).await.ok();
+                        let calendar = Calendar::load();
+                        output.text(format!("Days in {}", calendar.name()));
+                        let mut store = open_store(output);
                         for day_offset in 0..days {
-                            match DeviceManager::get_steps(&conn, day_offset as i8).await {
+                            let date = calendar.today() - chrono::Duration::days(day_offset as i64);
+                            match DeviceManager::get_day_steps(&conn, &calendar, date).await {
                                 Ok(StepsResult::Details(details)) => {
                                     if details.is_empty() {
-                                        println!("Day -{day_offset}: no activity");
+                                        output.text(format!("{date}: no activity"));
                                         continue;
                                     }
+                                    if let Some(store) = &mut store {
+                                        warn_if_unsaved(
+                                            output,
+                                            store.save_activity(selected_device.id(), &details),
+                                        );
+                                    }
+                                    for series in Series::activity(selected_device.id(), &details) {
+                                        output.records(&series.measurements());
+                                    }
                                     let total_steps: u32 =
                                         details.iter().map(|d| d.steps as u32).sum();
                                     let total_calories: f64 =
                                         details.iter().map(|d| d.calories).sum();
                                     let total_distance: u32 =
                                         details.iter().map(|d| d.distance as u32).sum();
-                                    let first = &details[0];
-                                    let date = format!(
-                                        "{:04}-{:02}-{:02}",
-                                        first.year, first.month, first.day
-                                    );
-                                    let first_slot =
-                                        details.iter().map(|d| d.time_index).min().unwrap();
-                                    let last_slot =
-                                        details.iter().map(|d| d.time_index).max().unwrap();
-                                    let fmt_slot = |slot: u8| {
-                                        format!("{:02}:{:02}", slot / 4, (slot % 4) * 15)
-                                    };
-                                    println!(
-                                        "Day -{} ({}): {} steps, {:.0} kcal, {} m, active {}–{}",
-                                        day_offset,
-                                        date,
-                                        total_steps,
-                                        total_calories,
-                                        total_distance,
-                                        fmt_slot(first_slot),
-                                        fmt_slot(last_slot)
+                                    let slots = || details.iter().filter_map(|d| d.timestamp());
+                                    let summary = format!(
+                                        "{date}: {total_steps} steps, {total_calories:.0} kcal, {total_distance} m"
                                     );
+                                    if let (Some(first), Some(last)) =
+                                        (slots().min(), slots().max())
+                                    {
+                                        output.text(format!(
+                                            "{summary}, active {}–{}",
+                                            calendar.format(first, "%H:%M"),
+                                            calendar.format(last, "%H:%M")
+                                        ));
+                                    } else {
+                                        output.text(summary);
+                                    }
+                                    // The ring's totals cover its own (UTC) day, so compare
+                                    // them against that day's raw slots.
+                                    if day_offset == 0
+                                        && let Some(totals) = &totals
+                                        && let Ok(StepsResult::Details(ring_day)) =
+                                            DeviceManager::get_steps(&conn, 0).await
+                                        && let Some(missing) = detail_shortfall(totals, &ring_day)
+                                    {
+                                        output.warning(format!(
+                                            "details incomplete: {missing} of {} steps missing",
+                                            totals.steps
+                                        ));
+                                    }
                                 }
-                                Ok(StepsResult::NoData) => println!("Day -{day_offset}: no data"),
-                                Err(err) => println!("{err}"),
+                                Ok(StepsResult::NoData) => output.text(format!("{date}: no data")),
+                                Err(err) => output.error(&err),
                             }
                         }
                     }
-                    Err(err) => println!("{err}"),
+                    Err(err) => output.error(&err),
                 }
             }
         }
-        Err(err) => println!("{err}"),
+        Err(err) => output.error(&err),
     }
 }
 
-pub async fn sleep() {
+pub async fn sleep(output: &Output) {
     match filter_devices(true).await {
         Ok(devices) => {
-            println!("Found {} device(s):", devices.len());
+            output.text(format!("Found {} device(s):", devices.len()));
 
-            if let Some(selected_device) = tui::select_device(devices) {
+            if let Some(selected_device) = choose_device(output, devices) {
                 match DeviceManager::connect_and_setup(&selected_device).await {
                     Ok(conn) => match DeviceManager::get_sleep(&conn).await {
-                        Ok(SleepData { days }) => {
-                            if days.is_empty() {
-                                println!("No sleep data available");
+                        Ok(sleep) => {
+                            // The ring's clock is set in UTC.
+                            let sessions = sleep.sessions(&Utc);
+                            let calendar = Calendar::load();
+                            if let Some(mut store) = open_store(output) {
+                                warn_if_unsaved(
+                                    output,
+                                    store.save_sleep(selected_device.id(), &sessions),
+                                );
+                            }
+                            output.records(
+                                &Series::sleep(selected_device.id(), &sessions).measurements(),
+                            );
+                            if sessions.is_empty() {
+                                output.text("No sleep data available");
                             } else {
-                                for day in &days {
-                                    let total: u16 =
-                                        day.phases.iter().map(|p| p.minutes as u16).sum();
-                                    println!(
-                                        "Sleep {} nights ago: {}h {:02}m ({}:{:02} → {}:{:02})",
-                                        day.days_ago,
+                                for session in &sessions {
+                                    let total = session.total_minutes();
+                                    output.text(format!(
+                                        "Sleep {}: {}h {:02}m ({} → {})",
+                                        calendar.date_of(session.end),
                                         total / 60,
                                         total % 60,
-                                        day.start_minutes / 60,
-                                        day.start_minutes % 60,
-                                        day.end_minutes / 60,
-                                        day.end_minutes % 60
-                                    );
-                                    let mut breakdown: Vec<(&str, u16)> = Vec::new();
-                                    for phase in &day.phases {
-                                        let label = sleep_phase_label(phase.phase_type);
-                                        if let Some(entry) =
-                                            breakdown.iter_mut().find(|(l, _)| *l == label)
-                                        {
-                                            entry.1 += phase.minutes as u16;
-                                        } else {
-                                            breakdown.push((label, phase.minutes as u16));
-                                        }
-                                    }
-                                    for (label, minutes) in breakdown {
-                                        println!("  {label}: {minutes}m");
+                                        calendar.format(session.start, "%H:%M"),
+                                        calendar.format(session.end, "%H:%M")
+                                    ));
+                                    for (label, minutes) in session.stage_breakdown() {
+                                        output.text(format!("  {label}: {minutes}m"));
                                     }
                                 }
                             }
                         }
-                        Err(err) => println!("{err}"),
+                        Err(err) => output.error(&err),
                     },
-                    Err(err) => println!("{err}"),
+                    Err(err) => output.error(&err),
                 }
             }
         }
-        Err(err) => println!("{err}"),
+        Err(err) => output.error(&err),
     }
 }
 
-pub async fn spo2() {
+pub async fn spo2(output: &Output) {
     match filter_devices(true).await {
         Ok(devices) => {
-            println!("Found {} device(s):", devices.len());
+            output.text(format!("Found {} device(s):", devices.len()));
 
-            if let Some(selected_device) = tui::select_device(devices) {
+            if let Some(selected_device) = choose_device(output, devices) {
                 match DeviceManager::connect_and_setup(&selected_device).await {
                     Ok(conn) => match DeviceManager::get_oxygen(&conn).await {
                         Ok(OxygenData { days }) => {
+                            if let Some(mut store) = open_store(output) {
+                                warn_if_unsaved(
+                                    output,
+                                    store.save_oxygen(
+                                        selected_device.id(),
+                                        Utc::now().date_naive(),
+                                        &days,
+                                    ),
+                                );
+                            }
+                            output.records(
+                                &Series::oxygen(selected_device.id(), &days).measurements(),
+                            );
                             if days.is_empty() {
-                                println!("No blood-oxygen data available");
+                                output.text("No blood-oxygen data available");
                             } else {
                                 for day in &days {
                                     let valid: Vec<_> = day
@@ -300,7 +408,10 @@ pub async fn spo2() {
                                         .filter(|s| s.min > 0 || s.max > 0)
                                         .collect();
                                     if valid.is_empty() {
-                                        println!("SpO2 {} nights ago: no samples", day.days_ago);
+                                        output.text(format!(
+                                            "SpO2 {} nights ago: no samples",
+                                            day.days_ago
+                                        ));
                                         continue;
                                     }
                                     let min_avg: u32 =
@@ -309,91 +420,1093 @@ pub async fn spo2() {
                                     let max_avg: u32 =
                                         valid.iter().map(|s| s.max as u32).sum::<u32>()
                                             / valid.len() as u32;
-                                    println!(
+                                    output.text(format!(
                                         "SpO2 {} nights ago: {} samples, avg range {}–{}%",
                                         day.days_ago,
                                         valid.len(),
                                         min_avg,
                                         max_avg
-                                    );
+                                    ));
                                 }
                             }
                         }
-                        Err(err) => println!("{err}"),
+                        Err(err) => output.error(&err),
                     },
-                    Err(err) => println!("{err}"),
+                    Err(err) => output.error(&err),
                 }
             }
         }
-        Err(err) => println!("{err}"),
+        Err(err) => output.error(&err),
     }
 }
 
-pub async fn realtime(reading_type: &str, seconds: u64) {
-    let reading_type = match reading_type {
-        "hr" | "heart-rate" => ReadingType::HeartRateBatch,
-        "spo2" | "blood-oxygen" => ReadingType::BloodOxygen,
-        "hrv" => ReadingType::Hrv,
-        other => {
-            println!("Unknown reading type '{other}'. Use hr, spo2 or hrv.");
+pub async fn live_steps(output: &Output) {
+    let calendar = Calendar::load();
+    match filter_devices(true).await {
+        Ok(devices) => {
+            output.text(format!("Found {} device(s):", devices.len()));
+
+            if let Some(selected_device) = choose_device(output, devices) {
+                match DeviceManager::connect_and_setup(&selected_device).await {
+                    Ok(conn) => {
+                        let mut activity = conn.subscribe_live_activity();
+                        output.text("Waiting for live activity (walk around; Ctrl-C to stop)...");
+
+                        loop {
+                            tokio::select! {
+                                update = activity.recv() => match update {
+                                    Ok(LiveActivity { steps, calories, distance }) => {
+                                        let now = Utc::now();
+                                        output.text(format!(
+                                            "  {} | {} steps | {:.1} kcal | {} m",
+                                            calendar.format(now, "%H:%M:%S"),
+                                            steps,
+                                            calories,
+                                            distance
+                                        ));
+                                        output.records(&Measurement::daily_totals(
+                                            selected_device.id(),
+                                            now,
+                                            &DailyTotals { steps, calories, distance },
+                                        ));
+                                    }
+                                    Err(RecvError::Lagged(_)) => continue,
+                                    Err(RecvError::Closed) => break,
+                                },
+                                _ = tokio::signal::ctrl_c() => break,
+                            }
+                        }
+                    }
+                    Err(err) => output.error(&err),
+                }
+            }
+        }
+        Err(err) => output.error(&err),
+    }
+}
+
+pub async fn sample(output: &Output) {
+    match filter_devices(true).await {
+        Ok(devices) => {
+            output.text(format!("Found {} device(s):", devices.len()));
+
+            if let Some(selected_device) = choose_device(output, devices) {
+                match DeviceManager::connect_and_setup(&selected_device).await {
+                    Ok(conn) => {
+                        let reading_types = load_device_features()
+                            .map(|features| features.realtime_types())
+                            .unwrap_or_else(|| vec![ReadingType::HeartRateBatch]);
+
+                        output.text("Sampling (keep still and wear the ring snugly)...");
+                        let cancel = cancel_on_ctrl_c();
+                        let mut stdout = std::io::stdout();
+                        let result = DeviceManager::sample_realtime(
+                            &conn,
+                            &reading_types,
+                            |progress| {
+                                if !output.is_text() {
+                                    return;
+                                }
+                                match progress {
+                                    SampleProgress::Waiting {
+                                        reading_type,
+                                        elapsed,
+                                    } => print!(
+                                        "\r  {}: warming up {}s ",
+                                        reading_type.label(),
+                                        elapsed.as_secs()
+                                    ),
+                                    SampleProgress::Sample(reading) => print!(
+                                        "\r  {}: {} {}      ",
+                                        reading.reading_type.label(),
+                                        reading.value,
+                                        reading.reading_type.unit()
+                                    ),
+                                }
+                                let _ = stdout.flush();
+                            },
+                            cancel,
+                        )
+                        .await;
+                        output.text("");
+
+                        match result {
+                            Ok(sample) => {
+                                let sampled_at = Utc::now();
+                                for reading_type in reading_types {
+                                    match sample.get(reading_type) {
+                                        Some(value) => {
+                                            output.text(format!(
+                                                "{}: {} {}",
+                                                reading_type.label(),
+                                                value,
+                                                reading_type.unit()
+                                            ));
+                                            output.record(&Measurement::reading(
+                                                selected_device.id(),
+                                                &RealtimeReading {
+                                                    reading_type,
+                                                    value,
+                                                    timestamp: sampled_at,
+                                                },
+                                            ));
+                                        }
+                                        None => output.text(format!(
+                                            "{}: no stable reading",
+                                            reading_type.label()
+                                        )),
+                                    }
+                                }
+                            }
+                            Err(err) => output.error(&err),
+                        }
+                    }
+                    Err(err) => output.error(&err),
+                }
+            }
+        }
+        Err(err) => output.error(&err),
+    }
+}
+
+pub async fn realtime(
+    output: &Output,
+    reading_types: &str,
+    seconds: u64,
+    continuous: bool,
+    cycle: Option<u64>,
+    influx: Option<&influx::Target>,
+    influx_token: Option<&str>,
+) {
+    let calendar = Calendar::load();
+    let reading_types = match ReadingType::parse_list(reading_types) {
+        Ok(types) if !types.is_empty() => types,
+        Ok(_) => {
+            output.error(&CliError::InvalidArgument(
+                "No reading type given. Use hr, spo2 or hrv.".to_string(),
+            ));
+            return;
+        }
+        Err(other) => {
+            output.error(&CliError::InvalidArgument(format!(
+                "Unknown reading type '{other}'. Use hr, spo2 or hrv."
+            )));
+            return;
+        }
+    };
+    let session = RealtimeSession {
+        reading_types,
+        mode: match cycle {
+            Some(secs) => SessionMode::Cycle(Duration::from_secs(secs.max(1))),
+            None => SessionMode::Together,
+        },
+    };
+    if let Some(influx::Target::Stdout) = influx
+        && let Err(err) = output.give_up_stdout("--influx -")
+    {
+        output.error(&err);
+        return;
+    }
+    let mut influx = match influx
+        .map(|target| {
+            influx::LineWriter::open(target, influx_token)
+                .map(|writer| influx::LineStream::spawn(writer, target))
+        })
+        .transpose()
+    {
+        Ok(stream) => stream,
+        Err(err) => {
+            output.error(&err);
             return;
         }
     };
 
     match filter_devices(true).await {
         Ok(devices) => {
-            println!("Found {} device(s):", devices.len());
+            output.text(format!("Found {} device(s):", devices.len()));
 
-            if let Some(selected_device) = tui::select_device(devices) {
+            if let Some(selected_device) = choose_device(output, devices) {
                 match DeviceManager::connect_and_setup(&selected_device).await {
+                    Ok(conn) if continuous => {
+                        output.text(format!(
+                            "Monitoring {} continuously (values appear after ~30s warm-up; Ctrl-C to stop)...",
+                            session.label()
+                        ));
+
+                        let (tx, mut rx) = tokio::sync::mpsc::channel::<MonitorEvent>(64);
+                        let cancel = cancel_on_ctrl_c();
+                        let device_id = selected_device.id().to_string();
+                        let mut saver = SessionSaver::open(output, &device_id);
+
+                        let monitor_task = tokio::spawn(async move {
+                            DeviceManager::monitor_realtime(
+                                &selected_device,
+                                conn,
+                                session,
+                                tx,
+                                cancel,
+                            )
+                            .await
+                        });
+
+                        while let Some(event) = rx.recv().await {
+                            match event {
+                                MonitorEvent::Reading(reading) => {
+                                    print_reading(output, &calendar, &device_id, &reading);
+                                    write_line(output, &mut influx, &device_id, &reading);
+                                    saver.push(output, reading);
+                                }
+                                MonitorEvent::Restarted => {
+                                    output.text("  Ring went quiet, restarted measurement")
+                                }
+                                MonitorEvent::Reconnecting { attempt } => output.text(format!(
+                                    "  Connection lost, reconnecting (attempt {attempt})..."
+                                )),
+                                MonitorEvent::Reconnected(_) => output.text("  Reconnected"),
+                            }
+                        }
+
+                        match monitor_task.await {
+                            Ok(Ok(_)) => output.text("Monitoring finished"),
+                            Ok(Err(err)) => output.error_in("Monitoring", &err),
+                            Err(err) => output.error_in("Monitoring", &CliError::from(err)),
+                        }
+                        saver.flush(output);
+                        close_lines(output, influx).await;
+                    }
                     Ok(conn) => {
                         let (tx, mut rx) = tokio::sync::mpsc::channel::<RealtimeReading>(64);
+                        let cancel = cancel_on_ctrl_c();
+                        let mut saver = SessionSaver::open(output, selected_device.id());
+
+                        output.text(format!(
+                            "Streaming {} for {}s (wear the ring; values appear after ~30s warm-up)...",
+                            session.label(),
+                            seconds
+                        ));
 
                         let stream_task = tokio::spawn(async move {
                             DeviceManager::stream_realtime(
                                 &conn,
-                                reading_type,
+                                &session,
                                 Duration::from_secs(seconds),
                                 tx,
+                                cancel,
                             )
                             .await
                         });
 
-                        println!(
-                            "Streaming {} for {}s (wear the ring; values appear after ~30s warm-up)...",
-                            reading_type.label(),
-                            seconds
-                        );
-
                         while let Some(reading) = rx.recv().await {
-                            println!(
-                                "  {} = {} {}",
-                                reading.reading_type.label(),
-                                reading.value,
-                                reading.reading_type.unit()
-                            );
+                            print_reading(output, &calendar, selected_device.id(), &reading);
+                            write_line(output, &mut influx, selected_device.id(), &reading);
+                            saver.push(output, reading);
+                        }
+
+                        match stream_task.await {
+                            Ok(Ok(_)) => output.text("Streaming finished"),
+                            Ok(Err(err)) => output.error_in("Streaming", &err),
+                            Err(err) => output.error_in("Streaming", &CliError::from(err)),
+                        }
+                        saver.flush(output);
+                        close_lines(output, influx).await;
+                    }
+                    Err(err) => output.error(&err),
+                }
+            }
+        }
+        Err(err) => output.error(&err),
+    }
+}
+
+/// Saves a realtime session into the local store in small batches as the
+/// readings arrive, so long sessions stay small in memory and a crash loses
+/// at most the last batch.
+struct SessionSaver {
+    store: Option<Store>,
+    device: String,
+    started_at: DateTime<Utc>,
+    pending: Vec<RealtimeReading>,
+    last_saved: std::time::Instant,
+}
+
+impl SessionSaver {
+    const BATCH_READINGS: usize = 60;
+    const BATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+    fn open(output: &Output, device: &str) -> Self {
+        Self {
+            store: open_store(output),
+            device: device.to_string(),
+            started_at: Utc::now(),
+            pending: Vec::new(),
+            last_saved: std::time::Instant::now(),
+        }
+    }
+
+    fn push(&mut self, output: &Output, reading: RealtimeReading) {
+        if self.store.is_none() {
+            return;
+        }
+        self.pending.push(reading);
+        if self.pending.len() >= Self::BATCH_READINGS
+            || self.last_saved.elapsed() >= Self::BATCH_INTERVAL
+        {
+            self.flush(output);
+        }
+    }
+
+    /// Saves what is pending and moves the session's end to now. A failed
+    /// batch is dropped after a warning rather than kept around.
+    fn flush(&mut self, output: &Output) {
+        let Some(store) = self.store.as_mut() else {
+            return;
+        };
+        warn_if_unsaved(
+            output,
+            store.save_realtime_session(&self.device, self.started_at, Utc::now(), &self.pending),
+        );
+        self.pending.clear();
+        self.last_saved = std::time::Instant::now();
+    }
+}
+
+fn print_reading(output: &Output, calendar: &Calendar, device: &str, reading: &RealtimeReading) {
+    output.text(format!(
+        "  {} {} = {} {}",
+        calendar.format(reading.timestamp, "%H:%M:%S"),
+        reading.reading_type.label(),
+        reading.value,
+        reading.reading_type.unit()
+    ));
+    output.record(&Measurement::reading(device, reading));
+}
+
+/// Queues `reading` as line protocol and reports how earlier writes went.
+fn write_line(
+    output: &Output,
+    influx: &mut Option<influx::LineStream>,
+    device: &str,
+    reading: &RealtimeReading,
+) {
+    let Some(stream) = influx.as_mut() else {
+        return;
+    };
+    if let Some(line) = influx::line(&Measurement::reading(device, reading)) {
+        stream.push(line);
+    }
+    for notice in stream.notices() {
+        output.warning(notice);
+    }
+}
+
+/// Writes the line protocol still queued before the command exits.
+async fn close_lines(output: &Output, influx: Option<influx::LineStream>) {
+    if let Some(stream) = influx
+        && let Err(err) = stream.close(|notice| output.warning(notice)).await
+    {
+        output.warning(format!("Line protocol not fully written: {err}"));
+    }
+}
+
+pub async fn workout(output: &Output, sport: &str, max_hr: u8) {
+    let Some(sport_type) = SportType::from_name(sport) else {
+        output.error(&CliError::InvalidArgument(format!(
+            "Unknown sport '{sport}'. Use walk, run, hike, cycle or other."
+        )));
+        return;
+    };
+
+    match filter_devices(true).await {
+        Ok(devices) => {
+            output.text(format!("Found {} device(s):", devices.len()));
+
+            if let Some(selected_device) = choose_device(output, devices) {
+                match DeviceManager::connect_and_setup(&selected_device).await {
+                    Ok(conn) => {
+                        let (tx, mut rx) = tokio::sync::mpsc::channel::<WorkoutEvent>(64);
+                        let (control_tx, control_rx) =
+                            tokio::sync::mpsc::channel::<WorkoutAction>(8);
+
+                        let cancel = cancel_on_ctrl_c();
+                        let stream_cancel = cancel.clone();
+
+                        let stream_task = tokio::spawn(async move {
+                            DeviceManager::stream_workout(
+                                &conn,
+                                sport_type,
+                                control_rx,
+                                tx,
+                                stream_cancel,
+                            )
+                            .await
+                        });
+
+                        output.text(format!(
+                            "Started {} workout. Type p + ENTER to pause, r to resume, s to stop.",
+                            sport_type.label()
+                        ));
+
+                        let mut session = WorkoutSession::new(sport_type, max_hr);
+                        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+                        loop {
+                            tokio::select! {
+                                _ = cancel.cancelled() => break,
+                                event = rx.recv() => {
+                                    let Some(event) = event else {
+                                        break;
+                                    };
+                                    session.record(&event);
+                                    print_workout_progress(output, &session);
+                                }
+                                line = lines.next_line() => {
+                                    let action = match line {
+                                        Ok(Some(line)) => match line.trim() {
+                                            "p" => WorkoutAction::Pause,
+                                            "r" => WorkoutAction::Resume,
+                                            "s" | "q" => WorkoutAction::End,
+                                            _ => continue,
+                                        },
+                                        _ => WorkoutAction::End,
+                                    };
+                                    match action {
+                                        WorkoutAction::Pause => {
+                                            session.pause();
+                                            output.text("Paused");
+                                        }
+                                        WorkoutAction::Resume => {
+                                            session.resume();
+                                            output.text("Resumed");
+                                        }
+                                        _ => {}
+                                    }
+                                    if control_tx.send(action).await.is_err()
+                                        || action == WorkoutAction::End
+                                    {
+                                        break;
+                                    }
+                                }
+                            }
                         }
+                        drop(control_tx);
 
                         match stream_task.await {
-                            Ok(Ok(_)) => println!("Streaming finished"),
-                            Ok(Err(err)) => println!("Streaming error: {err}"),
-                            Err(_) => println!("Streaming task panicked"),
+                            Ok(Ok(_)) => {
+                                let summary = session.finish();
+                                print_workout_summary(output, &summary);
+                                output.record(&WorkoutRecord::new(selected_device.id(), &summary));
+                                if let Some(mut store) = open_store(output) {
+                                    warn_if_unsaved(
+                                        output,
+                                        store.save_workout(selected_device.id(), &summary),
+                                    );
+                                }
+                            }
+                            Ok(Err(err)) => output.error_in("Workout", &err),
+                            Err(err) => output.error_in("Workout", &CliError::from(err)),
                         }
                     }
-                    Err(err) => println!("{err}"),
+                    Err(err) => output.error(&err),
                 }
             }
         }
-        Err(err) => println!("{err}"),
+        Err(err) => output.error(&err),
     }
 }
 
-pub async fn settings_hr(enable: bool, disable: bool, interval: Option<u8>) {
+fn print_workout_progress(output: &Output, session: &WorkoutSession) {
+    let heart_rate = match session.heart_rates.last() {
+        Some(&(_, hr)) => format!("{hr} bpm (zone {})", session.zone(hr)),
+        None => "-- bpm".to_string(),
+    };
+    let (steps, calories, distance) = session
+        .latest
+        .as_ref()
+        .map(|p| (p.steps, p.calories, p.distance))
+        .unwrap_or((0, 0.0, 0));
+    output.text(format!(
+        "  {} | {} | {} steps | {:.1} kcal | {} m",
+        format_duration(session.elapsed()),
+        heart_rate,
+        steps,
+        calories,
+        distance
+    ));
+}
+
+fn print_workout_summary(output: &Output, summary: &WorkoutSummary) {
+    let calendar = Calendar::load();
+    output.text(format!(
+        "Workout ({}) {} → {}: {}",
+        summary.sport_type.label(),
+        calendar.format(summary.started_at, "%H:%M"),
+        calendar.format(summary.ended_at, "%H:%M"),
+        format_duration(summary.duration)
+    ));
+    match (summary.avg_heart_rate, summary.max_heart_rate) {
+        (Some(avg), Some(max)) => {
+            output.text(format!("  Heart rate: avg {avg} bpm, max {max} bpm"))
+        }
+        _ => output.text("  Heart rate: no readings"),
+    }
+    output.text(format!(
+        "  {} steps | {:.1} kcal | {} m",
+        summary.steps, summary.calories, summary.distance
+    ));
+    for (zone, time) in summary.time_in_zones.iter().enumerate() {
+        let label = if zone == 0 {
+            "Below zones".to_string()
+        } else {
+            format!("Zone {zone}")
+        };
+        output.text(format!("  {label}: {}", format_duration(*time)));
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs % 3600 / 60, secs % 60)
+    } else {
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+pub async fn sync(output: &Output) {
+    let mut store = match Store::open_default() {
+        Ok(store) => store,
+        Err(err) => {
+            output.error(&err);
+            return;
+        }
+    };
+
     match filter_devices(true).await {
         Ok(devices) => {
-            println!("Found {} device(s):", devices.len());
+            output.text(format!("Found {} device(s):", devices.len()));
 
-            if let Some(selected_device) = tui::select_device(devices) {
+            if let Some(selected_device) = choose_device(output, devices) {
+                match DeviceManager::connect_and_setup(&selected_device).await {
+                    Ok(conn) => {
+                        let device_id = selected_device.id();
+                        let started = std::time::Instant::now();
+                        let mut total = 0;
+
+                        if let Ok(battery) = DeviceManager::get_battery_level(&conn).await {
+                            warn_if_unsaved(
+                                output,
+                                store.save_battery(device_id, Utc::now(), &battery),
+                            );
+                        }
+                        if let Ok((firmware, hardware, manufacturer)) =
+                            DeviceManager::get_device_info(&conn).await
+                        {
+                            let info = DeviceInfo {
+                                name: selected_device.name().to_string(),
+                                manufacturer: Some(manufacturer),
+                                firmware: Some(firmware),
+                                hardware: Some(hardware),
+                            };
+                            warn_if_unsaved(output, store.save_device_info(device_id, &info));
+                        }
+
+                        for metric in SyncMetric::ALL {
+                            match sync_metric(&conn, device_id, &mut store, metric).await {
+                                Ok(MetricSync {
+                                    metric,
+                                    days,
+                                    records,
+                                    skipped,
+                                    incomplete,
+                                }) => {
+                                    total += records;
+                                    output.text(format!(
+                                        "{:<10} {records} records written from {days} day(s), {skipped} already synced",
+                                        metric.label()
+                                    ));
+                                    if incomplete > 0 {
+                                        output.warning(format!(
+                                            "{}: {incomplete} past day(s) came back without data; the next sync asks again",
+                                            metric.label()
+                                        ));
+                                    }
+                                    output.record(&SyncRecord {
+                                        device: device_id.to_string(),
+                                        metric: metric.key(),
+                                        days,
+                                        records,
+                                        skipped,
+                                    });
+                                }
+                                Err(err) => output.error_in(metric.label(), &err),
+                            }
+                        }
+
+                        output.text(format!(
+                            "Synced in {:.1}s, {total} records written",
+                            started.elapsed().as_secs_f64()
+                        ));
+                    }
+                    Err(err) => output.error(&err),
+                }
+            }
+        }
+        Err(err) => output.error(&err),
+    }
+}
+
+pub fn schema(output: &Output) {
+    match serde_json::to_string_pretty(&model::json_schema()) {
+        Ok(schema) => println!("{schema}"),
+        Err(err) => output.error(&CliError::Output(err.to_string())),
+    }
+}
+
+pub fn export_csv(
+    output: &Output,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    metrics: &[ExportMetric],
+    dir: &Path,
+) {
+    let calendar = Calendar::load();
+    let Some((from, to)) = export_days(output, &calendar, from, to) else {
+        return;
+    };
+    let metrics = if metrics.is_empty() {
+        &ExportMetric::ALL[..]
+    } else {
+        metrics
+    };
+
+    match Store::open_default() {
+        Ok(store) => {
+            output.text(format!("Days {from} to {to} in {}", calendar.name()));
+            for &metric in metrics {
+                match export::csv::export_metric(&store, &calendar, metric, from, to, dir) {
+                    Ok(export) => {
+                        output.text(format!(
+                            "{:<10} {} rows → {}",
+                            metric.label(),
+                            export.rows,
+                            export.path.display()
+                        ));
+                        output.record(&ExportRecord {
+                            format: "csv",
+                            metric: metric.key(),
+                            path: export.path.display().to_string(),
+                            rows: export.rows,
+                        });
+                    }
+                    Err(err) => output.error_in(metric.label(), &err),
+                }
+            }
+        }
+        Err(err) => output.error(&err),
+    }
+}
+
+pub fn export_fhir(output: &Output, args: &HistoryArgs, patient: Option<&str>, out: &Path) {
+    with_history(output, args, |_, _, history| {
+        let bundle = fhir::bundle(&history, patient);
+        match export::write_json(&bundle, out, fhir::validate(&bundle)) {
+            Ok(()) => {
+                let rows = bundle.observation_count();
+                output.text(format!("Wrote {rows} observations → {}", out.display()));
+                output.record(&ExportRecord {
+                    format: "fhir",
+                    metric: "all",
+                    path: out.display().to_string(),
+                    rows,
+                });
+            }
+            Err(err) => output.error(&err),
+        }
+    });
+}
+
+pub fn export_omh(output: &Output, args: &HistoryArgs, user: Option<&str>, out: &Path) {
+    with_history(output, args, |_, _, history| {
+        let points = omh::data_points(&history, &history.info.name, user);
+        match export::write_json(&points, out, omh::validate(&points)) {
+            Ok(()) => {
+                output.text(format!(
+                    "Wrote {} data points → {}",
+                    points.len(),
+                    out.display()
+                ));
+                output.record(&ExportRecord {
+                    format: "omh",
+                    metric: "all",
+                    path: out.display().to_string(),
+                    rows: points.len(),
+                });
+            }
+            Err(err) => output.error(&err),
+        }
+    });
+}
+
+pub async fn export_influx(
+    output: &Output,
+    args: &HistoryArgs,
+    out: &influx::Target,
+    token: Option<&str>,
+) {
+    if let influx::Target::Stdout = out
+        && let Err(err) = output.give_up_stdout("--out -")
+    {
+        output.error(&err);
+        return;
+    }
+    let mut writer = match influx::LineWriter::open(out, token) {
+        Ok(writer) => writer,
+        Err(err) => {
+            output.error(&err);
+            return;
+        }
+    };
+
+    let Some(lines) = with_history(output, args, |_, _, history| {
+        influx::history_lines(&history)
+    }) else {
+        return;
+    };
+    match writer.write(&lines).await {
+        Ok(()) => {
+            output.text(format!("Wrote {} lines → {out}", lines.len()));
+            output.record(&ExportRecord {
+                format: "influx",
+                metric: "all",
+                path: out.to_string(),
+                rows: lines.len(),
+            });
+        }
+        Err(err) => output.error(&err),
+    }
+}
+
+pub fn export_edf(output: &Output, args: &HistoryArgs, hr: bool, spo2: bool, dir: &Path) {
+    with_history(output, args, |_, calendar, history| {
+        let mut signals = Vec::new();
+        if hr {
+            signals.push(edf::Signal::heart_rate(&history.heart_rate));
+        }
+        if spo2 {
+            signals.push(edf::Signal::oxygen(&history.oxygen));
+        }
+
+        if history.sleep.is_empty() {
+            output.text("No sleep recorded");
+        }
+        for session in &history.sleep {
+            let night = calendar.date_of(session.end);
+            let path = dir.join(format!("sleep_{night}.edf"));
+            let hypnogram = edf::hypnogram(session, &history.info.name, calendar, &signals);
+            match hypnogram.write(&path) {
+                Ok(()) => {
+                    output.text(format!(
+                        "{night}: {} stages → {}",
+                        hypnogram.stages,
+                        path.display()
+                    ));
+                    output.record(&ExportRecord {
+                        format: "edf",
+                        metric: "sleep",
+                        path: path.display().to_string(),
+                        rows: hypnogram.stages,
+                    });
+                }
+                Err(err) => output.error_in(&night.to_string(), &err),
+            }
+        }
+    });
+}
+
+pub fn export_ics(output: &Output, args: &HistoryArgs, out: &Path) {
+    with_history(output, args, |store, calendar, history| {
+        let device = history.device.as_str();
+        let (start, end) = (history.start, history.end);
+        let mut ics = ics::Ics::new(calendar);
+
+        for session in &history.sleep {
+            ics.sleep(device, session);
+        }
+        match store.workouts(start, end) {
+            Ok(workouts) => {
+                for stored in workouts.iter().filter(|w| w.device == device) {
+                    ics.workout(device, &stored.summary);
+                }
+            }
+            Err(err) => output.warning(err),
+        }
+        match store.realtime_sessions(start, end) {
+            Ok(sessions) => {
+                for session in sessions.iter().filter(|s| s.device == device) {
+                    ics.realtime_session(session);
+                }
+            }
+            Err(err) => output.warning(err),
+        }
+
+        match ics.write(out) {
+            Ok(()) => {
+                let rows = ics.events();
+                output.text(format!("Wrote {rows} events → {}", out.display()));
+                output.record(&ExportRecord {
+                    format: "ics",
+                    metric: "sessions",
+                    path: out.display().to_string(),
+                    rows,
+                });
+            }
+            Err(err) => output.error(&err),
+        }
+    });
+}
+
+pub fn import_gadgetbridge(output: &Output, path: &Path, device: Option<&str>) {
+    let gadgetbridge = match Gadgetbridge::open(path) {
+        Ok(gadgetbridge) => gadgetbridge,
+        Err(err) => return output.error(&err),
+    };
+    let mut store = match Store::open_default() {
+        Ok(store) => store,
+        Err(err) => return output.error(&err),
+    };
+    let devices = match device {
+        Some(address) => match gadgetbridge.device(address) {
+            Ok(Some(device)) => vec![device],
+            Ok(None) => {
+                return output.error(&GadgetbridgeError::UnknownDevice(address.to_string()));
+            }
+            Err(err) => return output.error(&err),
+        },
+        None => match gadgetbridge.colmi_devices() {
+            Ok(devices) => devices,
+            Err(err) => return output.error(&err),
+        },
+    };
+    if devices.is_empty() {
+        output.text("No Colmi samples in the Gadgetbridge database");
+    }
+
+    for device in devices {
+        let address = address_key(&device.identifier);
+        output.text(format!("{} ({address}):", device.name));
+        let samples = match gadgetbridge.read(&device, &address) {
+            Ok(samples) => samples,
+            Err(err) => {
+                output.error_in(&address, &err);
+                continue;
+            }
+        };
+        let results = [
+            (
+                "heart_rate",
+                samples.heart_rate.len(),
+                store.insert_heart_rates(&samples.heart_rate),
+            ),
+            (
+                "activity",
+                samples.activity.len(),
+                store.insert_activity_slots(&samples.activity),
+            ),
+            (
+                "sleep",
+                samples.sleep.len(),
+                store.insert_sleep(&samples.sleep),
+            ),
+            (
+                "spo2",
+                samples.oxygen.len(),
+                store.insert_oxygen_hours(&samples.oxygen),
+            ),
+        ];
+        for (metric, total, result) in results {
+            match result {
+                Ok(imported) => {
+                    output.text(format!(
+                        "  {metric}: {imported} imported, {} already stored",
+                        total - imported
+                    ));
+                    output.record(&ImportRecord {
+                        source: "gadgetbridge",
+                        device: address.clone(),
+                        metric,
+                        imported,
+                        skipped: total - imported,
+                    });
+                }
+                Err(err) => output.error_in(metric, &err),
+            }
+        }
+    }
+}
+
+pub fn export_gadgetbridge(output: &Output, path: &Path, device: Option<&str>) {
+    let mut gadgetbridge = match Gadgetbridge::open(path) {
+        Ok(gadgetbridge) => gadgetbridge,
+        Err(err) => return output.error(&err),
+    };
+    let store = match Store::open_default() {
+        Ok(store) => store,
+        Err(err) => return output.error(&err),
+    };
+    let addresses = match device {
+        Some(address) => vec![address_key(address)],
+        None => match store.devices() {
+            Ok(devices) => devices,
+            Err(err) => return output.error(&err),
+        },
+    };
+
+    let (start, end) = (DateTime::<Utc>::UNIX_EPOCH, DateTime::<Utc>::MAX_UTC);
+    for address in addresses {
+        let target = match gadgetbridge.device(&address) {
+            Ok(Some(target)) => target,
+            Ok(None) if device.is_some() => {
+                output.error(&GadgetbridgeError::UnknownDevice(address));
+                continue;
+            }
+            Ok(None) => {
+                output.warning(format!("{address} is not paired in Gadgetbridge, skipped"));
+                continue;
+            }
+            Err(err) => {
+                output.error(&err);
+                continue;
+            }
+        };
+        let read = || -> Result<Samples, StoreError> {
+            let mine = |stored: &String| stored.eq_ignore_ascii_case(&address);
+            Ok(Samples {
+                heart_rate: store
+                    .heart_rate_samples(start, end)?
+                    .into_iter()
+                    .filter(|s| mine(&s.device))
+                    .collect(),
+                activity: store
+                    .activity_slots(start, end)?
+                    .into_iter()
+                    .filter(|s| mine(&s.device))
+                    .collect(),
+                sleep: store
+                    .sleep_sessions(start, end)?
+                    .into_iter()
+                    .filter(|s| mine(&s.device))
+                    .collect(),
+                oxygen: store
+                    .oxygen_hours(start, end)?
+                    .into_iter()
+                    .filter(|s| mine(&s.device))
+                    .collect(),
+            })
+        };
+        let samples = match read() {
+            Ok(samples) => samples,
+            Err(err) => {
+                output.error_in(&address, &err);
+                continue;
+            }
+        };
+
+        match gadgetbridge.write(&target, &samples) {
+            Ok(transfers) => {
+                output.text(format!("{} ({address}):", target.name));
+                for transfer in transfers {
+                    output.text(format!(
+                        "  {}: {} exported, {} already in Gadgetbridge",
+                        transfer.metric, transfer.inserted, transfer.skipped
+                    ));
+                    output.record(&ExportRecord {
+                        format: "gadgetbridge",
+                        metric: transfer.metric,
+                        path: path.display().to_string(),
+                        rows: transfer.inserted,
+                    });
+                }
+            }
+            Err(err) => output.error_in(&address, &err),
+        }
+    }
+}
+
+pub fn export_fit(output: &Output, args: &HistoryArgs, dir: &Path) {
+    with_history(output, args, |store, calendar, history| {
+        let stamp = |instant| calendar.format(instant, "%Y%m%d-%H%M%S");
+        let (start, end) = (history.start, history.end);
+        let mut files = vec![(
+            "monitoring",
+            format!(
+                "monitoring_{}_{}.fit",
+                calendar.date_of(start),
+                calendar.date_of(end - chrono::Duration::seconds(1))
+            ),
+            fit::monitoring(&history, calendar),
+        )];
+
+        match store.workouts(start, end) {
+            Ok(workouts) => files.extend(
+                workouts
+                    .iter()
+                    .filter(|stored| stored.device == history.device)
+                    .map(|stored| {
+                        let summary = &stored.summary;
+                        (
+                            "workout",
+                            format!(
+                                "workout_{}_{}.fit",
+                                summary.sport_type.label(),
+                                stamp(summary.started_at)
+                            ),
+                            fit::workout(&stored.device, summary, calendar),
+                        )
+                    }),
+            ),
+            Err(err) => output.warning(err),
+        }
+        match store.realtime_sessions(start, end) {
+            Ok(sessions) => files.extend(
+                sessions
+                    .iter()
+                    .filter(|session| session.device == history.device)
+                    .map(|session| {
+                        (
+                            "realtime",
+                            format!("realtime_{}.fit", stamp(session.started_at)),
+                            fit::realtime_session(session, calendar),
+                        )
+                    }),
+            ),
+            Err(err) => output.warning(err),
+        }
+
+        for (metric, name, file) in files {
+            let path = dir.join(name);
+            match file.write(&path) {
+                Ok(()) => {
+                    let rows = file.messages();
+                    output.text(format!("{metric}: {rows} messages → {}", path.display()));
+                    output.record(&ExportRecord {
+                        format: "fit",
+                        metric,
+                        path: path.display().to_string(),
+                        rows,
+                    });
+                }
+                Err(err) => output.error_in(metric, &err),
+            }
+        }
+    });
+}
+
+pub async fn settings_hr(output: &Output, enable: bool, disable: bool, interval: Option<u8>) {
+    match filter_devices(true).await {
+        Ok(devices) => {
+            output.text(format!("Found {} device(s):", devices.len()));
+
+            if let Some(selected_device) = choose_device(output, devices) {
                 match DeviceManager::connect_and_setup(&selected_device).await {
                     Ok(conn) => {
                         if enable || disable {
@@ -403,36 +1516,50 @@ pub async fn settings_hr(enable: bool, disable: bool, interval: Option<u8>) {
                             )
                             .await
                             {
-                                Ok(_) => println!(
-                                    "Heart-rate logging set: enabled={enable} interval={interval}m"
-                                ),
-                                Err(err) => println!("{err}"),
+                                Ok(_) => {
+                                    output.text(format!(
+                                        "Heart-rate logging set: enabled={enable} interval={interval}m"
+                                    ));
+                                    output.record(&HeartRateSettingsRecord {
+                                        device: selected_device.id().to_string(),
+                                        enabled: enable,
+                                        interval,
+                                    });
+                                }
+                                Err(err) => output.error(&err),
                             }
                         } else {
                             match DeviceManager::get_heart_rate_log_settings(&conn).await {
-                                Ok(HeartRateLogSettings { enabled, interval }) => println!(
-                                    "Heart-rate logging: {} | interval: {} minutes",
-                                    if enabled { "enabled" } else { "disabled" },
-                                    interval
-                                ),
-                                Err(err) => println!("{err}"),
+                                Ok(HeartRateLogSettings { enabled, interval }) => {
+                                    output.text(format!(
+                                        "Heart-rate logging: {} | interval: {} minutes",
+                                        if enabled { "enabled" } else { "disabled" },
+                                        interval
+                                    ));
+                                    output.record(&HeartRateSettingsRecord {
+                                        device: selected_device.id().to_string(),
+                                        enabled,
+                                        interval,
+                                    });
+                                }
+                                Err(err) => output.error(&err),
                             }
                         }
                     }
-                    Err(err) => println!("{err}"),
+                    Err(err) => output.error(&err),
                 }
             }
         }
-        Err(err) => println!("{err}"),
+        Err(err) => output.error(&err),
     }
 }
 
-pub async fn reset() {
+pub async fn reset(output: &Output) {
     match filter_devices(true).await {
         Ok(devices) => {
-            println!("Found {} device(s):", devices.len());
+            output.text(format!("Found {} device(s):", devices.len()));
 
-            if let Some(selected_device) = tui::select_device(devices) {
+            if let Some(selected_device) = choose_device(output, devices) {
                 match DeviceManager::connect_and_setup(&selected_device).await {
                     Ok(conn) => {
                         match Confirm::new("This will reset the device. Continue?")
@@ -440,62 +1567,559 @@ pub async fn reset() {
                             .prompt()
                         {
                             Ok(true) => match DeviceManager::reset(&conn).await {
-                                Ok(_) => (),
+                                Ok(_) => {
+                                    output.text("Ring reset to factory settings");
+                                    output.record(&ActionRecord {
+                                        device: selected_device.id().to_string(),
+                                        action: "reset",
+                                        status: "done",
+                                    });
+                                }
                                 Err(err) => {
-                                    println!("{err}");
+                                    output.error(&err);
                                 }
                             },
                             Ok(false) => {
-                                println!("Reset cancelled.");
+                                output.text("Reset cancelled.");
+                                output.record(&ActionRecord {
+                                    device: selected_device.id().to_string(),
+                                    action: "reset",
+                                    status: "cancelled",
+                                });
                             }
                             Err(err) => {
-                                println!("{err}");
+                                output.error(&CliError::from(err));
                             }
                         }
                     }
-                    Err(err) => println!("{err}"),
+                    Err(err) => output.error(&err),
                 }
             }
         }
-        Err(err) => println!("{err}"),
+        Err(err) => output.error(&err),
     }
 }
 
-pub async fn reboot() {
+pub async fn reboot(output: &Output) {
     match filter_devices(true).await {
         Ok(devices) => {
-            println!("Found {} device(s):", devices.len());
+            output.text(format!("Found {} device(s):", devices.len()));
 
-            if let Some(selected_device) = tui::select_device(devices) {
+            if let Some(selected_device) = choose_device(output, devices) {
                 match DeviceManager::connect_and_setup(&selected_device).await {
                     Ok(conn) => match DeviceManager::reboot(&conn).await {
-                        Ok(_) => (),
-                        Err(err) => println!("{err}"),
+                        Ok(_) => {
+                            output.text("Ring is rebooting");
+                            output.record(&ActionRecord {
+                                device: selected_device.id().to_string(),
+                                action: "reboot",
+                                status: "done",
+                            });
+                        }
+                        Err(err) => output.error(&err),
                     },
-                    Err(err) => println!("{err}"),
+                    Err(err) => output.error(&err),
                 }
             }
         }
-        Err(err) => println!("{err}"),
+        Err(err) => output.error(&err),
     }
 }
 
-pub async fn find() {
+pub async fn find(output: &Output) {
     match filter_devices(true).await {
         Ok(devices) => {
-            println!("Found {} device(s):", devices.len());
+            output.text(format!("Found {} device(s):", devices.len()));
 
-            if let Some(selected_device) = tui::select_device(devices) {
+            if let Some(selected_device) = choose_device(output, devices) {
                 match DeviceManager::connect_and_setup(&selected_device).await {
                     Ok(conn) => match DeviceManager::find(&conn).await {
-                        Ok(_) => (),
-                        Err(err) => println!("{err}"),
+                        Ok(_) => {
+                            output.text("Find signal sent to the ring");
+                            output.record(&ActionRecord {
+                                device: selected_device.id().to_string(),
+                                action: "find",
+                                status: "done",
+                            });
+                        }
+                        Err(err) => output.error(&err),
                     },
-                    Err(err) => println!("{err}"),
+                    Err(err) => output.error(&err),
                 }
             }
         }
-        Err(err) => println!("{err}"),
+        Err(err) => output.error(&err),
+    }
+}
+
+pub async fn serve_api(
+    output: &Output,
+    listen: &str,
+    selectors: &[String],
+    schedule: &ScheduleArgs,
+) {
+    if let Err(err) = check_schedule(schedule) {
+        output.error(&err);
+        return;
+    }
+    let listener = match tokio::net::TcpListener::bind(listen).await {
+        Ok(listener) => listener,
+        Err(source) => {
+            output.error(&ServeError::Bind {
+                address: listen.to_string(),
+                source,
+            });
+            return;
+        }
+    };
+
+    match filter_devices(true).await {
+        Ok(devices) => {
+            output.text(format!("Found {} device(s):", devices.len()));
+
+            if let Some(selected_devices) = select_devices(output, devices, selectors) {
+                let several = selected_devices.len() > 1;
+                let cancel = cancel_on_ctrl_c();
+                let mut rings = Vec::new();
+                let mut pollers = Vec::new();
+                for selected_device in selected_devices {
+                    let conn = match DeviceManager::connect_and_setup(&selected_device).await {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            output.error_in(selected_device.name(), &err);
+                            continue;
+                        }
+                    };
+                    let state = SharedState::default();
+                    let (commands_tx, commands) = mpsc::channel(8);
+                    rings.push(serve::api::Ring {
+                        address: selected_device.id().to_string(),
+                        state: state.clone(),
+                        commands: commands_tx,
+                    });
+                    output.text(format!("Serving {selected_device}"));
+
+                    let ring = several.then(|| selected_device.name().to_string());
+                    let poller = poller(output, selected_device, &state, schedule);
+                    let cancel = cancel.clone();
+                    pollers.push(async move {
+                        poller
+                            .run(
+                                conn,
+                                commands,
+                                |event| print_poll_event(output, ring.as_deref(), event),
+                                cancel,
+                            )
+                            .await
+                    });
+                }
+                if rings.is_empty() {
+                    return;
+                }
+
+                let shutdown = cancel.clone();
+                let router = serve::api::router(rings, cancel.clone());
+                let server = axum::serve(listener, router)
+                    .with_graceful_shutdown(async move { shutdown.cancelled().await });
+                let server_task = tokio::spawn(async move { server.await });
+                output.text(format!("API on http://{listen} (Ctrl-C to stop)"));
+
+                futures_util::future::join_all(pollers).await;
+                cancel.cancel();
+
+                match server_task.await {
+                    Ok(Ok(())) => output.text("Stopped serving"),
+                    Ok(Err(err)) => output.error(&ServeError::Server(err)),
+                    Err(err) => output.error(&CliError::TaskFailed(err)),
+                }
+            }
+        }
+        Err(err) => output.error(&err),
+    }
+}
+
+pub async fn serve_metrics(
+    output: &Output,
+    listen: &str,
+    device: Option<&str>,
+    schedule: &ScheduleArgs,
+) {
+    if let Err(err) = check_schedule(schedule) {
+        output.error(&err);
+        return;
+    }
+    let listener = match tokio::net::TcpListener::bind(listen).await {
+        Ok(listener) => listener,
+        Err(source) => {
+            output.error(&ServeError::Bind {
+                address: listen.to_string(),
+                source,
+            });
+            return;
+        }
+    };
+
+    match filter_devices(true).await {
+        Ok(devices) => {
+            output.text(format!("Found {} device(s):", devices.len()));
+
+            if let Some(selected_device) = select_device(output, devices, device) {
+                match DeviceManager::connect_and_setup(&selected_device).await {
+                    Ok(conn) => {
+                        let state = SharedState::default();
+                        let cancel = cancel_on_ctrl_c();
+                        let shutdown = cancel.clone();
+                        let server = axum::serve(listener, serve::metrics::router(state.clone()))
+                            .with_graceful_shutdown(async move { shutdown.cancelled().await });
+                        let server_task = tokio::spawn(async move { server.await });
+                        output.text(format!(
+                            "Serving metrics for {selected_device} on http://{listen}/metrics (Ctrl-C to stop)"
+                        ));
+
+                        let poller = poller(output, selected_device, &state, schedule);
+                        // Metrics are read-only, so nothing sends commands.
+                        let (_, commands) = mpsc::channel(1);
+                        poller
+                            .run(
+                                conn,
+                                commands,
+                                |event| print_poll_event(output, None, event),
+                                cancel.clone(),
+                            )
+                            .await;
+                        cancel.cancel();
+
+                        match server_task.await {
+                            Ok(Ok(())) => output.text("Stopped serving metrics"),
+                            Ok(Err(err)) => output.error(&ServeError::Server(err)),
+                            Err(err) => output.error(&CliError::TaskFailed(err)),
+                        }
+                    }
+                    Err(err) => output.error(&err),
+                }
+            }
+        }
+        Err(err) => output.error(&err),
+    }
+}
+
+pub async fn serve_mqtt(
+    output: &Output,
+    args: &BrokerArgs,
+    selectors: &[String],
+    schedule: &ScheduleArgs,
+) {
+    if let Err(err) = check_schedule(schedule) {
+        output.error(&err);
+        return;
+    }
+    let broker = args.broker.as_str();
+    let Some((host, port)) = broker
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host.to_string(), port.parse().ok()?)))
+    else {
+        output.error(&CliError::InvalidArgument(format!(
+            "Invalid broker '{broker}'. Use host:port, e.g. localhost:1883."
+        )));
+        return;
+    };
+    let options = mqtt::Broker {
+        host,
+        port,
+        username: args.username.clone(),
+        password: args.password.clone(),
+        prefix: args.prefix.clone(),
+        discovery_prefix: args.discovery_prefix.clone(),
+    };
+
+    match filter_devices(true).await {
+        Ok(devices) => {
+            output.text(format!("Found {} device(s):", devices.len()));
+
+            if let Some(selected_devices) = select_devices(output, devices, selectors) {
+                let several = selected_devices.len() > 1;
+                let cancel = cancel_on_ctrl_c();
+                let mut rings = Vec::new();
+                for selected_device in selected_devices {
+                    let conn = match DeviceManager::connect_and_setup(&selected_device).await {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            output.error_in(selected_device.name(), &err);
+                            continue;
+                        }
+                    };
+                    let state = SharedState::default();
+                    let bridge = mqtt::Bridge::connect(
+                        options.clone(),
+                        selected_device.id(),
+                        selected_device.name(),
+                    );
+                    output.text(format!(
+                        "Publishing {selected_device} to {broker} (Ctrl-C to stop)"
+                    ));
+
+                    // Events only name their ring when there is more than one.
+                    let ring = several.then(|| selected_device.name().to_string());
+                    let poller = poller(output, selected_device, &state, schedule);
+                    let (commands_tx, commands) = mpsc::channel(8);
+                    let cancel = cancel.clone();
+                    rings.push(async move {
+                        let ring = ring.as_deref();
+                        tokio::join!(
+                            poller.run(
+                                conn,
+                                commands,
+                                |event| print_poll_event(output, ring, event),
+                                cancel.clone(),
+                            ),
+                            bridge.run(
+                                state,
+                                commands_tx,
+                                |event| print_bridge_event(output, ring, broker, event),
+                                cancel,
+                            ),
+                        );
+                    });
+                }
+                if !rings.is_empty() {
+                    futures_util::future::join_all(rings).await;
+                    output.text("Stopped publishing");
+                }
+            }
+        }
+        Err(err) => output.error(&err),
+    }
+}
+
+fn check_schedule(schedule: &ScheduleArgs) -> Result<(), CliError> {
+    if schedule.interval == 0 {
+        return Err(CliError::InvalidArgument(
+            "--interval must be at least 1 second".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// A poller for `device`; history is only synced when the store opens.
+fn poller(output: &Output, device: Device, state: &SharedState, args: &ScheduleArgs) -> Poller {
+    let schedule = Schedule {
+        status: Duration::from_secs(args.interval),
+        sample: Some(Duration::from_secs(args.sample_interval)),
+        sync: Some(Duration::from_secs(args.sync_interval)),
+    };
+    let store = if args.sync_interval > 0 {
+        open_store(output)
+    } else {
+        None
+    };
+    Poller::new(device, state.clone(), schedule, store)
+}
+
+/// `ring` names the ring the event is about when a daemon serves several.
+fn print_poll_event(output: &Output, ring: Option<&str>, event: PollEvent) {
+    let ring = ring.map(|name| format!("{name}: ")).unwrap_or_default();
+    match event {
+        PollEvent::Failed { task, error } => output.warning(format!("{ring}{task}: {error}")),
+        PollEvent::Done { command } => output.text(format!("  {ring}{} done", command.label())),
+        PollEvent::Synced { records } => {
+            output.text(format!("  {ring}Synced, {records} records written"))
+        }
+        PollEvent::Reconnecting { attempt } => output.text(format!(
+            "  {ring}Connection lost, reconnecting (attempt {attempt})..."
+        )),
+        PollEvent::Reconnected => output.text(format!("  {ring}Reconnected")),
+    }
+}
+
+fn print_bridge_event(output: &Output, ring: Option<&str>, broker: &str, event: BridgeEvent) {
+    let ring = ring.map(|name| format!("{name}: ")).unwrap_or_default();
+    match event {
+        BridgeEvent::Connected => output.text(format!("  {ring}Connected to {broker}")),
+        BridgeEvent::ConnectionLost(err) => output.warning(format!("{ring}MQTT: {err}")),
+        BridgeEvent::Command(command) => {
+            output.text(format!("  {ring}{} requested", command.label()))
+        }
+        BridgeEvent::Ignored { topic, payload } => output.warning(format!(
+            "{ring}Ignoring unknown command '{payload}' on {topic}"
+        )),
+    }
+}
+
+/// Readings are still printed when the local store is unavailable; it only
+/// produces a warning.
+fn open_store(output: &Output) -> Option<Store> {
+    match Store::open_default() {
+        Ok(store) => Some(store),
+        Err(err) => {
+            output.warning(err);
+            None
+        }
+    }
+}
+
+fn warn_if_unsaved<T>(output: &Output, result: Result<T, StoreError>) {
+    if let Err(err) = result {
+        output.warning(format!("Not saved: {err}"));
+    }
+}
+
+/// Token that is cancelled on Ctrl-C, so streaming commands can stop the
+/// ring's sensors before exiting instead of being killed mid-measurement.
+fn cancel_on_ctrl_c() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let token = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            token.cancel();
+        }
+    });
+    cancel
+}
+
+/// Lets the user pick one of `devices`. A prompt that fails, e.g. without a
+/// terminal, or is cancelled is reported as the command's error.
+fn choose_device(output: &Output, devices: Vec<Device>) -> Option<Device> {
+    match tui::select_device(devices) {
+        Ok(device) => Some(device),
+        Err(err) => {
+            output.error(&err);
+            None
+        }
+    }
+}
+
+/// The ring `selector` names, or the user's pick without one. Daemons take
+/// a selector so they can start unattended; one that matches no ring, or
+/// several, is the command's error rather than a reason to prompt.
+fn select_device(output: &Output, devices: Vec<Device>, selector: Option<&str>) -> Option<Device> {
+    let Some(selector) = selector else {
+        return choose_device(output, devices);
+    };
+    match find_device(&devices, selector) {
+        Ok(device) => Some(device.clone()),
+        Err(err) => {
+            output.error(&err);
+            None
+        }
+    }
+}
+
+/// Like [`select_device`], for any number of selectors; a ring named twice
+/// is served once.
+fn select_devices(
+    output: &Output,
+    devices: Vec<Device>,
+    selectors: &[String],
+) -> Option<Vec<Device>> {
+    if selectors.is_empty() {
+        return choose_device(output, devices).map(|device| vec![device]);
+    }
+    let mut selected: Vec<Device> = Vec::new();
+    for selector in selectors {
+        match find_device(&devices, selector) {
+            Ok(device) if selected.iter().any(|d| d.id() == device.id()) => {}
+            Ok(device) => selected.push(device.clone()),
+            Err(err) => {
+                output.error(&err);
+                return None;
+            }
+        }
+    }
+    Some(selected)
+}
+
+/// Matches by address first, then by name, ignoring case.
+fn find_device<'a>(devices: &'a [Device], selector: &str) -> Result<&'a Device, ScanError> {
+    let address = address_key(selector);
+    if let Some(device) = devices
+        .iter()
+        .find(|d| d.id().eq_ignore_ascii_case(&address))
+    {
+        return Ok(device);
+    }
+    let mut named = devices
+        .iter()
+        .filter(|d| d.name().eq_ignore_ascii_case(selector));
+    match (named.next(), named.next()) {
+        (Some(device), None) => Ok(device),
+        (Some(_), Some(_)) => Err(ScanError::AmbiguousDevice(selector.to_string())),
+        (None, _) => Err(ScanError::DeviceNotFound(selector.to_string())),
+    }
+}
+
+/// Local days `from` through `to`; by default the week up to today.
+fn export_days(
+    output: &Output,
+    calendar: &Calendar,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+) -> Option<(NaiveDate, NaiveDate)> {
+    let to = to.unwrap_or_else(|| calendar.today());
+    let from = from.unwrap_or(to - chrono::Duration::days(SYNC_LOOKBACK_DAYS - 1));
+    if from > to {
+        output.error(&CliError::InvalidArgument(format!(
+            "--from {from} is after --to {to}"
+        )));
+        return None;
+    }
+    Some((from, to))
+}
+
+/// Reads one ring's history for the requested local days from the local
+/// store and hands it to `export`. Without `--device` the store must hold
+/// exactly one ring. Store and argument errors are reported here.
+fn with_history<R>(
+    output: &Output,
+    args: &HistoryArgs,
+    export: impl FnOnce(&Store, &Calendar, History) -> R,
+) -> Option<R> {
+    let calendar = Calendar::load();
+    let (from, to) = export_days(output, &calendar, args.from, args.to)?;
+    let store = match Store::open_default() {
+        Ok(store) => store,
+        Err(err) => {
+            output.error(&err);
+            return None;
+        }
+    };
+    let device = match &args.device {
+        Some(device) => address_key(device),
+        None => match store.devices() {
+            Ok(devices) if devices.len() == 1 => devices[0].clone(),
+            Ok(devices) if devices.is_empty() => {
+                output.error(&CliError::InvalidArgument(
+                    "The local store has no history yet; run `colmi_client sync` first."
+                        .to_string(),
+                ));
+                return None;
+            }
+            Ok(devices) => {
+                output.error(&CliError::InvalidArgument(format!(
+                    "The local store has history of {}; pick one with --device.",
+                    devices.join(", ")
+                )));
+                return None;
+            }
+            Err(err) => {
+                output.error(&err);
+                return None;
+            }
+        },
+    };
+
+    let (start, end) = (calendar.day_start(from), calendar.day_range(to).1);
+    match History::load(&store, &device, start, end) {
+        Ok(history) => {
+            output.text(format!(
+                "{device}: days {from} to {to} in {}",
+                calendar.name()
+            ));
+            Some(export(&store, &calendar, history))
+        }
+        Err(err) => {
+            output.error(&err);
+            None
+        }
     }
 }
 
@@ -0,0 +1,588 @@
+use std::cell::{Cell, RefCell};
+use std::fmt::Display;
+use std::io::Write;
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{Value, json};
+
+use crate::error::{CliError, Failure};
+use crate::model::Measurement;
+use crate::protocol::workout::WorkoutSummary;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human-readable text.
+    #[default]
+    Text,
+    /// One JSON array of records, printed when the command finishes.
+    Json,
+    /// One JSON object per line, printed as records arrive.
+    Ndjson,
+    /// CSV with a header row.
+    Csv,
+}
+
+/// A row of machine-readable output. `COLUMNS` lists the serialized field
+/// names in CSV column order, including fields JSON may omit.
+pub trait Record: Serialize {
+    const COLUMNS: &'static [&'static str];
+}
+
+/// Stream records are written to: whole lines for JSON and NDJSON, one
+/// writer for all CSV rows.
+enum Records {
+    Lines(Box<dyn Write>),
+    Csv(Box<csv::Writer<Box<dyn Write>>>),
+}
+
+/// Where a command's results and errors go. Text mode keeps the familiar
+/// human output; the other formats print only records on stdout and report
+/// errors and warnings as JSON objects on stderr.
+pub struct Output {
+    format: OutputFormat,
+    json: RefCell<Vec<Value>>,
+    records: RefCell<Records>,
+    csv_columns: Cell<Option<&'static [&'static str]>>,
+    exit_code: Cell<i32>,
+    /// Set once something else writes to stdout; text then goes to stderr.
+    stdout_taken: Cell<bool>,
+}
+
+impl Output {
+    pub fn new(format: OutputFormat) -> Self {
+        Self::to(format, Box::new(std::io::stdout()))
+    }
+
+    /// Output whose records go to `records` instead of stdout.
+    fn to(format: OutputFormat, records: Box<dyn Write>) -> Self {
+        Self {
+            format,
+            json: RefCell::new(Vec::new()),
+            records: RefCell::new(match format {
+                // Record types differ in width, and each one gets its own header.
+                OutputFormat::Csv => Records::Csv(Box::new(
+                    csv::WriterBuilder::new()
+                        .flexible(true)
+                        .from_writer(records),
+                )),
+                _ => Records::Lines(records),
+            }),
+            csv_columns: Cell::new(None),
+            exit_code: Cell::new(0),
+            stdout_taken: Cell::new(false),
+        }
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.format == OutputFormat::Text
+    }
+
+    /// A line of human-readable output; ignored by the machine formats.
+    pub fn text(&self, line: impl Display) {
+        match (self.is_text(), self.stdout_taken.get()) {
+            (false, _) => {}
+            (true, false) => println!("{line}"),
+            (true, true) => eprintln!("{line}"),
+        }
+    }
+
+    /// Hands stdout to `owner`, e.g. line protocol written to `-`, and moves
+    /// human output to stderr. Records need stdout themselves, so only text
+    /// mode can share it.
+    pub fn give_up_stdout(&self, owner: &str) -> Result<(), CliError> {
+        if let Some(format) = self.format.to_possible_value().filter(|_| !self.is_text()) {
+            return Err(CliError::InvalidArgument(format!(
+                "{owner} and --format {} both need stdout; write it to a file or URL instead.",
+                format.get_name()
+            )));
+        }
+        self.stdout_taken.set(true);
+        Ok(())
+    }
+
+    pub fn record<R: Record>(&self, record: &R) {
+        let value = match serde_json::to_value(record) {
+            Ok(value) => value,
+            Err(err) => {
+                self.error(&CliError::Output(err.to_string()));
+                return;
+            }
+        };
+        match self.format {
+            OutputFormat::Text => {}
+            OutputFormat::Json => self.json.borrow_mut().push(value),
+            OutputFormat::Ndjson => self.write_line(&value),
+            OutputFormat::Csv => self.csv_row(R::COLUMNS, &value),
+        }
+    }
+
+    pub fn records<R: Record>(&self, records: &[R]) {
+        for record in records {
+            self.record(record);
+        }
+    }
+
+    /// Reports a failure. The first one decides the exit code.
+    pub fn error(&self, err: &dyn Failure) {
+        self.report(None, err);
+    }
+
+    /// Reports a failure of one part of a command, e.g. a single metric of
+    /// a sync.
+    pub fn error_in(&self, context: &str, err: &dyn Failure) {
+        self.report(Some(context), err);
+    }
+
+    /// A problem that does not fail the command.
+    pub fn warning(&self, message: impl Display) {
+        match self.format {
+            OutputFormat::Text if self.stdout_taken.get() => eprintln!("⚠ {message}"),
+            OutputFormat::Text => println!("⚠ {message}"),
+            _ => eprintln!("{}", json!({ "warning": message.to_string() })),
+        }
+    }
+
+    /// Flushes buffered JSON and returns the process exit code.
+    pub fn finish(&self) -> i32 {
+        if self.format == OutputFormat::Json {
+            match serde_json::to_string_pretty(&self.json.take()) {
+                Ok(json) => self.write_line(&json),
+                Err(err) => self.error(&CliError::Output(err.to_string())),
+            }
+        }
+        self.exit_code.get()
+    }
+
+    fn report(&self, context: Option<&str>, err: &dyn Failure) {
+        if self.exit_code.get() == 0 {
+            self.exit_code.set(err.exit_code());
+        }
+        match (self.format, context) {
+            (OutputFormat::Text, Some(context)) => eprintln!("{context}: {err}"),
+            (OutputFormat::Text, None) => eprintln!("{err}"),
+            _ => eprintln!(
+                "{}",
+                json!({
+                    "error": {
+                        "kind": err.kind(),
+                        "detail": err.detail(),
+                        "code": err.exit_code(),
+                        "context": context,
+                        "message": err.to_string(),
+                    }
+                })
+            ),
+        }
+    }
+
+    fn write_line(&self, line: &dyn Display) {
+        let result = match &mut *self.records.borrow_mut() {
+            Records::Lines(stream) => writeln!(stream, "{line}").and_then(|_| stream.flush()),
+            Records::Csv(_) => Ok(()),
+        };
+        if let Err(err) = result {
+            self.error(&CliError::Output(err.to_string()));
+        }
+    }
+
+    /// Writes one CSV row, preceded by a header whenever the record type
+    /// changes.
+    fn csv_row(&self, columns: &'static [&'static str], value: &Value) {
+        let mut records = self.records.borrow_mut();
+        let Records::Csv(writer) = &mut *records else {
+            return;
+        };
+        let mut result = Ok(());
+        if self.csv_columns.get() != Some(columns) {
+            self.csv_columns.set(Some(columns));
+            result = writer.write_record(columns);
+        }
+        let fields = columns.iter().map(|column| match &value[*column] {
+            Value::Null => String::new(),
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        });
+        if let Err(err) = result
+            .and_then(|_| writer.write_record(fields))
+            .and_then(|_| writer.flush().map_err(csv::Error::from))
+        {
+            drop(records);
+            self.error(&CliError::Output(err.to_string()));
+        }
+    }
+}
+
+impl Record for Measurement {
+    const COLUMNS: &'static [&'static str] = &[
+        "device",
+        "source",
+        "metric",
+        "unit",
+        "timestamp",
+        "end",
+        "value",
+        "min",
+        "max",
+        "label",
+    ];
+}
+
+#[derive(Serialize)]
+pub struct DeviceRecord {
+    pub name: String,
+    pub address: String,
+}
+
+impl Record for DeviceRecord {
+    const COLUMNS: &'static [&'static str] = &["name", "address"];
+}
+
+#[derive(Serialize)]
+pub struct InfoRecord {
+    pub device: String,
+    pub manufacturer: String,
+    pub firmware: String,
+    pub hardware: String,
+}
+
+impl Record for InfoRecord {
+    const COLUMNS: &'static [&'static str] = &["device", "manufacturer", "firmware", "hardware"];
+}
+
+/// Outcome of a command that only triggers something on the ring.
+#[derive(Serialize)]
+pub struct ActionRecord {
+    pub device: String,
+    pub action: &'static str,
+    /// `done` or `cancelled`.
+    pub status: &'static str,
+}
+
+impl Record for ActionRecord {
+    const COLUMNS: &'static [&'static str] = &["device", "action", "status"];
+}
+
+#[derive(Serialize)]
+pub struct HeartRateSettingsRecord {
+    pub device: String,
+    pub enabled: bool,
+    /// Minutes between logged samples.
+    pub interval: u8,
+}
+
+impl Record for HeartRateSettingsRecord {
+    const COLUMNS: &'static [&'static str] = &["device", "enabled", "interval"];
+}
+
+#[derive(Serialize)]
+pub struct SyncRecord {
+    pub device: String,
+    pub metric: &'static str,
+    pub days: usize,
+    pub records: usize,
+    pub skipped: usize,
+}
+
+impl Record for SyncRecord {
+    const COLUMNS: &'static [&'static str] = &["device", "metric", "days", "records", "skipped"];
+}
+
+/// A file written by one of the `export` commands.
+#[derive(Serialize)]
+pub struct ExportRecord {
+    pub format: &'static str,
+    pub metric: &'static str,
+    pub path: String,
+    pub rows: usize,
+}
+
+impl Record for ExportRecord {
+    const COLUMNS: &'static [&'static str] = &["format", "metric", "path", "rows"];
+}
+
+/// Samples brought into the local store from another app.
+#[derive(Serialize)]
+pub struct ImportRecord {
+    pub source: &'static str,
+    pub device: String,
+    pub metric: &'static str,
+    pub imported: usize,
+    /// Already in the store.
+    pub skipped: usize,
+}
+
+impl Record for ImportRecord {
+    const COLUMNS: &'static [&'static str] = &["source", "device", "metric", "imported", "skipped"];
+}
+
+/// Durations are in seconds.
+#[derive(Serialize)]
+pub struct WorkoutRecord {
+    pub device: String,
+    pub sport: &'static str,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub duration: u64,
+    pub avg_heart_rate: Option<u8>,
+    pub max_heart_rate: Option<u8>,
+    pub steps: u32,
+    pub calories: f64,
+    pub distance: u32,
+    pub below_zones: u64,
+    pub zone_1: u64,
+    pub zone_2: u64,
+    pub zone_3: u64,
+    pub zone_4: u64,
+    pub zone_5: u64,
+}
+
+impl WorkoutRecord {
+    pub fn new(device: &str, summary: &WorkoutSummary) -> Self {
+        let zones = summary.time_in_zones.map(|time| time.as_secs());
+        Self {
+            device: device.to_string(),
+            sport: summary.sport_type.label(),
+            started_at: summary.started_at,
+            ended_at: summary.ended_at,
+            duration: summary.duration.as_secs(),
+            avg_heart_rate: summary.avg_heart_rate,
+            max_heart_rate: summary.max_heart_rate,
+            steps: summary.steps,
+            calories: summary.calories,
+            distance: summary.distance,
+            below_zones: zones[0],
+            zone_1: zones[1],
+            zone_2: zones[2],
+            zone_3: zones[3],
+            zone_4: zones[4],
+            zone_5: zones[5],
+        }
+    }
+}
+
+impl Record for WorkoutRecord {
+    const COLUMNS: &'static [&'static str] = &[
+        "device",
+        "sport",
+        "started_at",
+        "ended_at",
+        "duration",
+        "avg_heart_rate",
+        "max_heart_rate",
+        "steps",
+        "calories",
+        "distance",
+        "below_zones",
+        "zone_1",
+        "zone_2",
+        "zone_3",
+        "zone_4",
+        "zone_5",
+    ];
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::error::{StoreError, SyncError};
+    use crate::model::{Metric, Source, Unit};
+
+    /// Collects what an [`Output`] writes.
+    #[derive(Clone, Default)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    fn output(format: OutputFormat) -> (Output, Buffer) {
+        let buffer = Buffer::default();
+        (Output::to(format, Box::new(buffer.clone())), buffer)
+    }
+
+    fn measurement() -> Measurement {
+        Measurement {
+            device: "AA:BB".to_string(),
+            source: Source::OxygenLog,
+            metric: Metric::BloodOxygen,
+            unit: Unit::Percent,
+            timestamp: Utc.with_ymd_and_hms(2024, 3, 10, 8, 0, 0).unwrap(),
+            end: Some(Utc.with_ymd_and_hms(2024, 3, 10, 9, 0, 0).unwrap()),
+            value: 97.0,
+            min: Some(96.0),
+            max: Some(98.0),
+            label: Some("hour".to_string()),
+        }
+    }
+
+    fn device(name: &str) -> DeviceRecord {
+        DeviceRecord {
+            name: name.to_string(),
+            address: "AA:BB".to_string(),
+        }
+    }
+
+    fn sync() -> SyncRecord {
+        SyncRecord {
+            device: "AA:BB".to_string(),
+            metric: "spo2",
+            days: 2,
+            records: 48,
+            skipped: 5,
+        }
+    }
+
+    /// Serialized field names, in declaration order.
+    fn fields<R: Record>(record: &R) -> Vec<String> {
+        let (output, buffer) = output(OutputFormat::Csv);
+        output.record(record);
+        let header = buffer.text().lines().next().unwrap().to_string();
+        let mut names: Vec<String> = header.split(',').map(String::from).collect();
+        let Value::Object(map) = serde_json::to_value(record).unwrap() else {
+            panic!("record is not an object");
+        };
+        names.retain(|name| map.contains_key(name));
+        assert_eq!(names.len(), map.len(), "fields missing from COLUMNS");
+        names
+    }
+
+    #[test]
+    fn columns_cover_every_serialized_field() {
+        assert_eq!(fields(&measurement()), Measurement::COLUMNS);
+        assert_eq!(fields(&device("R02")), DeviceRecord::COLUMNS);
+        assert_eq!(fields(&sync()), SyncRecord::COLUMNS);
+        let export = ExportRecord {
+            format: "csv",
+            metric: "heart_rate",
+            path: "hr.csv".to_string(),
+            rows: 1,
+        };
+        assert_eq!(fields(&export), ExportRecord::COLUMNS);
+    }
+
+    #[test]
+    fn field_names_are_stable() {
+        assert_eq!(
+            Measurement::COLUMNS,
+            [
+                "device",
+                "source",
+                "metric",
+                "unit",
+                "timestamp",
+                "end",
+                "value",
+                "min",
+                "max",
+                "label"
+            ]
+        );
+        assert_eq!(
+            SyncRecord::COLUMNS,
+            ["device", "metric", "days", "records", "skipped"]
+        );
+        assert_eq!(DeviceRecord::COLUMNS, ["name", "address"]);
+    }
+
+    #[test]
+    fn ndjson_writes_one_object_per_line() {
+        let (output, buffer) = output(OutputFormat::Ndjson);
+        output.record(&measurement());
+        output.record(&sync());
+        assert_eq!(output.finish(), 0);
+
+        let lines: Vec<Value> = buffer
+            .text()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["metric"], "blood_oxygen");
+        assert_eq!(lines[0]["unit"], "%");
+        assert_eq!(lines[0]["timestamp"], "2024-03-10T08:00:00Z");
+        assert_eq!(lines[1]["records"], 48);
+    }
+
+    #[test]
+    fn json_writes_one_array_on_finish() {
+        let (output, buffer) = output(OutputFormat::Json);
+        output.records(&[device("R02"), device("R06")]);
+        assert!(buffer.text().is_empty());
+        output.finish();
+
+        let array: Value = serde_json::from_str(&buffer.text()).unwrap();
+        assert_eq!(array[0]["name"], "R02");
+        assert_eq!(array[1]["name"], "R06");
+        assert_eq!(array[1]["address"], "AA:BB");
+    }
+
+    #[test]
+    fn csv_writes_a_header_per_record_type() {
+        let (output, buffer) = output(OutputFormat::Csv);
+        output.records(&[device("R02"), device("R06")]);
+        output.record(&sync());
+        output.record(&device("R09"));
+        assert_eq!(output.finish(), 0);
+
+        assert_eq!(
+            buffer.text(),
+            "name,address\nR02,AA:BB\nR06,AA:BB\n\
+             device,metric,days,records,skipped\nAA:BB,spo2,2,48,5\n\
+             name,address\nR09,AA:BB\n"
+        );
+    }
+
+    #[test]
+    fn csv_leaves_omitted_fields_empty() {
+        let (output, buffer) = output(OutputFormat::Csv);
+        output.record(&Measurement {
+            end: None,
+            min: None,
+            max: None,
+            label: None,
+            ..measurement()
+        });
+        let row = buffer.text().lines().nth(1).unwrap().to_string();
+        assert_eq!(
+            row,
+            "AA:BB,oxygen_log,blood_oxygen,%,2024-03-10T08:00:00Z,,97.0,,,"
+        );
+    }
+
+    #[test]
+    fn first_error_decides_the_exit_code() {
+        let (output, _) = output(OutputFormat::Ndjson);
+        output.error(&CliError::InvalidArgument("--days".to_string()));
+        output.error_in(
+            "sync",
+            &SyncError::Store(StoreError::Database(rusqlite::Error::InvalidQuery)),
+        );
+        assert_eq!(output.finish(), 2);
+    }
+
+    #[test]
+    fn text_mode_writes_no_records() {
+        let (output, buffer) = output(OutputFormat::Text);
+        output.record(&measurement());
+        output.finish();
+        assert!(buffer.text().is_empty());
+    }
+}
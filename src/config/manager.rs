@@ -1,5 +1,6 @@
 use std::fs;
 
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
 
 use crate::devices::models::Device;
@@ -8,6 +9,9 @@ use crate::protocol::features::FeatureResponse;
 #[derive(Serialize, Deserialize, Default)]
 pub struct Config {
     title: String,
+    /// IANA zone for day boundaries, e.g. "Europe/Berlin"; unset means the
+    /// system zone.
+    timezone: Option<String>,
     device_config: DeviceConfig,
 }
 
@@ -18,9 +22,15 @@ pub struct DeviceConfig {
     features: Option<FeatureResponse>,
 }
 
+fn load_config() -> Option<Config> {
+    let toml_string = fs::read_to_string("config.toml").ok()?;
+    toml::from_str(&toml_string).ok()
+}
+
 pub fn save_device_to_config(device: Device, features: FeatureResponse) {
     let config = Config {
         title: "Config for Colmi Client".to_string(),
+        timezone: load_config().and_then(|config| config.timezone),
         device_config: DeviceConfig {
             name: Some(device.name().to_string()),
             address: Some(device.id().to_string()),
@@ -31,3 +41,11 @@ pub fn save_device_to_config(device: Device, features: FeatureResponse) {
     let toml_string = toml::to_string(&config).unwrap();
     fs::write("config.toml", toml_string).unwrap();
 }
+
+pub fn load_device_features() -> Option<FeatureResponse> {
+    load_config()?.device_config.features
+}
+
+pub fn load_timezone() -> Option<Tz> {
+    load_config()?.timezone?.parse().ok()
+}
@@ -4,11 +4,14 @@ use btleplug::{
     api::{Characteristic, Peripheral, WriteType},
     platform::Peripheral as PlatformPeripheral,
 };
+use chrono::{DateTime, NaiveDate, Utc};
 use futures_util::stream::StreamExt;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
 use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
+    calendar::{Calendar, ring_today},
     config::manager::save_device_to_config,
     protocol::{
         DATA_NOTIFY_CHARACTERISTICS, DATA_SERVICE_UUID, DATA_WRITE_CHARACTERISTICS,
@@ -22,12 +25,27 @@ use crate::{
         },
         blink::BlinkRequest,
         find::FindRequest,
-        hr::{HeartRateLogParser, HeartRateRequest, HeartRateResult},
-        realtime::{ReadingType, RealtimeReading, RealtimeStartRequest, RealtimeStopRequest},
+        hr::{
+            CMD_READ_HEART_RATE, HeartRateLog, HeartRateLogParser, HeartRateRequest,
+            HeartRateResult,
+        },
+        realtime::{
+            KEEPALIVE_INTERVAL, MonitorEvent, QUIET_TIMEOUT, ReadingType, RealtimeContinueRequest,
+            RealtimeReading, RealtimeSample, RealtimeSession, RealtimeStartRequest,
+            RealtimeStopRequest, SAMPLE_COUNT, SAMPLE_TIMEOUT, SampleProgress, settled_value,
+            switched_types,
+        },
         reboot::RebootRequest,
         reset::ResetRequest,
         settings::{CMD_HEART_RATE_LOG_SETTINGS, HeartRateLogSettings, SettingsRequest},
-        steps::{ActivityDetailParser, StepsRequest, StepsResult},
+        steps::{
+            ActivityDetailParser, CMD_GET_ACTIVITY_DATA, CMD_GET_TODAY_TOTALS, DailyTotals,
+            LiveActivity, StepsRequest, StepsResult, TodayTotalsRequest,
+        },
+        workout::{
+            CMD_PHONE_SPORT_NOTIFY, SportType, WorkoutAction, WorkoutEvent, WorkoutProgress,
+            WorkoutRequest,
+        },
     },
 };
 use crate::{devices::models::Device, protocol::features::FeatureResponse};
@@ -41,13 +59,38 @@ pub struct Connection {
     pub peripheral: PlatformPeripheral,
     pub write_char: Characteristic,
     pub notify_char: Characteristic,
+    pub live_activity: broadcast::Sender<LiveActivity>,
+    /// Stops the task that feeds `live_activity` from this link.
+    live_watch: CancellationToken,
+}
+
+impl Connection {
+    /// Subscribes to the live activity packets the ring pushes while connected.
+    pub fn subscribe_live_activity(&self) -> broadcast::Receiver<LiveActivity> {
+        self.live_activity.subscribe()
+    }
+
+    /// Stops watching this link for live activity. Call when giving the
+    /// connection up; every clone shares the watcher.
+    pub fn close(&self) {
+        self.live_watch.cancel();
+    }
 }
 
 pub struct DeviceManager;
 
+/// Why a single continuous-monitoring session stopped.
+enum MonitorEnd {
+    Cancelled,
+    ReceiverDropped,
+    Disconnected,
+}
+
 impl DeviceManager {
     const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
     const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+    const RECONNECT_ATTEMPTS: u32 = 10;
+    const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
 
     pub async fn connect_and_setup(device: &Device) -> Result<Connection, DeviceError> {
         let conn = match tokio::time::timeout(Self::CONNECT_TIMEOUT, Self::connect(device)).await {
@@ -56,6 +99,7 @@ impl DeviceManager {
         };
 
         Self::subscribe_to_notifications(&conn).await?;
+        tokio::spawn(Self::watch_live_activity(conn.clone()));
 
         let request = FeatureRequest::new();
 
@@ -67,6 +111,33 @@ impl DeviceManager {
         Ok(conn)
     }
 
+    /// Re-establishes a dropped connection, keeping the live activity
+    /// subscribers of `previous` attached. Retries with exponential backoff
+    /// and reports each attempt through `on_attempt`.
+    pub async fn reconnect(
+        device: &Device,
+        previous: &Connection,
+        mut on_attempt: impl FnMut(u32),
+    ) -> Result<Connection, DeviceError> {
+        let mut backoff = Duration::from_secs(2);
+
+        for attempt in 1..=Self::RECONNECT_ATTEMPTS {
+            on_attempt(attempt);
+            if let Ok(Ok(mut conn)) = timeout(Self::CONNECT_TIMEOUT, Self::connect(device)).await
+                && Self::subscribe_to_notifications(&conn).await.is_ok()
+            {
+                previous.close();
+                conn.live_activity = previous.live_activity.clone();
+                tokio::spawn(Self::watch_live_activity(conn.clone()));
+                return Ok(conn);
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(Self::RECONNECT_MAX_BACKOFF);
+        }
+
+        Err(DeviceError::Connection(ConnectionError::ConnectionFailed))
+    }
+
     pub async fn connect(device: &Device) -> Result<Connection, ConnectionError> {
         match device.peripheral.connect().await {
             Ok(_) => {
@@ -92,6 +163,8 @@ impl DeviceManager {
                         peripheral: device.peripheral().clone(),
                         write_char,
                         notify_char,
+                        live_activity: broadcast::channel(16).0,
+                        live_watch: CancellationToken::new(),
                     }),
                     _ => Err(ConnectionError::CharacteristicsNotFound),
                 }
@@ -178,6 +251,27 @@ impl DeviceManager {
         }
     }
 
+    async fn watch_live_activity(conn: Connection) {
+        let Ok(mut notifications) = conn.peripheral.notifications().await else {
+            return;
+        };
+
+        loop {
+            let notification = tokio::select! {
+                _ = conn.live_watch.cancelled() => break,
+                notification = notifications.next() => notification,
+            };
+            let Some(notification) = notification else {
+                break;
+            };
+            if notification.uuid == conn.notify_char.uuid
+                && let Ok(activity) = LiveActivity::from_bytes(&notification.value)
+            {
+                let _ = conn.live_activity.send(activity);
+            }
+        }
+    }
+
     pub async fn subscribe_to_notifications(conn: &Connection) -> Result<(), ConnectionError> {
         conn.peripheral
             .subscribe(&conn.notify_char)
@@ -196,12 +290,13 @@ impl DeviceManager {
 
     pub async fn get_heart_rate_log(
         conn: &Connection,
-        timestamp: u32,
+        day_start: DateTime<Utc>,
     ) -> Result<HeartRateResult, DeviceError> {
-        Self::write_request(conn, HeartRateRequest::new(timestamp)).await?;
+        Self::write_request(conn, HeartRateRequest::new(day_start.timestamp() as u32)).await?;
 
-        let mut parser = HeartRateLogParser::new();
-        let result = Self::read_split_array(conn, |packet| parser.feed(packet)).await?;
+        let mut parser = HeartRateLogParser::new(day_start);
+        let result =
+            Self::read_split_array(conn, CMD_READ_HEART_RATE, |packet| parser.feed(packet)).await?;
         Ok(result)
     }
 
@@ -209,10 +304,72 @@ impl DeviceManager {
         Self::write_request(conn, StepsRequest::new(day_offset)).await?;
 
         let mut parser = ActivityDetailParser::new();
-        let result = Self::read_split_array(conn, |packet| parser.feed(packet)).await?;
+        let result =
+            Self::read_split_array(conn, CMD_GET_ACTIVITY_DATA, |packet| parser.feed(packet))
+                .await?;
         Ok(result)
     }
 
+    /// Heart-rate log for one local day of `calendar`, stitched together from
+    /// the ring days it overlaps.
+    pub async fn get_day_heart_rate(
+        conn: &Connection,
+        calendar: &Calendar,
+        date: NaiveDate,
+    ) -> Result<HeartRateResult, DeviceError> {
+        let mut logs = Vec::new();
+        for ring_day in calendar.ring_days(date) {
+            let day_start = ring_day.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc();
+            if let HeartRateResult::Log(log) = Self::get_heart_rate_log(conn, day_start).await? {
+                logs.push(log);
+            }
+        }
+
+        let (start, end) = calendar.day_range(date);
+        Ok(HeartRateLog::window(&logs, start, end)
+            .map(HeartRateResult::Log)
+            .unwrap_or(HeartRateResult::NoData))
+    }
+
+    /// 15-minute activity slots for one local day of `calendar`.
+    pub async fn get_day_steps(
+        conn: &Connection,
+        calendar: &Calendar,
+        date: NaiveDate,
+    ) -> Result<StepsResult, DeviceError> {
+        let ring_today = ring_today();
+        let mut details = Vec::new();
+        let mut any_data = false;
+        for ring_day in calendar.ring_days(date) {
+            let day_offset = (ring_today - ring_day).num_days();
+            if !(0..=i8::MAX as i64).contains(&day_offset) {
+                continue;
+            }
+            if let StepsResult::Details(day) = Self::get_steps(conn, day_offset as i8).await? {
+                any_data = true;
+                details.extend(day);
+            }
+        }
+
+        details.retain(|detail| {
+            detail
+                .timestamp()
+                .is_some_and(|timestamp| calendar.date_of(timestamp) == date)
+        });
+        Ok(if any_data {
+            StepsResult::Details(details)
+        } else {
+            StepsResult::NoData
+        })
+    }
+
+    pub async fn get_today_totals(conn: &Connection) -> Result<DailyTotals, DeviceError> {
+        Self::write_request(conn, TodayTotalsRequest::new()).await?;
+        let response =
+            Self::read_response_stream::<DailyTotals>(conn, CMD_GET_TODAY_TOTALS, 1000).await?;
+        Ok(response)
+    }
+
     pub async fn get_device_info(
         conn: &Connection,
     ) -> Result<(String, String, String), DeviceError> {
@@ -393,8 +550,12 @@ impl DeviceManager {
         }
     }
 
+    /// Feeds notifications for `expected_command_id` to `feed` until it
+    /// returns a result. Packets for other commands, such as the live activity
+    /// the ring pushes mid-download, are skipped instead of being parsed.
     async fn read_split_array<T>(
         conn: &Connection,
+        expected_command_id: u8,
         mut feed: impl FnMut(&[u8]) -> Result<Option<T>, crate::error::ProtocolError>,
     ) -> Result<T, DeviceError> {
         let mut notifications = conn
@@ -419,6 +580,10 @@ impl DeviceManager {
                             ));
                         }
 
+                        if packet[0] != expected_command_id {
+                            continue;
+                        }
+
                         if let Some(result) = feed(packet)? {
                             return Ok(result);
                         }
@@ -458,19 +623,16 @@ impl DeviceManager {
 
     pub async fn stream_realtime(
         conn: &Connection,
-        reading_type: ReadingType,
+        session: &RealtimeSession,
         duration: Duration,
         tx: mpsc::Sender<RealtimeReading>,
+        cancel: CancellationToken,
     ) -> Result<(), DeviceError> {
-        Self::write_with_timeout(
-            &conn.peripheral,
-            &conn.write_char,
-            &make_phone_info_packet(),
-        )
-        .await?;
-        tokio::time::sleep(Duration::from_millis(500)).await;
+        Self::send_phone_info(conn).await?;
 
-        Self::write_request(conn, RealtimeStartRequest::new(reading_type)).await?;
+        let start = std::time::Instant::now();
+        let mut active = session.active_types(Duration::ZERO);
+        Self::start_readings(conn, &active).await?;
 
         let mut notifications = conn
             .peripheral
@@ -478,31 +640,358 @@ impl DeviceManager {
             .await
             .map_err(|_| ConnectionError::SubscribeFailed)?;
 
-        let start = std::time::Instant::now();
+        let mut switch_check = tokio::time::interval(Duration::from_secs(1));
+
         loop {
             let remaining = duration.saturating_sub(start.elapsed());
             if remaining.is_zero() {
                 break;
             }
 
-            match timeout(remaining, notifications.next()).await {
-                Ok(Some(notification)) => {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = switch_check.tick() => {
+                    let wanted = session.active_types(start.elapsed());
+                    if wanted != active {
+                        let switched = Self::switch_readings(conn, &active, &wanted).await;
+                        active = wanted;
+                        if switched.is_err() {
+                            break;
+                        }
+                    }
+                }
+                next = timeout(remaining, notifications.next()) => match next {
+                    Ok(Some(notification)) => {
+                        if notification.uuid == conn.notify_char.uuid
+                            && let Ok(reading) = RealtimeReading::from_bytes(&notification.value, Utc::now())
+                            && active.contains(&reading.reading_type)
+                            && reading.value != 0
+                            && tx.send(reading).await.is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(_) => break,
+                },
+            }
+        }
+
+        drop(notifications);
+        Self::stop_readings(conn, &active).await;
+
+        Ok(())
+    }
+
+    /// Streams `session` until `cancel` fires or `tx` is dropped. Unlike
+    /// `stream_realtime` this keeps the ring streaming with periodic continue
+    /// packets, restarts the measurement when the ring goes quiet and
+    /// reconnects on link loss.
+    pub async fn monitor_realtime(
+        device: &Device,
+        conn: Connection,
+        session: RealtimeSession,
+        tx: mpsc::Sender<MonitorEvent>,
+        cancel: CancellationToken,
+    ) -> Result<(), DeviceError> {
+        let mut conn = conn;
+        // Cycling continues where it left off across reconnects.
+        let started = std::time::Instant::now();
+
+        loop {
+            match Self::run_monitor_session(&conn, &session, started, &tx, &cancel).await? {
+                MonitorEnd::Cancelled | MonitorEnd::ReceiverDropped => return Ok(()),
+                MonitorEnd::Disconnected => {
+                    let tx_attempt = tx.clone();
+                    let reconnect = Self::reconnect(device, &conn, |attempt| {
+                        let _ = tx_attempt.try_send(MonitorEvent::Reconnecting { attempt });
+                    });
+                    conn = tokio::select! {
+                        _ = cancel.cancelled() => return Ok(()),
+                        conn = reconnect => conn?,
+                    };
+                    if tx
+                        .send(MonitorEvent::Reconnected(conn.clone()))
+                        .await
+                        .is_err()
+                    {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn run_monitor_session(
+        conn: &Connection,
+        session: &RealtimeSession,
+        started: std::time::Instant,
+        tx: &mpsc::Sender<MonitorEvent>,
+        cancel: &CancellationToken,
+    ) -> Result<MonitorEnd, DeviceError> {
+        let mut active = session.active_types(started.elapsed());
+        if Self::send_phone_info(conn).await.is_err()
+            || Self::start_readings(conn, &active).await.is_err()
+        {
+            return Ok(MonitorEnd::Disconnected);
+        }
+
+        let mut notifications = conn
+            .peripheral
+            .notifications()
+            .await
+            .map_err(|_| ConnectionError::SubscribeFailed)?;
+
+        let mut keepalive = tokio::time::interval(KEEPALIVE_INTERVAL);
+        keepalive.tick().await;
+        let mut last_reading = std::time::Instant::now();
+
+        let end = loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break MonitorEnd::Cancelled,
+                notification = notifications.next() => {
+                    let Some(notification) = notification else {
+                        break MonitorEnd::Disconnected;
+                    };
                     if notification.uuid == conn.notify_char.uuid
-                        && let Ok(reading) = RealtimeReading::from_bytes(&notification.value)
-                        && reading.reading_type == reading_type
+                        && let Ok(reading) = RealtimeReading::from_bytes(&notification.value, Utc::now())
+                        && active.contains(&reading.reading_type)
                         && reading.value != 0
-                        && tx.send(reading).await.is_err()
                     {
+                        last_reading = std::time::Instant::now();
+                        if tx.send(MonitorEvent::Reading(reading)).await.is_err() {
+                            break MonitorEnd::ReceiverDropped;
+                        }
+                    }
+                }
+                _ = keepalive.tick() => {
+                    if tx.is_closed() {
+                        break MonitorEnd::ReceiverDropped;
+                    }
+                    let wanted = session.active_types(started.elapsed());
+                    let written = if wanted != active {
+                        last_reading = std::time::Instant::now();
+                        let switched = Self::switch_readings(conn, &active, &wanted).await;
+                        active = wanted;
+                        switched
+                    } else if last_reading.elapsed() > QUIET_TIMEOUT {
+                        last_reading = std::time::Instant::now();
+                        let _ = tx.send(MonitorEvent::Restarted).await;
+                        Self::stop_readings(conn, &active).await;
+                        Self::start_readings(conn, &active).await
+                    } else {
+                        Self::write_request(conn, RealtimeContinueRequest::new()).await
+                    };
+                    if written.is_err() {
+                        break MonitorEnd::Disconnected;
+                    }
+                }
+            }
+        };
+
+        drop(notifications);
+        if !matches!(end, MonitorEnd::Disconnected) {
+            Self::stop_readings(conn, &active).await;
+        }
+
+        Ok(end)
+    }
+
+    async fn start_readings(
+        conn: &Connection,
+        reading_types: &[ReadingType],
+    ) -> Result<(), ConnectionError> {
+        for &reading_type in reading_types {
+            Self::write_request(conn, RealtimeStartRequest::new(reading_type)).await?;
+        }
+        Ok(())
+    }
+
+    async fn stop_readings(conn: &Connection, reading_types: &[ReadingType]) {
+        for &reading_type in reading_types {
+            let _ = Self::write_request(conn, RealtimeStopRequest::new(reading_type)).await;
+        }
+    }
+
+    async fn switch_readings(
+        conn: &Connection,
+        from: &[ReadingType],
+        to: &[ReadingType],
+    ) -> Result<(), ConnectionError> {
+        let (stopping, starting) = switched_types(from, to);
+        Self::stop_readings(conn, &stopping).await;
+        Self::start_readings(conn, &starting).await
+    }
+
+    /// Streams each metric in `reading_types` over realtime, one after the
+    /// other, and keeps the median of its first few readings. Warm-up
+    /// progress and samples are reported through `on_progress`. The running
+    /// metric is always stopped, also when `cancel` fires.
+    pub async fn sample_realtime(
+        conn: &Connection,
+        reading_types: &[ReadingType],
+        mut on_progress: impl FnMut(SampleProgress),
+        cancel: CancellationToken,
+    ) -> Result<RealtimeSample, DeviceError> {
+        Self::send_phone_info(conn).await?;
+
+        let mut notifications = conn
+            .peripheral
+            .notifications()
+            .await
+            .map_err(|_| ConnectionError::SubscribeFailed)?;
+
+        let mut sample = RealtimeSample::default();
+
+        for &reading_type in reading_types {
+            Self::write_request(conn, RealtimeStartRequest::new(reading_type)).await?;
+
+            let start = std::time::Instant::now();
+            let mut samples = Vec::new();
+            let mut ticker = tokio::time::interval(Duration::from_secs(1));
+            let mut interrupted = None;
+
+            while samples.len() < SAMPLE_COUNT && start.elapsed() < SAMPLE_TIMEOUT {
+                tokio::select! {
+                    _ = cancel.cancelled() => {
+                        interrupted = Some(DeviceError::Cancelled);
                         break;
                     }
+                    notification = notifications.next() => {
+                        let Some(notification) = notification else {
+                            interrupted = Some(DeviceError::StreamEnded);
+                            break;
+                        };
+                        if notification.uuid == conn.notify_char.uuid
+                            && let Ok(reading) = RealtimeReading::from_bytes(&notification.value, Utc::now())
+                            && reading.reading_type == reading_type
+                            && reading.value != 0
+                        {
+                            samples.push(reading.value);
+                            on_progress(SampleProgress::Sample(reading));
+                        }
+                    }
+                    _ = ticker.tick() => on_progress(SampleProgress::Waiting {
+                        reading_type,
+                        elapsed: start.elapsed(),
+                    }),
                 }
-                Ok(None) => break,
-                Err(_) => break,
             }
+
+            let _ = Self::write_request(conn, RealtimeStopRequest::new(reading_type)).await;
+            if let Some(err) = interrupted {
+                return Err(err);
+            }
+            sample.set(reading_type, settled_value(&samples));
         }
 
-        let _ = Self::write_request(conn, RealtimeStopRequest::new(reading_type)).await;
+        Ok(sample)
+    }
+
+    /// Runs a phone-started sport session until `End` is received on
+    /// `control`, the control channel is dropped or `cancel` fires. Live heart
+    /// rate is measured alongside and paused together with the session.
+    pub async fn stream_workout(
+        conn: &Connection,
+        sport_type: SportType,
+        mut control: mpsc::Receiver<WorkoutAction>,
+        tx: mpsc::Sender<WorkoutEvent>,
+        cancel: CancellationToken,
+    ) -> Result<(), DeviceError> {
+        Self::send_phone_info(conn).await?;
+
+        // Subscribe before starting so early progress packets are not missed.
+        let mut notifications = conn
+            .peripheral
+            .notifications()
+            .await
+            .map_err(|_| ConnectionError::SubscribeFailed)?;
+
+        let mut failed = Self::write_pair(
+            conn,
+            WorkoutRequest::new(WorkoutAction::Start, sport_type),
+            RealtimeStartRequest::new(ReadingType::HeartRateBatch),
+        )
+        .await
+        .err();
 
+        while failed.is_none() {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                notification = notifications.next() => {
+                    let Some(notification) = notification else {
+                        break;
+                    };
+                    if notification.uuid != conn.notify_char.uuid {
+                        continue;
+                    }
+                    let packet = &notification.value;
+                    let event = if packet.first() == Some(&CMD_PHONE_SPORT_NOTIFY) {
+                        WorkoutProgress::from_bytes(packet).ok().map(WorkoutEvent::Progress)
+                    } else {
+                        RealtimeReading::from_bytes(packet, Utc::now())
+                            .ok()
+                            .filter(|r| {
+                                r.reading_type == ReadingType::HeartRateBatch && r.value != 0
+                            })
+                            .map(|r| WorkoutEvent::HeartRate(r.value))
+                    };
+                    if let Some(event) = event
+                        && tx.send(event).await.is_err()
+                    {
+                        break;
+                    }
+                }
+                action = control.recv() => {
+                    failed = match action {
+                        Some(WorkoutAction::Pause) => {
+                            let pause = WorkoutRequest::new(WorkoutAction::Pause, sport_type);
+                            let stop = RealtimeStopRequest::new(ReadingType::HeartRateBatch);
+                            Self::write_pair(conn, pause, stop).await.err()
+                        }
+                        Some(WorkoutAction::Resume) => {
+                            let resume = WorkoutRequest::new(WorkoutAction::Resume, sport_type);
+                            let start = RealtimeStartRequest::new(ReadingType::HeartRateBatch);
+                            Self::write_pair(conn, resume, start).await.err()
+                        }
+                        Some(WorkoutAction::Start) => None,
+                        Some(WorkoutAction::End) | None => break,
+                    };
+                }
+            }
+        }
+
+        // The session and HR sensor are stopped whatever ended the loop.
+        drop(notifications);
+        let _ =
+            Self::write_request(conn, WorkoutRequest::new(WorkoutAction::End, sport_type)).await;
+        let _ =
+            Self::write_request(conn, RealtimeStopRequest::new(ReadingType::HeartRateBatch)).await;
+
+        match failed {
+            Some(err) => Err(err.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes `first`, then `second` only if `first` went through.
+    async fn write_pair(
+        conn: &Connection,
+        first: impl Request,
+        second: impl Request,
+    ) -> Result<(), ConnectionError> {
+        Self::write_request(conn, first).await?;
+        Self::write_request(conn, second).await
+    }
+
+    async fn send_phone_info(conn: &Connection) -> Result<(), DeviceError> {
+        Self::write_with_timeout(
+            &conn.peripheral,
+            &conn.write_char,
+            &make_phone_info_packet(),
+        )
+        .await?;
+        tokio::time::sleep(Duration::from_millis(500)).await;
         Ok(())
     }
 }
@@ -1,6 +1,6 @@
 const COLMI_MANUFACTURER_ID: u16 = 4660;
 
-use btleplug::api::Peripheral;
+use btleplug::api::{BDAddr, Peripheral};
 use btleplug::platform::Peripheral as PlatformPeripheral;
 use std::fmt::Display;
 
@@ -8,6 +8,8 @@ use std::fmt::Display;
 pub struct Device {
     pub peripheral: PlatformPeripheral,
     pub name: String,
+    /// Bluetooth address, e.g. `AA:BB:CC:DD:EE:FF`; the local store and
+    /// exports key everything by it.
     pub id: String,
     pub is_colmi_device: bool,
 }
@@ -21,7 +23,12 @@ impl Device {
             .expect("Failed to retrieve device properties");
 
         let name = props.local_name.unwrap_or("Unknown Device".to_string());
-        let id = peripheral.id().to_string();
+        // Platforms that hide the address report it as all zeros.
+        let id = if props.address == BDAddr::default() {
+            address_key(&peripheral.id().to_string())
+        } else {
+            props.address.to_string()
+        };
         let is_colmi_device = props.manufacturer_data.contains_key(&COLMI_MANUFACTURER_ID);
 
         Self {
@@ -58,3 +65,30 @@ impl Display for Device {
         write!(f, "{}", self.display_name())
     }
 }
+
+/// The canonical `AA:BB:CC:DD:EE:FF` form of an address written any other
+/// way, e.g. `aa:bb:…` by Gadgetbridge or `hci0/dev_AA_BB_…` by BlueZ.
+/// Anything that is not an address, like a macOS device UUID, is kept.
+pub fn address_key(raw: &str) -> String {
+    let tail = raw.rsplit('/').next().unwrap_or(raw);
+    let tail = tail.strip_prefix("dev_").unwrap_or(tail);
+    let octets: Vec<&str> = tail.split([':', '_', '-']).collect();
+    if octets.len() == 6
+        && octets
+            .iter()
+            .all(|octet| octet.len() == 2 && octet.chars().all(|c| c.is_ascii_hexdigit()))
+    {
+        octets.join(":").to_uppercase()
+    } else {
+        raw.to_string()
+    }
+}
+
+/// An address as lowercase hex digits only, for MQTT topics and URL paths.
+pub fn node_id(address: &str) -> String {
+    address
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
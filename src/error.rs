@@ -11,6 +11,12 @@ pub enum ScanError {
     #[error("No Colmi devices found! Try `colmi_client scan --all` to see all devices.")]
     NoColmiDevices,
 
+    #[error("No ring matching '{0}' found! Try `colmi_client scan` to see nearby rings.")]
+    DeviceNotFound(String),
+
+    #[error("'{0}' matches more than one ring; select it by address.")]
+    AmbiguousDevice(String),
+
     #[error("Bluetooth operation failed: {0}")]
     BluetoothOperationFailed(#[from] btleplug::Error),
 }
@@ -58,6 +64,9 @@ pub enum ProtocolError {
 
     #[error("Device reported error for reading type {reading_type} with code {code}")]
     ReadingError { reading_type: u8, code: u8 },
+
+    #[error("Unknown notification type: {0}")]
+    UnknownNotification(u8),
 }
 
 #[derive(Error, Debug)]
@@ -76,6 +85,9 @@ pub enum DeviceError {
 
     #[error("Notification stream ended unexpectedly")]
     StreamEnded,
+
+    #[error("Operation cancelled")]
+    Cancelled,
 }
 
 #[derive(Error, Debug)]
@@ -95,3 +107,533 @@ pub enum TuiError {
     #[error("Event handling failed: {0}")]
     EventHandling(String),
 }
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("Local store error: {0}")]
+    Database(#[from] rusqlite::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum SyncError {
+    #[error(transparent)]
+    Device(#[from] DeviceError),
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error(transparent)]
+    Store(#[from] StoreError),
+
+    #[error("Failed to write export file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Failed to write CSV: {0}")]
+    Csv(#[from] csv::Error),
+
+    #[error("Failed to encode JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Export failed validation: {0}")]
+    Invalid(String),
+
+    #[error("Failed to push export: {0}")]
+    Push(String),
+}
+
+#[derive(Error, Debug)]
+pub enum GadgetbridgeError {
+    #[error("Gadgetbridge database error: {0}")]
+    Database(#[from] rusqlite::Error),
+
+    #[error("Not a Gadgetbridge database with Colmi support, missing tables: {0}")]
+    Schema(String),
+
+    #[error("Device {0} is not in the Gadgetbridge database")]
+    UnknownDevice(String),
+}
+
+#[derive(Error, Debug)]
+pub enum ServeError {
+    #[error("Cannot listen on {address}: {source}")]
+    Bind {
+        address: String,
+        source: std::io::Error,
+    },
+
+    #[error("HTTP server failed: {0}")]
+    Server(std::io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum CliError {
+    #[error("{0}")]
+    InvalidArgument(String),
+
+    #[error("Prompt failed: {0}")]
+    Prompt(#[from] inquire::InquireError),
+
+    #[error("Background task failed: {0}")]
+    TaskFailed(#[from] tokio::task::JoinError),
+
+    #[error("Failed to write output: {0}")]
+    Output(String),
+}
+
+/// Stable identity of an error for scripts: a snake_case `kind` reported in
+/// structured output and the process exit code.
+///
+/// Exit codes: 1 internal, 2 invalid argument, 3 prompt, 10-15 scanning,
+/// 20-24 connection, 30 protocol, 40-42 timeouts and dropped streams,
+/// 50 local store, 60 TUI, 70-72 export, 80-82 Gadgetbridge, 90 serve,
+/// 130 cancelled.
+pub trait Failure: std::fmt::Display {
+    fn kind(&self) -> &'static str;
+    fn exit_code(&self) -> i32;
+
+    /// Finer-grained cause within `kind`, where there is one.
+    fn detail(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+impl Failure for ScanError {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::NoAdapters => "no_adapters",
+            Self::NoDevices => "no_devices",
+            Self::NoColmiDevices => "no_colmi_devices",
+            Self::DeviceNotFound(_) => "device_not_found",
+            Self::AmbiguousDevice(_) => "ambiguous_device",
+            Self::BluetoothOperationFailed(_) => "bluetooth",
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            Self::NoAdapters => 10,
+            Self::NoDevices => 11,
+            Self::NoColmiDevices => 12,
+            Self::BluetoothOperationFailed(_) => 13,
+            Self::DeviceNotFound(_) => 14,
+            Self::AmbiguousDevice(_) => 15,
+        }
+    }
+}
+
+impl Failure for ConnectionError {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::ConnectionFailed => "connection_failed",
+            Self::CharacteristicsNotFound => "characteristics_not_found",
+            Self::WriteFailed => "write_failed",
+            Self::ReadFailed => "read_failed",
+            Self::SubscribeFailed => "subscribe_failed",
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            Self::ConnectionFailed => 20,
+            Self::CharacteristicsNotFound => 21,
+            Self::WriteFailed => 22,
+            Self::ReadFailed => 23,
+            Self::SubscribeFailed => 24,
+        }
+    }
+}
+
+impl ProtocolError {
+    /// Every [`variant`](Self::variant) name.
+    pub const VARIANTS: [&'static str; 9] = [
+        "checksum",
+        "packet_length",
+        "command_id",
+        "error_flag",
+        "malformed_split_array",
+        "invalid_magic",
+        "unknown_reading_type",
+        "reading_error",
+        "unknown_notification",
+    ];
+
+    /// Variant name in snake_case, e.g. `checksum`.
+    pub fn variant(&self) -> &'static str {
+        match self {
+            Self::Checksum { .. } => "checksum",
+            Self::PacketLength => "packet_length",
+            Self::CommandId { .. } => "command_id",
+            Self::ErrorFlag { .. } => "error_flag",
+            Self::MalformedSplitArray => "malformed_split_array",
+            Self::InvalidMagic { .. } => "invalid_magic",
+            Self::UnknownReadingType(_) => "unknown_reading_type",
+            Self::ReadingError { .. } => "reading_error",
+            Self::UnknownNotification(_) => "unknown_notification",
+        }
+    }
+}
+
+impl Failure for ProtocolError {
+    fn kind(&self) -> &'static str {
+        "protocol"
+    }
+
+    fn exit_code(&self) -> i32 {
+        30
+    }
+
+    fn detail(&self) -> Option<&'static str> {
+        Some(self.variant())
+    }
+}
+
+impl Failure for DeviceError {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Connection(err) => err.kind(),
+            Self::Protocol(err) => err.kind(),
+            Self::Timeout(_) => "timeout",
+            Self::BigDataTimeout => "big_data_timeout",
+            Self::StreamEnded => "stream_ended",
+            Self::Cancelled => "cancelled",
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            Self::Connection(err) => err.exit_code(),
+            Self::Protocol(err) => err.exit_code(),
+            Self::Timeout(_) => 40,
+            Self::BigDataTimeout => 41,
+            Self::StreamEnded => 42,
+            Self::Cancelled => 130,
+        }
+    }
+
+    fn detail(&self) -> Option<&'static str> {
+        match self {
+            Self::Protocol(err) => err.detail(),
+            _ => None,
+        }
+    }
+}
+
+impl Failure for StoreError {
+    fn kind(&self) -> &'static str {
+        "store"
+    }
+
+    fn exit_code(&self) -> i32 {
+        50
+    }
+}
+
+impl Failure for SyncError {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Device(err) => err.kind(),
+            Self::Store(err) => err.kind(),
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            Self::Device(err) => err.exit_code(),
+            Self::Store(err) => err.exit_code(),
+        }
+    }
+
+    fn detail(&self) -> Option<&'static str> {
+        match self {
+            Self::Device(err) => err.detail(),
+            Self::Store(_) => None,
+        }
+    }
+}
+
+impl Failure for ExportError {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Store(err) => err.kind(),
+            Self::Io(_) | Self::Csv(_) | Self::Json(_) => "export",
+            Self::Invalid(_) => "invalid_export",
+            Self::Push(_) => "push",
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            Self::Store(err) => err.exit_code(),
+            Self::Io(_) | Self::Csv(_) | Self::Json(_) => 70,
+            Self::Invalid(_) => 71,
+            Self::Push(_) => 72,
+        }
+    }
+}
+
+impl Failure for GadgetbridgeError {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Database(_) => "gadgetbridge",
+            Self::Schema(_) => "invalid_gadgetbridge",
+            Self::UnknownDevice(_) => "unknown_device",
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            Self::Database(_) => 80,
+            Self::Schema(_) => 81,
+            Self::UnknownDevice(_) => 82,
+        }
+    }
+}
+
+impl Failure for ServeError {
+    fn kind(&self) -> &'static str {
+        "serve"
+    }
+
+    fn exit_code(&self) -> i32 {
+        90
+    }
+}
+
+impl Failure for TuiError {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Scan(err) => err.kind(),
+            Self::Device(err) => err.kind(),
+            Self::TerminalInit(_) | Self::Rendering(_) | Self::EventHandling(_) => "terminal",
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            Self::Scan(err) => err.exit_code(),
+            Self::Device(err) => err.exit_code(),
+            Self::TerminalInit(_) | Self::Rendering(_) | Self::EventHandling(_) => 60,
+        }
+    }
+
+    fn detail(&self) -> Option<&'static str> {
+        match self {
+            Self::Device(err) => err.detail(),
+            _ => None,
+        }
+    }
+}
+
+impl Failure for CliError {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::InvalidArgument(_) => "invalid_argument",
+            Self::Prompt(_) => "prompt",
+            Self::TaskFailed(_) => "task_failed",
+            Self::Output(_) => "output",
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            Self::InvalidArgument(_) => 2,
+            Self::Prompt(_) => 3,
+            Self::TaskFailed(_) | Self::Output(_) => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn io() -> std::io::Error {
+        std::io::Error::other("disk full")
+    }
+
+    fn codes(failures: &[&dyn Failure]) -> Vec<(&'static str, i32)> {
+        failures
+            .iter()
+            .map(|failure| (failure.kind(), failure.exit_code()))
+            .collect()
+    }
+
+    #[test]
+    fn scan_errors_exit_10_to_15() {
+        assert_eq!(
+            codes(&[
+                &ScanError::NoAdapters,
+                &ScanError::NoDevices,
+                &ScanError::NoColmiDevices,
+                &ScanError::BluetoothOperationFailed(btleplug::Error::DeviceNotFound),
+                &ScanError::DeviceNotFound("R02".to_string()),
+                &ScanError::AmbiguousDevice("R02".to_string()),
+            ]),
+            [
+                ("no_adapters", 10),
+                ("no_devices", 11),
+                ("no_colmi_devices", 12),
+                ("bluetooth", 13),
+                ("device_not_found", 14),
+                ("ambiguous_device", 15),
+            ]
+        );
+    }
+
+    #[test]
+    fn connection_errors_exit_20_to_24() {
+        assert_eq!(
+            codes(&[
+                &ConnectionError::ConnectionFailed,
+                &ConnectionError::CharacteristicsNotFound,
+                &ConnectionError::WriteFailed,
+                &ConnectionError::ReadFailed,
+                &ConnectionError::SubscribeFailed,
+            ]),
+            [
+                ("connection_failed", 20),
+                ("characteristics_not_found", 21),
+                ("write_failed", 22),
+                ("read_failed", 23),
+                ("subscribe_failed", 24),
+            ]
+        );
+    }
+
+    #[test]
+    fn device_errors_keep_the_code_of_their_cause() {
+        let checksum = DeviceError::Protocol(ProtocolError::Checksum {
+            calculated: 1,
+            actual: 2,
+        });
+        assert_eq!(checksum.exit_code(), 30);
+        assert_eq!(checksum.kind(), "protocol");
+        assert_eq!(checksum.detail(), Some("checksum"));
+        assert_eq!(
+            codes(&[
+                &DeviceError::Connection(ConnectionError::WriteFailed),
+                &DeviceError::BigDataTimeout,
+                &DeviceError::StreamEnded,
+                &DeviceError::Cancelled,
+            ]),
+            [
+                ("write_failed", 22),
+                ("big_data_timeout", 41),
+                ("stream_ended", 42),
+                ("cancelled", 130),
+            ]
+        );
+        let sync = SyncError::Device(checksum);
+        assert_eq!((sync.exit_code(), sync.detail()), (30, Some("checksum")));
+        let tui = TuiError::Device(DeviceError::StreamEnded);
+        assert_eq!(tui.exit_code(), 42);
+    }
+
+    #[test]
+    fn protocol_errors_exit_30_with_their_variant() {
+        let errors = [
+            ProtocolError::Checksum {
+                calculated: 0,
+                actual: 0,
+            },
+            ProtocolError::PacketLength,
+            ProtocolError::CommandId {
+                expected: 0,
+                actual: 0,
+            },
+            ProtocolError::ErrorFlag { command_id: 0 },
+            ProtocolError::MalformedSplitArray,
+            ProtocolError::InvalidMagic {
+                expected: 0,
+                actual: 0,
+            },
+            ProtocolError::UnknownReadingType(0),
+            ProtocolError::ReadingError {
+                reading_type: 0,
+                code: 0,
+            },
+            ProtocolError::UnknownNotification(0),
+        ];
+        let variants: Vec<_> = errors.iter().map(ProtocolError::variant).collect();
+        assert_eq!(variants, ProtocolError::VARIANTS);
+        assert!(errors.iter().all(|err| err.exit_code() == 30));
+    }
+
+    #[test]
+    fn local_errors_have_their_own_codes() {
+        let store = || StoreError::Database(rusqlite::Error::InvalidQuery);
+        assert_eq!(
+            codes(&[
+                &CliError::InvalidArgument("--days".to_string()),
+                &CliError::Prompt(inquire::InquireError::OperationCanceled),
+                &CliError::Output("closed".to_string()),
+                &store(),
+                &SyncError::Store(store()),
+                &TuiError::TerminalInit("no tty".to_string()),
+            ]),
+            [
+                ("invalid_argument", 2),
+                ("prompt", 3),
+                ("output", 1),
+                ("store", 50),
+                ("store", 50),
+                ("terminal", 60),
+            ]
+        );
+    }
+
+    #[test]
+    fn export_errors_exit_from_70() {
+        let store = StoreError::Database(rusqlite::Error::InvalidQuery);
+        let csv = csv::Error::from(io());
+        assert_eq!(
+            codes(&[
+                &ExportError::Store(store),
+                &ExportError::Io(io()),
+                &ExportError::Csv(csv),
+                &ExportError::Json(serde_json::from_str::<u8>("x").unwrap_err()),
+                &ExportError::Invalid("empty".to_string()),
+                &ExportError::Push("503".to_string()),
+            ]),
+            [
+                ("store", 50),
+                ("export", 70),
+                ("export", 70),
+                ("export", 70),
+                ("invalid_export", 71),
+                ("push", 72),
+            ]
+        );
+    }
+
+    #[test]
+    fn gadgetbridge_errors_exit_80_to_82() {
+        assert_eq!(
+            codes(&[
+                &GadgetbridgeError::Database(rusqlite::Error::InvalidQuery),
+                &GadgetbridgeError::Schema("DEVICE".to_string()),
+                &GadgetbridgeError::UnknownDevice("AA:BB".to_string()),
+            ]),
+            [
+                ("gadgetbridge", 80),
+                ("invalid_gadgetbridge", 81),
+                ("unknown_device", 82),
+            ]
+        );
+    }
+
+    #[test]
+    fn serve_errors_exit_90() {
+        let bind = ServeError::Bind {
+            address: "127.0.0.1:9000".to_string(),
+            source: io(),
+        };
+        assert_eq!(
+            codes(&[&bind, &ServeError::Server(io())]),
+            [("serve", 90), ("serve", 90)]
+        );
+    }
+}
@@ -0,0 +1,242 @@
+pub mod csv;
+pub mod edf;
+pub mod fhir;
+pub mod fit;
+pub mod ics;
+pub mod influx;
+pub mod omh;
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::error::{ExportError, StoreError};
+use crate::protocol::bigdata::SleepSession;
+use crate::protocol::realtime::RealtimeReading;
+use crate::store::database::{DeviceInfo, Store, StoredActivity, StoredHeartRate, StoredOxygen};
+
+/// History kept in the local store that can be exported.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportMetric {
+    #[value(name = "hr")]
+    HeartRate,
+    Activity,
+    Sleep,
+    #[value(name = "spo2")]
+    Oxygen,
+    Realtime,
+}
+
+impl ExportMetric {
+    pub const ALL: [ExportMetric; 5] = [
+        Self::HeartRate,
+        Self::Activity,
+        Self::Sleep,
+        Self::Oxygen,
+        Self::Realtime,
+    ];
+
+    /// Stable name used in file names and structured output.
+    pub fn key(&self) -> &'static str {
+        match self {
+            Self::HeartRate => "heart_rate",
+            Self::Activity => "activity",
+            Self::Sleep => "sleep_stages",
+            Self::Oxygen => "spo2_hourly",
+            Self::Realtime => "realtime",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::HeartRate => "Heart rate",
+            Self::Activity => "Activity",
+            Self::Sleep => "Sleep",
+            Self::Oxygen => "SpO2",
+            Self::Realtime => "Realtime",
+        }
+    }
+}
+
+/// One ring's stored history over `[start, end)`, as the exporters that
+/// map it into other formats take it.
+pub struct History {
+    pub device: String,
+    /// The ring's name and firmware as of its last sync; only the address
+    /// is known for rings that were never synced.
+    pub info: DeviceInfo,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub exported_at: DateTime<Utc>,
+    /// Logged samples, oldest first; empty slots are not stored.
+    pub heart_rate: Vec<StoredHeartRate>,
+    pub activity: Vec<StoredActivity>,
+    /// Sessions that ended in the range.
+    pub sleep: Vec<SleepSession>,
+    pub oxygen: Vec<StoredOxygen>,
+    pub realtime: Vec<RealtimeReading>,
+}
+
+impl History {
+    /// Reads everything stored for `device` in `[start, end)`. Nothing here
+    /// talks to the ring; `sync` brings its history into the store.
+    pub fn load(
+        store: &Store,
+        device: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Self, StoreError> {
+        let mine = |stored: &String| stored.eq_ignore_ascii_case(device);
+        Ok(Self {
+            device: device.to_string(),
+            info: store.device_info(device)?.unwrap_or_else(|| DeviceInfo {
+                name: device.to_string(),
+                manufacturer: None,
+                firmware: None,
+                hardware: None,
+            }),
+            start,
+            end,
+            exported_at: Utc::now(),
+            heart_rate: store
+                .heart_rate_samples(start, end)?
+                .into_iter()
+                .filter(|s| mine(&s.device))
+                .collect(),
+            activity: store
+                .activity_slots(start, end)?
+                .into_iter()
+                .filter(|s| mine(&s.device))
+                .collect(),
+            sleep: store
+                .sleep_sessions(start, end)?
+                .into_iter()
+                .filter(|s| mine(&s.device))
+                .map(|s| s.session)
+                .collect(),
+            oxygen: store
+                .oxygen_hours(start, end)?
+                .into_iter()
+                .filter(|s| mine(&s.device))
+                .collect(),
+            realtime: store
+                .realtime_readings(start, end)?
+                .into_iter()
+                .filter(|s| mine(&s.device))
+                .map(|s| s.reading)
+                .collect(),
+        })
+    }
+}
+
+/// Name-based UUID for one exported value, derived from the ring's
+/// address, where the value came from, what it is and when, so exporting
+/// the same data twice yields the same ids.
+pub fn value_id(device: &str, source: &str, kind: &str, at: DateTime<Utc>) -> String {
+    let name = format!("colmi:{device}:{source}:{kind}:{}", at.timestamp_millis());
+    Uuid::new_v5(&Uuid::NAMESPACE_URL, name.as_bytes()).to_string()
+}
+
+/// Writes `document` as pretty JSON once `validation` has passed.
+pub fn write_json<T: Serialize>(
+    document: &T,
+    path: &Path,
+    validation: Result<(), ExportError>,
+) -> Result<(), ExportError> {
+    validation?;
+    let writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(writer, document)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+    use crate::protocol::bigdata::SleepStage;
+    use crate::protocol::hr::{HeartRateLog, HeartRateSample};
+
+    const RING: &str = "AA:BB:CC:DD:EE:01";
+    const OTHER: &str = "AA:BB:CC:DD:EE:02";
+
+    fn at(day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, day, hour, 0, 0).unwrap()
+    }
+
+    fn log(day: u32, bpm: u8) -> HeartRateLog {
+        let interval = Duration::minutes(5);
+        HeartRateLog {
+            day_start: at(day, 0),
+            interval,
+            samples: (0..3)
+                .map(|i| HeartRateSample {
+                    timestamp: at(day, 0) + interval * i,
+                    bpm: (i != 1).then_some(bpm),
+                })
+                .collect(),
+        }
+    }
+
+    fn night(day: u32) -> SleepSession {
+        SleepSession {
+            start: at(day, 1),
+            end: at(day, 2),
+            stages: vec![SleepStage {
+                phase_type: 3,
+                start: at(day, 1),
+                end: at(day, 2),
+            }],
+        }
+    }
+
+    fn store() -> Store {
+        let mut store = Store::open(":memory:").unwrap();
+        for device in [RING, OTHER] {
+            for day in [9, 10, 11] {
+                store.save_heart_rate_log(device, &log(day, 60)).unwrap();
+            }
+            store.save_sleep(device, &[night(9), night(10)]).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn loads_one_ring_within_the_range() {
+        let history = History::load(&store(), RING, at(10, 0), at(11, 0)).unwrap();
+        assert_eq!(history.device, RING);
+        let times: Vec<_> = history.heart_rate.iter().map(|s| s.timestamp).collect();
+        assert_eq!(
+            times,
+            [at(10, 0), at(10, 0) + Duration::minutes(10)],
+            "empty slots and other days are not stored or loaded"
+        );
+        assert!(history.heart_rate.iter().all(|s| s.device == RING));
+        assert_eq!(history.sleep.len(), 1);
+        assert_eq!(history.sleep[0].end, at(10, 2));
+    }
+
+    #[test]
+    fn device_info_comes_from_the_last_sync() {
+        let mut store = store();
+        let history = History::load(&store, RING, at(10, 0), at(11, 0)).unwrap();
+        assert_eq!(history.info.name, RING);
+        assert_eq!(history.info.firmware, None);
+
+        let info = DeviceInfo {
+            name: "R02_01".to_string(),
+            manufacturer: Some("Colmi".to_string()),
+            firmware: Some("RY02_3.00.06".to_string()),
+            hardware: Some("RY02_V3.0".to_string()),
+        };
+        store.save_device_info(RING, &info).unwrap();
+        let history = History::load(&store, RING, at(10, 0), at(11, 0)).unwrap();
+        assert_eq!(history.info.name, "R02_01");
+        assert_eq!(history.info.firmware.as_deref(), Some("RY02_3.00.06"));
+    }
+}
@@ -0,0 +1,138 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{Duration, NaiveDate};
+
+use crate::calendar::Calendar;
+use crate::error::ExportError;
+use crate::export::ExportMetric;
+use crate::protocol::bigdata::sleep_phase_label;
+use crate::store::database::Store;
+
+/// ISO 8601 in the calendar's zone, with its UTC offset, so spreadsheets
+/// show local times and the instant stays unambiguous across DST changes.
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%:z";
+const TIMESTAMP_MS_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3f%:z";
+
+pub struct CsvExport {
+    pub path: PathBuf,
+    pub rows: usize,
+}
+
+/// Writes `<metric>_<from>_<to>.csv` into `dir`, covering the local days
+/// `from` through `to`.
+pub fn export_metric(
+    store: &Store,
+    calendar: &Calendar,
+    metric: ExportMetric,
+    from: NaiveDate,
+    to: NaiveDate,
+    dir: &Path,
+) -> Result<CsvExport, ExportError> {
+    let start = calendar.day_start(from);
+    let (_, end) = calendar.day_range(to);
+    let time = |instant| calendar.format(instant, TIMESTAMP_FORMAT);
+
+    let path = dir.join(format!("{}_{from}_{to}.csv", metric.key()));
+    let mut writer = ::csv::Writer::from_path(&path)?;
+
+    let rows = match metric {
+        ExportMetric::HeartRate => {
+            let samples = store.heart_rate_samples(start, end)?;
+            writer.write_record(["device", "timestamp", "bpm"])?;
+            for sample in &samples {
+                writer.write_record([
+                    sample.device.clone(),
+                    time(sample.timestamp),
+                    sample.bpm.to_string(),
+                ])?;
+            }
+            samples.len()
+        }
+        ExportMetric::Activity => {
+            let slots = store.activity_slots(start, end)?;
+            writer.write_record(["device", "start", "end", "steps", "calories", "distance"])?;
+            for slot in &slots {
+                writer.write_record([
+                    slot.device.clone(),
+                    time(slot.start),
+                    time(slot.start + Duration::minutes(15)),
+                    slot.steps.to_string(),
+                    slot.calories.to_string(),
+                    slot.distance.to_string(),
+                ])?;
+            }
+            slots.len()
+        }
+        ExportMetric::Sleep => {
+            let sleeps = store.sleep_sessions(start, end)?;
+            writer.write_record([
+                "device",
+                "night",
+                "session_start",
+                "session_end",
+                "start",
+                "end",
+                "stage",
+                "minutes",
+            ])?;
+            let mut rows = 0;
+            for sleep in &sleeps {
+                let session = &sleep.session;
+                for stage in &session.stages {
+                    writer.write_record([
+                        sleep.device.clone(),
+                        calendar.date_of(session.end).to_string(),
+                        time(session.start),
+                        time(session.end),
+                        time(stage.start),
+                        time(stage.end),
+                        sleep_phase_label(stage.phase_type).to_string(),
+                        stage.minutes().to_string(),
+                    ])?;
+                    rows += 1;
+                }
+            }
+            rows
+        }
+        ExportMetric::Oxygen => {
+            let hours = store.oxygen_hours(start, end)?;
+            writer.write_record(["device", "start", "end", "min", "max"])?;
+            for hour in &hours {
+                writer.write_record([
+                    hour.device.clone(),
+                    time(hour.start),
+                    time(hour.start + Duration::hours(1)),
+                    hour.min.to_string(),
+                    hour.max.to_string(),
+                ])?;
+            }
+            hours.len()
+        }
+        ExportMetric::Realtime => {
+            let readings = store.realtime_readings(start, end)?;
+            writer.write_record([
+                "device",
+                "session_start",
+                "timestamp",
+                "type",
+                "value",
+                "unit",
+            ])?;
+            for stored in &readings {
+                let reading = &stored.reading;
+                writer.write_record([
+                    stored.device.clone(),
+                    time(stored.session_start),
+                    calendar.format(reading.timestamp, TIMESTAMP_MS_FORMAT),
+                    reading.reading_type.label().to_string(),
+                    reading.value.to_string(),
+                    reading.reading_type.unit().to_string(),
+                ])?;
+            }
+            readings.len()
+        }
+    };
+
+    writer.flush()?;
+    Ok(CsvExport { path, rows })
+}
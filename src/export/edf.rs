@@ -0,0 +1,314 @@
+//! EDF+ writer for hypnograms: one continuous (`EDF+C`) recording per
+//! night with the sleep stages as annotations and, optionally, heart rate
+//! and SpO2 resampled into the data records.
+
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::calendar::Calendar;
+use crate::error::ExportError;
+use crate::protocol::bigdata::{SleepSession, sleep_phase_label};
+use crate::protocol::hr::DEFAULT_LOG_INTERVAL_MINUTES;
+use crate::store::database::{StoredHeartRate, StoredOxygen};
+
+/// The usual scoring epoch; every data record covers one.
+const RECORD_SECONDS: i64 = 30;
+const ANNOTATIONS_LABEL: &str = "EDF Annotations";
+/// Written for records no value covers. Signal ranges start at it, one
+/// below any real reading, and the transducer field says what it means.
+const NO_DATA: i16 = -1;
+
+/// A slow signal with one sample per data record. Each source value holds
+/// for its own interval; records no value covers are written as [`NO_DATA`].
+pub struct Signal {
+    label: &'static str,
+    dimension: &'static str,
+    physical_max: i16,
+    values: Vec<(DateTime<Utc>, DateTime<Utc>, f64)>,
+}
+
+impl Signal {
+    /// Logged heart rate. Each sample holds for the log interval, taken as
+    /// the shortest gap between stored samples, so slots the ring left
+    /// empty stay [`NO_DATA`].
+    pub fn heart_rate(samples: &[StoredHeartRate]) -> Self {
+        let interval = samples
+            .windows(2)
+            .map(|pair| pair[1].timestamp - pair[0].timestamp)
+            .filter(|gap| *gap > Duration::zero())
+            .min()
+            .unwrap_or(Duration::minutes(DEFAULT_LOG_INTERVAL_MINUTES as i64));
+        let values = samples
+            .iter()
+            .map(|sample| {
+                (
+                    sample.timestamp,
+                    sample.timestamp + interval,
+                    sample.bpm as f64,
+                )
+            })
+            .collect();
+        Self {
+            label: "HR",
+            dimension: "bpm",
+            physical_max: 250,
+            values,
+        }
+    }
+
+    /// Hourly SpO2, at the middle of each hour's range.
+    pub fn oxygen(hours: &[StoredOxygen]) -> Self {
+        let values = hours
+            .iter()
+            .map(|hour| {
+                (
+                    hour.start,
+                    hour.start + Duration::hours(1),
+                    (hour.min as f64 + hour.max as f64) / 2.0,
+                )
+            })
+            .collect();
+        Self {
+            label: "SpO2",
+            dimension: "%",
+            physical_max: 100,
+            values,
+        }
+    }
+
+    fn at(&self, instant: DateTime<Utc>) -> i16 {
+        self.values
+            .iter()
+            .find(|(start, end, _)| *start <= instant && instant < *end)
+            .map(|(_, _, value)| value.round().clamp(0.0, self.physical_max as f64) as i16)
+            .unwrap_or(NO_DATA)
+    }
+}
+
+/// An encoded EDF+ file.
+pub struct Hypnogram {
+    bytes: Vec<u8>,
+    pub stages: usize,
+}
+
+impl Hypnogram {
+    pub fn write(&self, path: &Path) -> Result<(), ExportError> {
+        fs::write(path, &self.bytes)?;
+        Ok(())
+    }
+}
+
+/// One night as EDF+. Stages are annotated as `Sleep stage <label>` with
+/// [`sleep_phase_label`]'s names; header times are in the calendar's zone
+/// since EDF has no notion of time zones.
+pub fn hypnogram(
+    session: &SleepSession,
+    equipment: &str,
+    calendar: &Calendar,
+    signals: &[Signal],
+) -> Hypnogram {
+    let start = session.start;
+    let end = session
+        .stages
+        .iter()
+        .map(|stage| stage.end)
+        .fold(session.end, DateTime::max);
+    let seconds = (end - start).num_seconds().max(1);
+    let records = ((seconds + RECORD_SECONDS - 1) / RECORD_SECONDS) as usize;
+
+    // Time-keeping TAL first, then the stages starting within the record.
+    let mut annotations = vec![Vec::new(); records];
+    for (record, tals) in annotations.iter_mut().enumerate() {
+        tals.extend(format!("+{}\x14\x14\0", record as i64 * RECORD_SECONDS).bytes());
+    }
+    let mut stages = 0;
+    for stage in &session.stages {
+        let onset = (stage.start - start).num_seconds();
+        let duration = (stage.end - stage.start).num_seconds();
+        let record = (onset / RECORD_SECONDS).clamp(0, records as i64 - 1) as usize;
+        annotations[record].extend(
+            format!(
+                "+{onset}\x15{duration}\x14Sleep stage {}\x14\0",
+                sleep_phase_label(stage.phase_type)
+            )
+            .bytes(),
+        );
+        stages += 1;
+    }
+    let annotation_samples = annotations
+        .iter()
+        .map(Vec::len)
+        .max()
+        .unwrap_or(0)
+        .div_ceil(2);
+
+    let mut bytes = header(
+        start,
+        equipment,
+        calendar,
+        records,
+        signals,
+        annotation_samples,
+    );
+    for (record, mut tals) in annotations.into_iter().enumerate() {
+        let midpoint =
+            start + Duration::seconds(record as i64 * RECORD_SECONDS + RECORD_SECONDS / 2);
+        for signal in signals {
+            bytes.extend(signal.at(midpoint).to_le_bytes());
+        }
+        tals.resize(annotation_samples * 2, 0);
+        bytes.extend(tals);
+    }
+
+    Hypnogram { bytes, stages }
+}
+
+fn header(
+    start: DateTime<Utc>,
+    equipment: &str,
+    calendar: &Calendar,
+    records: usize,
+    signals: &[Signal],
+    annotation_samples: usize,
+) -> Vec<u8> {
+    let count = signals.len() + 1;
+    let mut header = Vec::with_capacity(256 * (count + 1));
+    let equipment: String = equipment
+        .chars()
+        .map(|c| if c == ' ' { '_' } else { c })
+        .collect();
+
+    field(&mut header, "0", 8);
+    field(&mut header, "X X X X", 80);
+    field(
+        &mut header,
+        &format!(
+            "Startdate {} X X {equipment}",
+            calendar.format(start, "%d-%b-%Y").to_uppercase()
+        ),
+        80,
+    );
+    field(&mut header, &calendar.format(start, "%d.%m.%y"), 8);
+    field(&mut header, &calendar.format(start, "%H.%M.%S"), 8);
+    field(&mut header, &(256 * (count + 1)).to_string(), 8);
+    field(&mut header, "EDF+C", 44);
+    field(&mut header, &records.to_string(), 8);
+    field(&mut header, &RECORD_SECONDS.to_string(), 8);
+    field(&mut header, &count.to_string(), 4);
+
+    let mut specs: Vec<SignalSpec> = signals
+        .iter()
+        .map(|signal| SignalSpec {
+            label: signal.label,
+            dimension: signal.dimension,
+            transducer: "No data: -1",
+            physical: (NO_DATA as i32, signal.physical_max as i32),
+            digital: (NO_DATA as i32, signal.physical_max as i32),
+            samples: 1,
+        })
+        .collect();
+    specs.push(SignalSpec {
+        label: ANNOTATIONS_LABEL,
+        dimension: "",
+        transducer: "",
+        physical: (-1, 1),
+        digital: (i16::MIN as i32, i16::MAX as i32),
+        samples: annotation_samples,
+    });
+
+    // Each header field is stored for all signals before the next field.
+    let columns: [(usize, Column); 10] = [
+        (16, |spec| spec.label.to_string()),
+        (80, |spec| spec.transducer.to_string()),
+        (8, |spec| spec.dimension.to_string()),
+        (8, |spec| spec.physical.0.to_string()),
+        (8, |spec| spec.physical.1.to_string()),
+        (8, |spec| spec.digital.0.to_string()),
+        (8, |spec| spec.digital.1.to_string()),
+        (80, |_| String::new()),
+        (8, |spec| spec.samples.to_string()),
+        (32, |_| String::new()),
+    ];
+    for (width, value) in columns {
+        for spec in &specs {
+            field(&mut header, &value(spec), width);
+        }
+    }
+    header
+}
+
+struct SignalSpec {
+    label: &'static str,
+    transducer: &'static str,
+    dimension: &'static str,
+    physical: (i32, i32),
+    digital: (i32, i32),
+    samples: usize,
+}
+
+/// Fills one per-signal header field.
+type Column = fn(&SignalSpec) -> String;
+
+/// Left-aligned printable ASCII, padded with spaces to `width`.
+fn field(header: &mut Vec<u8>, value: &str, width: usize) {
+    let mut bytes: Vec<u8> = value
+        .bytes()
+        .filter(|b| (0x20..0x7F).contains(b))
+        .take(width)
+        .collect();
+    bytes.resize(width, b' ');
+    header.extend(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn at(minute: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 10, 1, 0, 0).unwrap() + Duration::minutes(minute)
+    }
+
+    fn sample(minute: i64, bpm: u8) -> StoredHeartRate {
+        StoredHeartRate {
+            device: "AA:BB".to_string(),
+            timestamp: at(minute),
+            bpm,
+        }
+    }
+
+    #[test]
+    fn heart_rate_gaps_are_no_data() {
+        // Logged every 5 minutes with nothing at minute 10.
+        let signal = Signal::heart_rate(&[sample(0, 58), sample(5, 61), sample(15, 64)]);
+        assert_eq!(signal.at(at(2)), 58);
+        assert_eq!(signal.at(at(9)), 61);
+        assert_eq!(signal.at(at(12)), NO_DATA);
+        assert_eq!(signal.at(at(19)), 64);
+        assert_eq!(signal.at(at(20)), NO_DATA);
+        assert_eq!(signal.at(at(-1)), NO_DATA);
+    }
+
+    #[test]
+    fn lone_heart_rate_sample_holds_for_the_default_interval() {
+        let signal = Signal::heart_rate(&[sample(0, 70)]);
+        assert_eq!(signal.at(at(4)), 70);
+        assert_eq!(signal.at(at(5)), NO_DATA);
+    }
+
+    #[test]
+    fn oxygen_holds_for_its_hour() {
+        let hour = StoredOxygen {
+            device: "AA:BB".to_string(),
+            start: at(0),
+            min: 95,
+            max: 98,
+        };
+        let signal = Signal::oxygen(&[hour]);
+        assert_eq!(signal.at(at(59)), 97);
+        assert_eq!(signal.at(at(60)), NO_DATA);
+    }
+}
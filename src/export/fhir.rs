@@ -0,0 +1,599 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::error::ExportError;
+use crate::export::{self, History};
+use crate::model::{Metric, Series};
+use crate::protocol::bigdata::SleepSession;
+use crate::protocol::realtime::{ReadingType, RealtimeReading};
+use crate::store::database::DeviceInfo;
+
+const LOINC: &str = "http://loinc.org";
+const UCUM: &str = "http://unitsofmeasure.org";
+const OBSERVATION_CATEGORY: &str = "http://terminology.hl7.org/CodeSystem/observation-category";
+
+const OBSERVATION_STATUSES: [&str; 4] = ["registered", "preliminary", "final", "amended"];
+
+/// A LOINC code with its display name.
+#[derive(Clone, Copy)]
+struct Loinc(&'static str, &'static str);
+
+const HEART_RATE: Loinc = Loinc("8867-4", "Heart rate");
+const OXYGEN_SATURATION: Loinc = Loinc("2708-6", "Oxygen saturation in Arterial blood");
+const PULSE_OXIMETRY: Loinc = Loinc(
+    "59408-5",
+    "Oxygen saturation in Arterial blood by Pulse oximetry",
+);
+const HRV: Loinc = Loinc(
+    "80404-7",
+    "R-R interval.standard deviation (Heart rate variability)",
+);
+const STEPS: Loinc = Loinc("55423-8", "Number of steps in unspecified time Pedometer");
+const SLEEP_DURATION: Loinc = Loinc("93832-4", "Sleep duration");
+const DEEP_SLEEP: Loinc = Loinc("93831-6", "Deep sleep duration");
+const LIGHT_SLEEP: Loinc = Loinc("93830-8", "Light sleep duration");
+const REM_SLEEP: Loinc = Loinc("93829-0", "REM sleep duration");
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bundle {
+    resource_type: &'static str,
+    id: String,
+    #[serde(rename = "type")]
+    bundle_type: &'static str,
+    timestamp: DateTime<Utc>,
+    entry: Vec<Entry>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    full_url: String,
+    resource: Resource,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "resourceType")]
+enum Resource {
+    Device(DeviceResource),
+    Observation(Box<Observation>),
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeviceResource {
+    id: String,
+    identifier: Vec<Identifier>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manufacturer: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    device_name: Vec<DeviceName>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    version: Vec<DeviceVersion>,
+}
+
+#[derive(Serialize)]
+struct Identifier {
+    #[serde(rename = "type")]
+    identifier_type: CodeableConcept,
+    value: String,
+}
+
+#[derive(Serialize)]
+struct DeviceName {
+    name: String,
+    #[serde(rename = "type")]
+    name_type: &'static str,
+}
+
+#[derive(Serialize)]
+struct DeviceVersion {
+    #[serde(rename = "type")]
+    version_type: CodeableConcept,
+    value: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Observation {
+    id: String,
+    status: &'static str,
+    category: Vec<CodeableConcept>,
+    code: CodeableConcept,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subject: Option<Reference>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    effective_date_time: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    effective_period: Option<Period>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_quantity: Option<Quantity>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_range: Option<Range>,
+    device: Reference,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    component: Vec<Component>,
+}
+
+#[derive(Serialize)]
+struct CodeableConcept {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    coding: Vec<Coding>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Coding {
+    system: &'static str,
+    code: &'static str,
+    display: &'static str,
+}
+
+#[derive(Serialize)]
+struct Quantity {
+    value: f64,
+    unit: &'static str,
+    system: &'static str,
+    code: &'static str,
+}
+
+#[derive(Serialize)]
+struct Range {
+    low: Quantity,
+    high: Quantity,
+}
+
+#[derive(Serialize)]
+struct Period {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct Reference {
+    reference: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Component {
+    code: CodeableConcept,
+    value_quantity: Quantity,
+}
+
+impl Bundle {
+    pub fn observation_count(&self) -> usize {
+        self.entry
+            .iter()
+            .filter(|entry| matches!(entry.resource, Resource::Observation(_)))
+            .count()
+    }
+}
+
+/// A `collection` bundle with one Device resource for the ring and an
+/// Observation per recorded value. `patient` is a FHIR id on the receiving
+/// server; without it observations have no subject.
+pub fn bundle(history: &History, patient: Option<&str>) -> Bundle {
+    let mut builder = Builder::new(&history.device, &history.info, patient);
+    builder.heart_rate(&Series::stored_heart_rate(
+        &history.device,
+        &history.heart_rate,
+    ));
+    for series in Series::stored_activity(&history.device, &history.activity) {
+        if series.metric == Metric::Steps {
+            builder.steps(&series);
+        }
+    }
+    builder.sleep(&history.sleep);
+    builder.oxygen(&Series::stored_oxygen(&history.device, &history.oxygen));
+    builder.realtime(&history.realtime);
+    builder.finish(history.exported_at)
+}
+
+struct Builder<'a> {
+    address: &'a str,
+    device: &'a DeviceInfo,
+    device_id: String,
+    subject: Option<Reference>,
+    observations: Vec<Observation>,
+}
+
+impl<'a> Builder<'a> {
+    fn new(address: &'a str, device: &'a DeviceInfo, patient: Option<&str>) -> Self {
+        Self {
+            address,
+            device,
+            device_id: resource_id(&format!("colmi:device:{address}")),
+            subject: patient.map(|id| Reference {
+                reference: format!("Patient/{id}"),
+            }),
+            observations: Vec::new(),
+        }
+    }
+
+    fn observation(
+        &self,
+        source: &str,
+        category: &'static str,
+        code: CodeableConcept,
+        at: DateTime<Utc>,
+        end: Option<DateTime<Utc>>,
+    ) -> Observation {
+        let key = code.coding.first().map(|c| c.code).unwrap_or_default();
+        Observation {
+            id: export::value_id(self.address, source, key, at),
+            status: "final",
+            category: vec![CodeableConcept {
+                coding: vec![Coding {
+                    system: OBSERVATION_CATEGORY,
+                    code: category,
+                    display: match category {
+                        "vital-signs" => "Vital Signs",
+                        _ => "Activity",
+                    },
+                }],
+                text: None,
+            }],
+            code,
+            subject: self.subject.as_ref().map(|subject| Reference {
+                reference: subject.reference.clone(),
+            }),
+            effective_date_time: end.is_none().then_some(at),
+            effective_period: end.map(|end| Period { start: at, end }),
+            value_quantity: None,
+            value_range: None,
+            device: Reference {
+                reference: format!("urn:uuid:{}", self.device_id),
+            },
+            component: Vec::new(),
+        }
+    }
+
+    fn heart_rate(&mut self, heart_rate: &Series) {
+        for measurement in heart_rate.measurements() {
+            let mut observation = self.observation(
+                "log",
+                "vital-signs",
+                concept(&[HEART_RATE]),
+                measurement.timestamp,
+                None,
+            );
+            observation.value_quantity = Some(beats_per_minute(measurement.value));
+            self.observations.push(observation);
+        }
+    }
+
+    /// Steps per 15-minute slot; slots without steps are left out.
+    fn steps(&mut self, steps: &Series) {
+        for measurement in steps.measurements() {
+            if measurement.value <= 0.0 {
+                continue;
+            }
+            let mut observation = self.observation(
+                "log",
+                "activity",
+                concept(&[STEPS]),
+                measurement.timestamp,
+                measurement.end,
+            );
+            observation.value_quantity = Some(Quantity {
+                value: measurement.value,
+                unit: "steps",
+                system: UCUM,
+                code: "{steps}",
+            });
+            self.observations.push(observation);
+        }
+    }
+
+    /// One observation per night: total sleep, with the time in each stage
+    /// as components. Awake time has no LOINC code and is named in text.
+    fn sleep(&mut self, sessions: &[SleepSession]) {
+        for session in sessions {
+            let mut observation = self.observation(
+                "log",
+                "activity",
+                concept(&[SLEEP_DURATION]),
+                session.start,
+                Some(session.end),
+            );
+            observation.value_quantity = Some(minutes(session.total_minutes() as f64));
+            observation.component = session
+                .stage_breakdown()
+                .into_iter()
+                .filter_map(|(label, stage_minutes)| {
+                    let code = match label {
+                        "deep" => concept(&[DEEP_SLEEP]),
+                        "light" => concept(&[LIGHT_SLEEP]),
+                        "REM" => concept(&[REM_SLEEP]),
+                        "awake" => CodeableConcept {
+                            coding: Vec::new(),
+                            text: Some("Awake duration".to_string()),
+                        },
+                        _ => return None,
+                    };
+                    Some(Component {
+                        code,
+                        value_quantity: minutes(stage_minutes as f64),
+                    })
+                })
+                .collect();
+            self.observations.push(observation);
+        }
+    }
+
+    /// Hourly SpO2 as a low/high range.
+    fn oxygen(&mut self, oxygen: &Series) {
+        for measurement in oxygen.measurements() {
+            let (Some(low), Some(high)) = (measurement.min, measurement.max) else {
+                continue;
+            };
+            let mut observation = self.observation(
+                "log",
+                "vital-signs",
+                concept(&[OXYGEN_SATURATION, PULSE_OXIMETRY]),
+                measurement.timestamp,
+                measurement.end,
+            );
+            observation.value_range = Some(Range {
+                low: percent(low),
+                high: percent(high),
+            });
+            self.observations.push(observation);
+        }
+    }
+
+    fn realtime(&mut self, readings: &[RealtimeReading]) {
+        for reading in readings {
+            let value = reading.value as f64;
+            let (code, quantity) = match reading.reading_type {
+                ReadingType::HeartRateBatch => (concept(&[HEART_RATE]), beats_per_minute(value)),
+                ReadingType::BloodOxygen => (
+                    concept(&[OXYGEN_SATURATION, PULSE_OXIMETRY]),
+                    percent(value),
+                ),
+                ReadingType::Hrv => (
+                    concept(&[HRV]),
+                    Quantity {
+                        value,
+                        unit: "ms",
+                        system: UCUM,
+                        code: "ms",
+                    },
+                ),
+            };
+            let mut observation =
+                self.observation("realtime", "vital-signs", code, reading.timestamp, None);
+            observation.value_quantity = Some(quantity);
+            self.observations.push(observation);
+        }
+    }
+
+    fn finish(self, timestamp: DateTime<Utc>) -> Bundle {
+        let device = DeviceResource {
+            id: self.device_id.clone(),
+            identifier: vec![Identifier {
+                identifier_type: CodeableConcept {
+                    coding: Vec::new(),
+                    text: Some("Bluetooth address".to_string()),
+                },
+                value: self.address.to_string(),
+            }],
+            manufacturer: self.device.manufacturer.clone(),
+            device_name: vec![DeviceName {
+                name: self.device.name.clone(),
+                name_type: "user-friendly-name",
+            }],
+            version: [
+                ("firmware", &self.device.firmware),
+                ("hardware", &self.device.hardware),
+            ]
+            .into_iter()
+            .filter_map(|(kind, value)| {
+                Some(DeviceVersion {
+                    version_type: CodeableConcept {
+                        coding: Vec::new(),
+                        text: Some(kind.to_string()),
+                    },
+                    value: value.clone()?,
+                })
+            })
+            .collect(),
+        };
+
+        let mut entry = vec![Entry {
+            full_url: format!("urn:uuid:{}", device.id),
+            resource: Resource::Device(device),
+        }];
+        entry.extend(self.observations.into_iter().map(|observation| Entry {
+            full_url: format!("urn:uuid:{}", observation.id),
+            resource: Resource::Observation(Box::new(observation)),
+        }));
+
+        Bundle {
+            resource_type: "Bundle",
+            id: resource_id(&format!(
+                "colmi:bundle:{}:{}",
+                self.address,
+                timestamp.timestamp_millis()
+            )),
+            bundle_type: "collection",
+            timestamp,
+            entry,
+        }
+    }
+}
+
+fn resource_id(name: &str) -> String {
+    Uuid::new_v5(&Uuid::NAMESPACE_URL, name.as_bytes()).to_string()
+}
+
+fn concept(codes: &[Loinc]) -> CodeableConcept {
+    CodeableConcept {
+        coding: codes
+            .iter()
+            .map(|&Loinc(code, display)| Coding {
+                system: LOINC,
+                code,
+                display,
+            })
+            .collect(),
+        text: codes.first().map(|&Loinc(_, display)| display.to_string()),
+    }
+}
+
+fn beats_per_minute(value: f64) -> Quantity {
+    Quantity {
+        value,
+        unit: "beats/minute",
+        system: UCUM,
+        code: "/min",
+    }
+}
+
+fn percent(value: f64) -> Quantity {
+    Quantity {
+        value,
+        unit: "%",
+        system: UCUM,
+        code: "%",
+    }
+}
+
+fn minutes(value: f64) -> Quantity {
+    Quantity {
+        value,
+        unit: "min",
+        system: UCUM,
+        code: "min",
+    }
+}
+
+/// Checks the structural rules a FHIR server would reject the bundle for,
+/// so problems show up before the file is sent anywhere.
+pub fn validate(bundle: &Bundle) -> Result<(), ExportError> {
+    let mut issues = Vec::new();
+    let mut full_urls = HashSet::new();
+    let devices: HashSet<&str> = bundle
+        .entry
+        .iter()
+        .filter(|entry| matches!(entry.resource, Resource::Device(_)))
+        .map(|entry| entry.full_url.as_str())
+        .collect();
+
+    if bundle.resource_type != "Bundle" || bundle.bundle_type != "collection" {
+        issues.push("bundle must be a collection".to_string());
+    }
+
+    for (index, entry) in bundle.entry.iter().enumerate() {
+        let mut issue = |message: String| issues.push(format!("entry {index}: {message}"));
+
+        if !full_urls.insert(entry.full_url.as_str()) {
+            issue(format!("duplicate fullUrl {}", entry.full_url));
+        }
+        let id = match &entry.resource {
+            Resource::Device(device) => &device.id,
+            Resource::Observation(observation) => &observation.id,
+        };
+        if !is_fhir_id(id) {
+            issue(format!("invalid id '{id}'"));
+        }
+        if entry.full_url != format!("urn:uuid:{id}") || Uuid::parse_str(id).is_err() {
+            issue(format!("fullUrl {} does not match its id", entry.full_url));
+        }
+
+        let Resource::Observation(observation) = &entry.resource else {
+            continue;
+        };
+        if !OBSERVATION_STATUSES.contains(&observation.status) {
+            issue(format!("invalid status '{}'", observation.status));
+        }
+        if !is_valid_concept(&observation.code) {
+            issue("code needs a coding or text".to_string());
+        }
+        for component in &observation.component {
+            if !is_valid_concept(&component.code) {
+                issue("component code needs a coding or text".to_string());
+            }
+        }
+        match (
+            &observation.effective_date_time,
+            &observation.effective_period,
+        ) {
+            (Some(_), None) => {}
+            (None, Some(period)) if period.start <= period.end => {}
+            (None, Some(_)) => issue("effectivePeriod ends before it starts".to_string()),
+            _ => issue("needs exactly one effective[x]".to_string()),
+        }
+        match (&observation.value_quantity, &observation.value_range) {
+            (Some(_), Some(_)) => issue("has more than one value[x]".to_string()),
+            (None, None) if observation.component.is_empty() => {
+                issue("has neither a value nor components".to_string())
+            }
+            _ => {}
+        }
+        let quantities = observation
+            .value_quantity
+            .iter()
+            .chain(
+                observation
+                    .value_range
+                    .iter()
+                    .flat_map(|r| [&r.low, &r.high]),
+            )
+            .chain(observation.component.iter().map(|c| &c.value_quantity));
+        for quantity in quantities {
+            if !quantity.value.is_finite() || quantity.system != UCUM || quantity.code.is_empty() {
+                issue(format!(
+                    "invalid quantity {} {}",
+                    quantity.value, quantity.unit
+                ));
+            }
+        }
+        if let Some(range) = &observation.value_range
+            && range.low.value > range.high.value
+        {
+            issue("valueRange low is above high".to_string());
+        }
+        if !devices.contains(observation.device.reference.as_str()) {
+            issue(format!(
+                "device reference {} is not in the bundle",
+                observation.device.reference
+            ));
+        }
+        if let Some(subject) = &observation.subject
+            && !subject
+                .reference
+                .strip_prefix("Patient/")
+                .is_some_and(is_fhir_id)
+        {
+            issue(format!("invalid subject reference {}", subject.reference));
+        }
+    }
+
+    if issues.is_empty() {
+        Ok(())
+    } else {
+        Err(ExportError::Invalid(issues.join("; ")))
+    }
+}
+
+fn is_valid_concept(concept: &CodeableConcept) -> bool {
+    concept
+        .coding
+        .iter()
+        .all(|coding| !coding.system.is_empty() && !coding.code.is_empty())
+        && (!concept.coding.is_empty() || concept.text.is_some())
+}
+
+/// FHIR ids: 1-64 letters, digits, `-` or `.`.
+fn is_fhir_id(id: &str) -> bool {
+    (1..=64).contains(&id.len())
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
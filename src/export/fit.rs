@@ -0,0 +1,551 @@
+//! Encoder for Garmin's FIT (Flexible and Interoperable Data Transfer)
+//! format: a 14-byte header, definition messages describing the layout of
+//! each local message type, little-endian data messages and a trailing
+//! CRC-16.
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::calendar::Calendar;
+use crate::error::ExportError;
+use crate::export::History;
+use crate::protocol::realtime::ReadingType;
+use crate::protocol::workout::{SportType, WorkoutSummary};
+use crate::store::database::StoredSession;
+
+const HEADER_SIZE: u8 = 14;
+const PROTOCOL_VERSION: u8 = 0x20;
+const PROFILE_VERSION: u16 = 2132;
+/// 1989-12-31T00:00:00Z, where FIT timestamps count from.
+const FIT_EPOCH: i64 = 631_065_600;
+/// FIT reserves `0xFF` as "development" manufacturer.
+const MANUFACTURER_DEVELOPMENT: u16 = 255;
+const LOCAL_TYPES: usize = 16;
+
+const CRC_TABLE: [u16; 16] = [
+    0x0000, 0xCC01, 0xD801, 0x1400, 0xF001, 0x3C00, 0x2800, 0xE401, 0xA001, 0x6C00, 0x7800, 0xB401,
+    0x5000, 0x9C01, 0x8801, 0x4400,
+];
+
+// Global message numbers from the FIT profile.
+const FILE_ID: u16 = 0;
+const SESSION: u16 = 18;
+const LAP: u16 = 19;
+const RECORD: u16 = 20;
+const EVENT: u16 = 21;
+const ACTIVITY: u16 = 34;
+const MONITORING: u16 = 55;
+const MONITORING_INFO: u16 = 103;
+const SLEEP_LEVEL: u16 = 275;
+
+/// Field 253 of every message that has one.
+const TIMESTAMP: u8 = 253;
+const MESSAGE_INDEX: u8 = 254;
+
+const FILE_ACTIVITY: u8 = 4;
+const FILE_MONITORING_B: u8 = 32;
+
+const EVENT_TIMER: u8 = 0;
+const EVENT_SESSION: u8 = 8;
+const EVENT_LAP: u8 = 9;
+const EVENT_ACTIVITY: u8 = 26;
+const EVENT_TYPE_START: u8 = 0;
+const EVENT_TYPE_STOP: u8 = 1;
+const EVENT_TYPE_STOP_ALL: u8 = 4;
+
+const ACTIVITY_TYPE_WALKING: u8 = 6;
+
+#[derive(Clone, Copy)]
+enum Value {
+    Enum(u8),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U32z(u32),
+}
+
+impl Value {
+    fn base_type(&self) -> u8 {
+        match self {
+            Self::Enum(_) => 0x00,
+            Self::U8(_) => 0x02,
+            Self::U16(_) => 0x84,
+            Self::U32(_) => 0x86,
+            Self::U32z(_) => 0x8C,
+        }
+    }
+
+    fn size(&self) -> u8 {
+        match self {
+            Self::Enum(_) | Self::U8(_) => 1,
+            Self::U16(_) => 2,
+            Self::U32(_) | Self::U32z(_) => 4,
+        }
+    }
+
+    fn write(&self, data: &mut Vec<u8>) {
+        match *self {
+            Self::Enum(v) | Self::U8(v) => data.push(v),
+            Self::U16(v) => data.extend_from_slice(&v.to_le_bytes()),
+            Self::U32(v) | Self::U32z(v) => data.extend_from_slice(&v.to_le_bytes()),
+        }
+    }
+}
+
+/// Field number, size and base type, as written in a definition message.
+type Layout = Vec<(u8, u8, u8)>;
+
+type Fields = Vec<(u8, Value)>;
+
+/// A FIT file being built in memory.
+pub struct FitFile {
+    data: Vec<u8>,
+    /// Global message number and layout per local message type.
+    definitions: Vec<(u16, Layout)>,
+    messages: usize,
+}
+
+impl FitFile {
+    fn new(file_type: u8, device: &str, created: DateTime<Utc>) -> Self {
+        let mut file = Self {
+            data: Vec::new(),
+            definitions: Vec::new(),
+            messages: 0,
+        };
+        let mut fields = vec![
+            (0, Value::Enum(file_type)),
+            (1, Value::U16(MANUFACTURER_DEVELOPMENT)),
+            (2, Value::U16(0)),
+            (4, Value::U32(fit_time(created))),
+        ];
+        if let Some(serial) = serial_number(device) {
+            fields.push((3, Value::U32z(serial)));
+        }
+        file.message(FILE_ID, &fields);
+        file
+    }
+
+    /// Data messages written, not counting the file id.
+    pub fn messages(&self) -> usize {
+        self.messages - 1
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), ExportError> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE as usize + self.data.len() + 2);
+        bytes.push(HEADER_SIZE);
+        bytes.push(PROTOCOL_VERSION);
+        bytes.extend_from_slice(&PROFILE_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(b".FIT");
+        bytes.extend_from_slice(&crc(&bytes).to_le_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes.extend_from_slice(&crc(&bytes).to_le_bytes());
+        bytes
+    }
+
+    /// Appends a data message, preceded by a definition whenever the
+    /// message's layout differs from what its local type last described.
+    fn message(&mut self, global: u16, fields: &[(u8, Value)]) {
+        let layout: Layout = fields
+            .iter()
+            .map(|(number, value)| (*number, value.size(), value.base_type()))
+            .collect();
+        let local = match self.definitions.iter().position(|(g, _)| *g == global) {
+            Some(local) if self.definitions[local].1 == layout => local,
+            Some(local) => {
+                self.define(local, global, layout);
+                local
+            }
+            None => {
+                let local = self.definitions.len() % LOCAL_TYPES;
+                self.define(local, global, layout);
+                local
+            }
+        };
+
+        self.data.push(local as u8);
+        for (_, value) in fields {
+            value.write(&mut self.data);
+        }
+        self.messages += 1;
+    }
+
+    fn define(&mut self, local: usize, global: u16, layout: Layout) {
+        self.data.push(0x40 | local as u8);
+        self.data.push(0); // reserved
+        self.data.push(0); // little-endian
+        self.data.extend_from_slice(&global.to_le_bytes());
+        self.data.push(layout.len() as u8);
+        for &(number, size, base_type) in &layout {
+            self.data.extend_from_slice(&[number, size, base_type]);
+        }
+        if local < self.definitions.len() {
+            self.definitions[local] = (global, layout);
+        } else {
+            self.definitions.push((global, layout));
+        }
+    }
+}
+
+/// Monitoring file with logged heart rate, steps, calories and distance
+/// per 15-minute slot, and sleep stages. Steps, calories and distance are
+/// cumulative over each local day, as FIT monitoring expects.
+pub fn monitoring(history: &History, calendar: &Calendar) -> FitFile {
+    let mut file = FitFile::new(FILE_MONITORING_B, &history.device, history.exported_at);
+    file.message(
+        MONITORING_INFO,
+        &[
+            (TIMESTAMP, Value::U32(fit_time(history.start))),
+            (0, Value::U32(local_time(calendar, history.start))),
+        ],
+    );
+
+    let mut messages: Vec<(DateTime<Utc>, u16, Fields)> = Vec::new();
+
+    for sample in &history.heart_rate {
+        messages.push((
+            sample.timestamp,
+            MONITORING,
+            vec![(27, Value::U8(sample.bpm))],
+        ));
+    }
+
+    let mut day = None;
+    let (mut steps, mut calories, mut distance) = (0u32, 0.0, 0u32);
+    for slot in &history.activity {
+        if day != Some(calendar.date_of(slot.start)) {
+            day = Some(calendar.date_of(slot.start));
+            (steps, calories, distance) = (0, 0.0, 0);
+        }
+        steps += slot.steps;
+        calories += slot.calories;
+        distance = distance.saturating_add(slot.distance);
+        messages.push((
+            slot.start + Duration::minutes(15),
+            MONITORING,
+            vec![
+                (5, Value::Enum(ACTIVITY_TYPE_WALKING)),
+                (3, Value::U32(steps)),
+                (
+                    1,
+                    Value::U16(calories.round().min(u16::MAX as f64 - 1.0) as u16),
+                ),
+                (2, centimeters(distance)),
+            ],
+        ));
+    }
+
+    for session in &history.sleep {
+        for stage in &session.stages {
+            let level = sleep_level(stage.phase_type);
+            messages.push((stage.start, SLEEP_LEVEL, vec![(0, Value::Enum(level))]));
+        }
+        messages.push((session.end, SLEEP_LEVEL, vec![(0, Value::Enum(1))]));
+    }
+
+    messages.sort_by_key(|(at, _, _)| *at);
+    for (at, global, mut fields) in messages {
+        fields.insert(0, (TIMESTAMP, Value::U32(fit_time(at))));
+        file.message(global, &fields);
+    }
+    file
+}
+
+/// Activity file for a finished workout.
+pub fn workout(device: &str, summary: &WorkoutSummary, calendar: &Calendar) -> FitFile {
+    // FIT counts strides for foot sports, i.e. every other step.
+    let strides = match summary.sport_type {
+        SportType::Walking | SportType::Running | SportType::Hiking => Some(summary.steps / 2),
+        SportType::Cycling | SportType::Other => None,
+    };
+    let totals = Totals {
+        calories: Some(summary.calories),
+        distance: Some(summary.distance),
+        strides,
+        heart_rate: summary.avg_heart_rate.zip(summary.max_heart_rate),
+    };
+    activity(
+        device,
+        sport(summary.sport_type),
+        (summary.started_at, summary.ended_at),
+        summary.duration,
+        &summary.heart_rates,
+        &totals,
+        calendar,
+    )
+}
+
+/// Activity file for a realtime session. Only heart rate fits into FIT
+/// records; SpO2 and HRV readings are left out.
+pub fn realtime_session(session: &StoredSession, calendar: &Calendar) -> FitFile {
+    let heart_rates: Vec<_> = session
+        .readings
+        .iter()
+        .filter(|reading| reading.reading_type == ReadingType::HeartRateBatch)
+        .map(|reading| (reading.timestamp, reading.value))
+        .collect();
+    let bpm = heart_rates.iter().map(|&(_, bpm)| bpm as u32);
+    let totals = Totals {
+        heart_rate: bpm.clone().max().map(|max| {
+            (
+                (bpm.sum::<u32>() / heart_rates.len() as u32) as u8,
+                max as u8,
+            )
+        }),
+        ..Totals::default()
+    };
+    let elapsed = (session.ended_at - session.started_at)
+        .to_std()
+        .unwrap_or_default();
+    activity(
+        &session.device,
+        0,
+        (session.started_at, session.ended_at),
+        elapsed,
+        &heart_rates,
+        &totals,
+        calendar,
+    )
+}
+
+#[derive(Default)]
+struct Totals {
+    calories: Option<f64>,
+    /// Meters.
+    distance: Option<u32>,
+    strides: Option<u32>,
+    /// Average and maximum.
+    heart_rate: Option<(u8, u8)>,
+}
+
+fn activity(
+    device: &str,
+    sport: u8,
+    (start, end): (DateTime<Utc>, DateTime<Utc>),
+    timer: StdDuration,
+    heart_rates: &[(DateTime<Utc>, u8)],
+    totals: &Totals,
+    calendar: &Calendar,
+) -> FitFile {
+    let mut file = FitFile::new(FILE_ACTIVITY, device, end);
+    let elapsed = (end - start).to_std().unwrap_or_default();
+    let ms = |duration: StdDuration| Value::U32(duration.as_millis().min(u32::MAX as u128) as u32);
+
+    file.message(
+        EVENT,
+        &[
+            (TIMESTAMP, Value::U32(fit_time(start))),
+            (0, Value::Enum(EVENT_TIMER)),
+            (1, Value::Enum(EVENT_TYPE_START)),
+        ],
+    );
+    for &(at, bpm) in heart_rates {
+        file.message(
+            RECORD,
+            &[(TIMESTAMP, Value::U32(fit_time(at))), (3, Value::U8(bpm))],
+        );
+    }
+    file.message(
+        EVENT,
+        &[
+            (TIMESTAMP, Value::U32(fit_time(end))),
+            (0, Value::Enum(EVENT_TIMER)),
+            (1, Value::Enum(EVENT_TYPE_STOP_ALL)),
+        ],
+    );
+
+    let mut summary = vec![
+        (TIMESTAMP, Value::U32(fit_time(end))),
+        (MESSAGE_INDEX, Value::U16(0)),
+        (2, Value::U32(fit_time(start))),
+        (7, ms(elapsed)),
+        (8, ms(timer)),
+    ];
+    if let Some(calories) = totals.calories {
+        summary.push((
+            11,
+            Value::U16(calories.round().min(u16::MAX as f64 - 1.0) as u16),
+        ));
+    }
+    if let Some(distance) = totals.distance {
+        summary.push((9, centimeters(distance)));
+    }
+    if let Some(strides) = totals.strides {
+        summary.push((10, Value::U32(strides)));
+    }
+    let mut lap = summary.clone();
+    lap.extend([
+        (0, Value::Enum(EVENT_LAP)),
+        (1, Value::Enum(EVENT_TYPE_STOP)),
+    ]);
+    if let Some((avg, max)) = totals.heart_rate {
+        lap.extend([(15, Value::U8(avg)), (16, Value::U8(max))]);
+    }
+    file.message(LAP, &lap);
+
+    let mut session = summary;
+    session.extend([
+        (0, Value::Enum(EVENT_SESSION)),
+        (1, Value::Enum(EVENT_TYPE_STOP)),
+        (5, Value::Enum(sport)),
+        (6, Value::Enum(0)),
+        (25, Value::U16(0)),
+        (26, Value::U16(1)),
+    ]);
+    if let Some((avg, max)) = totals.heart_rate {
+        session.extend([(16, Value::U8(avg)), (17, Value::U8(max))]);
+    }
+    file.message(SESSION, &session);
+
+    file.message(
+        ACTIVITY,
+        &[
+            (TIMESTAMP, Value::U32(fit_time(end))),
+            (0, ms(timer)),
+            (1, Value::U16(1)),
+            (2, Value::Enum(0)), // manual
+            (3, Value::Enum(EVENT_ACTIVITY)),
+            (4, Value::Enum(EVENT_TYPE_STOP)),
+            (5, Value::U32(local_time(calendar, end))),
+        ],
+    );
+    file
+}
+
+fn sport(sport_type: SportType) -> u8 {
+    match sport_type {
+        SportType::Running => 1,
+        SportType::Cycling => 2,
+        SportType::Walking => 11,
+        SportType::Hiking => 17,
+        SportType::Other => 0,
+    }
+}
+
+/// FIT sleep levels: unmeasurable, awake, light, deep, REM.
+fn sleep_level(phase_type: u8) -> u8 {
+    match phase_type {
+        2 => 2,
+        3 => 3,
+        4 => 4,
+        5 => 1,
+        _ => 0,
+    }
+}
+
+/// FIT distances are in centimeters; all ones means invalid, so long
+/// distances stop just short of it.
+fn centimeters(meters: u32) -> Value {
+    Value::U32(meters.saturating_mul(100).min(u32::MAX - 1))
+}
+
+fn fit_time(instant: DateTime<Utc>) -> u32 {
+    (instant.timestamp() - FIT_EPOCH).clamp(0, u32::MAX as i64) as u32
+}
+
+fn local_time(calendar: &Calendar, instant: DateTime<Utc>) -> u32 {
+    fit_time(instant + calendar.utc_offset(instant))
+}
+
+/// The last 32 bits of the Bluetooth address, if it has any.
+fn serial_number(device: &str) -> Option<u32> {
+    let hex: String = device.chars().filter(char::is_ascii_hexdigit).collect();
+    let tail = &hex[hex.len().saturating_sub(8)..];
+    u32::from_str_radix(tail, 16)
+        .ok()
+        .filter(|&serial| serial != 0)
+}
+
+fn crc(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, &byte| {
+        let crc = (crc >> 4) ^ CRC_TABLE[(crc & 0xF) as usize] ^ CRC_TABLE[(byte & 0xF) as usize];
+        (crc >> 4) ^ CRC_TABLE[(crc & 0xF) as usize] ^ CRC_TABLE[(byte >> 4) as usize]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc_matches_known_vector() {
+        // FIT's CRC is CRC-16/ARC, whose check value for "123456789" is 0xBB3D.
+        assert_eq!(crc(b"123456789"), 0xBB3D);
+        assert_eq!(crc(&[]), 0);
+    }
+
+    #[test]
+    fn header_and_definition_round_trip() {
+        let created = DateTime::from_timestamp(FIT_EPOCH + 1_000, 0).unwrap();
+        let mut file = FitFile::new(FILE_MONITORING_B, "AA:BB:CC:DD:EE:FF", created);
+        file.message(
+            MONITORING,
+            &[(TIMESTAMP, Value::U32(42)), (2, centimeters(u32::MAX))],
+        );
+        let bytes = file.to_bytes();
+
+        // Header: size, versions, data length, ".FIT" and its own CRC.
+        let header = &bytes[..HEADER_SIZE as usize];
+        assert_eq!(header[0], HEADER_SIZE);
+        assert_eq!(header[1], PROTOCOL_VERSION);
+        assert_eq!(u16::from_le_bytes([header[2], header[3]]), PROFILE_VERSION);
+        let data_len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        assert_eq!(&header[8..12], b".FIT");
+        assert_eq!(crc(header), 0);
+        assert_eq!(bytes.len(), HEADER_SIZE as usize + data_len + 2);
+        assert_eq!(crc(&bytes), 0);
+
+        // Walk the records, checking each data message against the
+        // definition its local type last received.
+        let data = &bytes[HEADER_SIZE as usize..HEADER_SIZE as usize + data_len];
+        let mut definitions = vec![None; LOCAL_TYPES];
+        let mut messages = Vec::new();
+        let mut index = 0;
+        while index < data.len() {
+            let record = data[index];
+            let local = (record & 0x0F) as usize;
+            if record & 0x40 != 0 {
+                assert_eq!(data[index + 2], 0, "little-endian");
+                let global = u16::from_le_bytes([data[index + 3], data[index + 4]]);
+                let count = data[index + 5] as usize;
+                let fields: Vec<_> = data[index + 6..index + 6 + count * 3]
+                    .chunks(3)
+                    .map(|field| (field[0], field[1], field[2]))
+                    .collect();
+                definitions[local] = Some((global, fields));
+                index += 6 + count * 3;
+            } else {
+                let (global, fields) = definitions[local].clone().expect("defined local type");
+                let size: usize = fields.iter().map(|&(_, size, _)| size as usize).sum();
+                messages.push((global, fields, data[index + 1..index + 1 + size].to_vec()));
+                index += 1 + size;
+            }
+        }
+        assert_eq!(index, data.len());
+
+        assert_eq!(messages.len(), 2);
+        let (global, fields, values) = &messages[0];
+        assert_eq!(*global, FILE_ID);
+        assert_eq!(fields[0], (0, 1, 0x00));
+        assert_eq!(values[0], FILE_MONITORING_B);
+        assert!(fields.contains(&(3, 4, 0x8C)));
+
+        let (global, fields, values) = &messages[1];
+        assert_eq!(*global, MONITORING);
+        assert_eq!(fields, &vec![(TIMESTAMP, 4, 0x86), (2, 4, 0x86)]);
+        assert_eq!(u32::from_le_bytes(values[..4].try_into().unwrap()), 42);
+        assert_eq!(
+            u32::from_le_bytes(values[4..].try_into().unwrap()),
+            u32::MAX - 1
+        );
+        assert_eq!(file.messages(), 1);
+    }
+}
@@ -0,0 +1,224 @@
+//! iCalendar (RFC 5545) events for sleep, workouts and realtime sessions.
+//! Event times are written in UTC, so nights that cross midnight or a DST
+//! change land where they happened whatever zone the viewer is in; the
+//! descriptions repeat the times in the configured zone.
+
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::calendar::Calendar;
+use crate::error::ExportError;
+use crate::protocol::bigdata::SleepSession;
+use crate::protocol::realtime::ReadingType;
+use crate::protocol::workout::WorkoutSummary;
+use crate::store::database::StoredSession;
+
+const UTC_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+/// Content lines are folded after this many octets.
+const LINE_OCTETS: usize = 75;
+
+/// A VCALENDAR being assembled.
+pub struct Ics<'a> {
+    calendar: &'a Calendar,
+    created: DateTime<Utc>,
+    events: Vec<Vec<(&'static str, String)>>,
+}
+
+impl<'a> Ics<'a> {
+    pub fn new(calendar: &'a Calendar) -> Self {
+        Self {
+            calendar,
+            created: Utc::now(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn events(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn sleep(&mut self, device: &str, session: &SleepSession) {
+        let mut description = vec![
+            self.span(session.start, session.end),
+            format!("Total: {}", hours_minutes(session.total_minutes())),
+        ];
+        description.extend(
+            session
+                .stage_breakdown()
+                .into_iter()
+                .map(|(label, minutes)| format!("{label}: {}", hours_minutes(minutes))),
+        );
+        self.event(
+            device,
+            "sleep",
+            (session.start, session.end),
+            format!("Sleep {}", hours_minutes(session.total_minutes())),
+            description,
+        );
+    }
+
+    pub fn workout(&mut self, device: &str, summary: &WorkoutSummary) {
+        let minutes = summary.duration.as_secs() as i64 / 60;
+        let mut description = vec![
+            self.span(summary.started_at, summary.ended_at),
+            format!("Active: {}", hours_minutes(minutes)),
+        ];
+        if let (Some(avg), Some(max)) = (summary.avg_heart_rate, summary.max_heart_rate) {
+            description.push(format!("Heart rate: avg {avg} bpm, max {max} bpm"));
+        }
+        description.push(format!(
+            "{} steps | {:.1} kcal | {} m",
+            summary.steps, summary.calories, summary.distance
+        ));
+        for (zone, time) in summary.time_in_zones.iter().enumerate().skip(1) {
+            description.push(format!(
+                "Zone {zone}: {}",
+                hours_minutes(time.as_secs() as i64 / 60)
+            ));
+        }
+        self.event(
+            device,
+            "workout",
+            (summary.started_at, summary.ended_at),
+            format!(
+                "Workout: {} {}",
+                summary.sport_type.label(),
+                hours_minutes(minutes)
+            ),
+            description,
+        );
+    }
+
+    pub fn realtime_session(&mut self, session: &StoredSession) {
+        let mut description = vec![self.span(session.started_at, session.ended_at)];
+        for reading_type in [
+            ReadingType::HeartRateBatch,
+            ReadingType::BloodOxygen,
+            ReadingType::Hrv,
+        ] {
+            let values: Vec<u32> = session
+                .readings
+                .iter()
+                .filter(|reading| reading.reading_type == reading_type)
+                .map(|reading| reading.value as u32)
+                .collect();
+            let (Some(min), Some(max)) = (values.iter().min(), values.iter().max()) else {
+                continue;
+            };
+            let unit = reading_type.unit();
+            description.push(format!(
+                "{}: avg {} {unit}, min {min} {unit}, max {max} {unit} ({} readings)",
+                reading_type.label(),
+                values.iter().sum::<u32>() / values.len() as u32,
+                values.len()
+            ));
+        }
+        self.event(
+            &session.device,
+            "realtime",
+            (session.started_at, session.ended_at),
+            "Realtime measurement".to_string(),
+            description,
+        );
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), ExportError> {
+        let mut lines = vec![
+            ("BEGIN", "VCALENDAR".to_string()),
+            ("VERSION", "2.0".to_string()),
+            ("PRODID", "-//colmi_client//EN".to_string()),
+            ("CALSCALE", "GREGORIAN".to_string()),
+            ("METHOD", "PUBLISH".to_string()),
+            ("X-WR-CALNAME", "Colmi ring".to_string()),
+        ];
+        if let Calendar::Named(tz) = self.calendar {
+            lines.push(("X-WR-TIMEZONE", tz.name().to_string()));
+        }
+        for event in &self.events {
+            lines.push(("BEGIN", "VEVENT".to_string()));
+            lines.extend(event.iter().cloned());
+            lines.push(("END", "VEVENT".to_string()));
+        }
+        lines.push(("END", "VCALENDAR".to_string()));
+
+        let text: String = lines
+            .iter()
+            .map(|(name, value)| fold(&format!("{name}:{value}")))
+            .collect();
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// UIDs are derived from device, kind and start, so re-importing an
+    /// export updates events instead of duplicating them.
+    fn event(
+        &mut self,
+        device: &str,
+        kind: &'static str,
+        (start, end): (DateTime<Utc>, DateTime<Utc>),
+        summary: String,
+        description: Vec<String>,
+    ) {
+        let uid = Uuid::new_v5(
+            &Uuid::NAMESPACE_URL,
+            format!("colmi:{device}:{kind}:{}", start.timestamp()).as_bytes(),
+        );
+        self.events.push(vec![
+            ("UID", format!("{uid}@colmi")),
+            ("DTSTAMP", self.created.format(UTC_FORMAT).to_string()),
+            ("DTSTART", start.format(UTC_FORMAT).to_string()),
+            ("DTEND", end.max(start).format(UTC_FORMAT).to_string()),
+            ("SUMMARY", escape(&summary)),
+            ("DESCRIPTION", escape(&description.join("\n"))),
+            ("CATEGORIES", kind.to_uppercase()),
+            ("TRANSP", "TRANSPARENT".to_string()),
+        ]);
+    }
+
+    /// Local start and end, with the date on both ends when they differ.
+    fn span(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> String {
+        let end_format = if self.calendar.date_of(start) == self.calendar.date_of(end) {
+            "%H:%M"
+        } else {
+            "%a %d %b %H:%M"
+        };
+        format!(
+            "{} – {} ({})",
+            self.calendar.format(start, "%a %d %b %H:%M"),
+            self.calendar.format(end, end_format),
+            self.calendar.name()
+        )
+    }
+}
+
+fn hours_minutes(minutes: i64) -> String {
+    format!("{}h {:02}m", minutes / 60, minutes % 60)
+}
+
+/// TEXT value escaping.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Splits a content line into CRLF-terminated chunks of at most
+/// [`LINE_OCTETS`] octets, never inside a UTF-8 character.
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 8);
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > LINE_OCTETS {
+            folded.push_str("\r\n ");
+            octets = 1;
+        }
+        folded.push(c);
+        octets += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
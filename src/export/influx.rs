@@ -0,0 +1,491 @@
+//! InfluxDB line protocol: one measurement per [`Metric`], tagged with the
+//! ring's address and the [`Source`] it was read from, with nanosecond
+//! timestamps. Names are the snake_case ones of the JSON schema.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::ExportError;
+use crate::export::History;
+use crate::model::{Measurement, Series};
+
+/// Lines per HTTP request when pushing history.
+const BATCH_LINES: usize = 5000;
+/// Lines a [`LineStream`] holds while its target is slow or down.
+const QUEUE_LINES: usize = 10_000;
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+/// Attempts left for what is still queued once the stream is closed.
+const CLOSING_ATTEMPTS: u32 = 3;
+
+/// Every measurement in `history`, oldest first within each metric.
+pub fn history_lines(history: &History) -> Vec<String> {
+    let device = history.device.as_str();
+    let mut series = vec![Series::stored_heart_rate(device, &history.heart_rate)];
+    series.extend(Series::stored_activity(device, &history.activity));
+    series.push(Series::sleep(device, &history.sleep));
+    series.push(Series::stored_oxygen(device, &history.oxygen));
+
+    series
+        .iter()
+        .flat_map(Series::measurements)
+        .chain(
+            history
+                .realtime
+                .iter()
+                .map(|reading| Measurement::reading(device, reading)),
+        )
+        .filter_map(|measurement| line(&measurement))
+        .collect()
+}
+
+/// `None` for values line protocol cannot carry, such as times beyond 2262.
+pub fn line(measurement: &Measurement) -> Option<String> {
+    let timestamp = measurement.timestamp.timestamp_nanos_opt()?;
+    let mut line = format!(
+        "{},device={},source={}",
+        escape(&name(&measurement.metric), ", "),
+        escape(&measurement.device, ",= "),
+        escape(&name(&measurement.source), ",= ")
+    );
+    if let Some(label) = &measurement.label {
+        line.push_str(&format!(",label={}", escape(label, ",= ")));
+    }
+
+    let mut fields = vec![format!("value={}", float(measurement.value)?)];
+    for (key, value) in [("min", measurement.min), ("max", measurement.max)] {
+        if let Some(value) = value {
+            fields.push(format!("{key}={}", float(value)?));
+        }
+    }
+    if let Some(end) = measurement.end {
+        fields.push(format!("end={}i", end.timestamp_nanos_opt()?));
+    }
+
+    Some(format!("{line} {} {timestamp}", fields.join(",")))
+}
+
+/// Where lines go: `-` for stdout, an `http://` or `https://` write URL,
+/// or otherwise a file path.
+#[derive(Clone, Debug)]
+pub enum Target {
+    Stdout,
+    File(PathBuf),
+    Http(String),
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(target: &str) -> Result<Self, Self::Err> {
+        Ok(if target == "-" {
+            Self::Stdout
+        } else if target.starts_with("http://") || target.starts_with("https://") {
+            Self::Http(target.to_string())
+        } else if target.is_empty() {
+            return Err("target must not be empty".to_string());
+        } else {
+            Self::File(PathBuf::from(target))
+        })
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stdout => write!(f, "stdout"),
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Http(url) => write!(f, "{url}"),
+        }
+    }
+}
+
+/// An open [`Target`]. HTTP targets get the lines POSTed as-is, so the URL
+/// carries the database or bucket, e.g.
+/// `http://localhost:8086/api/v2/write?org=home&bucket=colmi&precision=ns`.
+pub struct LineWriter {
+    sink: Sink,
+}
+
+enum Sink {
+    Stdout,
+    File(BufWriter<File>),
+    Http { url: String, token: Option<String> },
+}
+
+impl LineWriter {
+    /// `token` is sent as `Authorization: Token <token>` to HTTP targets.
+    pub fn open(target: &Target, token: Option<&str>) -> Result<Self, ExportError> {
+        let sink = match target {
+            Target::Stdout => Sink::Stdout,
+            Target::File(path) => Sink::File(BufWriter::new(File::create(path)?)),
+            Target::Http(url) => Sink::Http {
+                url: url.clone(),
+                token: token.map(str::to_string),
+            },
+        };
+        Ok(Self { sink })
+    }
+
+    pub async fn write(&mut self, lines: &[String]) -> Result<(), ExportError> {
+        match &mut self.sink {
+            Sink::Stdout => {
+                let mut stdout = std::io::stdout().lock();
+                for line in lines {
+                    writeln!(stdout, "{line}")?;
+                }
+                stdout.flush()?;
+            }
+            Sink::File(file) => {
+                for line in lines {
+                    writeln!(file, "{line}")?;
+                }
+                file.flush()?;
+            }
+            Sink::Http { url, token } => {
+                for batch in lines.chunks(BATCH_LINES) {
+                    let body = batch.join("\n");
+                    let (url, token) = (url.clone(), token.clone());
+                    tokio::task::spawn_blocking(move || post(&url, token.as_deref(), body))
+                        .await
+                        .map_err(|err| ExportError::Push(err.to_string()))??;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A [`LineWriter`] fed from a queue and drained by a background task, so
+/// a slow or unreachable target never holds up the readings. Queued lines
+/// go out in batches; a failed batch is retried with growing delays and
+/// kept until it is written, while new lines wait in the queue or, once it
+/// is full, are dropped.
+pub struct LineStream {
+    target: String,
+    lines: mpsc::Sender<String>,
+    notices: mpsc::UnboundedReceiver<String>,
+    notify: mpsc::UnboundedSender<String>,
+    closing: CancellationToken,
+    task: JoinHandle<Result<(), ExportError>>,
+    dropping: bool,
+}
+
+impl LineStream {
+    pub fn spawn(writer: LineWriter, target: &Target) -> Self {
+        let (lines, queue) = mpsc::channel(QUEUE_LINES);
+        let (notify, notices) = mpsc::unbounded_channel();
+        let closing = CancellationToken::new();
+        let task = tokio::spawn(drain(writer, queue, notify.clone(), closing.clone()));
+        Self {
+            target: target.to_string(),
+            lines,
+            notices,
+            notify,
+            closing,
+            task,
+            dropping: false,
+        }
+    }
+
+    /// Queues `line` without waiting.
+    pub fn push(&mut self, line: String) {
+        match self.lines.try_send(line) {
+            Ok(()) => self.dropping = false,
+            Err(TrySendError::Full(_)) if !self.dropping => {
+                self.dropping = true;
+                let _ = self.notify.send(format!(
+                    "Line protocol queue is full, dropping lines until {} catches up",
+                    self.target
+                ));
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// What happened to the writes since the last call, as warnings.
+    pub fn notices(&mut self) -> impl Iterator<Item = String> + '_ {
+        std::iter::from_fn(|| self.notices.try_recv().ok())
+    }
+
+    /// Writes what is still queued, with a few last attempts, and stops the
+    /// task. `notice` receives the warnings not yet collected.
+    pub async fn close(mut self, mut notice: impl FnMut(String)) -> Result<(), ExportError> {
+        drop(self.lines);
+        self.closing.cancel();
+        let result = (&mut self.task)
+            .await
+            .map_err(|err| ExportError::Push(err.to_string()))
+            .and_then(|result| result);
+        while let Ok(message) = self.notices.try_recv() {
+            notice(message);
+        }
+        result
+    }
+}
+
+async fn drain(
+    mut writer: LineWriter,
+    mut queue: mpsc::Receiver<String>,
+    notify: mpsc::UnboundedSender<String>,
+    closing: CancellationToken,
+) -> Result<(), ExportError> {
+    let mut batch = Vec::new();
+    loop {
+        match queue.recv().await {
+            Some(line) => batch.push(line),
+            None => return Ok(()),
+        }
+        while batch.len() < BATCH_LINES
+            && let Ok(line) = queue.try_recv()
+        {
+            batch.push(line);
+        }
+
+        let (mut delay, mut failures) = (FIRST_RETRY, 0);
+        while let Err(err) = writer.write(&batch).await {
+            failures += 1;
+            if closing.is_cancelled() && failures >= CLOSING_ATTEMPTS {
+                return Err(err);
+            }
+            if failures == 1 {
+                let _ = notify.send(format!("Writing line protocol failed, retrying: {err}"));
+            }
+            // Closing cuts a long wait short, but still leaves a moment
+            // between the last attempts.
+            let wait = if closing.is_cancelled() {
+                FIRST_RETRY
+            } else {
+                delay
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = closing.cancelled(), if !closing.is_cancelled() => {}
+            }
+            delay = (delay * 2).min(MAX_RETRY);
+        }
+        if failures > 0 {
+            let _ = notify.send(format!(
+                "Writing line protocol again after {failures} failed attempt(s)"
+            ));
+        }
+        batch.clear();
+    }
+}
+
+fn post(url: &str, token: Option<&str>, body: String) -> Result<(), ExportError> {
+    let mut request = ureq::post(url).content_type("text/plain; charset=utf-8");
+    if let Some(token) = token {
+        request = request.header("Authorization", &format!("Token {token}"));
+    }
+    request
+        .send(body)
+        .map_err(|err| ExportError::Push(err.to_string()))?;
+    Ok(())
+}
+
+/// The serde name of a schema enum.
+fn name<T: Serialize>(value: &T) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+fn float(value: f64) -> Option<String> {
+    value.is_finite().then(|| format!("{value}"))
+}
+
+/// Backslash-escapes `special` characters of a name, tag key or tag value.
+fn escape(text: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '\\' || special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::extract::State;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use chrono::{DateTime, TimeZone, Utc};
+
+    use super::*;
+    use crate::model::{Metric, Source, Unit};
+
+    fn measurement() -> Measurement {
+        Measurement {
+            device: "AA:BB".to_string(),
+            source: Source::SleepLog,
+            metric: Metric::SleepStage,
+            unit: Unit::Minute,
+            timestamp: Utc.with_ymd_and_hms(2024, 3, 10, 1, 0, 0).unwrap(),
+            end: None,
+            value: 30.0,
+            min: None,
+            max: None,
+            label: None,
+        }
+    }
+
+    #[test]
+    fn escape_backslashes_special_characters() {
+        assert_eq!(escape("deep sleep", ", "), "deep\\ sleep");
+        assert_eq!(escape("a,b=c d", ",= "), "a\\,b\\=c\\ d");
+        assert_eq!(escape("a=b", ", "), "a=b");
+        assert_eq!(escape("C:\\ring", ",= "), "C:\\\\ring");
+    }
+
+    #[test]
+    fn line_has_tags_fields_and_nanoseconds() {
+        assert_eq!(
+            line(&measurement()).unwrap(),
+            "sleep_stage,device=AA:BB,source=sleep_log value=30 1710032400000000000"
+        );
+
+        let labelled = Measurement {
+            end: Some(Utc.with_ymd_and_hms(2024, 3, 10, 1, 30, 0).unwrap()),
+            min: Some(1.5),
+            max: Some(2.0),
+            label: Some("light sleep".to_string()),
+            ..measurement()
+        };
+        assert_eq!(
+            line(&labelled).unwrap(),
+            "sleep_stage,device=AA:BB,source=sleep_log,label=light\\ sleep \
+             value=30,min=1.5,max=2,end=1710034200000000000i 1710032400000000000"
+        );
+    }
+
+    #[test]
+    fn line_skips_what_line_protocol_cannot_carry() {
+        let not_a_number = Measurement {
+            value: f64::NAN,
+            ..measurement()
+        };
+        assert_eq!(line(&not_a_number), None);
+        let far_future = Measurement {
+            timestamp: DateTime::<Utc>::MAX_UTC,
+            ..measurement()
+        };
+        assert_eq!(line(&far_future), None);
+    }
+
+    /// `Authorization` header and lines of one request.
+    type Request = (Option<String>, Vec<String>);
+
+    /// What a local write endpoint received. The first `fail` requests get
+    /// a 503.
+    #[derive(Clone, Default)]
+    struct Receiver {
+        requests: Arc<Mutex<Vec<Request>>>,
+        fail: Arc<Mutex<usize>>,
+    }
+
+    async fn receive(
+        State(receiver): State<Receiver>,
+        headers: HeaderMap,
+        body: String,
+    ) -> StatusCode {
+        let mut fail = receiver.fail.lock().unwrap();
+        if *fail > 0 {
+            *fail -= 1;
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+        let token = headers
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let lines = body.lines().map(str::to_string).collect();
+        receiver.requests.lock().unwrap().push((token, lines));
+        StatusCode::NO_CONTENT
+    }
+
+    async fn serve(fail: usize) -> (Target, Receiver) {
+        let receiver = Receiver::default();
+        *receiver.fail.lock().unwrap() = fail;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/write", listener.local_addr().unwrap());
+        let app = axum::Router::new()
+            .route("/write", post(receive))
+            .with_state(receiver.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url.parse().unwrap(), receiver)
+    }
+
+    fn lines(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|i| format!("heart_rate value={i} {i}"))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn pushes_batches_with_the_token() {
+        let (target, receiver) = serve(0).await;
+        let mut writer = LineWriter::open(&target, Some("secret")).unwrap();
+        writer.write(&lines(BATCH_LINES + 2)).await.unwrap();
+
+        let requests = receiver.requests.lock().unwrap();
+        let sizes: Vec<_> = requests.iter().map(|(_, lines)| lines.len()).collect();
+        assert_eq!(sizes, [BATCH_LINES, 2]);
+        assert!(
+            requests
+                .iter()
+                .all(|(token, _)| token.as_deref() == Some("Token secret"))
+        );
+        assert_eq!(
+            requests[1].1[1],
+            format!("heart_rate value={0} {0}", BATCH_LINES + 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_push_is_an_error() {
+        let (target, receiver) = serve(1).await;
+        let mut writer = LineWriter::open(&target, None).unwrap();
+        assert!(matches!(
+            writer.write(&lines(1)).await,
+            Err(ExportError::Push(_))
+        ));
+        writer.write(&lines(1)).await.unwrap();
+        assert_eq!(receiver.requests.lock().unwrap()[0].0, None);
+    }
+
+    #[tokio::test]
+    async fn stream_retries_a_failed_batch_until_it_is_written() {
+        let (target, receiver) = serve(2).await;
+        let writer = LineWriter::open(&target, Some("secret")).unwrap();
+        let mut stream = LineStream::spawn(writer, &target);
+        for line in lines(3) {
+            stream.push(line);
+        }
+
+        let mut notices = Vec::new();
+        stream.close(|notice| notices.push(notice)).await.unwrap();
+
+        let requests = receiver.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].1, lines(3));
+        assert_eq!(notices.len(), 2, "{notices:?}");
+        assert!(notices[0].starts_with("Writing line protocol failed, retrying"));
+        assert_eq!(
+            notices[1],
+            "Writing line protocol again after 2 failed attempt(s)"
+        );
+    }
+}
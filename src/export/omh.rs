@@ -0,0 +1,392 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::error::ExportError;
+use crate::export::{self, History};
+use crate::model::{Metric, Series};
+use crate::protocol::bigdata::SleepSession;
+use crate::protocol::realtime::{ReadingType, RealtimeReading};
+
+const HEART_RATE: SchemaId = SchemaId::omh("heart-rate", "2.0");
+const STEP_COUNT: SchemaId = SchemaId::omh("step-count", "3.0");
+const SLEEP_EPISODE: SchemaId = SchemaId::omh("sleep-episode", "1.1");
+const OXYGEN_SATURATION: SchemaId = SchemaId::omh("oxygen-saturation", "2.0");
+
+/// An Open mHealth data point: a header naming the schema, and a body
+/// that follows it.
+#[derive(Serialize)]
+pub struct DataPoint {
+    header: Header,
+    body: Body,
+}
+
+#[derive(Serialize)]
+struct Header {
+    id: String,
+    creation_date_time: DateTime<Utc>,
+    schema_id: SchemaId,
+    acquisition_provenance: Provenance,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<String>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+struct SchemaId {
+    namespace: &'static str,
+    name: &'static str,
+    version: &'static str,
+}
+
+impl SchemaId {
+    const fn omh(name: &'static str, version: &'static str) -> Self {
+        Self {
+            namespace: "omh",
+            name,
+            version,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Provenance {
+    source_name: String,
+    modality: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Body {
+    HeartRate {
+        heart_rate: UnitValue,
+        effective_time_frame: TimeFrame,
+    },
+    StepCount {
+        step_count: UnitValue,
+        effective_time_frame: TimeFrame,
+    },
+    SleepEpisode {
+        effective_time_frame: TimeFrame,
+        total_sleep_time: UnitValue,
+        number_of_awakenings: u32,
+        sleep_maintenance_efficiency_percentage: UnitValue,
+        is_main_sleep_episode: bool,
+    },
+    OxygenSaturation {
+        oxygen_saturation: UnitValue,
+        effective_time_frame: TimeFrame,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        descriptive_statistic: Option<&'static str>,
+        measurement_method: &'static str,
+    },
+}
+
+#[derive(Serialize)]
+struct UnitValue {
+    value: f64,
+    unit: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum TimeFrame {
+    DateTime { date_time: DateTime<Utc> },
+    Interval { time_interval: TimeInterval },
+}
+
+#[derive(Serialize)]
+struct TimeInterval {
+    start_date_time: DateTime<Utc>,
+    end_date_time: DateTime<Utc>,
+}
+
+impl TimeFrame {
+    fn interval(start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+        Self::Interval {
+            time_interval: TimeInterval {
+                start_date_time: start,
+                end_date_time: end,
+            },
+        }
+    }
+}
+
+/// Data points for everything in `history`. `source_name` is the ring's
+/// advertised name; `user_id` is the pipeline's id for the wearer.
+pub fn data_points(history: &History, source_name: &str, user_id: Option<&str>) -> Vec<DataPoint> {
+    let mut builder = Builder {
+        address: &history.device,
+        source_name,
+        user_id,
+        created: history.exported_at,
+        points: Vec::new(),
+    };
+    builder.heart_rate(&Series::stored_heart_rate(
+        &history.device,
+        &history.heart_rate,
+    ));
+    for series in Series::stored_activity(&history.device, &history.activity) {
+        if series.metric == Metric::Steps {
+            builder.steps(&series);
+        }
+    }
+    builder.sleep(&history.sleep);
+    builder.oxygen(&Series::stored_oxygen(&history.device, &history.oxygen));
+    builder.realtime(&history.realtime);
+    builder.points
+}
+
+struct Builder<'a> {
+    address: &'a str,
+    source_name: &'a str,
+    user_id: Option<&'a str>,
+    created: DateTime<Utc>,
+    points: Vec<DataPoint>,
+}
+
+impl Builder<'_> {
+    fn push(&mut self, source: &str, schema_id: SchemaId, at: DateTime<Utc>, body: Body) {
+        self.points.push(DataPoint {
+            header: Header {
+                id: export::value_id(self.address, source, schema_id.name, at),
+                creation_date_time: self.created,
+                schema_id,
+                acquisition_provenance: Provenance {
+                    source_name: self.source_name.to_string(),
+                    modality: "sensed",
+                },
+                user_id: self.user_id.map(str::to_string),
+            },
+            body,
+        });
+    }
+
+    fn heart_rate(&mut self, heart_rate: &Series) {
+        for measurement in heart_rate.measurements() {
+            self.push(
+                "log",
+                HEART_RATE,
+                measurement.timestamp,
+                Body::HeartRate {
+                    heart_rate: beats_per_minute(measurement.value),
+                    effective_time_frame: TimeFrame::DateTime {
+                        date_time: measurement.timestamp,
+                    },
+                },
+            );
+        }
+    }
+
+    /// Steps per 15-minute slot; slots without steps are left out.
+    fn steps(&mut self, steps: &Series) {
+        for measurement in steps.measurements() {
+            let Some(end) = measurement.end.filter(|_| measurement.value > 0.0) else {
+                continue;
+            };
+            self.push(
+                "log",
+                STEP_COUNT,
+                measurement.timestamp,
+                Body::StepCount {
+                    step_count: UnitValue {
+                        value: measurement.value,
+                        unit: "steps",
+                    },
+                    effective_time_frame: TimeFrame::interval(measurement.timestamp, end),
+                },
+            );
+        }
+    }
+
+    /// The ring keeps one sleep per night, so every session is the main
+    /// episode. Stages other than awake count as sleep.
+    fn sleep(&mut self, sessions: &[SleepSession]) {
+        for session in sessions {
+            let total = session.total_minutes();
+            let awake: Vec<_> = session
+                .stages
+                .iter()
+                .filter(|stage| stage.phase_type == 5)
+                .collect();
+            let asleep = total - awake.iter().map(|stage| stage.minutes()).sum::<i64>();
+            let efficiency = if total > 0 {
+                asleep as f64 * 100.0 / total as f64
+            } else {
+                0.0
+            };
+            self.push(
+                "log",
+                SLEEP_EPISODE,
+                session.start,
+                Body::SleepEpisode {
+                    effective_time_frame: TimeFrame::interval(session.start, session.end),
+                    total_sleep_time: UnitValue {
+                        value: asleep as f64,
+                        unit: "min",
+                    },
+                    number_of_awakenings: awake.len() as u32,
+                    sleep_maintenance_efficiency_percentage: percent(efficiency),
+                    is_main_sleep_episode: true,
+                },
+            );
+        }
+    }
+
+    /// Hourly SpO2 as a minimum and a maximum over the hour.
+    fn oxygen(&mut self, oxygen: &Series) {
+        for measurement in oxygen.measurements() {
+            let (Some(end), Some(min), Some(max)) =
+                (measurement.end, measurement.min, measurement.max)
+            else {
+                continue;
+            };
+            for (statistic, value) in [("minimum", min), ("maximum", max)] {
+                self.push(
+                    &format!("log-{statistic}"),
+                    OXYGEN_SATURATION,
+                    measurement.timestamp,
+                    Body::OxygenSaturation {
+                        oxygen_saturation: percent(value),
+                        effective_time_frame: TimeFrame::interval(measurement.timestamp, end),
+                        descriptive_statistic: Some(statistic),
+                        measurement_method: "pulse oximetry",
+                    },
+                );
+            }
+        }
+    }
+
+    /// HRV has no Open mHealth schema and is left out.
+    fn realtime(&mut self, readings: &[RealtimeReading]) {
+        for reading in readings {
+            let value = reading.value as f64;
+            let effective_time_frame = TimeFrame::DateTime {
+                date_time: reading.timestamp,
+            };
+            let (schema_id, body) = match reading.reading_type {
+                ReadingType::HeartRateBatch => (
+                    HEART_RATE,
+                    Body::HeartRate {
+                        heart_rate: beats_per_minute(value),
+                        effective_time_frame,
+                    },
+                ),
+                ReadingType::BloodOxygen => (
+                    OXYGEN_SATURATION,
+                    Body::OxygenSaturation {
+                        oxygen_saturation: percent(value),
+                        effective_time_frame,
+                        descriptive_statistic: None,
+                        measurement_method: "pulse oximetry",
+                    },
+                ),
+                ReadingType::Hrv => continue,
+            };
+            self.push("realtime", schema_id, reading.timestamp, body);
+        }
+    }
+}
+
+fn beats_per_minute(value: f64) -> UnitValue {
+    UnitValue {
+        value,
+        unit: "beats/min",
+    }
+}
+
+fn percent(value: f64) -> UnitValue {
+    UnitValue { value, unit: "%" }
+}
+
+/// Checks each data point against the rules of its schema that the
+/// exporter could get wrong: matching body, units, value ranges and time
+/// frames.
+pub fn validate(points: &[DataPoint]) -> Result<(), ExportError> {
+    let mut issues = Vec::new();
+    let mut ids = HashSet::new();
+
+    for (index, point) in points.iter().enumerate() {
+        let mut issue = |message: String| issues.push(format!("data point {index}: {message}"));
+        let header = &point.header;
+
+        if !ids.insert(header.id.as_str()) {
+            issue(format!("duplicate id {}", header.id));
+        }
+        if header.acquisition_provenance.source_name.is_empty() {
+            issue("provenance needs a source name".to_string());
+        }
+
+        let (schema_id, values, frame) = match &point.body {
+            Body::HeartRate {
+                heart_rate,
+                effective_time_frame,
+            } => (
+                HEART_RATE,
+                vec![(heart_rate, "beats/min")],
+                effective_time_frame,
+            ),
+            Body::StepCount {
+                step_count,
+                effective_time_frame,
+            } => (
+                STEP_COUNT,
+                vec![(step_count, "steps")],
+                effective_time_frame,
+            ),
+            Body::SleepEpisode {
+                effective_time_frame,
+                total_sleep_time,
+                sleep_maintenance_efficiency_percentage,
+                ..
+            } => {
+                if matches!(effective_time_frame, TimeFrame::DateTime { .. }) {
+                    issue("sleep episodes need a time interval".to_string());
+                }
+                (
+                    SLEEP_EPISODE,
+                    vec![
+                        (total_sleep_time, "min"),
+                        (sleep_maintenance_efficiency_percentage, "%"),
+                    ],
+                    effective_time_frame,
+                )
+            }
+            Body::OxygenSaturation {
+                oxygen_saturation,
+                effective_time_frame,
+                ..
+            } => (
+                OXYGEN_SATURATION,
+                vec![(oxygen_saturation, "%")],
+                effective_time_frame,
+            ),
+        };
+
+        if header.schema_id != schema_id {
+            issue(format!(
+                "body does not match schema {}:{}:{}",
+                header.schema_id.namespace, header.schema_id.name, header.schema_id.version
+            ));
+        }
+        for (value, unit) in values {
+            if !value.value.is_finite() || value.value < 0.0 || value.unit != unit {
+                issue(format!("invalid value {} {}", value.value, value.unit));
+            }
+            if unit == "%" && value.value > 100.0 {
+                issue(format!("percentage {} is above 100", value.value));
+            }
+        }
+        if let TimeFrame::Interval { time_interval } = frame
+            && time_interval.start_date_time > time_interval.end_date_time
+        {
+            issue("time interval ends before it starts".to_string());
+        }
+    }
+
+    if issues.is_empty() {
+        Ok(())
+    } else {
+        Err(ExportError::Invalid(issues.join("; ")))
+    }
+}
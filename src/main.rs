@@ -1,43 +1,137 @@
 mod bluetooth;
+mod calendar;
 mod cli;
 mod config;
 mod devices;
 mod error;
+mod export;
+mod model;
 mod protocol;
+mod serve;
+mod store;
 mod tui;
 
 use clap::Parser;
-use cli::{Cli, Commands};
+use cli::{Cli, Commands, output::Output};
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
+    let output = Output::new(cli.format);
 
     match cli.command {
-        Commands::Scan { all } => cli::commands::scan(!all).await,
-        Commands::Connect { all } => cli::commands::connect(!all).await,
-        Commands::Battery => cli::commands::battery().await,
-        Commands::Info => cli::commands::info().await,
-        Commands::Blink => cli::commands::blink().await,
-        Commands::Reset => cli::commands::reset().await,
-        Commands::Reboot => cli::commands::reboot().await,
-        Commands::Find => cli::commands::find().await,
-        Commands::Hr { days } => cli::commands::hr(days).await,
-        Commands::Steps { days } => cli::commands::steps(days).await,
-        Commands::Sleep => cli::commands::sleep().await,
-        Commands::Spo2 => cli::commands::spo2().await,
-        Commands::Realtime { r#type, seconds } => cli::commands::realtime(&r#type, seconds).await,
+        Commands::Scan { all } => cli::commands::scan(&output, !all).await,
+        Commands::Connect { all } => cli::commands::connect(&output, !all).await,
+        Commands::Battery => cli::commands::battery(&output).await,
+        Commands::Info => cli::commands::info(&output).await,
+        Commands::Blink => cli::commands::blink(&output).await,
+        Commands::Reset => cli::commands::reset(&output).await,
+        Commands::Reboot => cli::commands::reboot(&output).await,
+        Commands::Find => cli::commands::find(&output).await,
+        Commands::Hr { days } => cli::commands::hr(&output, days).await,
+        Commands::Steps { days, today } => cli::commands::steps(&output, days, today).await,
+        Commands::Sleep => cli::commands::sleep(&output).await,
+        Commands::Spo2 => cli::commands::spo2(&output).await,
+        Commands::LiveSteps => cli::commands::live_steps(&output).await,
+        Commands::Sample => cli::commands::sample(&output).await,
+        Commands::Realtime {
+            r#type,
+            seconds,
+            continuous,
+            cycle,
+            influx,
+            influx_token,
+        } => {
+            cli::commands::realtime(
+                &output,
+                &r#type,
+                seconds,
+                continuous,
+                cycle,
+                influx.as_ref(),
+                influx_token.as_deref(),
+            )
+            .await
+        }
+        Commands::Workout { sport, max_hr } => {
+            cli::commands::workout(&output, &sport, max_hr).await
+        }
+        Commands::Sync => cli::commands::sync(&output).await,
+        Commands::Schema => cli::commands::schema(&output),
+        Commands::Export { command } => match command {
+            cli::ExportCommands::Csv {
+                from,
+                to,
+                metric,
+                dir,
+            } => cli::commands::export_csv(&output, from, to, &metric, &dir),
+            cli::ExportCommands::Fhir {
+                history,
+                patient,
+                out,
+            } => cli::commands::export_fhir(&output, &history, patient.as_deref(), &out),
+            cli::ExportCommands::Omh { history, user, out } => {
+                cli::commands::export_omh(&output, &history, user.as_deref(), &out)
+            }
+            cli::ExportCommands::Edf {
+                history,
+                hr,
+                spo2,
+                dir,
+            } => cli::commands::export_edf(&output, &history, hr, spo2, &dir),
+            cli::ExportCommands::Ics { history, out } => {
+                cli::commands::export_ics(&output, &history, &out)
+            }
+            cli::ExportCommands::Influx {
+                history,
+                out,
+                token,
+            } => cli::commands::export_influx(&output, &history, &out, token.as_deref()).await,
+            cli::ExportCommands::Gadgetbridge { path, device } => {
+                cli::commands::export_gadgetbridge(&output, &path, device.as_deref())
+            }
+            cli::ExportCommands::Fit { history, dir } => {
+                cli::commands::export_fit(&output, &history, &dir)
+            }
+        },
+        Commands::Import { command } => match command {
+            cli::ImportCommands::Gadgetbridge { path, device } => {
+                cli::commands::import_gadgetbridge(&output, &path, device.as_deref())
+            }
+        },
         Commands::Settings { command } => match command {
             cli::SettingsCommands::Hr {
                 enable,
                 disable,
                 interval,
-            } => cli::commands::settings_hr(enable, disable, interval).await,
+            } => cli::commands::settings_hr(&output, enable, disable, interval).await,
+        },
+        Commands::Serve {
+            command,
+            listen,
+            device,
+            schedule,
+        } => match command {
+            None => cli::commands::serve_api(&output, &listen, &device, &schedule).await,
+            Some(cli::ServeCommands::Metrics {
+                listen,
+                device,
+                schedule,
+            }) => {
+                cli::commands::serve_metrics(&output, &listen, device.as_deref(), &schedule).await
+            }
+            Some(cli::ServeCommands::Mqtt {
+                broker,
+                device,
+                schedule,
+            }) => cli::commands::serve_mqtt(&output, &broker, &device, &schedule).await,
         },
         Commands::Tui => {
             if let Err(err) = tui::run_tui().await {
-                eprintln!("TUI Error: {err}");
+                output.error_in("TUI", &err);
             }
         }
     }
+
+    std::process::exit(output.finish());
 }
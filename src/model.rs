@@ -0,0 +1,384 @@
+use chrono::{DateTime, Duration, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{
+    battery::BatteryResponse,
+    bigdata::{OxygenDay, SleepSession, sleep_phase_label},
+    hr::HeartRateLog,
+    realtime::{ReadingType, RealtimeReading},
+    steps::{ActivityDetail, DailyTotals},
+};
+use crate::store::database::{StoredActivity, StoredHeartRate, StoredOxygen};
+
+/// Bumped whenever a field is renamed or removed.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unit {
+    #[serde(rename = "bpm")]
+    BeatsPerMinute,
+    #[serde(rename = "count")]
+    Count,
+    #[serde(rename = "kcal")]
+    Kilocalorie,
+    #[serde(rename = "m")]
+    Meter,
+    #[serde(rename = "%")]
+    Percent,
+    #[serde(rename = "ms")]
+    Millisecond,
+    #[serde(rename = "min")]
+    Minute,
+    #[serde(rename = "bool")]
+    Boolean,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    HeartRate,
+    Steps,
+    Calories,
+    Distance,
+    BloodOxygen,
+    Hrv,
+    /// Value is the stage length in minutes; `label` names the stage.
+    SleepStage,
+    BatteryLevel,
+    Charging,
+}
+
+impl Metric {
+    pub fn unit(&self) -> Unit {
+        match self {
+            Self::HeartRate => Unit::BeatsPerMinute,
+            Self::Steps => Unit::Count,
+            Self::Calories => Unit::Kilocalorie,
+            Self::Distance => Unit::Meter,
+            Self::BloodOxygen | Self::BatteryLevel => Unit::Percent,
+            Self::Hrv => Unit::Millisecond,
+            Self::SleepStage => Unit::Minute,
+            Self::Charging => Unit::Boolean,
+        }
+    }
+}
+
+impl From<ReadingType> for Metric {
+    fn from(reading_type: ReadingType) -> Self {
+        match reading_type {
+            ReadingType::HeartRateBatch => Self::HeartRate,
+            ReadingType::BloodOxygen => Self::BloodOxygen,
+            ReadingType::Hrv => Self::Hrv,
+        }
+    }
+}
+
+/// Which ring feature a value was read from.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    HeartRateLog,
+    ActivityLog,
+    DailyTotals,
+    SleepLog,
+    OxygenLog,
+    Realtime,
+    Battery,
+}
+
+/// A single value, self-describing enough to be written on its own line.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct Measurement {
+    /// Bluetooth address of the ring.
+    pub device: String,
+    pub source: Source,
+    pub metric: Metric,
+    pub unit: Unit,
+    pub timestamp: DateTime<Utc>,
+    /// End of the covered interval, for aggregated values.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<DateTime<Utc>>,
+    pub value: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+/// One point of a [`Series`]. `value` is `None` when the ring recorded
+/// nothing for the slot.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct Sample {
+    pub timestamp: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<DateTime<Utc>>,
+    pub value: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+impl Sample {
+    fn at(timestamp: DateTime<Utc>, value: Option<f64>) -> Self {
+        Self {
+            timestamp,
+            end: None,
+            value,
+            min: None,
+            max: None,
+            label: None,
+        }
+    }
+}
+
+/// Time-ordered samples of one metric from one device.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct Series {
+    pub device: String,
+    pub source: Source,
+    pub metric: Metric,
+    pub unit: Unit,
+    pub samples: Vec<Sample>,
+}
+
+/// Root of the published schema: every document is a series or a single
+/// measurement.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Document {
+    Series(Series),
+    Measurement(Measurement),
+}
+
+impl Series {
+    fn new(device: &str, source: Source, metric: Metric, samples: Vec<Sample>) -> Self {
+        Self {
+            device: device.to_string(),
+            source,
+            metric,
+            unit: metric.unit(),
+            samples,
+        }
+    }
+
+    pub fn heart_rate(device: &str, log: &HeartRateLog) -> Self {
+        let samples = log
+            .samples
+            .iter()
+            .map(|sample| Sample::at(sample.timestamp, sample.bpm.map(f64::from)))
+            .collect();
+        Self::new(device, Source::HeartRateLog, Metric::HeartRate, samples)
+    }
+
+    /// Steps, calories and distance series over 15-minute slots.
+    pub fn activity(device: &str, details: &[ActivityDetail]) -> Vec<Self> {
+        let slots: Vec<_> = details
+            .iter()
+            .filter_map(|detail| detail.timestamp().map(|start| (start, detail)))
+            .collect();
+        let series = |metric: Metric, value: fn(&ActivityDetail) -> f64| {
+            let samples = slots
+                .iter()
+                .map(|(start, detail)| Sample {
+                    end: Some(*start + Duration::minutes(15)),
+                    ..Sample::at(*start, Some(value(detail)))
+                })
+                .collect();
+            Self::new(device, Source::ActivityLog, metric, samples)
+        };
+        vec![
+            series(Metric::Steps, |d| d.steps as f64),
+            series(Metric::Calories, |d| d.calories),
+            series(Metric::Distance, |d| d.distance as f64),
+        ]
+    }
+
+    pub fn sleep(device: &str, sessions: &[SleepSession]) -> Self {
+        let samples = sessions
+            .iter()
+            .flat_map(|session| &session.stages)
+            .map(|stage| Sample {
+                end: Some(stage.end),
+                label: Some(sleep_phase_label(stage.phase_type).to_string()),
+                ..Sample::at(stage.start, Some(stage.minutes() as f64))
+            })
+            .collect();
+        Self::new(device, Source::SleepLog, Metric::SleepStage, samples)
+    }
+
+    /// Hourly SpO2 ranges; `value` is the middle of the hour's range.
+    pub fn oxygen(device: &str, days: &[OxygenDay]) -> Self {
+        let today = Utc::now().date_naive();
+        let mut samples = Vec::new();
+        for day in days {
+            for (start, sample) in day.hours(today) {
+                let recorded = sample.min > 0 || sample.max > 0;
+                samples.push(Sample {
+                    end: Some(start + Duration::hours(1)),
+                    min: recorded.then_some(sample.min as f64),
+                    max: recorded.then_some(sample.max as f64),
+                    ..Sample::at(
+                        start,
+                        recorded.then(|| (sample.min as f64 + sample.max as f64) / 2.0),
+                    )
+                });
+            }
+        }
+        samples.sort_by_key(|sample| sample.timestamp);
+        Self::new(device, Source::OxygenLog, Metric::BloodOxygen, samples)
+    }
+
+    /// Logged heart rate as kept in the local store, which leaves out empty
+    /// slots.
+    pub fn stored_heart_rate(device: &str, samples: &[StoredHeartRate]) -> Self {
+        let samples = samples
+            .iter()
+            .map(|sample| Sample::at(sample.timestamp, Some(sample.bpm as f64)))
+            .collect();
+        Self::new(device, Source::HeartRateLog, Metric::HeartRate, samples)
+    }
+
+    /// Steps, calories and distance series over stored 15-minute slots.
+    pub fn stored_activity(device: &str, slots: &[StoredActivity]) -> Vec<Self> {
+        let series = |metric: Metric, value: fn(&StoredActivity) -> f64| {
+            let samples = slots
+                .iter()
+                .map(|slot| Sample {
+                    end: Some(slot.start + Duration::minutes(15)),
+                    ..Sample::at(slot.start, Some(value(slot)))
+                })
+                .collect();
+            Self::new(device, Source::ActivityLog, metric, samples)
+        };
+        vec![
+            series(Metric::Steps, |s| s.steps as f64),
+            series(Metric::Calories, |s| s.calories),
+            series(Metric::Distance, |s| s.distance as f64),
+        ]
+    }
+
+    /// Stored hourly SpO2 ranges; `value` is the middle of the hour's range.
+    pub fn stored_oxygen(device: &str, hours: &[StoredOxygen]) -> Self {
+        let samples = hours
+            .iter()
+            .map(|hour| Sample {
+                end: Some(hour.start + Duration::hours(1)),
+                min: Some(hour.min as f64),
+                max: Some(hour.max as f64),
+                ..Sample::at(hour.start, Some((hour.min as f64 + hour.max as f64) / 2.0))
+            })
+            .collect();
+        Self::new(device, Source::OxygenLog, Metric::BloodOxygen, samples)
+    }
+
+    /// The recorded samples as standalone measurements.
+    pub fn measurements(&self) -> Vec<Measurement> {
+        self.samples
+            .iter()
+            .filter_map(|sample| {
+                Some(Measurement {
+                    device: self.device.clone(),
+                    source: self.source,
+                    metric: self.metric,
+                    unit: self.unit,
+                    timestamp: sample.timestamp,
+                    end: sample.end,
+                    value: sample.value?,
+                    min: sample.min,
+                    max: sample.max,
+                    label: sample.label.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl Measurement {
+    fn new(device: &str, source: Source, metric: Metric, at: DateTime<Utc>, value: f64) -> Self {
+        Self {
+            device: device.to_string(),
+            source,
+            metric,
+            unit: metric.unit(),
+            timestamp: at,
+            end: None,
+            value,
+            min: None,
+            max: None,
+            label: None,
+        }
+    }
+
+    pub fn battery(device: &str, at: DateTime<Utc>, battery: &BatteryResponse) -> Vec<Self> {
+        vec![
+            Self::new(
+                device,
+                Source::Battery,
+                Metric::BatteryLevel,
+                at,
+                battery.charge_pct as f64,
+            ),
+            Self::new(
+                device,
+                Source::Battery,
+                Metric::Charging,
+                at,
+                if battery.is_charging { 1.0 } else { 0.0 },
+            ),
+        ]
+    }
+
+    /// A realtime reading, streamed or sampled.
+    pub fn reading(device: &str, reading: &RealtimeReading) -> Self {
+        Self::new(
+            device,
+            Source::Realtime,
+            Metric::from(reading.reading_type),
+            reading.timestamp,
+            reading.value as f64,
+        )
+    }
+
+    /// The ring's running totals for its current day, stamped with `at`.
+    pub fn daily_totals(device: &str, at: DateTime<Utc>, totals: &DailyTotals) -> Vec<Self> {
+        vec![
+            Self::new(
+                device,
+                Source::DailyTotals,
+                Metric::Steps,
+                at,
+                totals.steps as f64,
+            ),
+            Self::new(
+                device,
+                Source::DailyTotals,
+                Metric::Calories,
+                at,
+                totals.calories,
+            ),
+            Self::new(
+                device,
+                Source::DailyTotals,
+                Metric::Distance,
+                at,
+                totals.distance as f64,
+            ),
+        ]
+    }
+}
+
+/// JSON Schema for [`Document`], as published in `schema/colmi.schema.json`.
+pub fn json_schema() -> serde_json::Value {
+    let mut schema = schemars::schema_for!(Document);
+    schema.insert(
+        "title".to_string(),
+        serde_json::Value::String(format!("Colmi client data (v{SCHEMA_VERSION})")),
+    );
+    schema.to_value()
+}
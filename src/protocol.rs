@@ -11,6 +11,7 @@ pub mod reboot;
 pub mod reset;
 pub mod settings;
 pub mod steps;
+pub mod workout;
 
 pub const SERVICE_UUID: &str = "6e40fff0-b5a3-f393-e0a9-e50e24dcca9e";
 pub const WRITE_CHARACTERISTICS: &str = "6e400002-b5a3-f393-e0a9-e50e24dcca9e";
@@ -84,3 +85,9 @@ pub fn to_bcd(value: u8) -> u8 {
     let ones = value % 10;
     (tens << 4) | ones
 }
+
+/// Reads the big-endian 24-bit counter at `bytes[i..i + 3]`; activity
+/// counters use this width, unlike the rest of the protocol.
+pub fn be24(bytes: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes([0, bytes[i], bytes[i + 1], bytes[i + 2]])
+}
@@ -1,3 +1,5 @@
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+
 use crate::error::ProtocolError;
 
 pub const BIG_DATA_MAGIC: u8 = 0xBC;
@@ -50,11 +52,107 @@ pub struct SleepDay {
     pub phases: Vec<SleepPhase>,
 }
 
+impl SleepDay {
+    /// Places the record on the timeline. `today` is the ring's current date
+    /// and `tz` the zone its clock runs in; a start later in the day than the
+    /// end means the session began the evening before.
+    pub fn to_session<Tz: TimeZone>(&self, today: NaiveDate, tz: &Tz) -> Option<SleepSession> {
+        let date = today - Duration::days(self.days_ago as i64);
+        let start_date = if self.start_minutes > self.end_minutes {
+            date - Duration::days(1)
+        } else {
+            date
+        };
+        let at = |date: NaiveDate, minutes: u16| {
+            let naive = date.and_hms_opt(0, 0, 0)? + Duration::minutes(minutes as i64);
+            tz.from_local_datetime(&naive)
+                .earliest()
+                .map(|dt| dt.with_timezone(&Utc))
+        };
+        let start = at(start_date, self.start_minutes)?;
+        let end = at(date, self.end_minutes)?;
+
+        let mut stages = Vec::with_capacity(self.phases.len());
+        let mut stage_start = start;
+        for phase in &self.phases {
+            let stage_end = stage_start + Duration::minutes(phase.minutes as i64);
+            stages.push(SleepStage {
+                phase_type: phase.phase_type,
+                start: stage_start,
+                end: stage_end,
+            });
+            stage_start = stage_end;
+        }
+
+        Some(SleepSession { start, end, stages })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SleepStage {
+    pub phase_type: u8,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+impl SleepStage {
+    pub fn minutes(&self) -> i64 {
+        (self.end - self.start).num_minutes()
+    }
+}
+
+/// One sleep (night or nap) with absolute start and end instants.
+#[derive(Clone, Debug)]
+pub struct SleepSession {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub stages: Vec<SleepStage>,
+}
+
+impl SleepSession {
+    /// Time covered by the recorded stages.
+    pub fn total_minutes(&self) -> i64 {
+        self.stages.iter().map(|stage| stage.minutes()).sum()
+    }
+
+    /// Total minutes per stage label, in order of first appearance.
+    pub fn stage_breakdown(&self) -> Vec<(&'static str, i64)> {
+        let mut breakdown: Vec<(&str, i64)> = Vec::new();
+        for stage in &self.stages {
+            let label = sleep_phase_label(stage.phase_type);
+            if let Some(entry) = breakdown.iter_mut().find(|(l, _)| *l == label) {
+                entry.1 += stage.minutes();
+            } else {
+                breakdown.push((label, stage.minutes()));
+            }
+        }
+        breakdown
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct SleepData {
     pub days: Vec<SleepDay>,
 }
 
+impl SleepData {
+    /// All records as sessions, oldest first. `tz` is the zone the ring's
+    /// clock runs in.
+    pub fn sessions<Tz: TimeZone>(&self, tz: &Tz) -> Vec<SleepSession> {
+        self.sessions_as_of(Utc::now().with_timezone(tz).date_naive(), tz)
+    }
+
+    fn sessions_as_of<Tz: TimeZone>(&self, today: NaiveDate, tz: &Tz) -> Vec<SleepSession> {
+        let mut sessions: Vec<SleepSession> = self
+            .days
+            .iter()
+            .filter_map(|day| day.to_session(today, tz))
+            .collect();
+        sessions.sort_by_key(|session| session.start);
+        sessions
+    }
+}
+
 pub fn parse_sleep_data(bytes: &[u8]) -> Result<SleepData, ProtocolError> {
     let (id, _data_len) = parse_big_data_header(bytes)?;
     if id != DATA_REQUEST_ID_SLEEP {
@@ -121,6 +219,19 @@ pub struct OxygenDay {
     pub samples: Vec<OxygenSample>,
 }
 
+impl OxygenDay {
+    /// Each hour's start with its sample. `today` is the ring's current
+    /// date; the ring counts days on its UTC clock.
+    pub fn hours(&self, today: NaiveDate) -> impl Iterator<Item = (DateTime<Utc>, &OxygenSample)> {
+        let date = today - Duration::days(self.days_ago as i64);
+        let midnight = date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc();
+        self.samples
+            .iter()
+            .enumerate()
+            .map(move |(hour, sample)| (midnight + Duration::hours(hour as i64), sample))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct OxygenData {
     pub days: Vec<OxygenDay>,
@@ -157,3 +268,104 @@ pub fn parse_oxygen_data(bytes: &[u8]) -> Result<OxygenData, ProtocolError> {
 
     Ok(OxygenData { days })
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::FixedOffset;
+
+    use super::*;
+
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, 10).unwrap()
+    }
+
+    fn utc(d: u32, h: u32, m: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, d, h, m, 0).unwrap()
+    }
+
+    fn sleep_day(
+        days_ago: u8,
+        start: (u16, u16),
+        end: (u16, u16),
+        phases: &[(u8, u8)],
+    ) -> SleepDay {
+        SleepDay {
+            days_ago,
+            start_minutes: start.0 * 60 + start.1,
+            end_minutes: end.0 * 60 + end.1,
+            phases: phases
+                .iter()
+                .map(|&(phase_type, minutes)| SleepPhase {
+                    phase_type,
+                    minutes,
+                })
+                .collect(),
+        }
+    }
+
+    fn night() -> SleepDay {
+        sleep_day(0, (23, 30), (6, 0), &[(2, 120), (3, 90), (4, 60), (2, 120)])
+    }
+
+    #[test]
+    fn session_starting_before_midnight_began_the_day_before() {
+        let session = night().to_session(today(), &Utc).unwrap();
+        assert_eq!(session.start, utc(9, 23, 30));
+        assert_eq!(session.end, utc(10, 6, 0));
+
+        let bounds: Vec<_> = session
+            .stages
+            .iter()
+            .map(|stage| (stage.phase_type, stage.start, stage.end))
+            .collect();
+        assert_eq!(
+            bounds,
+            [
+                (2, utc(9, 23, 30), utc(10, 1, 30)),
+                (3, utc(10, 1, 30), utc(10, 3, 0)),
+                (4, utc(10, 3, 0), utc(10, 4, 0)),
+                (2, utc(10, 4, 0), utc(10, 6, 0)),
+            ]
+        );
+        assert_eq!(session.total_minutes(), 390);
+        assert_eq!(
+            session.stage_breakdown(),
+            [("light", 240), ("deep", 90), ("REM", 60)]
+        );
+    }
+
+    #[test]
+    fn session_is_placed_in_the_ring_clock_zone() {
+        let cet = FixedOffset::east_opt(3600).unwrap();
+        let session = night().to_session(today(), &cet).unwrap();
+        assert_eq!(session.start, utc(9, 22, 30));
+        assert_eq!(session.end, utc(10, 5, 0));
+    }
+
+    #[test]
+    fn nap_stays_on_its_own_day() {
+        let nap = sleep_day(1, (13, 0), (13, 45), &[(2, 45)]);
+        let session = nap.to_session(today(), &Utc).unwrap();
+        assert_eq!(session.start, utc(9, 13, 0));
+        assert_eq!(session.end, utc(9, 13, 45));
+        assert_eq!(session.stages[0].end, session.end);
+    }
+
+    #[test]
+    fn two_sessions_on_one_day_are_kept_apart_in_order() {
+        let nap = sleep_day(0, (14, 0), (14, 40), &[(2, 40)]);
+        let data = SleepData {
+            days: vec![nap, night()],
+        };
+
+        let sessions = data.sessions_as_of(today(), &Utc);
+        let bounds: Vec<_> = sessions.iter().map(|s| (s.start, s.end)).collect();
+        assert_eq!(
+            bounds,
+            [
+                (utc(9, 23, 30), utc(10, 6, 0)),
+                (utc(10, 14, 0), utc(10, 14, 40)),
+            ]
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use crate::error::ProtocolError;
+use crate::protocol::realtime::ReadingType;
 use crate::protocol::{Request, Response, to_bcd};
 use chrono::{Datelike, Timelike, Utc};
 use serde::{Deserialize, Serialize};
@@ -143,3 +144,17 @@ impl Response for FeatureResponse {
         })
     }
 }
+
+impl FeatureResponse {
+    /// Realtime metrics this ring supports, in order.
+    pub fn realtime_types(&self) -> Vec<ReadingType> {
+        let mut types = vec![ReadingType::HeartRateBatch];
+        if self.supports_blood_oxygen {
+            types.push(ReadingType::BloodOxygen);
+        }
+        if self.supports_hrv {
+            types.push(ReadingType::Hrv);
+        }
+        types
+    }
+}
@@ -1,8 +1,12 @@
+use chrono::{DateTime, Duration, Utc};
+
 use crate::error::ProtocolError;
 use crate::protocol::Request;
 
 pub const CMD_READ_HEART_RATE: u8 = 0x15;
-pub const HEART_RATE_POINTS_PER_DAY: usize = 288;
+/// Interval assumed when the ring reports none (288 samples per day).
+pub const DEFAULT_LOG_INTERVAL_MINUTES: u8 = 5;
+const MINUTES_PER_DAY: usize = 24 * 60;
 
 pub struct HeartRateRequest {
     pub command_id: u8,
@@ -35,10 +39,72 @@ impl Request for HeartRateRequest {
     }
 }
 
+/// One slot of the heart-rate log. `bpm` is `None` when the ring recorded
+/// nothing for the slot (not worn, or logging disabled).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeartRateSample {
+    pub timestamp: DateTime<Utc>,
+    pub bpm: Option<u8>,
+}
+
+/// A day of logged heart rate as a time series starting at `day_start`,
+/// one sample every `interval`.
 #[derive(Clone, Debug)]
 pub struct HeartRateLog {
-    pub heart_rates: Vec<u8>,
-    pub range: u8,
+    pub day_start: DateTime<Utc>,
+    pub interval: Duration,
+    pub samples: Vec<HeartRateSample>,
+}
+
+impl HeartRateLog {
+    fn from_raw(day_start: DateTime<Utc>, range: u8, raw: &[u8]) -> Self {
+        let minutes = if range == 0 {
+            DEFAULT_LOG_INTERVAL_MINUTES
+        } else {
+            range
+        };
+        let interval = Duration::minutes(minutes as i64);
+        let slots = MINUTES_PER_DAY / minutes as usize;
+
+        let samples = (0..slots)
+            .map(|i| HeartRateSample {
+                timestamp: day_start + interval * i as i32,
+                bpm: raw.get(i).copied().filter(|&bpm| bpm > 0),
+            })
+            .collect();
+
+        Self {
+            day_start,
+            interval,
+            samples,
+        }
+    }
+
+    /// Joins the slots of several logs that fall within `[start, end)`, e.g.
+    /// the two ring days a local day overlaps. `None` if no log covers it.
+    pub fn window(logs: &[HeartRateLog], start: DateTime<Utc>, end: DateTime<Utc>) -> Option<Self> {
+        let interval = logs.first()?.interval;
+        let mut samples: Vec<HeartRateSample> = logs
+            .iter()
+            .flat_map(|log| log.samples.iter().copied())
+            .filter(|sample| sample.timestamp >= start && sample.timestamp < end)
+            .collect();
+        samples.sort_by_key(|sample| sample.timestamp);
+        samples.dedup_by_key(|sample| sample.timestamp);
+
+        Some(Self {
+            day_start: start,
+            interval,
+            samples,
+        })
+    }
+
+    /// Samples the ring actually recorded, as `(timestamp, bpm)`.
+    pub fn readings(&self) -> impl Iterator<Item = (DateTime<Utc>, u8)> + '_ {
+        self.samples
+            .iter()
+            .filter_map(|sample| sample.bpm.map(|bpm| (sample.timestamp, bpm)))
+    }
 }
 
 #[derive(Debug)]
@@ -48,6 +114,7 @@ pub enum HeartRateResult {
 }
 
 pub struct HeartRateLogParser {
+    day_start: DateTime<Utc>,
     size: usize,
     range: u8,
     raw: Vec<u8>,
@@ -56,8 +123,11 @@ pub struct HeartRateLogParser {
 }
 
 impl HeartRateLogParser {
-    pub fn new() -> Self {
+    /// `day_start` is the timestamp the log was requested for; sample times
+    /// are counted from it.
+    pub fn new(day_start: DateTime<Utc>) -> Self {
         Self {
+            day_start,
             size: 0,
             range: 0,
             raw: Vec::new(),
@@ -102,18 +172,142 @@ impl HeartRateLogParser {
         }
 
         if subtype as usize == self.size - 1 {
-            let mut heart_rates = self.raw.clone();
-            heart_rates.truncate(HEART_RATE_POINTS_PER_DAY);
-            heart_rates.resize(HEART_RATE_POINTS_PER_DAY, 0);
-
-            let result = HeartRateLog {
-                heart_rates,
-                range: self.range,
-            };
-            *self = Self::new();
+            let result = HeartRateLog::from_raw(self.day_start, self.range, &self.raw);
+            *self = Self::new(self.day_start);
             return Ok(Some(HeartRateResult::Log(result)));
         }
 
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::protocol::calculate_checksum;
+
+    fn packet(subtype: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0u8; 16];
+        bytes[0] = CMD_READ_HEART_RATE;
+        bytes[1] = subtype;
+        bytes[2..2 + payload.len()].copy_from_slice(payload);
+        bytes[15] = calculate_checksum(&bytes);
+        bytes
+    }
+
+    /// A full download as the ring sends it: the header with packet count
+    /// and interval, packet 1 with the day's timestamp and the first 9
+    /// values, then 13 values per packet.
+    fn download(day_start: DateTime<Utc>, range: u8, values: &[u8]) -> Vec<Vec<u8>> {
+        let size = 24u8;
+        let mut raw = values.to_vec();
+        raw.resize(size as usize * 13 - 4, 0);
+
+        let mut packets = vec![packet(0, &[size, range])];
+        let mut first = (day_start.timestamp() as u32).to_le_bytes().to_vec();
+        first.extend_from_slice(&raw[..9]);
+        packets.push(packet(1, &first));
+        for (subtype, chunk) in (2..size).zip(raw[9..].chunks(13)) {
+            packets.push(packet(subtype, chunk));
+        }
+        packets
+    }
+
+    fn parse(day_start: DateTime<Utc>, packets: &[Vec<u8>]) -> HeartRateLog {
+        let mut parser = HeartRateLogParser::new(day_start);
+        let (last, rest) = packets.split_last().unwrap();
+        for packet in rest {
+            assert!(parser.feed(packet).unwrap().is_none());
+        }
+        match parser.feed(last).unwrap() {
+            Some(HeartRateResult::Log(log)) => log,
+            other => panic!("expected a log, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn samples_are_timestamped_from_the_requested_day() {
+        let day_start = Utc.with_ymd_and_hms(2024, 3, 10, 0, 0, 0).unwrap();
+        let mut values = vec![0u8; 288];
+        values[2] = 62;
+        values[3] = 64;
+        // Straddles packets 1 and 2.
+        values[9] = 70;
+        values[287] = 58;
+
+        let log = parse(day_start, &download(day_start, 5, &values));
+
+        assert_eq!(log.interval, Duration::minutes(5));
+        assert_eq!(log.samples.len(), 288);
+        assert_eq!(
+            log.samples[0],
+            HeartRateSample {
+                timestamp: day_start,
+                bpm: None,
+            }
+        );
+        assert_eq!(
+            log.samples[2],
+            HeartRateSample {
+                timestamp: Utc.with_ymd_and_hms(2024, 3, 10, 0, 10, 0).unwrap(),
+                bpm: Some(62),
+            }
+        );
+        assert_eq!(log.samples[9].bpm, Some(70));
+        assert_eq!(
+            log.samples[287].timestamp,
+            Utc.with_ymd_and_hms(2024, 3, 10, 23, 55, 0).unwrap()
+        );
+        assert_eq!(log.samples[287].bpm, Some(58));
+
+        let readings: Vec<_> = log.readings().map(|(_, bpm)| bpm).collect();
+        assert_eq!(readings, [62, 64, 70, 58]);
+    }
+
+    #[test]
+    fn range_zero_means_five_minutes() {
+        let day_start = Utc.with_ymd_and_hms(2024, 3, 10, 0, 0, 0).unwrap();
+        let log = parse(day_start, &download(day_start, 0, &[0, 80]));
+        assert_eq!(log.interval, Duration::minutes(5));
+        assert_eq!(log.samples.len(), 288);
+        assert_eq!(log.samples[1].timestamp, day_start + Duration::minutes(5));
+        assert_eq!(log.samples[1].bpm, Some(80));
+    }
+
+    #[test]
+    fn range_sets_the_interval() {
+        let day_start = Utc.with_ymd_and_hms(2024, 3, 10, 0, 0, 0).unwrap();
+        let log = parse(day_start, &download(day_start, 30, &[0, 0, 75]));
+        assert_eq!(log.interval, Duration::minutes(30));
+        assert_eq!(log.samples.len(), 48);
+        assert_eq!(
+            log.samples[2],
+            HeartRateSample {
+                timestamp: Utc.with_ymd_and_hms(2024, 3, 10, 1, 0, 0).unwrap(),
+                bpm: Some(75),
+            }
+        );
+        assert!(log.samples[3..].iter().all(|sample| sample.bpm.is_none()));
+    }
+
+    #[test]
+    fn no_data_and_out_of_order_packets() {
+        let day_start = Utc.with_ymd_and_hms(2024, 3, 10, 0, 0, 0).unwrap();
+        let mut parser = HeartRateLogParser::new(day_start);
+        assert!(matches!(
+            parser.feed(&packet(0xFF, &[])),
+            Ok(Some(HeartRateResult::NoData))
+        ));
+
+        let packets = download(day_start, 5, &[]);
+        let mut parser = HeartRateLogParser::new(day_start);
+        parser.feed(&packets[0]).unwrap();
+        parser.feed(&packets[1]).unwrap();
+        assert!(matches!(
+            parser.feed(&packets[3]),
+            Err(ProtocolError::MalformedSplitArray)
+        ));
+    }
+}
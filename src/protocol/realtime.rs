@@ -1,11 +1,27 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::devices::manager::Connection;
 use crate::error::ProtocolError;
 use crate::protocol::Request;
 
 pub const CMD_START_REAL_TIME: u8 = 0x69;
 pub const CMD_STOP_REAL_TIME: u8 = 0x6A;
+pub const CMD_REAL_TIME_CONTINUE: u8 = 0x1E;
 pub const ACTION_START: u8 = 0x01;
+/// Payload byte of the continue packet; reference clients send ASCII '3'.
+pub const CONTINUE_PAYLOAD: u8 = b'3';
+/// How often continuous monitoring tells the ring to keep streaming.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// Restart the measurement if no reading arrived for this long.
+pub const QUIET_TIMEOUT: Duration = Duration::from_secs(90);
+/// Non-zero readings collected per metric by a realtime sample.
+pub const SAMPLE_COUNT: usize = 5;
+/// Give up on a metric if it has not settled within this time.
+pub const SAMPLE_TIMEOUT: Duration = Duration::from_secs(75);
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum ReadingType {
     HeartRateBatch = 0x01,
     BloodOxygen = 0x03,
@@ -13,6 +29,27 @@ pub enum ReadingType {
 }
 
 impl ReadingType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hr" | "heart-rate" => Some(Self::HeartRateBatch),
+            "spo2" | "blood-oxygen" => Some(Self::BloodOxygen),
+            "hrv" => Some(Self::Hrv),
+            _ => None,
+        }
+    }
+
+    /// Parses a comma-separated list such as `hr,spo2,hrv`.
+    pub fn parse_list(names: &str) -> Result<Vec<Self>, String> {
+        let mut types = Vec::new();
+        for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let reading_type = Self::from_name(name).ok_or_else(|| name.to_string())?;
+            if !types.contains(&reading_type) {
+                types.push(reading_type);
+            }
+        }
+        Ok(types)
+    }
+
     pub fn from_byte(value: u8) -> Result<Self, ProtocolError> {
         match value {
             0x01 => Ok(Self::HeartRateBatch),
@@ -102,14 +139,49 @@ impl Request for RealtimeStopRequest {
     }
 }
 
+pub struct RealtimeContinueRequest {
+    pub command_id: u8,
+    pub payload: u8,
+    pub padding: [u8; 13],
+    pub checksum: u8,
+}
+
+impl RealtimeContinueRequest {
+    pub fn new() -> Self {
+        let mut req = Self {
+            command_id: CMD_REAL_TIME_CONTINUE,
+            payload: CONTINUE_PAYLOAD,
+            padding: [0; 13],
+            checksum: 0,
+        };
+        req.checksum = req.update_checksum();
+        req
+    }
+}
+
+impl Request for RealtimeContinueRequest {
+    fn as_bytes(&self) -> [u8; 16] {
+        let mut bytes: [u8; 16] = [0; 16];
+        bytes[0] = self.command_id;
+        bytes[1] = self.payload;
+        bytes[2..15].copy_from_slice(&self.padding);
+        bytes[15] = self.checksum;
+        bytes
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct RealtimeReading {
     pub reading_type: ReadingType,
     pub value: u8,
+    /// When the packet was received.
+    pub timestamp: DateTime<Utc>,
 }
 
 impl RealtimeReading {
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+    /// The packet carries no time of its own, so the caller stamps it with
+    /// `received_at`.
+    pub fn from_bytes(bytes: &[u8], received_at: DateTime<Utc>) -> Result<Self, ProtocolError> {
         if bytes.len() != 16 {
             return Err(ProtocolError::PacketLength);
         }
@@ -133,6 +205,212 @@ impl RealtimeReading {
         Ok(Self {
             reading_type,
             value: bytes[3],
+            timestamp: received_at,
         })
     }
 }
+
+#[derive(Clone, Debug)]
+pub enum SessionMode {
+    /// All metrics measured at the same time.
+    Together,
+    /// One metric at a time, switching after each dwell period.
+    Cycle(Duration),
+}
+
+/// Which metrics a realtime session measures, and how.
+#[derive(Clone, Debug)]
+pub struct RealtimeSession {
+    pub reading_types: Vec<ReadingType>,
+    pub mode: SessionMode,
+}
+
+impl RealtimeSession {
+    /// Metrics that should be running `elapsed` into the session.
+    pub fn active_types(&self, elapsed: Duration) -> Vec<ReadingType> {
+        match self.mode {
+            SessionMode::Together => self.reading_types.clone(),
+            SessionMode::Cycle(dwell) => {
+                if self.reading_types.is_empty() {
+                    return Vec::new();
+                }
+                let step = (elapsed.as_secs() / dwell.as_secs().max(1)) as usize;
+                vec![self.reading_types[step % self.reading_types.len()]]
+            }
+        }
+    }
+
+    pub fn label(&self) -> String {
+        let labels: Vec<&str> = self.reading_types.iter().map(|t| t.label()).collect();
+        match self.mode {
+            SessionMode::Together => labels.join(" + "),
+            SessionMode::Cycle(dwell) => {
+                format!("{} (cycling every {}s)", labels.join(", "), dwell.as_secs())
+            }
+        }
+    }
+}
+
+/// Metrics to stop and to start when going from `from` to `to`. Metrics in
+/// both keep running untouched.
+pub fn switched_types(
+    from: &[ReadingType],
+    to: &[ReadingType],
+) -> (Vec<ReadingType>, Vec<ReadingType>) {
+    let stopping = from.iter().copied().filter(|t| !to.contains(t)).collect();
+    let starting = to.iter().copied().filter(|t| !from.contains(t)).collect();
+    (stopping, starting)
+}
+
+#[derive(Clone, Debug)]
+pub enum SampleProgress {
+    Waiting {
+        reading_type: ReadingType,
+        elapsed: Duration,
+    },
+    Sample(RealtimeReading),
+}
+
+/// Median readings of a realtime sample; metrics that did not settle in
+/// time are `None`.
+#[derive(Clone, Debug, Default)]
+pub struct RealtimeSample {
+    pub heart_rate: Option<u8>,
+    pub blood_oxygen: Option<u8>,
+    pub hrv: Option<u8>,
+}
+
+impl RealtimeSample {
+    pub fn set(&mut self, reading_type: ReadingType, value: Option<u8>) {
+        match reading_type {
+            ReadingType::HeartRateBatch => self.heart_rate = value,
+            ReadingType::BloodOxygen => self.blood_oxygen = value,
+            ReadingType::Hrv => self.hrv = value,
+        }
+    }
+
+    pub fn get(&self, reading_type: ReadingType) -> Option<u8> {
+        match reading_type {
+            ReadingType::HeartRateBatch => self.heart_rate,
+            ReadingType::BloodOxygen => self.blood_oxygen,
+            ReadingType::Hrv => self.hrv,
+        }
+    }
+}
+
+/// Median of the collected samples; the first readings after warm-up are
+/// often still drifting, so the median is steadier than the mean.
+pub fn settled_value(samples: &[u8]) -> Option<u8> {
+    let mut sorted = samples.to_vec();
+    sorted.sort_unstable();
+    sorted.get(sorted.len() / 2).copied()
+}
+
+#[derive(Clone)]
+pub enum MonitorEvent {
+    Reading(RealtimeReading),
+    /// The ring went quiet and the measurement was started again.
+    Restarted,
+    Reconnecting {
+        attempt: u32,
+    },
+    /// The link came back; the old connection is closed and this one
+    /// replaces it.
+    Reconnected(Connection),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ReadingType::{BloodOxygen, HeartRateBatch, Hrv};
+
+    #[test]
+    fn parse_list_accepts_names_and_aliases_in_order() {
+        assert_eq!(
+            ReadingType::parse_list("hr,spo2,hrv"),
+            Ok(vec![HeartRateBatch, BloodOxygen, Hrv])
+        );
+        assert_eq!(
+            ReadingType::parse_list(" hrv , blood-oxygen,heart-rate"),
+            Ok(vec![Hrv, BloodOxygen, HeartRateBatch])
+        );
+    }
+
+    #[test]
+    fn parse_list_drops_duplicates_and_empty_entries() {
+        assert_eq!(
+            ReadingType::parse_list("hr,,heart-rate,spo2,hr,"),
+            Ok(vec![HeartRateBatch, BloodOxygen])
+        );
+        assert_eq!(ReadingType::parse_list(""), Ok(vec![]));
+    }
+
+    #[test]
+    fn parse_list_reports_the_unknown_name() {
+        assert_eq!(
+            ReadingType::parse_list("hr, steps ,spo2"),
+            Err("steps".to_string())
+        );
+        assert_eq!(ReadingType::parse_list("HR"), Err("HR".to_string()));
+    }
+
+    #[test]
+    fn together_keeps_every_type_active() {
+        let session = RealtimeSession {
+            reading_types: vec![HeartRateBatch, BloodOxygen],
+            mode: SessionMode::Together,
+        };
+        assert_eq!(
+            session.active_types(Duration::from_secs(3600)),
+            vec![HeartRateBatch, BloodOxygen]
+        );
+    }
+
+    #[test]
+    fn cycle_moves_on_after_each_dwell_and_wraps() {
+        let session = RealtimeSession {
+            reading_types: vec![HeartRateBatch, BloodOxygen, Hrv],
+            mode: SessionMode::Cycle(Duration::from_secs(30)),
+        };
+        let active = |secs| session.active_types(Duration::from_secs(secs));
+        assert_eq!(active(0), vec![HeartRateBatch]);
+        assert_eq!(active(29), vec![HeartRateBatch]);
+        assert_eq!(active(30), vec![BloodOxygen]);
+        assert_eq!(active(89), vec![Hrv]);
+        assert_eq!(active(90), vec![HeartRateBatch]);
+
+        // A zero dwell still advances, once a second.
+        let session = RealtimeSession {
+            mode: SessionMode::Cycle(Duration::ZERO),
+            ..session
+        };
+        assert_eq!(
+            session.active_types(Duration::from_secs(1)),
+            vec![BloodOxygen]
+        );
+
+        let empty = RealtimeSession {
+            reading_types: vec![],
+            mode: SessionMode::Cycle(Duration::from_secs(30)),
+        };
+        assert!(empty.active_types(Duration::from_secs(45)).is_empty());
+    }
+
+    #[test]
+    fn switching_stops_and_starts_only_what_changed() {
+        assert_eq!(
+            switched_types(&[HeartRateBatch], &[BloodOxygen]),
+            (vec![HeartRateBatch], vec![BloodOxygen])
+        );
+        assert_eq!(
+            switched_types(&[HeartRateBatch, BloodOxygen], &[BloodOxygen, Hrv]),
+            (vec![HeartRateBatch], vec![Hrv])
+        );
+        assert_eq!(
+            switched_types(&[HeartRateBatch], &[HeartRateBatch]),
+            (vec![], vec![])
+        );
+        assert_eq!(switched_types(&[], &[Hrv]), (vec![], vec![Hrv]));
+    }
+}
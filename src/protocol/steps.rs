@@ -1,7 +1,12 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+
 use crate::error::ProtocolError;
-use crate::protocol::Request;
+use crate::protocol::{Request, Response, be24};
 
 pub const CMD_GET_ACTIVITY_DATA: u8 = 0x43;
+pub const CMD_GET_TODAY_TOTALS: u8 = 0x48;
+pub const CMD_NOTIFICATION: u8 = 0x73;
+pub const NOTIFICATION_LIVE_ACTIVITY: u8 = 0x12;
 
 pub struct StepsRequest {
     pub command_id: u8,
@@ -37,6 +42,67 @@ impl Request for StepsRequest {
     }
 }
 
+pub struct TodayTotalsRequest {
+    pub command_id: u8,
+    pub padding: [u8; 14],
+    pub checksum: u8,
+}
+
+impl TodayTotalsRequest {
+    pub fn new() -> Self {
+        let mut req = Self {
+            command_id: CMD_GET_TODAY_TOTALS,
+            padding: [0; 14],
+            checksum: 0,
+        };
+        req.checksum = req.update_checksum();
+        req
+    }
+}
+
+impl Request for TodayTotalsRequest {
+    fn as_bytes(&self) -> [u8; 16] {
+        let mut bytes: [u8; 16] = [0; 16];
+        bytes[0] = self.command_id;
+        bytes[1..15].copy_from_slice(&self.padding);
+        bytes[15] = self.checksum;
+        bytes
+    }
+}
+
+/// Single-packet summary of today's activity as counted by the ring.
+#[derive(Clone, Debug)]
+pub struct DailyTotals {
+    pub steps: u32,
+    /// Calories in kcal.
+    pub calories: f64,
+    /// Distance in meters.
+    pub distance: u32,
+}
+
+impl Response for DailyTotals {
+    const EXPECTED_COMMAND_ID: u8 = CMD_GET_TODAY_TOTALS;
+
+    fn from_bytes(bytes: Vec<u8>) -> Result<Self, ProtocolError> {
+        Self::validate_command_id(&bytes)?;
+        Self::verify_checksum(&bytes)?;
+
+        Ok(Self {
+            steps: be24(&bytes, 1),
+            // Raw value is in 0.1-kcal units, as in live activity packets.
+            calories: be24(&bytes, 4) as f64 / 10.0,
+            distance: be24(&bytes, 7),
+        })
+    }
+}
+
+/// Steps the ring counted today that are missing from the downloaded
+/// 15-minute details, or `None` when the details account for all of them.
+pub fn detail_shortfall(totals: &DailyTotals, details: &[ActivityDetail]) -> Option<u32> {
+    let summed: u32 = details.iter().map(|d| d.steps as u32).sum();
+    (summed < totals.steps).then(|| totals.steps - summed)
+}
+
 #[derive(Clone, Debug)]
 pub struct ActivityDetail {
     pub year: u16,
@@ -50,6 +116,15 @@ pub struct ActivityDetail {
     pub distance: u16,
 }
 
+impl ActivityDetail {
+    /// Start of the slot; the date and slot are on the ring's UTC clock.
+    pub fn timestamp(&self) -> Option<DateTime<Utc>> {
+        let date = NaiveDate::from_ymd_opt(self.year as i32, self.month as u32, self.day as u32)?;
+        let start = date.and_hms_opt(0, 0, 0)? + Duration::minutes(self.time_index as i64 * 15);
+        Some(start.and_utc())
+    }
+}
+
 #[derive(Debug)]
 pub enum StepsResult {
     Details(Vec<ActivityDetail>),
@@ -120,3 +195,37 @@ impl ActivityDetailParser {
         Ok(None)
     }
 }
+
+/// Running totals for today, pushed unsolicited by the ring while connected.
+#[derive(Clone, Debug)]
+pub struct LiveActivity {
+    pub steps: u32,
+    /// Calories in kcal.
+    pub calories: f64,
+    /// Distance in meters.
+    pub distance: u32,
+}
+
+impl LiveActivity {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        if bytes.len() != 16 {
+            return Err(ProtocolError::PacketLength);
+        }
+        if bytes[0] != CMD_NOTIFICATION {
+            return Err(ProtocolError::CommandId {
+                expected: CMD_NOTIFICATION,
+                actual: bytes[0],
+            });
+        }
+        if bytes[1] != NOTIFICATION_LIVE_ACTIVITY {
+            return Err(ProtocolError::UnknownNotification(bytes[1]));
+        }
+
+        Ok(Self {
+            steps: be24(bytes, 2),
+            // Raw value is in 0.1-kcal units.
+            calories: be24(bytes, 5) as f64 / 10.0,
+            distance: be24(bytes, 8),
+        })
+    }
+}
@@ -0,0 +1,288 @@
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+use crate::error::ProtocolError;
+use crate::protocol::{Request, be24};
+
+pub const CMD_PHONE_SPORT: u8 = 0x77;
+pub const CMD_PHONE_SPORT_NOTIFY: u8 = 0x78;
+pub const DEFAULT_MAX_HEART_RATE: u8 = 190;
+/// Zone lower bounds as a percentage of max heart rate (zones 1-5).
+pub const HEART_RATE_ZONE_BOUNDS: [u8; 5] = [50, 60, 70, 80, 90];
+
+/// Gaps between heart-rate samples longer than this are not credited to a zone.
+const MAX_SAMPLE_GAP: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkoutAction {
+    Start = 0x01,
+    Pause = 0x02,
+    Resume = 0x03,
+    End = 0x04,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SportType {
+    Walking = 0x04,
+    Running = 0x07,
+    Hiking = 0x08,
+    Cycling = 0x09,
+    Other = 0x0A,
+}
+
+impl SportType {
+    pub const ALL: [SportType; 5] = [
+        Self::Walking,
+        Self::Running,
+        Self::Hiking,
+        Self::Cycling,
+        Self::Other,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "walk" | "walking" => Some(Self::Walking),
+            "run" | "running" => Some(Self::Running),
+            "hike" | "hiking" => Some(Self::Hiking),
+            "cycle" | "cycling" | "bike" => Some(Self::Cycling),
+            "other" => Some(Self::Other),
+            _ => None,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Walking => "walking",
+            Self::Running => "running",
+            Self::Hiking => "hiking",
+            Self::Cycling => "cycling",
+            Self::Other => "other",
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        let index = Self::ALL.iter().position(|s| s == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+pub struct WorkoutRequest {
+    pub command_id: u8,
+    pub action: WorkoutAction,
+    pub sport_type: SportType,
+    pub padding: [u8; 12],
+    pub checksum: u8,
+}
+
+impl WorkoutRequest {
+    pub fn new(action: WorkoutAction, sport_type: SportType) -> Self {
+        let mut req = Self {
+            command_id: CMD_PHONE_SPORT,
+            action,
+            sport_type,
+            padding: [0; 12],
+            checksum: 0,
+        };
+        req.checksum = req.update_checksum();
+        req
+    }
+}
+
+impl Request for WorkoutRequest {
+    fn as_bytes(&self) -> [u8; 16] {
+        let mut bytes: [u8; 16] = [0; 16];
+        bytes[0] = self.command_id;
+        bytes[1] = self.action as u8;
+        bytes[2] = self.sport_type as u8;
+        bytes[3..15].copy_from_slice(&self.padding);
+        bytes[15] = self.checksum;
+        bytes
+    }
+}
+
+/// Periodic progress packet the ring pushes while a phone-started sport
+/// session is running.
+#[derive(Clone, Debug)]
+pub struct WorkoutProgress {
+    pub heart_rate: u8,
+    pub steps: u32,
+    /// Calories in kcal.
+    pub calories: f64,
+    /// Distance in meters.
+    pub distance: u32,
+}
+
+impl WorkoutProgress {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        if bytes.len() != 16 {
+            return Err(ProtocolError::PacketLength);
+        }
+        if bytes[0] != CMD_PHONE_SPORT_NOTIFY {
+            return Err(ProtocolError::CommandId {
+                expected: CMD_PHONE_SPORT_NOTIFY,
+                actual: bytes[0],
+            });
+        }
+
+        Ok(Self {
+            heart_rate: bytes[3],
+            steps: be24(bytes, 4),
+            // Raw value is in 0.1-kcal units.
+            calories: be24(bytes, 7) as f64 / 10.0,
+            distance: be24(bytes, 10),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum WorkoutEvent {
+    HeartRate(u8),
+    Progress(WorkoutProgress),
+}
+
+#[derive(Clone, Debug)]
+pub struct WorkoutSummary {
+    pub sport_type: SportType,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    /// Active time, excluding pauses.
+    pub duration: Duration,
+    pub avg_heart_rate: Option<u8>,
+    pub max_heart_rate: Option<u8>,
+    /// Time spent below zone 1, then in zones 1-5.
+    pub time_in_zones: [Duration; 6],
+    pub steps: u32,
+    pub calories: f64,
+    pub distance: u32,
+    /// Samples with the time they arrived.
+    pub heart_rates: Vec<(DateTime<Utc>, u8)>,
+}
+
+/// Client-side accumulator for a running workout: tracks pauses, heart-rate
+/// samples and the latest progress the ring reported.
+pub struct WorkoutSession {
+    pub sport_type: SportType,
+    pub max_heart_rate: u8,
+    pub started_at: DateTime<Utc>,
+    pub paused: bool,
+    pub heart_rates: Vec<(DateTime<Utc>, u8)>,
+    pub latest: Option<WorkoutProgress>,
+    started: Instant,
+    paused_at: Option<Instant>,
+    paused_total: Duration,
+    last_sample: Option<(Instant, u8)>,
+    time_in_zones: [Duration; 6],
+}
+
+impl WorkoutSession {
+    pub fn new(sport_type: SportType, max_heart_rate: u8) -> Self {
+        Self {
+            sport_type,
+            max_heart_rate,
+            started_at: Utc::now(),
+            paused: false,
+            heart_rates: Vec::new(),
+            latest: None,
+            started: Instant::now(),
+            paused_at: None,
+            paused_total: Duration::ZERO,
+            last_sample: None,
+            time_in_zones: [Duration::ZERO; 6],
+        }
+    }
+
+    pub fn record(&mut self, event: &WorkoutEvent) {
+        match event {
+            WorkoutEvent::HeartRate(value) => self.record_heart_rate(*value),
+            WorkoutEvent::Progress(progress) => {
+                self.record_heart_rate(progress.heart_rate);
+                self.latest = Some(progress.clone());
+            }
+        }
+    }
+
+    fn record_heart_rate(&mut self, value: u8) {
+        if value == 0 || self.paused {
+            return;
+        }
+        let now = Instant::now();
+        self.credit_zone(now);
+        self.heart_rates.push((Utc::now(), value));
+        self.last_sample = Some((now, value));
+    }
+
+    fn credit_zone(&mut self, now: Instant) {
+        if let Some((at, value)) = self.last_sample {
+            let gap = now.duration_since(at);
+            if gap <= MAX_SAMPLE_GAP {
+                self.time_in_zones[self.zone(value)] += gap;
+            }
+        }
+    }
+
+    /// Zone index for a heart rate: 0 below zone 1, otherwise 1-5.
+    pub fn zone(&self, heart_rate: u8) -> usize {
+        let pct = heart_rate as u32 * 100 / self.max_heart_rate.max(1) as u32;
+        HEART_RATE_ZONE_BOUNDS
+            .iter()
+            .filter(|&&bound| pct >= bound as u32)
+            .count()
+    }
+
+    pub fn pause(&mut self) {
+        if !self.paused {
+            let now = Instant::now();
+            self.credit_zone(now);
+            self.last_sample = None;
+            self.paused_at = Some(now);
+            self.paused = true;
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if let Some(at) = self.paused_at.take() {
+            self.paused_total += at.elapsed();
+        }
+        self.paused = false;
+    }
+
+    /// Active time so far, excluding pauses.
+    pub fn elapsed(&self) -> Duration {
+        let paused = self.paused_total + self.paused_at.map(|at| at.elapsed()).unwrap_or_default();
+        self.started.elapsed().saturating_sub(paused)
+    }
+
+    pub fn finish(mut self) -> WorkoutSummary {
+        if !self.paused {
+            self.credit_zone(Instant::now());
+        }
+        let duration = self.elapsed();
+        let avg_heart_rate = if self.heart_rates.is_empty() {
+            None
+        } else {
+            let sum: u32 = self.heart_rates.iter().map(|&(_, r)| r as u32).sum();
+            Some((sum / self.heart_rates.len() as u32) as u8)
+        };
+        let latest = self.latest.unwrap_or(WorkoutProgress {
+            heart_rate: 0,
+            steps: 0,
+            calories: 0.0,
+            distance: 0,
+        });
+
+        WorkoutSummary {
+            sport_type: self.sport_type,
+            started_at: self.started_at,
+            ended_at: Utc::now(),
+            duration,
+            avg_heart_rate,
+            max_heart_rate: self.heart_rates.iter().map(|&(_, r)| r).max(),
+            time_in_zones: self.time_in_zones,
+            steps: latest.steps,
+            calories: latest.calories,
+            distance: latest.distance,
+            heart_rates: self.heart_rates,
+        }
+    }
+}
@@ -0,0 +1,656 @@
+//! Daemon modes: keep one ring connected, poll it on a schedule, run
+//! commands sent from outside and publish its latest state.
+
+pub mod api;
+pub mod metrics;
+pub mod mqtt;
+
+use std::collections::BTreeMap;
+use std::future::pending;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use btleplug::api::Peripheral;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{Interval, MissedTickBehavior, interval};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::manager::load_device_features;
+use crate::devices::manager::{Connection, DeviceManager};
+use crate::devices::models::Device;
+use crate::error::{CliError, DeviceError, Failure, SyncError};
+use crate::protocol::bigdata::SleepSession;
+use crate::protocol::realtime::{
+    MonitorEvent, ReadingType, RealtimeReading, RealtimeSession, SessionMode,
+};
+use crate::protocol::settings::HeartRateLogSettings;
+use crate::protocol::steps::DailyTotals;
+use crate::store::database::Store;
+use crate::store::sync::{SyncMetric, sync_metric};
+
+/// Latest known state of the served ring.
+#[derive(Default)]
+pub struct RingState {
+    pub device: String,
+    pub name: String,
+    pub connected: bool,
+    pub battery: Option<Battery>,
+    pub heart_rate: Option<RealtimeReading>,
+    pub spo2: Option<RealtimeReading>,
+    pub hrv: Option<RealtimeReading>,
+    pub today: Option<DailyTotals>,
+    /// Most recent sleep the ring recorded.
+    pub last_sleep: Option<SleepSession>,
+    pub last_sync: Option<DateTime<Utc>>,
+    /// Whether a realtime stream is running.
+    pub realtime: bool,
+    /// Protocol errors seen so far, by [`ProtocolError::variant`].
+    ///
+    /// [`ProtocolError::variant`]: crate::error::ProtocolError::variant
+    pub protocol_errors: BTreeMap<&'static str, u64>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct Battery {
+    pub charge_pct: u8,
+    pub is_charging: bool,
+}
+
+impl RingState {
+    fn record_error(&mut self, err: &DeviceError) {
+        if let DeviceError::Protocol(err) = err {
+            *self.protocol_errors.entry(err.variant()).or_default() += 1;
+        }
+    }
+
+    fn set_reading(&mut self, reading: RealtimeReading) {
+        let slot = match reading.reading_type {
+            ReadingType::HeartRateBatch => &mut self.heart_rate,
+            ReadingType::BloodOxygen => &mut self.spo2,
+            ReadingType::Hrv => &mut self.hrv,
+        };
+        *slot = Some(reading);
+    }
+}
+
+/// [`RingState`] shared between the poller and whoever publishes it, plus
+/// the readings of a running realtime stream as they arrive.
+#[derive(Clone)]
+pub struct SharedState {
+    state: Arc<Mutex<RingState>>,
+    readings: broadcast::Sender<RealtimeReading>,
+    /// Extra stop signal for the next realtime stream the poller starts.
+    realtime_stop: Arc<Mutex<Option<CancellationToken>>>,
+}
+
+impl Default for SharedState {
+    fn default() -> Self {
+        Self {
+            state: Arc::default(),
+            readings: broadcast::channel(64).0,
+            realtime_stop: Arc::default(),
+        }
+    }
+}
+
+impl SharedState {
+    pub fn lock(&self) -> MutexGuard<'_, RingState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn subscribe_readings(&self) -> broadcast::Receiver<RealtimeReading> {
+        self.readings.subscribe()
+    }
+
+    /// Makes the stream started by the next [`Command::StartRealtime`] also
+    /// stop once `stop` is cancelled, even if that happens before it starts.
+    pub fn stop_next_realtime_with(&self, stop: CancellationToken) {
+        *self.realtime_stop() = Some(stop);
+    }
+
+    fn realtime_stop(&self) -> MutexGuard<'_, Option<CancellationToken>> {
+        self.realtime_stop
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// How often the poller talks to the ring. `None` disables a task.
+pub struct Schedule {
+    /// Battery and today's totals.
+    pub status: Duration,
+    /// Realtime sample of every metric the ring supports, taken in the
+    /// background. Skipped while a realtime stream is running.
+    pub sample: Option<Duration>,
+    /// Last night's sleep, and a history download into the local store.
+    pub sync: Option<Duration>,
+}
+
+/// Something to do on the ring besides the scheduled polls.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Blink,
+    Find,
+    Reboot,
+    Sync,
+    StartRealtime,
+    StopRealtime,
+    ReadBattery,
+    ReadInfo,
+    ReadSettings,
+    /// Heart-rate logging, `interval` in minutes.
+    WriteSettings {
+        enabled: bool,
+        interval: u8,
+    },
+}
+
+impl Command {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Blink => "Blink",
+            Self::Find => "Find",
+            Self::Reboot => "Reboot",
+            Self::Sync => "Sync",
+            Self::StartRealtime => "Start realtime",
+            Self::StopRealtime => "Stop realtime",
+            Self::ReadBattery => "Battery",
+            Self::ReadInfo => "Info",
+            Self::ReadSettings => "Settings",
+            Self::WriteSettings { .. } => "Update settings",
+        }
+    }
+
+    /// Reads only answer the caller; they are not reported as done.
+    fn is_read(&self) -> bool {
+        matches!(
+            self,
+            Self::ReadBattery | Self::ReadInfo | Self::ReadSettings
+        )
+    }
+}
+
+/// What a [`Command`] read from the ring.
+pub enum Reply {
+    Done,
+    Battery(Battery),
+    Info {
+        firmware: String,
+        hardware: String,
+        manufacturer: String,
+    },
+    Settings(HeartRateLogSettings),
+}
+
+/// Why a command failed, for whoever waits on its reply.
+#[derive(Debug)]
+pub struct CommandFailure {
+    pub kind: &'static str,
+    pub message: String,
+}
+
+impl CommandFailure {
+    fn new(error: &dyn Failure) -> Self {
+        Self {
+            kind: error.kind(),
+            message: error.to_string(),
+        }
+    }
+}
+
+pub type CommandResult = Result<Reply, CommandFailure>;
+
+/// A [`Command`] for the poller, optionally with somewhere to send the
+/// result.
+pub struct Request {
+    pub command: Command,
+    reply: Option<oneshot::Sender<CommandResult>>,
+}
+
+impl Request {
+    pub fn with_reply(command: Command) -> (Self, oneshot::Receiver<CommandResult>) {
+        let (reply, result) = oneshot::channel();
+        let request = Self {
+            command,
+            reply: Some(reply),
+        };
+        (request, result)
+    }
+}
+
+impl From<Command> for Request {
+    fn from(command: Command) -> Self {
+        Self {
+            command,
+            reply: None,
+        }
+    }
+}
+
+pub enum PollEvent<'a> {
+    Failed {
+        task: &'static str,
+        error: &'a dyn Failure,
+    },
+    Done {
+        command: Command,
+    },
+    Synced {
+        records: usize,
+    },
+    Reconnecting {
+        attempt: u32,
+    },
+    Reconnected,
+}
+
+/// A background job on the ring that can be told to stop.
+type Task<T> = (CancellationToken, JoinHandle<Result<T, DeviceError>>);
+
+/// Streams every metric the ring supports until cancelled.
+type RealtimeTask = Task<()>;
+
+/// Samples every metric the ring supports once into the state.
+type SampleTask = Task<()>;
+
+pub struct Poller {
+    device: Device,
+    state: SharedState,
+    schedule: Schedule,
+    store: Option<Store>,
+    realtime: Option<RealtimeTask>,
+    sampling: Option<SampleTask>,
+    readings: mpsc::Sender<MonitorEvent>,
+    readings_rx: mpsc::Receiver<MonitorEvent>,
+}
+
+impl Poller {
+    /// Syncing history needs `store`.
+    pub fn new(
+        device: Device,
+        state: SharedState,
+        schedule: Schedule,
+        store: Option<Store>,
+    ) -> Self {
+        let (readings, readings_rx) = mpsc::channel(64);
+        Self {
+            device,
+            state,
+            schedule,
+            store,
+            realtime: None,
+            sampling: None,
+            readings,
+            readings_rx,
+        }
+    }
+
+    /// Keeps the state up to date until `cancel` fires: polls on the
+    /// schedule, follows the ring's live activity, runs `commands` and
+    /// reconnects whenever the link drops.
+    pub async fn run(
+        mut self,
+        conn: Connection,
+        mut commands: mpsc::Receiver<Request>,
+        mut on_event: impl FnMut(PollEvent),
+        cancel: CancellationToken,
+    ) {
+        let mut conn = conn;
+        let mut live = conn.subscribe_live_activity();
+        let mut status = ticker(Some(self.schedule.status));
+        let mut sample = ticker(self.schedule.sample);
+        let mut sync = ticker(self.schedule.sync);
+
+        {
+            let mut state = self.state.lock();
+            state.device = self.device.id().to_string();
+            state.name = self.device.name().to_string();
+            state.connected = true;
+            state.last_sync = self
+                .store
+                .as_ref()
+                .and_then(|store| store.last_synced_at(self.device.id()).ok().flatten());
+        }
+
+        loop {
+            let (task, result) = tokio::select! {
+                _ = cancel.cancelled() => break,
+                activity = live.recv() => {
+                    match activity {
+                        Ok(activity) => {
+                            self.state.lock().today = Some(DailyTotals {
+                                steps: activity.steps,
+                                calories: activity.calories,
+                                distance: activity.distance,
+                            });
+                        }
+                        Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => live = conn.subscribe_live_activity(),
+                    }
+                    continue;
+                }
+                Some(event) = self.readings_rx.recv() => {
+                    match event {
+                        MonitorEvent::Reading(reading) => {
+                            self.state.lock().set_reading(reading.clone());
+                            let _ = self.state.readings.send(reading);
+                        }
+                        // The realtime task reconnected on its own.
+                        MonitorEvent::Reconnected(reconnected) => conn = reconnected,
+                        MonitorEvent::Restarted | MonitorEvent::Reconnecting { .. } => {}
+                    }
+                    continue;
+                }
+                finished = finished(&mut self.sampling) => {
+                    self.sampling = None;
+                    match finished {
+                        Ok(result) => ("Sample", result),
+                        Err(err) => {
+                            on_event(PollEvent::Failed {
+                                task: "Sample",
+                                error: &CliError::from(err),
+                            });
+                            continue;
+                        }
+                    }
+                }
+                finished = finished(&mut self.realtime) => {
+                    self.realtime = None;
+                    self.state.lock().realtime = false;
+                    match finished {
+                        Ok(result) => ("Realtime", result),
+                        Err(err) => {
+                            on_event(PollEvent::Failed {
+                                task: "Realtime",
+                                error: &CliError::from(err),
+                            });
+                            continue;
+                        }
+                    }
+                }
+                Some(Request { command, reply }) = commands.recv() => {
+                    let result = self.command(command, &conn, &cancel, &mut on_event).await;
+                    let result = match (result, reply) {
+                        (Ok(value), Some(reply)) => {
+                            let _ = reply.send(Ok(value));
+                            Ok(())
+                        }
+                        (Err(err), Some(reply)) => {
+                            let _ = reply.send(Err(CommandFailure::new(&err)));
+                            Err(err)
+                        }
+                        (result, None) => result.map(|_| ()),
+                    };
+                    (command.label(), result)
+                }
+                _ = tick(&mut status) => ("Status", poll_status(&conn, &self.state).await),
+                _ = tick(&mut sample), if self.realtime.is_none() && self.sampling.is_none() => {
+                    self.sampling = Some(spawn_sample(&conn, &self.state, &cancel));
+                    continue;
+                }
+                _ = tick(&mut sync) => ("Sync", self.sync(&conn, &mut on_event).await),
+            };
+
+            let Err(err) = result else {
+                continue;
+            };
+            if matches!(err, DeviceError::Cancelled) {
+                break;
+            }
+            self.state.lock().record_error(&err);
+            on_event(PollEvent::Failed { task, error: &err });
+
+            if conn.peripheral.is_connected().await.unwrap_or(false) {
+                continue;
+            }
+            self.state.lock().connected = false;
+            loop {
+                let reconnect = DeviceManager::reconnect(&self.device, &conn, |attempt| {
+                    on_event(PollEvent::Reconnecting { attempt })
+                });
+                let reconnected = tokio::select! {
+                    _ = cancel.cancelled() => return,
+                    reconnected = reconnect => reconnected,
+                };
+                match reconnected {
+                    Ok(reconnected) => {
+                        conn = reconnected;
+                        break;
+                    }
+                    Err(err) => on_event(PollEvent::Failed {
+                        task: "Reconnect",
+                        error: &err,
+                    }),
+                }
+            }
+            self.state.lock().connected = true;
+            on_event(PollEvent::Reconnected);
+        }
+
+        stop(self.sampling.take()).await;
+        stop(self.realtime.take()).await;
+        conn.close();
+        self.state.lock().connected = false;
+    }
+
+    async fn command(
+        &mut self,
+        command: Command,
+        conn: &Connection,
+        cancel: &CancellationToken,
+        on_event: &mut impl FnMut(PollEvent),
+    ) -> Result<Reply, DeviceError> {
+        let reply = match command {
+            Command::ReadBattery => {
+                let battery = DeviceManager::get_battery_level(conn).await?;
+                let battery = Battery {
+                    charge_pct: battery.charge_pct,
+                    is_charging: battery.is_charging,
+                };
+                self.state.lock().battery = Some(battery);
+                Reply::Battery(battery)
+            }
+            Command::ReadInfo => {
+                let (firmware, hardware, manufacturer) =
+                    DeviceManager::get_device_info(conn).await?;
+                Reply::Info {
+                    firmware,
+                    hardware,
+                    manufacturer,
+                }
+            }
+            Command::ReadSettings => {
+                Reply::Settings(DeviceManager::get_heart_rate_log_settings(conn).await?)
+            }
+            Command::WriteSettings { enabled, interval } => {
+                DeviceManager::set_heart_rate_log_settings(conn, enabled, interval).await?;
+                Reply::Settings(HeartRateLogSettings { enabled, interval })
+            }
+            Command::Blink => {
+                DeviceManager::blink(conn).await?;
+                Reply::Done
+            }
+            Command::Find => {
+                DeviceManager::find(conn).await?;
+                Reply::Done
+            }
+            Command::Reboot => {
+                DeviceManager::reboot(conn).await?;
+                Reply::Done
+            }
+            Command::Sync => {
+                self.sync(conn, on_event).await?;
+                Reply::Done
+            }
+            Command::StartRealtime if self.realtime.is_none() => {
+                // Both drive the same sensors; the stream takes over.
+                stop(self.sampling.take()).await;
+                let session = RealtimeSession {
+                    reading_types: realtime_types(),
+                    mode: SessionMode::Together,
+                };
+                let token = cancel.child_token();
+                let stop = self.state.realtime_stop().take();
+                let (device, conn, readings, monitor_token) = (
+                    self.device.clone(),
+                    conn.clone(),
+                    self.readings.clone(),
+                    token.clone(),
+                );
+                let task = tokio::spawn(async move {
+                    let monitor = DeviceManager::monitor_realtime(
+                        &device,
+                        conn,
+                        session,
+                        readings,
+                        monitor_token.clone(),
+                    );
+                    let Some(stop) = stop else {
+                        return monitor.await;
+                    };
+                    tokio::pin!(monitor);
+                    tokio::select! {
+                        result = &mut monitor => return result,
+                        _ = stop.cancelled() => monitor_token.cancel(),
+                    }
+                    monitor.await
+                });
+                self.realtime = Some((token, task));
+                self.state.lock().realtime = true;
+                Reply::Done
+            }
+            Command::StartRealtime => {
+                // Someone else's stream is running; it is not ours to stop.
+                self.state.realtime_stop().take();
+                Reply::Done
+            }
+            Command::StopRealtime => {
+                if let Some((token, _)) = &self.realtime {
+                    token.cancel();
+                }
+                Reply::Done
+            }
+        };
+        if !command.is_read() {
+            on_event(PollEvent::Done { command });
+        }
+        Ok(reply)
+    }
+
+    /// Syncs every metric when there is a store, reporting failures per
+    /// metric so one bad download does not hold back the others, then
+    /// reads last night's sleep.
+    async fn sync(
+        &mut self,
+        conn: &Connection,
+        on_event: &mut impl FnMut(PollEvent),
+    ) -> Result<(), DeviceError> {
+        if let Some(store) = self.store.as_mut() {
+            let device = self.device.id();
+            let mut records = 0;
+            for metric in SyncMetric::ALL {
+                match sync_metric(conn, device, store, metric).await {
+                    Ok(synced) => records += synced.records,
+                    Err(err) => {
+                        if let SyncError::Device(err) = &err {
+                            self.state.lock().record_error(err);
+                        }
+                        on_event(PollEvent::Failed {
+                            task: metric.label(),
+                            error: &err,
+                        });
+                    }
+                }
+            }
+            if let Ok(last_sync) = store.last_synced_at(device) {
+                self.state.lock().last_sync = last_sync;
+            }
+            on_event(PollEvent::Synced { records });
+        }
+
+        let sleep = DeviceManager::get_sleep(conn).await?;
+        self.state.lock().last_sleep = sleep.sessions(&Utc).pop();
+        Ok(())
+    }
+}
+
+async fn poll_status(conn: &Connection, state: &SharedState) -> Result<(), DeviceError> {
+    let battery = DeviceManager::get_battery_level(conn).await?;
+    state.lock().battery = Some(Battery {
+        charge_pct: battery.charge_pct,
+        is_charging: battery.is_charging,
+    });
+    let today = DeviceManager::get_today_totals(conn).await?;
+    state.lock().today = Some(today);
+    Ok(())
+}
+
+/// Samples every metric the ring supports on a task of its own, so commands
+/// and status polls keep running during the warm-up.
+fn spawn_sample(conn: &Connection, state: &SharedState, cancel: &CancellationToken) -> SampleTask {
+    let token = cancel.child_token();
+    let (conn, state, sample_token) = (conn.clone(), state.clone(), token.clone());
+    let task = tokio::spawn(async move {
+        let reading_types = realtime_types();
+        let sample =
+            DeviceManager::sample_realtime(&conn, &reading_types, |_| {}, sample_token).await?;
+
+        let sampled_at = Utc::now();
+        let mut state = state.lock();
+        for reading_type in reading_types {
+            if let Some(value) = sample.get(reading_type) {
+                state.set_reading(RealtimeReading {
+                    reading_type,
+                    value,
+                    timestamp: sampled_at,
+                });
+            }
+        }
+        Ok(())
+    });
+    (token, task)
+}
+
+fn realtime_types() -> Vec<ReadingType> {
+    load_device_features()
+        .map(|features| features.realtime_types())
+        .unwrap_or_else(|| vec![ReadingType::HeartRateBatch])
+}
+
+async fn finished<T>(task: &mut Option<Task<T>>) -> Result<Result<T, DeviceError>, JoinError> {
+    match task {
+        Some((_, task)) => task.await,
+        None => pending().await,
+    }
+}
+
+/// Cancels `task` and waits for it to wind down.
+async fn stop<T>(task: Option<Task<T>>) {
+    if let Some((token, task)) = task {
+        token.cancel();
+        let _ = task.await;
+    }
+}
+
+fn ticker(period: Option<Duration>) -> Option<Interval> {
+    period.filter(|p| !p.is_zero()).map(|period| {
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker
+    })
+}
+
+async fn tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => pending().await,
+    }
+}
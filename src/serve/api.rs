@@ -0,0 +1,563 @@
+//! Local HTTP JSON API for the served rings, so scripts and dashboards
+//! share one connection instead of each scanning and handshaking. Reads and
+//! commands go through each ring's poller; history comes from the local
+//! store.
+//!
+//! Every ring is served under `/rings/{node}`, `node` being its address in
+//! lowercase hex digits, and listed by `GET /rings`. A single ring is also
+//! served at the root.
+//!
+//! Errors are `{"error": {"kind", "message"}}` with `kind` as in the CLI's
+//! structured output.
+
+use std::convert::Infallible;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::ValueEnum;
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+use crate::calendar::Calendar;
+use crate::config::manager::load_device_features;
+use crate::devices::models::node_id;
+use crate::error::{Failure, StoreError};
+use crate::export::ExportMetric;
+use crate::model::{Measurement, Metric, Series, Source};
+use crate::serve::{Command, Reply, Request, SharedState};
+use crate::store::database::Store;
+
+/// Longest a request waits for the ring, e.g. while the poller reconnects.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(60);
+/// History range when `from` is not given, in days including `to`.
+const DEFAULT_DAYS: i64 = 7;
+
+#[derive(Clone)]
+struct Api {
+    state: SharedState,
+    commands: mpsc::Sender<Request>,
+    calendar: Calendar,
+    streams: Arc<Mutex<Streams>>,
+    /// Ends open streams so the server can shut down.
+    cancel: CancellationToken,
+}
+
+/// Open `/realtime` streams, and the stop signal of the realtime session
+/// they started, so the last one to close stops it again.
+#[derive(Default)]
+struct Streams {
+    open: usize,
+    stop: Option<CancellationToken>,
+}
+
+/// One served ring: its address, state and the poller's command queue.
+pub struct Ring {
+    pub address: String,
+    pub state: SharedState,
+    pub commands: mpsc::Sender<Request>,
+}
+
+pub fn router(rings: Vec<Ring>, cancel: CancellationToken) -> Router {
+    let calendar = Calendar::load();
+    let single = rings.len() == 1;
+    let listed: Vec<(String, SharedState)> = rings
+        .iter()
+        .map(|ring| (ring.address.clone(), ring.state.clone()))
+        .collect();
+    let mut router = Router::new()
+        .route("/rings", get(list_rings))
+        .with_state(Arc::new(listed));
+    for ring in rings {
+        let routes = ring_router(Api {
+            state: ring.state,
+            commands: ring.commands,
+            calendar,
+            streams: Arc::default(),
+            cancel: cancel.clone(),
+        });
+        router = router.nest(
+            &format!("/rings/{}", node_id(&ring.address)),
+            routes.clone(),
+        );
+        if single {
+            router = router.merge(routes);
+        }
+    }
+    router
+}
+
+fn ring_router(api: Api) -> Router {
+    Router::new()
+        .route("/status", get(status))
+        .route("/battery", get(battery))
+        .route("/info", get(info))
+        .route("/capabilities", get(capabilities))
+        .route("/settings", get(settings).put(update_settings))
+        .route("/commands/{command}", post(command))
+        .route("/history/{metric}", get(history))
+        .route("/realtime", get(realtime))
+        .with_state(api)
+}
+
+struct ApiError {
+    status: StatusCode,
+    kind: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, kind: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            kind,
+            message: message.into(),
+        }
+    }
+
+    fn unavailable() -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "unavailable",
+            "The ring is no longer being served",
+        )
+    }
+}
+
+impl From<StoreError> for ApiError {
+    fn from(err: StoreError) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            err.kind(),
+            err.to_string(),
+        )
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = json!({ "error": { "kind": self.kind, "message": self.message } });
+        (self.status, Json(body)).into_response()
+    }
+}
+
+impl Api {
+    /// Runs `command` on the poller and waits for its reply.
+    async fn request(&self, command: Command) -> Result<Reply, ApiError> {
+        let (request, reply) = Request::with_reply(command);
+        self.commands
+            .send(request)
+            .await
+            .map_err(|_| ApiError::unavailable())?;
+        match timeout(REPLY_TIMEOUT, reply).await {
+            Ok(Ok(Ok(reply))) => Ok(reply),
+            Ok(Ok(Err(failure))) => Err(ApiError::new(
+                StatusCode::BAD_GATEWAY,
+                failure.kind,
+                failure.message,
+            )),
+            Ok(Err(_)) => Err(ApiError::unavailable()),
+            Err(_) => Err(ApiError::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                "timeout",
+                format!("{} got no answer from the ring", command.label()),
+            )),
+        }
+    }
+
+    fn device(&self) -> String {
+        self.state.lock().device.clone()
+    }
+}
+
+/// Every served ring with where its API is.
+async fn list_rings(State(rings): State<Arc<Vec<(String, SharedState)>>>) -> Json<Value> {
+    Json(json!(
+        rings
+            .iter()
+            .map(|(address, state)| {
+                let state = state.lock();
+                json!({
+                    "device": address,
+                    "name": state.name,
+                    "connected": state.connected,
+                    "path": format!("/rings/{}", node_id(address)),
+                })
+            })
+            .collect::<Vec<_>>()
+    ))
+}
+
+/// The poller's latest view of the ring, without talking to it.
+async fn status(State(api): State<Api>) -> Json<Value> {
+    let state = api.state.lock();
+    let readings: Vec<Measurement> = [&state.heart_rate, &state.spo2, &state.hrv]
+        .into_iter()
+        .flatten()
+        .map(|reading| Measurement::reading(&state.device, reading))
+        .collect();
+    let last_sleep = state.last_sleep.as_ref().map(|sleep| {
+        json!({
+            "start": sleep.start,
+            "end": sleep.end,
+            "total_minutes": sleep.total_minutes(),
+        })
+    });
+    Json(json!({
+        "device": state.device,
+        "name": state.name,
+        "connected": state.connected,
+        "realtime": state.realtime,
+        "battery": state.battery,
+        "readings": readings,
+        "today": state.today.as_ref().map(|today| json!({
+            "steps": today.steps,
+            "calories": today.calories,
+            "distance": today.distance,
+        })),
+        "last_sleep": last_sleep,
+        "last_sync": state.last_sync,
+        "protocol_errors": state.protocol_errors,
+    }))
+}
+
+async fn battery(State(api): State<Api>) -> Result<Json<Value>, ApiError> {
+    match api.request(Command::ReadBattery).await? {
+        Reply::Battery(battery) => Ok(Json(json!(battery))),
+        _ => Err(ApiError::unavailable()),
+    }
+}
+
+async fn info(State(api): State<Api>) -> Result<Json<Value>, ApiError> {
+    match api.request(Command::ReadInfo).await? {
+        Reply::Info {
+            firmware,
+            hardware,
+            manufacturer,
+        } => Ok(Json(json!({
+            "device": api.device(),
+            "manufacturer": manufacturer,
+            "firmware": firmware,
+            "hardware": hardware,
+        }))),
+        _ => Err(ApiError::unavailable()),
+    }
+}
+
+/// The feature flags the ring reported at the handshake.
+async fn capabilities() -> Result<Json<Value>, ApiError> {
+    load_device_features()
+        .map(|features| Json(json!(features)))
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "No features saved for the ring",
+            )
+        })
+}
+
+async fn settings(State(api): State<Api>) -> Result<Json<Value>, ApiError> {
+    settings_reply(api.request(Command::ReadSettings).await?)
+}
+
+/// Same shape as the settings that `GET /settings` returns.
+#[derive(Deserialize)]
+struct SettingsUpdate {
+    heart_rate_log: HeartRateLogUpdate,
+}
+
+/// Fields left out keep their current value.
+#[derive(Deserialize)]
+struct HeartRateLogUpdate {
+    enabled: Option<bool>,
+    /// Logging interval in minutes.
+    interval: Option<u8>,
+}
+
+async fn update_settings(
+    State(api): State<Api>,
+    Json(update): Json<SettingsUpdate>,
+) -> Result<Json<Value>, ApiError> {
+    let HeartRateLogUpdate { enabled, interval } = update.heart_rate_log;
+    if interval == Some(0) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_argument",
+            "interval must be at least 1 minute",
+        ));
+    }
+    let (enabled, interval) = match (enabled, interval) {
+        (Some(enabled), Some(interval)) => (enabled, interval),
+        (enabled, interval) => match api.request(Command::ReadSettings).await? {
+            Reply::Settings(current) => (
+                enabled.unwrap_or(current.enabled),
+                interval.unwrap_or(current.interval),
+            ),
+            _ => return Err(ApiError::unavailable()),
+        },
+    };
+    settings_reply(
+        api.request(Command::WriteSettings { enabled, interval })
+            .await?,
+    )
+}
+
+fn settings_reply(reply: Reply) -> Result<Json<Value>, ApiError> {
+    match reply {
+        Reply::Settings(settings) => Ok(Json(json!({
+            "heart_rate_log": {
+                "enabled": settings.enabled,
+                "interval": settings.interval,
+            }
+        }))),
+        _ => Err(ApiError::unavailable()),
+    }
+}
+
+/// `blink`, `find`, `reboot`, `sync`, `realtime_start` or `realtime_stop`,
+/// answered once the ring has done it.
+async fn command(
+    State(api): State<Api>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let command = match name.as_str() {
+        "blink" => Command::Blink,
+        "find" => Command::Find,
+        "reboot" => Command::Reboot,
+        "sync" => Command::Sync,
+        "realtime_start" => Command::StartRealtime,
+        "realtime_stop" => Command::StopRealtime,
+        _ => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                format!("Unknown command '{name}'"),
+            ));
+        }
+    };
+    api.request(command).await?;
+    Ok(Json(json!({ "command": name, "done": true })))
+}
+
+/// Local days `from` through `to`, optionally for one `device` of the store.
+#[derive(Deserialize)]
+struct HistoryQuery {
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    device: Option<String>,
+}
+
+/// Stored history of `metric` (`hr`, `activity`, `sleep`, `spo2` or
+/// `realtime`) as schema measurements, oldest first.
+async fn history(
+    State(api): State<Api>,
+    Path(metric): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Vec<Measurement>>, ApiError> {
+    let metric = ExportMetric::from_str(&metric, true).map_err(|_| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("Unknown metric '{metric}'. Use hr, activity, sleep, spo2 or realtime."),
+        )
+    })?;
+    let to = query.to.unwrap_or_else(|| api.calendar.today());
+    let from = query
+        .from
+        .unwrap_or(to - chrono::Duration::days(DEFAULT_DAYS - 1));
+    if from > to {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_argument",
+            format!("from ({from}) is after to ({to})"),
+        ));
+    }
+    let start = api.calendar.day_start(from);
+    let (_, end) = api.calendar.day_range(to);
+
+    let measurements = tokio::task::spawn_blocking(move || {
+        let store = Store::open_default()?;
+        stored_measurements(&store, metric, start, end)
+    })
+    .await
+    .map_err(|err| {
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            err.to_string(),
+        )
+    })??;
+
+    Ok(Json(match query.device {
+        Some(device) => measurements
+            .into_iter()
+            .filter(|measurement| measurement.device == device)
+            .collect(),
+        None => measurements,
+    }))
+}
+
+fn stored_measurements(
+    store: &Store,
+    metric: ExportMetric,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Vec<Measurement>, StoreError> {
+    Ok(match metric {
+        ExportMetric::HeartRate => store
+            .heart_rate_samples(start, end)?
+            .iter()
+            .map(|sample| {
+                measurement(
+                    &sample.device,
+                    Source::HeartRateLog,
+                    Metric::HeartRate,
+                    sample.timestamp,
+                    sample.bpm as f64,
+                )
+            })
+            .collect(),
+        ExportMetric::Activity => store
+            .activity_slots(start, end)?
+            .iter()
+            .flat_map(|slot| {
+                [
+                    (Metric::Steps, slot.steps as f64),
+                    (Metric::Calories, slot.calories),
+                    (Metric::Distance, slot.distance as f64),
+                ]
+                .map(|(metric, value)| Measurement {
+                    end: Some(slot.start + chrono::Duration::minutes(15)),
+                    ..measurement(&slot.device, Source::ActivityLog, metric, slot.start, value)
+                })
+            })
+            .collect(),
+        ExportMetric::Sleep => store
+            .sleep_sessions(start, end)?
+            .iter()
+            .flat_map(|sleep| {
+                Series::sleep(&sleep.device, slice::from_ref(&sleep.session)).measurements()
+            })
+            .collect(),
+        ExportMetric::Oxygen => store
+            .oxygen_hours(start, end)?
+            .iter()
+            .map(|hour| Measurement {
+                end: Some(hour.start + chrono::Duration::hours(1)),
+                min: Some(hour.min as f64),
+                max: Some(hour.max as f64),
+                ..measurement(
+                    &hour.device,
+                    Source::OxygenLog,
+                    Metric::BloodOxygen,
+                    hour.start,
+                    (hour.min as f64 + hour.max as f64) / 2.0,
+                )
+            })
+            .collect(),
+        ExportMetric::Realtime => store
+            .realtime_readings(start, end)?
+            .iter()
+            .map(|stored| Measurement::reading(&stored.device, &stored.reading))
+            .collect(),
+    })
+}
+
+fn measurement(
+    device: &str,
+    source: Source,
+    metric: Metric,
+    at: DateTime<Utc>,
+    value: f64,
+) -> Measurement {
+    Measurement {
+        device: device.to_string(),
+        source,
+        metric,
+        unit: metric.unit(),
+        timestamp: at,
+        end: None,
+        value,
+        min: None,
+        max: None,
+        label: None,
+    }
+}
+
+/// Server-sent `reading` events, each a schema measurement. The first
+/// stream starts a realtime session unless one is running; the last one to
+/// close stops it again.
+async fn realtime(
+    State(api): State<Api>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let readings = api.state.subscribe_readings();
+    let start = {
+        let mut streams = api.streams.lock().unwrap_or_else(|p| p.into_inner());
+        streams.open += 1;
+        if streams.open == 1 && !api.state.lock().realtime {
+            let stop = CancellationToken::new();
+            streams.stop = Some(stop.clone());
+            Some(stop)
+        } else {
+            None
+        }
+    };
+    let guard = StreamGuard { api: api.clone() };
+    if let Some(stop) = start {
+        api.state.stop_next_realtime_with(stop);
+        api.request(Command::StartRealtime).await?;
+    }
+
+    let device = api.device();
+    let cancel = api.cancel.clone();
+    let events = futures_util::stream::unfold((readings, guard), move |(mut readings, guard)| {
+        let device = device.clone();
+        async move {
+            loop {
+                match readings.recv().await {
+                    Ok(reading) => {
+                        let measurement = Measurement::reading(&device, &reading);
+                        let event = Event::default()
+                            .event("reading")
+                            .data(json!(measurement).to_string());
+                        return Some((Ok(event), (readings, guard)));
+                    }
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    })
+    .take_until(cancel.cancelled_owned());
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Counts an open `/realtime` stream until it is dropped.
+struct StreamGuard {
+    api: Api,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let mut streams = self.api.streams.lock().unwrap_or_else(|p| p.into_inner());
+        streams.open -= 1;
+        if streams.open == 0
+            && let Some(stop) = streams.stop.take()
+        {
+            stop.cancel();
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Prometheus text exposition of the served ring's [`RingState`].
+
+use std::fmt::Write;
+
+use axum::Router;
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+
+use crate::error::ProtocolError;
+use crate::protocol::realtime::RealtimeReading;
+use crate::serve::{RingState, SharedState};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+pub fn router(state: SharedState) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(state)
+}
+
+async fn metrics(State(state): State<SharedState>) -> impl IntoResponse {
+    let body = render(&state.lock());
+    ([(header::CONTENT_TYPE, CONTENT_TYPE)], body)
+}
+
+/// Values that are not known yet are left out rather than reported as 0.
+fn render(state: &RingState) -> String {
+    let mut exposition = Exposition {
+        text: String::new(),
+        device: escape(&state.device),
+    };
+
+    exposition.gauge(
+        "colmi_connected",
+        "Whether the ring is connected (1) or not (0).",
+        Some(bool_value(state.connected)),
+    );
+    exposition.gauge(
+        "colmi_battery_percent",
+        "Battery charge in percent.",
+        state.battery.map(|battery| battery.charge_pct as f64),
+    );
+    exposition.gauge(
+        "colmi_battery_charging",
+        "Whether the ring is charging (1) or not (0).",
+        state.battery.map(|battery| bool_value(battery.is_charging)),
+    );
+    exposition.gauge(
+        "colmi_heart_rate_bpm",
+        "Latest measured heart rate in beats per minute.",
+        reading_value(&state.heart_rate),
+    );
+    exposition.gauge(
+        "colmi_spo2_percent",
+        "Latest measured blood oxygen saturation in percent.",
+        reading_value(&state.spo2),
+    );
+    exposition.gauge(
+        "colmi_hrv_ms",
+        "Latest measured heart rate variability in milliseconds.",
+        reading_value(&state.hrv),
+    );
+    exposition.gauge(
+        "colmi_steps_today",
+        "Steps counted by the ring today.",
+        state.today.as_ref().map(|today| today.steps as f64),
+    );
+    exposition.gauge(
+        "colmi_calories_today_kcal",
+        "Calories burned today in kcal.",
+        state.today.as_ref().map(|today| today.calories),
+    );
+    exposition.gauge(
+        "colmi_last_sync_timestamp_seconds",
+        "Unix time of the last history sync into the local store.",
+        state.last_sync.map(|at| at.timestamp() as f64),
+    );
+
+    exposition.header(
+        "colmi_protocol_errors_total",
+        "Protocol errors by kind since the daemon started.",
+        "counter",
+    );
+    // Every variant from the start, so rate() sees the first error.
+    for variant in ProtocolError::VARIANTS {
+        let count = state.protocol_errors.get(variant).copied().unwrap_or(0);
+        let _ = writeln!(
+            exposition.text,
+            "colmi_protocol_errors_total{{device=\"{}\",variant=\"{variant}\"}} {count}",
+            exposition.device
+        );
+    }
+
+    exposition.text
+}
+
+struct Exposition {
+    text: String,
+    device: String,
+}
+
+impl Exposition {
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.text, "# HELP {name} {help}");
+        let _ = writeln!(self.text, "# TYPE {name} {kind}");
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: Option<f64>) {
+        self.header(name, help, "gauge");
+        if let Some(value) = value {
+            let _ = writeln!(self.text, "{name}{{device=\"{}\"}} {value}", self.device);
+        }
+    }
+}
+
+fn reading_value(reading: &Option<RealtimeReading>) -> Option<f64> {
+    reading.as_ref().map(|reading| reading.value as f64)
+}
+
+fn bool_value(value: bool) -> f64 {
+    if value { 1.0 } else { 0.0 }
+}
+
+/// Label value escaping.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,393 @@
+//! MQTT publishing of the served ring's [`RingState`], with Home Assistant
+//! discovery so its sensors, buttons and realtime switch appear on their
+//! own. Topics live under `<prefix>/<node>`, `<node>` being the ring's
+//! address without separators, so several rings can share a broker.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, ConnectionError, Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde_json::{Value, json};
+use tokio::sync::mpsc;
+use tokio::time::{interval, timeout};
+use tokio_util::sync::CancellationToken;
+
+use crate::devices::models::node_id;
+use crate::protocol::realtime::RealtimeReading;
+use crate::serve::{Command, Request, RingState, SharedState};
+
+/// How often changed values are published.
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+/// Pause before the client retries a lost broker connection.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct Broker {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Root of the ring's own topics.
+    pub prefix: String,
+    /// Root Home Assistant watches for discovery messages.
+    pub discovery_prefix: String,
+}
+
+pub enum BridgeEvent<'a> {
+    Connected,
+    ConnectionLost(&'a ConnectionError),
+    Command(Command),
+    Ignored { topic: &'a str, payload: &'a str },
+}
+
+/// Connection to the broker for one ring.
+pub struct Bridge {
+    client: AsyncClient,
+    events: mpsc::Receiver<Result<Event, ConnectionError>>,
+    broker: Broker,
+    address: String,
+    name: String,
+    node: String,
+    /// Last payload sent per state topic, so only changes go out.
+    published: HashMap<String, String>,
+}
+
+impl Bridge {
+    /// Starts connecting in the background; the broker is announced to on
+    /// every (re)connect.
+    pub fn connect(broker: Broker, address: &str, name: &str) -> Self {
+        let node = node_id(address);
+
+        let mut options = MqttOptions::new(format!("colmi-{node}"), &broker.host, broker.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(
+            format!("{}/{node}/availability", broker.prefix),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some(username) = &broker.username {
+            options.set_credentials(username, broker.password.clone().unwrap_or_default());
+        }
+
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+        let (events_tx, events) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let event = eventloop.poll().await;
+                let failed = event.is_err();
+                if events_tx.send(event).await.is_err() {
+                    break;
+                }
+                if failed {
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        });
+
+        Self {
+            client,
+            events,
+            broker,
+            address: address.to_string(),
+            name: name.to_string(),
+            node,
+            published: HashMap::new(),
+        }
+    }
+
+    /// Publishes `state` as it changes and forwards commands to `commands`
+    /// until `cancel` fires, then marks the ring offline.
+    pub async fn run(
+        mut self,
+        state: SharedState,
+        commands: mpsc::Sender<Request>,
+        mut on_event: impl FnMut(BridgeEvent),
+        cancel: CancellationToken,
+    ) {
+        let mut publish = interval(PUBLISH_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                Some(event) = self.events.recv() => match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        self.published.clear();
+                        self.announce().await;
+                        on_event(BridgeEvent::Connected);
+                    }
+                    Ok(Event::Incoming(Packet::Publish(message))) => {
+                        let payload = String::from_utf8_lossy(&message.payload);
+                        match self.command(&message.topic, &payload) {
+                            Some(command) => {
+                                on_event(BridgeEvent::Command(command));
+                                let _ = commands.send(Request::from(command)).await;
+                            }
+                            None => on_event(BridgeEvent::Ignored {
+                                topic: &message.topic,
+                                payload: &payload,
+                            }),
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => on_event(BridgeEvent::ConnectionLost(&err)),
+                },
+                _ = publish.tick() => {
+                    let states = states(&state.lock());
+                    self.publish_changes(states);
+                }
+            }
+        }
+
+        let _ = self
+            .client
+            .publish(
+                self.topic("availability"),
+                QoS::AtLeastOnce,
+                true,
+                "offline",
+            )
+            .await;
+        let _ = self.client.disconnect().await;
+        // Let the event loop flush both before it is dropped.
+        let _ = timeout(Duration::from_secs(2), async {
+            while let Some(Ok(event)) = self.events.recv().await {
+                if matches!(event, Event::Outgoing(Outgoing::Disconnect)) {
+                    break;
+                }
+            }
+        })
+        .await;
+    }
+
+    fn topic(&self, object: &str) -> String {
+        format!("{}/{}/{object}", self.broker.prefix, self.node)
+    }
+
+    /// Discovery configs, availability and command subscriptions. Failures
+    /// only mean the client is disconnected again and will redo this.
+    async fn announce(&self) {
+        for (component, object, config) in self.entities() {
+            let topic = format!(
+                "{}/{component}/{}/{object}/config",
+                self.broker.discovery_prefix, self.node
+            );
+            let _ = self
+                .client
+                .publish(topic, QoS::AtLeastOnce, true, config.to_string())
+                .await;
+        }
+        let _ = self
+            .client
+            .publish(self.topic("availability"), QoS::AtLeastOnce, true, "online")
+            .await;
+        for topic in [self.topic("command"), self.topic("realtime/set")] {
+            let _ = self.client.subscribe(topic, QoS::AtLeastOnce).await;
+        }
+    }
+
+    fn publish_changes(&mut self, states: Vec<(&'static str, String)>) {
+        for (object, payload) in states {
+            let topic = self.topic(object);
+            if self.published.get(&topic) == Some(&payload) {
+                continue;
+            }
+            // Never waits on a full request queue, which only drains while
+            // the event loop is connected; unsent values are retried.
+            if self
+                .client
+                .try_publish(topic.clone(), QoS::AtLeastOnce, true, payload.clone())
+                .is_ok()
+            {
+                self.published.insert(topic, payload);
+            }
+        }
+    }
+
+    /// `command` takes `blink`, `find`, `sync`, `realtime_start` and
+    /// `realtime_stop`; `realtime/set` takes the switch's `ON` and `OFF`.
+    fn command(&self, topic: &str, payload: &str) -> Option<Command> {
+        let payload = payload.trim().to_ascii_lowercase();
+        if topic == self.topic("command") {
+            match payload.as_str() {
+                "blink" => Some(Command::Blink),
+                "find" => Some(Command::Find),
+                "sync" => Some(Command::Sync),
+                "realtime_start" => Some(Command::StartRealtime),
+                "realtime_stop" => Some(Command::StopRealtime),
+                _ => None,
+            }
+        } else if topic == self.topic("realtime/set") {
+            match payload.as_str() {
+                "on" => Some(Command::StartRealtime),
+                "off" => Some(Command::StopRealtime),
+                _ => None,
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Home Assistant entities as `(component, object id, config)`.
+    fn entities(&self) -> Vec<(&'static str, &'static str, Value)> {
+        let sensor = |unit: &str, device_class: Option<&str>, state_class: &str| {
+            let mut config = json!({
+                "unit_of_measurement": unit,
+                "state_class": state_class,
+            });
+            if let Some(device_class) = device_class {
+                config["device_class"] = json!(device_class);
+            }
+            config
+        };
+        let button = |payload: &str| {
+            json!({
+                "command_topic": self.topic("command"),
+                "payload_press": payload,
+            })
+        };
+        let diagnostic = |mut config: Value| {
+            config["entity_category"] = json!("diagnostic");
+            config
+        };
+
+        let entities = vec![
+            (
+                "sensor",
+                "battery",
+                "Battery",
+                diagnostic(sensor("%", Some("battery"), "measurement")),
+            ),
+            (
+                "binary_sensor",
+                "charging",
+                "Charging",
+                diagnostic(json!({ "device_class": "battery_charging" })),
+            ),
+            (
+                "binary_sensor",
+                "connected",
+                "Connected",
+                diagnostic(json!({ "device_class": "connectivity" })),
+            ),
+            (
+                "sensor",
+                "heart_rate",
+                "Heart rate",
+                sensor("bpm", None, "measurement"),
+            ),
+            (
+                "sensor",
+                "spo2",
+                "Blood oxygen",
+                sensor("%", None, "measurement"),
+            ),
+            ("sensor", "hrv", "HRV", sensor("ms", None, "measurement")),
+            (
+                "sensor",
+                "steps",
+                "Steps today",
+                sensor("steps", None, "total_increasing"),
+            ),
+            (
+                "sensor",
+                "calories",
+                "Calories today",
+                sensor("kcal", None, "total_increasing"),
+            ),
+            (
+                "sensor",
+                "sleep",
+                "Last sleep",
+                json!({
+                    "unit_of_measurement": "min",
+                    "device_class": "duration",
+                    "value_template": "{{ value_json.total_minutes }}",
+                    "json_attributes_topic": self.topic("sleep"),
+                }),
+            ),
+            (
+                "sensor",
+                "last_sync",
+                "Last sync",
+                diagnostic(json!({ "device_class": "timestamp" })),
+            ),
+            ("button", "blink", "Blink", button("blink")),
+            ("button", "find", "Find", button("find")),
+            ("button", "sync", "Sync", button("sync")),
+            (
+                "switch",
+                "realtime",
+                "Realtime",
+                json!({ "command_topic": self.topic("realtime/set") }),
+            ),
+        ];
+
+        entities
+            .into_iter()
+            .map(|(component, object, name, mut config)| {
+                config["name"] = json!(name);
+                config["unique_id"] = json!(format!("colmi_{}_{object}", self.node));
+                config["availability_topic"] = json!(self.topic("availability"));
+                config["device"] = json!({
+                    "identifiers": [format!("colmi_{}", self.node)],
+                    "connections": [["bluetooth", self.address]],
+                    "name": self.name,
+                    "manufacturer": "Colmi",
+                });
+                if component != "button" {
+                    config["state_topic"] = json!(self.topic(object));
+                }
+                (component, object, config)
+            })
+            .collect()
+    }
+}
+
+/// State topic payloads by object id. Unknown values are not published, so
+/// Home Assistant keeps showing the last one.
+fn states(state: &RingState) -> Vec<(&'static str, String)> {
+    let mut states = vec![
+        ("connected", on_off(state.connected)),
+        ("realtime", on_off(state.realtime)),
+    ];
+    if let Some(battery) = state.battery {
+        states.push(("battery", battery.charge_pct.to_string()));
+        states.push(("charging", on_off(battery.is_charging)));
+    }
+    for (object, reading) in [
+        ("heart_rate", &state.heart_rate),
+        ("spo2", &state.spo2),
+        ("hrv", &state.hrv),
+    ] {
+        if let Some(RealtimeReading { value, .. }) = reading {
+            states.push((object, value.to_string()));
+        }
+    }
+    if let Some(today) = &state.today {
+        states.push(("steps", today.steps.to_string()));
+        states.push(("calories", format!("{:.1}", today.calories)));
+    }
+    if let Some(sleep) = &state.last_sleep {
+        let stages: serde_json::Map<String, Value> = sleep
+            .stage_breakdown()
+            .into_iter()
+            .map(|(label, minutes)| (label.to_string(), json!(minutes)))
+            .collect();
+        let summary = json!({
+            "total_minutes": sleep.total_minutes(),
+            "start": sleep.start.to_rfc3339(),
+            "end": sleep.end.to_rfc3339(),
+            "stages": stages,
+        });
+        states.push(("sleep", summary.to_string()));
+    }
+    if let Some(last_sync) = state.last_sync {
+        states.push(("last_sync", last_sync.to_rfc3339()));
+    }
+    states
+}
+
+fn on_off(value: bool) -> String {
+    if value { "ON" } else { "OFF" }.to_string()
+}
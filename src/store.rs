@@ -0,0 +1,3 @@
+pub mod database;
+pub mod gadgetbridge;
+pub mod sync;
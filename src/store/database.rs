@@ -0,0 +1,1203 @@
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, NaiveDate, Timelike, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::devices::models::address_key;
+use crate::error::StoreError;
+use crate::protocol::{
+    battery::BatteryResponse,
+    bigdata::{OxygenDay, SleepSession, SleepStage},
+    hr::HeartRateLog,
+    realtime::{ReadingType, RealtimeReading},
+    steps::ActivityDetail,
+    workout::{SportType, WorkoutSummary},
+};
+
+/// Database file, kept next to `config.toml`.
+pub const DATABASE_PATH: &str = "colmi.db";
+
+/// Version 2 keys rows by Bluetooth address instead of the platform's
+/// peripheral id.
+const SCHEMA_VERSION: i32 = 2;
+
+/// Every table, all of which have a `device` column.
+const TABLES: [&str; 12] = [
+    "devices",
+    "heart_rate_samples",
+    "activity_slots",
+    "sleep_sessions",
+    "sleep_stages",
+    "spo2_hourly",
+    "realtime_sessions",
+    "realtime_readings",
+    "battery_readings",
+    "workouts",
+    "workout_heart_rates",
+    "sync_checkpoints",
+];
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS devices (
+    device TEXT NOT NULL PRIMARY KEY,
+    name TEXT NOT NULL,
+    manufacturer TEXT,
+    firmware TEXT,
+    hardware TEXT
+);
+CREATE TABLE IF NOT EXISTS heart_rate_samples (
+    device TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    bpm INTEGER NOT NULL,
+    PRIMARY KEY (device, timestamp)
+);
+CREATE TABLE IF NOT EXISTS activity_slots (
+    device TEXT NOT NULL,
+    date TEXT NOT NULL,
+    slot INTEGER NOT NULL,
+    steps INTEGER NOT NULL,
+    calories REAL NOT NULL,
+    distance INTEGER NOT NULL,
+    PRIMARY KEY (device, date, slot)
+);
+CREATE TABLE IF NOT EXISTS sleep_sessions (
+    device TEXT NOT NULL,
+    start INTEGER NOT NULL,
+    end INTEGER NOT NULL,
+    date TEXT NOT NULL,
+    PRIMARY KEY (device, start)
+);
+CREATE TABLE IF NOT EXISTS sleep_stages (
+    device TEXT NOT NULL,
+    session_start INTEGER NOT NULL,
+    start INTEGER NOT NULL,
+    minutes INTEGER NOT NULL,
+    stage INTEGER NOT NULL,
+    PRIMARY KEY (device, start)
+);
+CREATE TABLE IF NOT EXISTS spo2_hourly (
+    device TEXT NOT NULL,
+    date TEXT NOT NULL,
+    hour INTEGER NOT NULL,
+    min INTEGER NOT NULL,
+    max INTEGER NOT NULL,
+    PRIMARY KEY (device, date, hour)
+);
+CREATE TABLE IF NOT EXISTS realtime_sessions (
+    device TEXT NOT NULL,
+    started_at INTEGER NOT NULL,
+    ended_at INTEGER NOT NULL,
+    reading_types TEXT NOT NULL,
+    PRIMARY KEY (device, started_at)
+);
+CREATE TABLE IF NOT EXISTS realtime_readings (
+    device TEXT NOT NULL,
+    session_start INTEGER NOT NULL,
+    timestamp_ms INTEGER NOT NULL,
+    reading_type INTEGER NOT NULL,
+    value INTEGER NOT NULL,
+    PRIMARY KEY (device, timestamp_ms, reading_type)
+);
+CREATE TABLE IF NOT EXISTS battery_readings (
+    device TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    charge_pct INTEGER NOT NULL,
+    is_charging INTEGER NOT NULL,
+    PRIMARY KEY (device, timestamp)
+);
+CREATE TABLE IF NOT EXISTS workouts (
+    device TEXT NOT NULL,
+    started_at INTEGER NOT NULL,
+    ended_at INTEGER NOT NULL,
+    sport TEXT NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    steps INTEGER NOT NULL,
+    calories REAL NOT NULL,
+    distance INTEGER NOT NULL,
+    zone_seconds TEXT NOT NULL,
+    PRIMARY KEY (device, started_at)
+);
+CREATE TABLE IF NOT EXISTS workout_heart_rates (
+    device TEXT NOT NULL,
+    workout_start INTEGER NOT NULL,
+    timestamp_ms INTEGER NOT NULL,
+    bpm INTEGER NOT NULL,
+    PRIMARY KEY (device, timestamp_ms)
+);
+CREATE TABLE IF NOT EXISTS sync_checkpoints (
+    device TEXT NOT NULL,
+    metric TEXT NOT NULL,
+    complete_through TEXT NOT NULL,
+    synced_at INTEGER NOT NULL,
+    PRIMARY KEY (device, metric)
+);
+";
+
+/// Moves rows saved under a peripheral id that spells out an address, as
+/// BlueZ's `hci0/dev_AA_BB_…` does, to the address itself. Where both keys
+/// hold the same row, the one already under the address is kept.
+fn rekey_devices(conn: &mut Connection) -> Result<(), StoreError> {
+    let tx = conn.transaction()?;
+    for table in TABLES {
+        let devices: Vec<String> = tx
+            .prepare(&format!("SELECT DISTINCT device FROM {table}"))?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        for device in devices {
+            let address = address_key(&device);
+            if address != device {
+                tx.execute(
+                    &format!("UPDATE OR IGNORE {table} SET device = ?1 WHERE device = ?2"),
+                    params![address, device],
+                )?;
+                tx.execute(
+                    &format!("DELETE FROM {table} WHERE device = ?1"),
+                    params![device],
+                )?;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// What the last sync read about a ring.
+pub struct DeviceInfo {
+    /// Advertised name, e.g. `R02_1A2B`.
+    pub name: String,
+    pub manufacturer: Option<String>,
+    pub firmware: Option<String>,
+    pub hardware: Option<String>,
+}
+
+pub struct StoredHeartRate {
+    pub device: String,
+    pub timestamp: DateTime<Utc>,
+    pub bpm: u8,
+}
+
+/// One 15-minute activity slot.
+pub struct StoredActivity {
+    pub device: String,
+    pub start: DateTime<Utc>,
+    pub steps: u32,
+    pub calories: f64,
+    pub distance: u32,
+}
+
+pub struct StoredSleep {
+    pub device: String,
+    pub session: SleepSession,
+}
+
+/// One hour's SpO2 range.
+pub struct StoredOxygen {
+    pub device: String,
+    pub start: DateTime<Utc>,
+    pub min: u8,
+    pub max: u8,
+}
+
+pub struct StoredReading {
+    pub device: String,
+    /// Start of the realtime session the reading belongs to.
+    pub session_start: DateTime<Utc>,
+    pub reading: RealtimeReading,
+}
+
+/// A realtime session with its readings.
+pub struct StoredSession {
+    pub device: String,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub readings: Vec<RealtimeReading>,
+}
+
+pub struct StoredWorkout {
+    pub device: String,
+    pub summary: WorkoutSummary,
+}
+
+/// Local time-series store of everything read from the ring. Every table is
+/// keyed by device address plus time, so saving the same data twice
+/// overwrites rather than duplicates.
+pub struct Store {
+    conn: Connection,
+}
+
+impl Store {
+    pub fn open_default() -> Result<Self, StoreError> {
+        Self::open(DATABASE_PATH)
+    }
+
+    pub fn open(path: &str) -> Result<Self, StoreError> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// Creates missing tables and migrates older schemas.
+    fn with_connection(mut conn: Connection) -> Result<Self, StoreError> {
+        conn.execute_batch(SCHEMA)?;
+        let version: i32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version < 2 {
+            rekey_devices(&mut conn)?;
+        }
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        Ok(Self { conn })
+    }
+
+    /// Stores the recorded samples of one day's log; empty slots are skipped.
+    pub fn save_heart_rate_log(
+        &mut self,
+        device: &str,
+        log: &HeartRateLog,
+    ) -> Result<usize, StoreError> {
+        let tx = self.conn.transaction()?;
+        let mut saved = 0;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO heart_rate_samples (device, timestamp, bpm) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (device, timestamp) DO UPDATE SET bpm = excluded.bpm",
+            )?;
+            for (timestamp, bpm) in log.readings() {
+                stmt.execute(params![device, timestamp.timestamp(), bpm])?;
+                saved += 1;
+            }
+        }
+        tx.commit()?;
+        Ok(saved)
+    }
+
+    pub fn save_activity(
+        &mut self,
+        device: &str,
+        details: &[ActivityDetail],
+    ) -> Result<usize, StoreError> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO activity_slots (device, date, slot, steps, calories, distance)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT (device, date, slot) DO UPDATE SET
+                     steps = excluded.steps,
+                     calories = excluded.calories,
+                     distance = excluded.distance",
+            )?;
+            for detail in details {
+                let date = format!("{:04}-{:02}-{:02}", detail.year, detail.month, detail.day);
+                stmt.execute(params![
+                    device,
+                    date,
+                    detail.time_index,
+                    detail.steps,
+                    detail.calories,
+                    detail.distance
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(details.len())
+    }
+
+    /// Replaces each session's stages, so a re-synced night never keeps
+    /// stale stages.
+    pub fn save_sleep(
+        &mut self,
+        device: &str,
+        sessions: &[SleepSession],
+    ) -> Result<usize, StoreError> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut session_stmt = tx.prepare(
+                "INSERT INTO sleep_sessions (device, start, end, date) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (device, start) DO UPDATE SET
+                     end = excluded.end,
+                     date = excluded.date",
+            )?;
+            let mut clear_stmt =
+                tx.prepare("DELETE FROM sleep_stages WHERE device = ?1 AND session_start = ?2")?;
+            let mut stage_stmt = tx.prepare(
+                "INSERT OR REPLACE INTO sleep_stages (device, session_start, start, minutes, stage)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+
+            for session in sessions {
+                let start = session.start.timestamp();
+                session_stmt.execute(params![
+                    device,
+                    start,
+                    session.end.timestamp(),
+                    session.end.date_naive().to_string()
+                ])?;
+                clear_stmt.execute(params![device, start])?;
+
+                for stage in &session.stages {
+                    stage_stmt.execute(params![
+                        device,
+                        start,
+                        stage.start.timestamp(),
+                        stage.minutes(),
+                        stage.phase_type
+                    ])?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(sessions.len())
+    }
+
+    /// Stores hourly SpO2 samples relative to `today`, skipping empty hours.
+    pub fn save_oxygen(
+        &mut self,
+        device: &str,
+        today: NaiveDate,
+        days: &[OxygenDay],
+    ) -> Result<usize, StoreError> {
+        let tx = self.conn.transaction()?;
+        let mut saved = 0;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO spo2_hourly (device, date, hour, min, max) VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (device, date, hour) DO UPDATE SET
+                     min = excluded.min,
+                     max = excluded.max",
+            )?;
+            for day in days {
+                let date = (today - Duration::days(day.days_ago as i64)).to_string();
+                for (hour, sample) in (0u8..).zip(&day.samples) {
+                    if sample.min == 0 && sample.max == 0 {
+                        continue;
+                    }
+                    stmt.execute(params![device, date, hour, sample.min, sample.max])?;
+                    saved += 1;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(saved)
+    }
+
+    /// Can be called again with further readings of the same session; its
+    /// end moves forward and its reading types accumulate.
+    pub fn save_realtime_session(
+        &mut self,
+        device: &str,
+        started_at: DateTime<Utc>,
+        ended_at: DateTime<Utc>,
+        readings: &[RealtimeReading],
+    ) -> Result<usize, StoreError> {
+        let tx = self.conn.transaction()?;
+        {
+            let saved: Option<String> = tx
+                .query_row(
+                    "SELECT reading_types FROM realtime_sessions
+                     WHERE device = ?1 AND started_at = ?2",
+                    params![device, started_at.timestamp()],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let mut labels: Vec<String> = saved
+                .iter()
+                .flat_map(|types| types.split(','))
+                .filter(|label| !label.is_empty())
+                .map(str::to_string)
+                .collect();
+            for reading in readings {
+                let label = reading.reading_type.label();
+                if !labels.iter().any(|saved| saved == label) {
+                    labels.push(label.to_string());
+                }
+            }
+
+            tx.execute(
+                "INSERT INTO realtime_sessions (device, started_at, ended_at, reading_types)
+                 VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (device, started_at) DO UPDATE SET
+                     ended_at = excluded.ended_at,
+                     reading_types = excluded.reading_types",
+                params![
+                    device,
+                    started_at.timestamp(),
+                    ended_at.timestamp(),
+                    labels.join(",")
+                ],
+            )?;
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO realtime_readings
+                     (device, session_start, timestamp_ms, reading_type, value)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for reading in readings {
+                stmt.execute(params![
+                    device,
+                    started_at.timestamp(),
+                    reading.timestamp.timestamp_millis(),
+                    reading.reading_type as u8,
+                    reading.value
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(readings.len())
+    }
+
+    pub fn save_workout(
+        &mut self,
+        device: &str,
+        summary: &WorkoutSummary,
+    ) -> Result<(), StoreError> {
+        let started_at = summary.started_at.timestamp();
+        let zone_seconds: Vec<String> = summary
+            .time_in_zones
+            .iter()
+            .map(|time| time.as_secs().to_string())
+            .collect();
+
+        let tx = self.conn.transaction()?;
+        {
+            tx.execute(
+                "INSERT OR REPLACE INTO workouts
+                     (device, started_at, ended_at, sport, duration_ms, steps, calories, distance,
+                      zone_seconds)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    device,
+                    started_at,
+                    summary.ended_at.timestamp(),
+                    summary.sport_type.label(),
+                    summary.duration.as_millis() as i64,
+                    summary.steps,
+                    summary.calories,
+                    summary.distance,
+                    zone_seconds.join(",")
+                ],
+            )?;
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO workout_heart_rates (device, workout_start, timestamp_ms, bpm)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (timestamp, bpm) in &summary.heart_rates {
+                stmt.execute(params![
+                    device,
+                    started_at,
+                    timestamp.timestamp_millis(),
+                    bpm
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Inserts the samples not stored yet, e.g. from an import; returns
+    /// how many were new.
+    pub fn insert_heart_rates(&mut self, samples: &[StoredHeartRate]) -> Result<usize, StoreError> {
+        let tx = self.conn.transaction()?;
+        let mut inserted = 0;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR IGNORE INTO heart_rate_samples (device, timestamp, bpm)
+                 VALUES (?1, ?2, ?3)",
+            )?;
+            for sample in samples {
+                inserted += stmt.execute(params![
+                    sample.device,
+                    sample.timestamp.timestamp(),
+                    sample.bpm
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(inserted)
+    }
+
+    /// Inserts the slots not stored yet; returns how many were new.
+    pub fn insert_activity_slots(&mut self, slots: &[StoredActivity]) -> Result<usize, StoreError> {
+        let tx = self.conn.transaction()?;
+        let mut inserted = 0;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR IGNORE INTO activity_slots (device, date, slot, steps, calories, distance)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for slot in slots {
+                inserted += stmt.execute(params![
+                    slot.device,
+                    slot.start.date_naive().to_string(),
+                    slot.start.num_seconds_from_midnight() / 900,
+                    slot.steps,
+                    slot.calories,
+                    slot.distance
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(inserted)
+    }
+
+    /// Inserts the nights not stored yet, with their stages; returns how
+    /// many were new. Nights already stored keep their stages.
+    pub fn insert_sleep(&mut self, sleeps: &[StoredSleep]) -> Result<usize, StoreError> {
+        let tx = self.conn.transaction()?;
+        let mut inserted = 0;
+        {
+            let mut session_stmt = tx.prepare(
+                "INSERT OR IGNORE INTO sleep_sessions (device, start, end, date)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            let mut stage_stmt = tx.prepare(
+                "INSERT OR IGNORE INTO sleep_stages (device, session_start, start, minutes, stage)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for sleep in sleeps {
+                let session = &sleep.session;
+                let start = session.start.timestamp();
+                if session_stmt.execute(params![
+                    sleep.device,
+                    start,
+                    session.end.timestamp(),
+                    session.end.date_naive().to_string()
+                ])? == 0
+                {
+                    continue;
+                }
+                inserted += 1;
+                for stage in &session.stages {
+                    stage_stmt.execute(params![
+                        sleep.device,
+                        start,
+                        stage.start.timestamp(),
+                        stage.minutes(),
+                        stage.phase_type
+                    ])?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(inserted)
+    }
+
+    /// Inserts the hours not stored yet; returns how many were new.
+    pub fn insert_oxygen_hours(&mut self, hours: &[StoredOxygen]) -> Result<usize, StoreError> {
+        let tx = self.conn.transaction()?;
+        let mut inserted = 0;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR IGNORE INTO spo2_hourly (device, date, hour, min, max)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for hour in hours {
+                inserted += stmt.execute(params![
+                    hour.device,
+                    hour.start.date_naive().to_string(),
+                    hour.start.hour(),
+                    hour.min,
+                    hour.max
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(inserted)
+    }
+
+    /// Every device with logged history, sorted.
+    pub fn devices(&self) -> Result<Vec<String>, StoreError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT device FROM heart_rate_samples
+             UNION SELECT device FROM activity_slots
+             UNION SELECT device FROM sleep_sessions
+             UNION SELECT device FROM spo2_hourly
+             ORDER BY device",
+        )?;
+        let devices = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(devices)
+    }
+
+    pub fn save_device_info(&mut self, device: &str, info: &DeviceInfo) -> Result<(), StoreError> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO devices (device, name, manufacturer, firmware, hardware)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                device,
+                info.name,
+                info.manufacturer,
+                info.firmware,
+                info.hardware
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn device_info(&self, device: &str) -> Result<Option<DeviceInfo>, StoreError> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT name, manufacturer, firmware, hardware FROM devices
+                 WHERE device = ?1 COLLATE NOCASE",
+                params![device],
+                |row| {
+                    Ok(DeviceInfo {
+                        name: row.get(0)?,
+                        manufacturer: row.get(1)?,
+                        firmware: row.get(2)?,
+                        hardware: row.get(3)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    pub fn save_battery(
+        &mut self,
+        device: &str,
+        at: DateTime<Utc>,
+        battery: &BatteryResponse,
+    ) -> Result<(), StoreError> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO battery_readings (device, timestamp, charge_pct, is_charging)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                device,
+                at.timestamp(),
+                battery.charge_pct,
+                battery.is_charging
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Last day of `metric` that has been fully downloaded, if any.
+    pub fn checkpoint(&self, device: &str, metric: &str) -> Result<Option<NaiveDate>, StoreError> {
+        let date: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT complete_through FROM sync_checkpoints WHERE device = ?1 AND metric = ?2",
+                params![device, metric],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(date.and_then(|d| d.parse().ok()))
+    }
+
+    /// When any metric of `device` was last synced.
+    pub fn last_synced_at(&self, device: &str) -> Result<Option<DateTime<Utc>>, StoreError> {
+        let timestamp: Option<i64> = self.conn.query_row(
+            "SELECT MAX(synced_at) FROM sync_checkpoints WHERE device = ?1",
+            params![device],
+            |row| row.get(0),
+        )?;
+        Ok(timestamp.and_then(|t| DateTime::from_timestamp(t, 0)))
+    }
+
+    pub fn save_checkpoint(
+        &mut self,
+        device: &str,
+        metric: &str,
+        complete_through: NaiveDate,
+        synced_at: DateTime<Utc>,
+    ) -> Result<(), StoreError> {
+        self.conn.execute(
+            "INSERT INTO sync_checkpoints (device, metric, complete_through, synced_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (device, metric) DO UPDATE SET
+                 complete_through = excluded.complete_through,
+                 synced_at = excluded.synced_at",
+            params![
+                device,
+                metric,
+                complete_through.to_string(),
+                synced_at.timestamp()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Heart-rate samples in `[start, end)`, oldest first.
+    pub fn heart_rate_samples(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<StoredHeartRate>, StoreError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT device, timestamp, bpm FROM heart_rate_samples
+             WHERE timestamp >= ?1 AND timestamp < ?2
+             ORDER BY timestamp, device",
+        )?;
+        let rows = stmt.query_map(params![start.timestamp(), end.timestamp()], |row| {
+            Ok(StoredHeartRate {
+                device: row.get(0)?,
+                timestamp: from_unix(row.get(1)?),
+                bpm: row.get(2)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Activity slots starting in `[start, end)`, oldest first.
+    pub fn activity_slots(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<StoredActivity>, StoreError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT device, unixepoch(date) + slot * 900 AS start, steps, calories, distance
+             FROM activity_slots
+             WHERE start >= ?1 AND start < ?2
+             ORDER BY start, device",
+        )?;
+        let rows = stmt.query_map(params![start.timestamp(), end.timestamp()], |row| {
+            Ok(StoredActivity {
+                device: row.get(0)?,
+                start: from_unix(row.get(1)?),
+                steps: row.get(2)?,
+                calories: row.get(3)?,
+                distance: row.get(4)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Sleep sessions that ended in `[start, end)`, with their stages.
+    pub fn sleep_sessions(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<StoredSleep>, StoreError> {
+        let mut session_stmt = self.conn.prepare(
+            "SELECT device, start, end FROM sleep_sessions
+             WHERE end >= ?1 AND end < ?2
+             ORDER BY start, device",
+        )?;
+        let mut stage_stmt = self.conn.prepare(
+            "SELECT start, minutes, stage FROM sleep_stages
+             WHERE device = ?1 AND session_start = ?2
+             ORDER BY start",
+        )?;
+
+        let sessions: Vec<(String, i64, i64)> = session_stmt
+            .query_map(params![start.timestamp(), end.timestamp()], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<Result<_, _>>()?;
+
+        let mut stored = Vec::with_capacity(sessions.len());
+        for (device, session_start, session_end) in sessions {
+            let stages = stage_stmt
+                .query_map(params![device, session_start], |row| {
+                    let start = from_unix(row.get(0)?);
+                    let minutes: i64 = row.get(1)?;
+                    Ok(SleepStage {
+                        phase_type: row.get(2)?,
+                        start,
+                        end: start + Duration::minutes(minutes),
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            stored.push(StoredSleep {
+                device,
+                session: SleepSession {
+                    start: from_unix(session_start),
+                    end: from_unix(session_end),
+                    stages,
+                },
+            });
+        }
+        Ok(stored)
+    }
+
+    /// Hourly SpO2 ranges starting in `[start, end)`, oldest first.
+    pub fn oxygen_hours(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<StoredOxygen>, StoreError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT device, unixepoch(date) + hour * 3600 AS start, min, max
+             FROM spo2_hourly
+             WHERE start >= ?1 AND start < ?2
+             ORDER BY start, device",
+        )?;
+        let rows = stmt.query_map(params![start.timestamp(), end.timestamp()], |row| {
+            Ok(StoredOxygen {
+                device: row.get(0)?,
+                start: from_unix(row.get(1)?),
+                min: row.get(2)?,
+                max: row.get(3)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Realtime readings taken in `[start, end)`, oldest first. Readings of
+    /// a type this version does not know are skipped.
+    pub fn realtime_readings(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<StoredReading>, StoreError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT device, session_start, timestamp_ms, reading_type, value
+             FROM realtime_readings
+             WHERE timestamp_ms >= ?1 AND timestamp_ms < ?2
+             ORDER BY timestamp_ms, device",
+        )?;
+        let rows = stmt.query_map(
+            params![start.timestamp_millis(), end.timestamp_millis()],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, u8>(3)?,
+                    row.get::<_, u8>(4)?,
+                ))
+            },
+        )?;
+
+        let mut readings = Vec::new();
+        for row in rows {
+            let (device, session_start, timestamp_ms, reading_type, value) = row?;
+            let Ok(reading_type) = ReadingType::from_byte(reading_type) else {
+                continue;
+            };
+            readings.push(StoredReading {
+                device,
+                session_start: from_unix(session_start),
+                reading: RealtimeReading {
+                    reading_type,
+                    value,
+                    timestamp: DateTime::from_timestamp_millis(timestamp_ms).unwrap_or_default(),
+                },
+            });
+        }
+        Ok(readings)
+    }
+
+    /// Realtime sessions that started in `[start, end)`, with their readings.
+    pub fn realtime_sessions(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<StoredSession>, StoreError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT device, started_at, ended_at FROM realtime_sessions
+             WHERE started_at >= ?1 AND started_at < ?2
+             ORDER BY started_at, device",
+        )?;
+        let sessions: Vec<(String, i64, i64)> = stmt
+            .query_map(params![start.timestamp(), end.timestamp()], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<Result<_, _>>()?;
+
+        let mut readings = self.realtime_readings(start, DateTime::<Utc>::MAX_UTC)?;
+        Ok(sessions
+            .into_iter()
+            .map(|(device, started_at, ended_at)| {
+                let started_at = from_unix(started_at);
+                let (own, rest) = readings.drain(..).partition(|stored: &StoredReading| {
+                    stored.device == device && stored.session_start == started_at
+                });
+                readings = rest;
+                StoredSession {
+                    device,
+                    started_at,
+                    ended_at: from_unix(ended_at),
+                    readings: own.into_iter().map(|stored| stored.reading).collect(),
+                }
+            })
+            .collect())
+    }
+
+    /// Workouts that started in `[start, end)`, oldest first.
+    pub fn workouts(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<StoredWorkout>, StoreError> {
+        let mut workout_stmt = self.conn.prepare(
+            "SELECT device, started_at, ended_at, sport, duration_ms, steps, calories, distance,
+                    zone_seconds
+             FROM workouts
+             WHERE started_at >= ?1 AND started_at < ?2
+             ORDER BY started_at, device",
+        )?;
+        let mut heart_rate_stmt = self.conn.prepare(
+            "SELECT timestamp_ms, bpm FROM workout_heart_rates
+             WHERE device = ?1 AND workout_start = ?2
+             ORDER BY timestamp_ms",
+        )?;
+
+        let rows = workout_stmt
+            .query_map(params![start.timestamp(), end.timestamp()], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, i64>(4)?,
+                    row.get::<_, u32>(5)?,
+                    row.get::<_, f64>(6)?,
+                    row.get::<_, u32>(7)?,
+                    row.get::<_, String>(8)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut workouts = Vec::with_capacity(rows.len());
+        for (device, started_at, ended_at, sport, duration_ms, steps, calories, distance, zones) in
+            rows
+        {
+            let heart_rates: Vec<(DateTime<Utc>, u8)> = heart_rate_stmt
+                .query_map(params![device, started_at], |row| {
+                    Ok((
+                        DateTime::from_timestamp_millis(row.get(0)?).unwrap_or_default(),
+                        row.get(1)?,
+                    ))
+                })?
+                .collect::<Result<_, _>>()?;
+            let mut time_in_zones = [StdDuration::ZERO; 6];
+            for (zone, secs) in time_in_zones.iter_mut().zip(zones.split(',')) {
+                *zone = StdDuration::from_secs(secs.parse().unwrap_or(0));
+            }
+            let bpm = || heart_rates.iter().map(|&(_, bpm)| bpm as u32);
+            let avg_heart_rate = (!heart_rates.is_empty())
+                .then(|| (bpm().sum::<u32>() / heart_rates.len() as u32) as u8);
+            let max_heart_rate = bpm().max().map(|max| max as u8);
+
+            workouts.push(StoredWorkout {
+                device,
+                summary: WorkoutSummary {
+                    sport_type: SportType::from_name(&sport).unwrap_or(SportType::Other),
+                    started_at: from_unix(started_at),
+                    ended_at: from_unix(ended_at),
+                    duration: StdDuration::from_millis(duration_ms.max(0) as u64),
+                    avg_heart_rate,
+                    max_heart_rate,
+                    time_in_zones,
+                    steps,
+                    calories,
+                    distance,
+                    heart_rates,
+                },
+            });
+        }
+        Ok(workouts)
+    }
+}
+
+fn from_unix(secs: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp(secs, 0).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::protocol::bigdata::OxygenSample;
+    use crate::protocol::hr::HeartRateSample;
+
+    const DEVICE: &str = "AA:BB:CC:DD:EE:FF";
+
+    fn store() -> Store {
+        Store::with_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn count(conn: &Connection, table: &str) -> i64 {
+        conn.query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 10, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn saving_a_heart_rate_log_twice_keeps_one_row_per_sample() {
+        let mut store = store();
+        let log = HeartRateLog {
+            day_start: at(0, 0),
+            interval: Duration::minutes(5),
+            samples: vec![
+                HeartRateSample {
+                    timestamp: at(0, 0),
+                    bpm: Some(61),
+                },
+                HeartRateSample {
+                    timestamp: at(0, 5),
+                    bpm: None,
+                },
+                HeartRateSample {
+                    timestamp: at(0, 10),
+                    bpm: Some(64),
+                },
+            ],
+        };
+
+        assert_eq!(store.save_heart_rate_log(DEVICE, &log).unwrap(), 2);
+        assert_eq!(store.save_heart_rate_log(DEVICE, &log).unwrap(), 2);
+        assert_eq!(count(&store.conn, "heart_rate_samples"), 2);
+    }
+
+    #[test]
+    fn saving_an_activity_day_twice_updates_its_slots() {
+        let mut store = store();
+        let slot = |time_index, steps| ActivityDetail {
+            year: 2024,
+            month: 3,
+            day: 10,
+            time_index,
+            calories: 12.5,
+            steps,
+            distance: 80,
+        };
+
+        store
+            .save_activity(DEVICE, &[slot(32, 100), slot(33, 250)])
+            .unwrap();
+        store
+            .save_activity(DEVICE, &[slot(32, 100), slot(33, 300)])
+            .unwrap();
+
+        assert_eq!(count(&store.conn, "activity_slots"), 2);
+        let steps: u32 = store
+            .conn
+            .query_row(
+                "SELECT steps FROM activity_slots WHERE date = '2024-03-10' AND slot = 33",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(steps, 300);
+    }
+
+    #[test]
+    fn saving_a_night_twice_replaces_its_stages() {
+        let mut store = store();
+        let stage = |phase_type, start: DateTime<Utc>, minutes| SleepStage {
+            phase_type,
+            start,
+            end: start + Duration::minutes(minutes),
+        };
+        let start = at(0, 30);
+        let night = SleepSession {
+            start,
+            end: at(6, 0),
+            stages: vec![
+                stage(2, start, 120),
+                stage(3, at(2, 30), 90),
+                stage(2, at(4, 0), 120),
+            ],
+        };
+
+        store
+            .save_sleep(DEVICE, std::slice::from_ref(&night))
+            .unwrap();
+        store
+            .save_sleep(DEVICE, std::slice::from_ref(&night))
+            .unwrap();
+        assert_eq!(count(&store.conn, "sleep_sessions"), 1);
+        assert_eq!(count(&store.conn, "sleep_stages"), 3);
+
+        // A later download that splits the night differently leaves no
+        // stale stages behind.
+        let resynced = SleepSession {
+            stages: vec![stage(2, start, 210), stage(4, at(4, 0), 120)],
+            ..night
+        };
+        store.save_sleep(DEVICE, &[resynced]).unwrap();
+        assert_eq!(count(&store.conn, "sleep_sessions"), 1);
+        assert_eq!(count(&store.conn, "sleep_stages"), 2);
+    }
+
+    #[test]
+    fn saving_spo2_twice_keeps_one_row_per_hour() {
+        let mut store = store();
+        let mut samples = vec![OxygenSample { min: 0, max: 0 }; 24];
+        samples[2] = OxygenSample { min: 94, max: 98 };
+        samples[3] = OxygenSample { min: 95, max: 99 };
+        let days = [OxygenDay {
+            days_ago: 1,
+            samples,
+        }];
+        let today = at(0, 0).date_naive();
+
+        assert_eq!(store.save_oxygen(DEVICE, today, &days).unwrap(), 2);
+        assert_eq!(store.save_oxygen(DEVICE, today, &days).unwrap(), 2);
+        assert_eq!(count(&store.conn, "spo2_hourly"), 2);
+
+        let first: (String, u8) = store
+            .conn
+            .query_row(
+                "SELECT date, hour FROM spo2_hourly ORDER BY hour LIMIT 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(first, ("2024-03-09".to_string(), 2));
+    }
+
+    #[test]
+    fn opening_a_version_1_store_moves_rows_to_the_address() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        let bluez = "hci0/dev_AA_BB_CC_DD_EE_FF";
+        let uuid = "6c1e3a52-8c1b-4a6e-9f0e-2b1d0c9e7a11";
+        for (device, timestamp, bpm) in [
+            (bluez, 100, 60),
+            (bluez, 200, 61),
+            (DEVICE, 200, 70),
+            (uuid, 100, 80),
+        ] {
+            conn.execute(
+                "INSERT INTO heart_rate_samples (device, timestamp, bpm) VALUES (?1, ?2, ?3)",
+                params![device, timestamp, bpm],
+            )
+            .unwrap();
+        }
+        conn.execute(
+            "INSERT INTO sync_checkpoints (device, metric, complete_through, synced_at)
+             VALUES (?1, 'hr', '2024-03-09', 0)",
+            params![bluez],
+        )
+        .unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+
+        let store = Store::with_connection(conn).unwrap();
+
+        let rows: Vec<(String, i64, u8)> = store
+            .conn
+            .prepare(
+                "SELECT device, timestamp, bpm FROM heart_rate_samples ORDER BY device, timestamp",
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (uuid.to_string(), 100, 80),
+                (DEVICE.to_string(), 100, 60),
+                // The row already under the address wins.
+                (DEVICE.to_string(), 200, 70),
+            ]
+        );
+        assert_eq!(
+            store.checkpoint(DEVICE, "hr").unwrap(),
+            NaiveDate::from_ymd_opt(2024, 3, 9)
+        );
+        assert_eq!(store.checkpoint(bluez, "hr").unwrap(), None);
+
+        let version: i32 = store
+            .conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+    }
+}
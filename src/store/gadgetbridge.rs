@@ -0,0 +1,376 @@
+//! Reads and writes the Colmi sample tables of a Gadgetbridge database
+//! export, so history can move between Gadgetbridge and the local store.
+//! Gadgetbridge keeps the ring's raw sleep stage numbers, logs calories
+//! in small calories and stamps most samples in milliseconds.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use rusqlite::types::Value;
+use rusqlite::{Connection, OpenFlags, params};
+
+use crate::error::GadgetbridgeError;
+use crate::protocol::bigdata::{SleepSession, SleepStage};
+use crate::store::database::{StoredActivity, StoredHeartRate, StoredOxygen, StoredSleep};
+
+const ACTIVITY: &str = "COLMI_ACTIVITY_SAMPLE";
+const HEART_RATE: &str = "COLMI_HEART_RATE_SAMPLE";
+const SPO2: &str = "COLMI_SPO2_SAMPLE";
+const SLEEP_SESSION: &str = "COLMI_SLEEP_SESSION_SAMPLE";
+const SLEEP_STAGE: &str = "COLMI_SLEEP_STAGE_SAMPLE";
+const TABLES: [&str; 7] = [
+    "DEVICE",
+    "USER",
+    ACTIVITY,
+    HEART_RATE,
+    SPO2,
+    SLEEP_SESSION,
+    SLEEP_STAGE,
+];
+
+/// Gadgetbridge's marker for values the device did not measure.
+const NOT_MEASURED: i64 = -1;
+
+pub struct GadgetbridgeDevice {
+    pub id: i64,
+    /// Bluetooth address, as Gadgetbridge stores it.
+    pub identifier: String,
+    pub name: String,
+}
+
+/// History of one device, in the local store's shapes.
+#[derive(Default)]
+pub struct Samples {
+    pub heart_rate: Vec<StoredHeartRate>,
+    pub activity: Vec<StoredActivity>,
+    pub sleep: Vec<StoredSleep>,
+    pub oxygen: Vec<StoredOxygen>,
+}
+
+/// Rows written to or read from one kind of sample table.
+pub struct Transfer {
+    pub metric: &'static str,
+    pub inserted: usize,
+    pub skipped: usize,
+}
+
+pub struct Gadgetbridge {
+    conn: Connection,
+}
+
+impl Gadgetbridge {
+    /// Opens an existing database; it is never created, since Gadgetbridge
+    /// only takes back files it exported itself.
+    pub fn open(path: &Path) -> Result<Self, GadgetbridgeError> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        let missing: Vec<&str> = TABLES
+            .into_iter()
+            .filter(|table| {
+                conn.query_row(
+                    "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+                    [table],
+                    |_| Ok(()),
+                )
+                .is_err()
+            })
+            .collect();
+        if !missing.is_empty() {
+            return Err(GadgetbridgeError::Schema(missing.join(", ")));
+        }
+        Ok(Self { conn })
+    }
+
+    /// Devices with rows in any of the Colmi sample tables.
+    pub fn colmi_devices(&self) -> Result<Vec<GadgetbridgeDevice>, GadgetbridgeError> {
+        let sql = format!(
+            "SELECT _id, IDENTIFIER, NAME FROM DEVICE WHERE _id IN (
+                 SELECT DEVICE_ID FROM {ACTIVITY} UNION SELECT DEVICE_ID FROM {HEART_RATE}
+                 UNION SELECT DEVICE_ID FROM {SPO2} UNION SELECT DEVICE_ID FROM {SLEEP_SESSION})
+             ORDER BY _id"
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let devices = stmt
+            .query_map([], |row| {
+                Ok(GadgetbridgeDevice {
+                    id: row.get(0)?,
+                    identifier: row.get(1)?,
+                    name: row.get(2)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(devices)
+    }
+
+    /// The device paired under `address`, compared case-insensitively.
+    pub fn device(&self, address: &str) -> Result<Option<GadgetbridgeDevice>, GadgetbridgeError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT _id, IDENTIFIER, NAME FROM DEVICE WHERE upper(IDENTIFIER) = upper(?1)",
+        )?;
+        let mut rows = stmt.query_map([address], |row| {
+            Ok(GadgetbridgeDevice {
+                id: row.get(0)?,
+                identifier: row.get(1)?,
+                name: row.get(2)?,
+            })
+        })?;
+        Ok(rows.next().transpose()?)
+    }
+
+    /// Everything logged for `device`, stored under `address`. Activity is
+    /// summed into the ring's 15-minute slots and SpO2 into hourly ranges.
+    pub fn read(
+        &self,
+        device: &GadgetbridgeDevice,
+        address: &str,
+    ) -> Result<Samples, GadgetbridgeError> {
+        let mut samples = Samples::default();
+
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT TIMESTAMP, HEART_RATE FROM {HEART_RATE}
+             WHERE DEVICE_ID = ?1 AND HEART_RATE > 0 ORDER BY TIMESTAMP"
+        ))?;
+        for row in stmt.query_map([device.id], |row| Ok((row.get::<_, i64>(0)?, row.get(1)?)))? {
+            let (timestamp, bpm) = row?;
+            samples.heart_rate.push(StoredHeartRate {
+                device: address.to_string(),
+                timestamp: from_millis(timestamp),
+                bpm,
+            });
+        }
+
+        let mut slots: BTreeMap<DateTime<Utc>, StoredActivity> = BTreeMap::new();
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT TIMESTAMP, STEPS, CALORIES, DISTANCE FROM {ACTIVITY} WHERE DEVICE_ID = ?1"
+        ))?;
+        let rows = stmt.query_map([device.id], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, i64>(3)?,
+            ))
+        })?;
+        for row in rows {
+            let (timestamp, steps, calories, distance) = row?;
+            let start = floor(from_millis(timestamp * 1000), Duration::minutes(15));
+            let slot = slots.entry(start).or_insert_with(|| StoredActivity {
+                device: address.to_string(),
+                start,
+                steps: 0,
+                calories: 0.0,
+                distance: 0,
+            });
+            slot.steps += steps.max(0) as u32;
+            slot.calories += calories.max(0) as f64 / 1000.0;
+            slot.distance += distance.max(0) as u32;
+        }
+        samples.activity = slots.into_values().collect();
+
+        let mut hours: BTreeMap<DateTime<Utc>, StoredOxygen> = BTreeMap::new();
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT TIMESTAMP, SPO2 FROM {SPO2} WHERE DEVICE_ID = ?1 AND SPO2 > 0"
+        ))?;
+        for row in stmt.query_map([device.id], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, u8>(1)?))
+        })? {
+            let (timestamp, spo2) = row?;
+            let start = floor(from_millis(timestamp), Duration::hours(1));
+            let hour = hours.entry(start).or_insert_with(|| StoredOxygen {
+                device: address.to_string(),
+                start,
+                min: spo2,
+                max: spo2,
+            });
+            hour.min = hour.min.min(spo2);
+            hour.max = hour.max.max(spo2);
+        }
+        samples.oxygen = hours.into_values().collect();
+
+        let mut session_stmt = self.conn.prepare(&format!(
+            "SELECT TIMESTAMP, WAKEUP_TIME FROM {SLEEP_SESSION} WHERE DEVICE_ID = ?1 ORDER BY TIMESTAMP"
+        ))?;
+        let mut stage_stmt = self.conn.prepare(&format!(
+            "SELECT TIMESTAMP, DURATION, STAGE FROM {SLEEP_STAGE}
+             WHERE DEVICE_ID = ?1 AND TIMESTAMP >= ?2 AND TIMESTAMP < ?3 ORDER BY TIMESTAMP"
+        ))?;
+        let sessions: Vec<(i64, i64)> = session_stmt
+            .query_map([device.id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        for (start, end) in sessions {
+            let stages = stage_stmt
+                .query_map(params![device.id, start, end], |row| {
+                    let start = from_millis(row.get(0)?);
+                    Ok(SleepStage {
+                        start,
+                        end: start + Duration::minutes(row.get(1)?),
+                        phase_type: row.get(2)?,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            samples.sleep.push(StoredSleep {
+                device: address.to_string(),
+                session: SleepSession {
+                    start: from_millis(start),
+                    end: from_millis(end),
+                    stages,
+                },
+            });
+        }
+
+        Ok(samples)
+    }
+
+    /// Adds `samples` to the device's tables. Rows Gadgetbridge already has
+    /// for the same time are kept and counted as skipped.
+    pub fn write(
+        &mut self,
+        device: &GadgetbridgeDevice,
+        samples: &Samples,
+    ) -> Result<Vec<Transfer>, GadgetbridgeError> {
+        let user: i64 =
+            self.conn
+                .query_row("SELECT _id FROM USER ORDER BY _id LIMIT 1", [], |row| {
+                    row.get(0)
+                })?;
+        let tx = self.conn.transaction()?;
+        let writer = Writer {
+            conn: &tx,
+            device: device.id,
+            user,
+        };
+
+        let heart_rate = writer.insert(
+            HEART_RATE,
+            samples.heart_rate.iter().map(|sample| {
+                vec![
+                    (
+                        "TIMESTAMP",
+                        Value::Integer(sample.timestamp.timestamp_millis()),
+                    ),
+                    ("HEART_RATE", Value::Integer(sample.bpm as i64)),
+                ]
+            }),
+        )?;
+        let activity = writer.insert(
+            ACTIVITY,
+            samples.activity.iter().map(|slot| {
+                vec![
+                    ("TIMESTAMP", Value::Integer(slot.start.timestamp())),
+                    ("RAW_INTENSITY", Value::Integer(NOT_MEASURED)),
+                    ("STEPS", Value::Integer(slot.steps as i64)),
+                    ("RAW_KIND", Value::Integer(NOT_MEASURED)),
+                    ("HEART_RATE", Value::Integer(NOT_MEASURED)),
+                    (
+                        "CALORIES",
+                        Value::Integer((slot.calories * 1000.0).round() as i64),
+                    ),
+                    ("DISTANCE", Value::Integer(slot.distance as i64)),
+                ]
+            }),
+        )?;
+        let oxygen = writer.insert(
+            SPO2,
+            samples.oxygen.iter().map(|hour| {
+                let spo2 = (hour.min as i64 + hour.max as i64 + 1) / 2;
+                vec![
+                    ("TIMESTAMP", Value::Integer(hour.start.timestamp_millis())),
+                    ("SPO2", Value::Integer(spo2)),
+                ]
+            }),
+        )?;
+        let sleep = writer.insert(
+            SLEEP_SESSION,
+            samples.sleep.iter().map(|sleep| {
+                let stages: Vec<u8> = sleep
+                    .session
+                    .stages
+                    .iter()
+                    .flat_map(|stage| [stage.phase_type, stage.minutes().clamp(0, 255) as u8])
+                    .collect();
+                vec![
+                    (
+                        "TIMESTAMP",
+                        Value::Integer(sleep.session.start.timestamp_millis()),
+                    ),
+                    (
+                        "WAKEUP_TIME",
+                        Value::Integer(sleep.session.end.timestamp_millis()),
+                    ),
+                    ("SLEEP_STAGES", Value::Blob(stages)),
+                ]
+            }),
+        )?;
+        writer.insert(
+            SLEEP_STAGE,
+            samples
+                .sleep
+                .iter()
+                .flat_map(|sleep| &sleep.session.stages)
+                .map(|stage| {
+                    vec![
+                        ("TIMESTAMP", Value::Integer(stage.start.timestamp_millis())),
+                        ("DURATION", Value::Integer(stage.minutes())),
+                        ("STAGE", Value::Integer(stage.phase_type as i64)),
+                    ]
+                }),
+        )?;
+        tx.commit()?;
+
+        Ok(vec![
+            transfer("heart_rate", heart_rate, samples.heart_rate.len()),
+            transfer("activity", activity, samples.activity.len()),
+            transfer("sleep", sleep, samples.sleep.len()),
+            transfer("spo2", oxygen, samples.oxygen.len()),
+        ])
+    }
+}
+
+struct Writer<'a> {
+    conn: &'a Connection,
+    device: i64,
+    user: i64,
+}
+
+impl Writer<'_> {
+    /// Inserts rows into `table`, skipping any whose key already exists;
+    /// returns how many were new.
+    fn insert(
+        &self,
+        table: &str,
+        rows: impl Iterator<Item = Vec<(&'static str, Value)>>,
+    ) -> Result<usize, GadgetbridgeError> {
+        let mut inserted = 0;
+        for row in rows {
+            let columns: Vec<&str> = row.iter().map(|(column, _)| *column).collect();
+            let sql = format!(
+                "INSERT OR IGNORE INTO {table} (DEVICE_ID, USER_ID, {}) VALUES (?1, ?2{})",
+                columns.join(", "),
+                (3..=columns.len() + 2)
+                    .map(|i| format!(", ?{i}"))
+                    .collect::<String>()
+            );
+            let mut stmt = self.conn.prepare_cached(&sql)?;
+            let mut values = vec![Value::Integer(self.device), Value::Integer(self.user)];
+            values.extend(row.into_iter().map(|(_, value)| value));
+            inserted += stmt.execute(rusqlite::params_from_iter(values))?;
+        }
+        Ok(inserted)
+    }
+}
+
+fn transfer(metric: &'static str, inserted: usize, total: usize) -> Transfer {
+    Transfer {
+        metric,
+        inserted,
+        skipped: total - inserted,
+    }
+}
+
+fn from_millis(millis: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(millis).unwrap_or_default()
+}
+
+fn floor(instant: DateTime<Utc>, step: Duration) -> DateTime<Utc> {
+    instant.duration_trunc(step).unwrap_or(instant)
+}
@@ -0,0 +1,219 @@
+use chrono::{Duration, NaiveDate, Utc};
+
+use crate::calendar::ring_today;
+use crate::devices::manager::{Connection, DeviceManager};
+use crate::error::SyncError;
+use crate::protocol::bigdata::OxygenData;
+use crate::protocol::hr::HeartRateResult;
+use crate::protocol::steps::StepsResult;
+use crate::store::database::Store;
+
+/// How far back a first sync reaches; the ring keeps about a week of history.
+pub const SYNC_LOOKBACK_DAYS: i64 = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncMetric {
+    HeartRate,
+    Activity,
+    Sleep,
+    Oxygen,
+}
+
+impl SyncMetric {
+    pub const ALL: [SyncMetric; 4] = [Self::HeartRate, Self::Activity, Self::Sleep, Self::Oxygen];
+
+    /// Name used for the metric's checkpoint row.
+    pub fn key(&self) -> &'static str {
+        match self {
+            Self::HeartRate => "heart_rate",
+            Self::Activity => "activity",
+            Self::Sleep => "sleep",
+            Self::Oxygen => "spo2",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::HeartRate => "Heart rate",
+            Self::Activity => "Activity",
+            Self::Sleep => "Sleep",
+            Self::Oxygen => "SpO2",
+        }
+    }
+}
+
+pub struct MetricSync {
+    pub metric: SyncMetric,
+    /// Days downloaded in this run.
+    pub days: usize,
+    /// Samples, slots or sessions written to the store. Today is always
+    /// downloaded again, so rows that were already stored count too.
+    pub records: usize,
+    /// Days skipped because an earlier sync already completed them.
+    pub skipped: usize,
+    /// Past days that came back without data; the next sync asks again.
+    pub incomplete: usize,
+}
+
+/// Days after the checkpoint up to and including today, oldest first and
+/// at most [`SYNC_LOOKBACK_DAYS`] of them. Today is always included since
+/// it is never complete.
+fn pending_days(checkpoint: Option<NaiveDate>, today: NaiveDate) -> Vec<NaiveDate> {
+    (0..SYNC_LOOKBACK_DAYS)
+        .rev()
+        .map(|days_ago| today - Duration::days(days_ago))
+        .filter(|date| checkpoint.is_none_or(|c| *date > c))
+        .collect()
+}
+
+/// New checkpoint: the last of the pending days before today that came back
+/// with data, as long as every pending day before it did too. A day that
+/// came back empty holds the checkpoint so the next sync retries it.
+fn complete_through(
+    pending: &[NaiveDate],
+    complete: &[NaiveDate],
+    today: NaiveDate,
+) -> Option<NaiveDate> {
+    pending
+        .iter()
+        .take_while(|date| **date < today && complete.contains(date))
+        .last()
+        .copied()
+}
+
+/// Downloads everything for `metric` that is newer than its checkpoint and
+/// moves the checkpoint past the days that came back complete.
+pub async fn sync_metric(
+    conn: &Connection,
+    device: &str,
+    store: &mut Store,
+    metric: SyncMetric,
+) -> Result<MetricSync, SyncError> {
+    let today = ring_today();
+    let checkpoint = store.checkpoint(device, metric.key())?;
+    let pending = pending_days(checkpoint, today);
+    let mut records = 0;
+    // Pending days the ring returned data for.
+    let mut complete = Vec::new();
+
+    match metric {
+        SyncMetric::HeartRate => {
+            for &date in &pending {
+                let day_start = date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc();
+                if let HeartRateResult::Log(log) =
+                    DeviceManager::get_heart_rate_log(conn, day_start).await?
+                {
+                    let saved = store.save_heart_rate_log(device, &log)?;
+                    if saved > 0 {
+                        complete.push(date);
+                    }
+                    records += saved;
+                }
+            }
+        }
+        SyncMetric::Activity => {
+            for &date in &pending {
+                let days_ago = (today - date).num_days() as i8;
+                if let StepsResult::Details(details) =
+                    DeviceManager::get_steps(conn, days_ago).await?
+                    && !details.is_empty()
+                {
+                    records += store.save_activity(device, &details)?;
+                    complete.push(date);
+                }
+            }
+        }
+        SyncMetric::Sleep => {
+            // Sleep and SpO2 arrive as one big-data blob covering every stored day.
+            let sleep = DeviceManager::get_sleep(conn).await?;
+            let new_sessions: Vec<_> = sleep
+                .sessions(&Utc)
+                .into_iter()
+                .filter(|session| pending.contains(&session.end.date_naive()))
+                .collect();
+            complete.extend(new_sessions.iter().map(|session| session.end.date_naive()));
+            records += store.save_sleep(device, &new_sessions)?;
+        }
+        SyncMetric::Oxygen => {
+            let OxygenData { days } = DeviceManager::get_oxygen(conn).await?;
+            let new_days: Vec<_> = days
+                .into_iter()
+                .filter(|day| pending.contains(&(today - Duration::days(day.days_ago as i64))))
+                .collect();
+            complete.extend(
+                new_days
+                    .iter()
+                    .map(|day| today - Duration::days(day.days_ago as i64)),
+            );
+            records += store.save_oxygen(device, today, &new_days)?;
+        }
+    }
+
+    if let Some(through) = complete_through(&pending, &complete, today) {
+        store.save_checkpoint(device, metric.key(), through, Utc::now())?;
+    }
+    Ok(MetricSync {
+        metric,
+        days: pending.len(),
+        records,
+        skipped: SYNC_LOOKBACK_DAYS as usize - pending.len(),
+        incomplete: pending
+            .iter()
+            .filter(|date| **date < today && !complete.contains(date))
+            .count(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, day).unwrap()
+    }
+
+    #[test]
+    fn first_sync_covers_the_whole_lookback() {
+        let pending = pending_days(None, date(10));
+        assert_eq!(pending.len(), SYNC_LOOKBACK_DAYS as usize);
+        assert_eq!(pending.first(), Some(&date(4)));
+        assert_eq!(pending.last(), Some(&date(10)));
+    }
+
+    #[test]
+    fn pending_days_start_after_the_checkpoint() {
+        assert_eq!(
+            pending_days(Some(date(7)), date(10)),
+            vec![date(8), date(9), date(10)]
+        );
+        // Synced up to yesterday: only today is downloaded again.
+        assert_eq!(pending_days(Some(date(9)), date(10)), vec![date(10)]);
+    }
+
+    #[test]
+    fn pending_days_stop_at_the_lookback_after_a_long_gap() {
+        let pending = pending_days(Some(date(1)), date(20));
+        assert_eq!(pending.len(), SYNC_LOOKBACK_DAYS as usize);
+        assert_eq!(pending.first(), Some(&date(14)));
+    }
+
+    #[test]
+    fn checkpoint_moves_through_complete_days_only() {
+        let pending = [date(7), date(8), date(9), date(10)];
+
+        assert_eq!(
+            complete_through(&pending, &[date(7), date(8), date(9), date(10)], date(10)),
+            Some(date(9))
+        );
+        // Day 8 came back empty, so it and everything after it is retried.
+        assert_eq!(
+            complete_through(&pending, &[date(7), date(9), date(10)], date(10)),
+            Some(date(7))
+        );
+        assert_eq!(
+            complete_through(&pending, &[date(8), date(9)], date(10)),
+            None
+        );
+        assert_eq!(complete_through(&[date(10)], &[date(10)], date(10)), None);
+    }
+}
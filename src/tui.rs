@@ -14,17 +14,15 @@ use crossterm::{
 use ratatui::{Terminal, prelude::CrosstermBackend};
 
 use crate::{
-    error::TuiError,
+    error::{CliError, TuiError},
     tui::{app::App, ui::render_app},
 };
 
 use crate::devices::models::Device;
 use inquire::Select;
 
-pub fn select_device(devices: Vec<Device>) -> Option<Device> {
-    Select::new("Choose the device to connect to:", devices)
-        .prompt()
-        .ok()
+pub fn select_device(devices: Vec<Device>) -> Result<Device, CliError> {
+    Ok(Select::new("Choose the device to connect to:", devices).prompt()?)
 }
 
 pub async fn run_tui() -> Result<(), TuiError> {
@@ -61,6 +59,7 @@ pub async fn run_tui() -> Result<(), TuiError> {
         }
     }
 
+    app.shutdown().await;
     cleanup_terminal();
 
     Ok(())
@@ -1,19 +1,34 @@
 use crate::{
     bluetooth::scanner,
+    calendar::Calendar,
+    config::manager::load_device_features,
     devices::{manager::Connection, manager::DeviceManager, models::Device},
     error::{DeviceError, ScanError},
     protocol::{
         battery::BatteryResponse,
         bigdata::{OxygenData, SleepData},
         hr::HeartRateResult,
-        realtime::{ReadingType, RealtimeReading},
-        steps::StepsResult,
+        realtime::{MonitorEvent, ReadingType, RealtimeReading, RealtimeSession, SessionMode},
+        steps::{DailyTotals, LiveActivity, StepsResult},
+        workout::{
+            DEFAULT_MAX_HEART_RATE, SportType, WorkoutAction, WorkoutEvent, WorkoutSession,
+            WorkoutSummary,
+        },
     },
 };
-use chrono::{Datelike, TimeZone, Utc};
 use crossterm::event::{KeyCode, KeyEvent};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
 use std::time::{Duration, Instant};
-use tokio::{sync::mpsc, task};
+use tokio::{
+    sync::{broadcast, broadcast::error::TryRecvError, mpsc},
+    task,
+};
+use tokio_util::sync::CancellationToken;
+
+/// Recent readings kept for the sparkline; older ones only feed `LiveStats`.
+const LIVE_READINGS_LIMIT: usize = 120;
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
 
 type DeviceInfo = (String, String, String);
 type HistoryData = (HeartRateResult, StepsResult, SleepData, OxygenData);
@@ -33,10 +48,36 @@ pub enum Screen {
 pub enum ConnectedTab {
     Live,
     Today,
+    Workout,
     Controls,
 }
 
+/// Running aggregate over every reading of a monitoring session, so long
+/// sessions can report min/avg/max without keeping all readings.
+#[derive(Default)]
+pub struct LiveStats {
+    pub count: u64,
+    pub sum: u64,
+    pub min: Option<u8>,
+    pub max: Option<u8>,
+}
+
+impl LiveStats {
+    fn record(&mut self, value: u8) {
+        self.count += 1;
+        self.sum += value as u64;
+        self.min = Some(self.min.map_or(value, |m| m.min(value)));
+        self.max = Some(self.max.map_or(value, |m| m.max(value)));
+    }
+
+    pub fn average(&self) -> Option<u64> {
+        (self.count > 0).then(|| self.sum / self.count)
+    }
+}
+
 pub struct App {
+    /// Decides where "today" begins for history fetched from the ring.
+    pub calendar: Calendar,
     pub current_screen: Screen,
     pub should_quit: bool,
 
@@ -54,6 +95,9 @@ pub struct App {
 
     pub connected_device: Option<Device>,
     pub connection: Option<Connection>,
+    /// Cancelled when the connection is given up; request tasks hold child
+    /// tokens and stop between requests.
+    pub connection_cancel: Option<CancellationToken>,
     pub is_operation_in_progress: bool,
     pub connection_task: Option<task::JoinHandle<Result<Connection, DeviceError>>>,
     pub operation_task: Option<task::JoinHandle<Result<(), DeviceError>>>,
@@ -63,17 +107,34 @@ pub struct App {
     pub device_info: Option<DeviceInfo>,
     pub history_task: Option<task::JoinHandle<Result<HistoryData, DeviceError>>>,
     pub history: Option<HistoryData>,
+    pub totals_task: Option<task::JoinHandle<Result<DailyTotals, DeviceError>>>,
+    pub today_totals: Option<DailyTotals>,
+    pub live_activity: Option<LiveActivity>,
+    pub live_activity_rx: Option<broadcast::Receiver<LiveActivity>>,
     pub is_monitoring: bool,
-    pub live_readings: Vec<RealtimeReading>,
-    pub live_rx: Option<mpsc::Receiver<RealtimeReading>>,
+    pub live_readings: VecDeque<RealtimeReading>,
+    pub live_stats: HashMap<ReadingType, LiveStats>,
+    pub live_types: Vec<ReadingType>,
+    /// Metric shown on its own in the Live tab; `None` overlays all of them.
+    pub live_view: Option<ReadingType>,
+    pub live_rx: Option<mpsc::Receiver<MonitorEvent>>,
     pub monitor_task: Option<task::JoinHandle<Result<(), DeviceError>>>,
+    pub monitor_cancel: Option<CancellationToken>,
     pub monitor_started_at: Option<Instant>,
+    pub workout_sport: SportType,
+    pub workout_session: Option<WorkoutSession>,
+    pub workout_summary: Option<WorkoutSummary>,
+    pub workout_rx: Option<mpsc::Receiver<WorkoutEvent>>,
+    pub workout_control: Option<mpsc::Sender<WorkoutAction>>,
+    pub workout_task: Option<task::JoinHandle<Result<(), DeviceError>>>,
+    pub workout_cancel: Option<CancellationToken>,
     pub connected_tab: ConnectedTab,
 }
 
 impl App {
     pub fn new() -> Self {
         Self {
+            calendar: Calendar::load(),
             current_screen: Screen::Idle,
             should_quit: false,
             devices: Vec::new(),
@@ -86,6 +147,7 @@ impl App {
             connecting_device_name: None,
             connected_device: None,
             connection: None,
+            connection_cancel: None,
             is_operation_in_progress: false,
             connection_task: None,
             operation_task: None,
@@ -95,11 +157,26 @@ impl App {
             device_info: None,
             history_task: None,
             history: None,
+            totals_task: None,
+            today_totals: None,
+            live_activity: None,
+            live_activity_rx: None,
             is_monitoring: false,
-            live_readings: Vec::new(),
+            live_readings: VecDeque::with_capacity(LIVE_READINGS_LIMIT),
+            live_stats: HashMap::new(),
+            live_types: Vec::new(),
+            live_view: None,
             live_rx: None,
             monitor_task: None,
+            monitor_cancel: None,
             monitor_started_at: None,
+            workout_sport: SportType::Running,
+            workout_session: None,
+            workout_summary: None,
+            workout_rx: None,
+            workout_control: None,
+            workout_task: None,
+            workout_cancel: None,
             connected_tab: ConnectedTab::Live,
         }
     }
@@ -112,6 +189,10 @@ impl App {
             KeyCode::Char('b') => self.fetch_battery(),
             KeyCode::Char('h') => self.fetch_history(),
             KeyCode::Char('l') => self.toggle_monitoring(),
+            KeyCode::Char('m') => self.cycle_live_view(),
+            KeyCode::Char('w') => self.toggle_workout(),
+            KeyCode::Char('p') => self.toggle_workout_pause(),
+            KeyCode::Left | KeyCode::Right => self.cycle_workout_sport(),
             KeyCode::Tab | KeyCode::Char('\t') => self.cycle_tab(),
             KeyCode::Char('1') => self.blink_device(),
             KeyCode::Char('2') => self.find_device(),
@@ -158,40 +239,81 @@ impl App {
     }
 
     fn disconnect(&mut self) {
-        if let Some(task) = &mut self.monitor_task {
-            task.abort();
+        // Cancel rather than abort, so the ring gets its stop packets and no
+        // write is cut short; the detached tasks finish on their own.
+        for cancel in [
+            self.monitor_cancel.take(),
+            self.workout_cancel.take(),
+            self.connection_cancel.take(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            cancel.cancel();
         }
         self.monitor_task = None;
         self.live_rx = None;
         self.is_monitoring = false;
         self.monitor_started_at = None;
-        if let Some(task) = &mut self.operation_task {
-            task.abort();
-        }
+        self.workout_task = None;
+        self.workout_rx = None;
+        self.workout_control = None;
+        self.workout_session = None;
+        self.workout_summary = None;
         self.operation_task = None;
-        if let Some(task) = &mut self.battery_task {
-            task.abort();
-        }
         self.battery_task = None;
-        if let Some(task) = &mut self.history_task {
-            task.abort();
-        }
         self.history_task = None;
-        if let Some(task) = &mut self.device_info_task {
-            task.abort();
-        }
+        self.totals_task = None;
         self.device_info_task = None;
 
-        self.connection = None;
+        if let Some(conn) = self.connection.take() {
+            conn.close();
+        }
         self.connected_device = None;
         self.battery_level = None;
         self.device_info = None;
         self.history = None;
+        self.today_totals = None;
+        self.live_activity = None;
+        self.live_activity_rx = None;
         self.connected_tab = ConnectedTab::Live;
         self.current_screen = Screen::Idle;
         self.status_message = "Disconnected".to_string();
     }
 
+    /// Cancels running tasks and waits briefly for them to wind down, so
+    /// quitting never leaves the ring's sensors on or a write half done.
+    pub async fn shutdown(&mut self) {
+        for cancel in [
+            self.monitor_cancel.take(),
+            self.workout_cancel.take(),
+            self.connection_cancel.take(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            cancel.cancel();
+        }
+        let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+            settle(self.monitor_task.take()).await;
+            settle(self.workout_task.take()).await;
+            settle(self.operation_task.take()).await;
+            settle(self.battery_task.take()).await;
+            settle(self.device_info_task.take()).await;
+            settle(self.history_task.take()).await;
+            settle(self.totals_task.take()).await;
+        })
+        .await;
+    }
+
+    /// A token for a request task on the current connection.
+    fn request_cancel(&self) -> CancellationToken {
+        self.connection_cancel
+            .as_ref()
+            .map(CancellationToken::child_token)
+            .unwrap_or_default()
+    }
+
     fn cancel_connection(&mut self) {
         if let Some(task) = &mut self.connection_task {
             task.abort();
@@ -268,7 +390,9 @@ impl App {
                 Ok(Ok(connection)) => {
                     if let Some(selected) = self.selected_device {
                         self.connected_device = Some(self.devices[selected].clone());
+                        self.live_activity_rx = Some(connection.subscribe_live_activity());
                         self.connection = Some(connection);
+                        self.connection_cancel = Some(CancellationToken::new());
                         self.current_screen = Screen::Connected;
                         self.status_message = format!(
                             "Connected to {}",
@@ -369,10 +493,32 @@ impl App {
             self.history_task = None;
         }
 
+        if let Some(task) = &mut self.totals_task
+            && task.is_finished()
+        {
+            match task.await {
+                Ok(Ok(totals)) => {
+                    self.today_totals = Some(totals);
+                }
+                Ok(Err(err)) => {
+                    self.error_message = Some(format!("Daily totals fetch failed: {err}"));
+                }
+                Err(_) => {
+                    self.error_message = Some("Daily totals task panicked".to_string());
+                }
+            }
+            self.totals_task = None;
+        }
+
         if let Some(task) = &mut self.monitor_task
             && task.is_finished()
         {
+            let cancelled = self
+                .monitor_cancel
+                .take()
+                .is_some_and(|cancel| cancel.is_cancelled());
             match task.await {
+                Ok(Ok(_)) if cancelled => self.status_message = "Monitoring stopped".to_string(),
                 Ok(Ok(_)) => self.status_message = "Monitoring finished".to_string(),
                 Ok(Err(err)) => self.status_message = format!("Monitoring error: {err}"),
                 Err(_) => self.status_message = "Monitoring task panicked".to_string(),
@@ -383,12 +529,64 @@ impl App {
             self.monitor_started_at = None;
         }
 
-        if let Some(rx) = &mut self.live_rx {
-            while let Ok(reading) = rx.try_recv() {
-                self.live_readings.push(reading);
+        if let Some(task) = &mut self.workout_task
+            && task.is_finished()
+        {
+            match task.await {
+                Ok(Ok(_)) => self.status_message = "Workout finished".to_string(),
+                Ok(Err(err)) => self.status_message = format!("Workout error: {err}"),
+                Err(_) => self.status_message = "Workout task panicked".to_string(),
+            }
+            self.workout_task = None;
+            self.workout_cancel = None;
+            self.workout_rx = None;
+            self.workout_control = None;
+            if let Some(session) = self.workout_session.take() {
+                self.workout_summary = Some(session.finish());
+            }
+        }
+
+        if let (Some(rx), Some(session)) = (&mut self.workout_rx, &mut self.workout_session) {
+            while let Ok(event) = rx.try_recv() {
+                session.record(&event);
             }
-            if self.live_readings.len() > 50 {
-                self.live_readings.drain(..self.live_readings.len() - 50);
+        }
+
+        if let Some(rx) = &mut self.live_activity_rx {
+            loop {
+                match rx.try_recv() {
+                    Ok(activity) => self.live_activity = Some(activity),
+                    Err(TryRecvError::Lagged(_)) => continue,
+                    Err(_) => break,
+                }
+            }
+        }
+
+        if let Some(rx) = &mut self.live_rx {
+            while let Ok(event) = rx.try_recv() {
+                match event {
+                    MonitorEvent::Reading(reading) => {
+                        self.live_stats
+                            .entry(reading.reading_type)
+                            .or_default()
+                            .record(reading.value);
+                        if self.live_readings.len() == LIVE_READINGS_LIMIT {
+                            self.live_readings.pop_front();
+                        }
+                        self.live_readings.push_back(reading);
+                    }
+                    MonitorEvent::Restarted => {
+                        self.status_message = "Ring went quiet, restarted measurement".to_string();
+                    }
+                    MonitorEvent::Reconnecting { attempt } => {
+                        self.status_message =
+                            format!("Connection lost, reconnecting ({attempt})...");
+                    }
+                    MonitorEvent::Reconnected(conn) => {
+                        self.connection = Some(conn);
+                        self.status_message = "Reconnected, monitoring...".to_string();
+                    }
+                }
             }
         }
     }
@@ -435,8 +633,9 @@ impl App {
         {
             self.status_message = "Fetching battery level...".to_string();
             let conn = conn.clone();
+            let cancel = self.request_cancel();
             self.battery_task = Some(tokio::spawn(async move {
-                DeviceManager::get_battery_level(&conn).await
+                unless_cancelled(&cancel, DeviceManager::get_battery_level(&conn)).await
             }));
         }
     }
@@ -446,8 +645,9 @@ impl App {
             && let Some(conn) = &self.connection
         {
             let conn = conn.clone();
+            let cancel = self.request_cancel();
             self.device_info_task = Some(tokio::spawn(async move {
-                DeviceManager::get_device_info(&conn).await
+                unless_cancelled(&cancel, DeviceManager::get_device_info(&conn)).await
             }));
         }
     }
@@ -458,7 +658,8 @@ impl App {
         }
         self.connected_tab = match self.connected_tab {
             ConnectedTab::Live => ConnectedTab::Today,
-            ConnectedTab::Today => ConnectedTab::Controls,
+            ConnectedTab::Today => ConnectedTab::Workout,
+            ConnectedTab::Workout => ConnectedTab::Controls,
             ConnectedTab::Controls => ConnectedTab::Live,
         };
     }
@@ -469,62 +670,181 @@ impl App {
         }
         if self.is_monitoring {
             self.stop_monitoring();
+        } else if self.monitor_task.is_some() {
+            self.status_message = "Still stopping the previous monitoring...".to_string();
+        } else if self.workout_task.is_some() {
+            self.status_message = "Stop the workout before live monitoring".to_string();
         } else {
             self.start_monitoring();
         }
     }
 
     fn start_monitoring(&mut self) {
-        if let Some(conn) = &self.connection {
-            self.status_message = "Monitoring heart rate...".to_string();
+        if let (Some(conn), Some(device)) = (&self.connection, &self.connected_device) {
+            // Measure everything the ring advertises; older configs only know HR.
+            let reading_types = load_device_features()
+                .map(|features| features.realtime_types())
+                .filter(|types| !types.is_empty())
+                .unwrap_or_else(|| vec![ReadingType::HeartRateBatch]);
+            let session = RealtimeSession {
+                reading_types,
+                mode: SessionMode::Together,
+            };
+
+            self.status_message = format!("Monitoring {}...", session.label());
             self.is_monitoring = true;
             self.monitor_started_at = Some(Instant::now());
             self.live_readings.clear();
+            self.live_stats.clear();
+            self.live_types = session.reading_types.clone();
+            self.live_view = None;
 
             let conn = conn.clone();
-            let (tx, rx) = mpsc::channel::<RealtimeReading>(64);
+            let device = device.clone();
+            let (tx, rx) = mpsc::channel::<MonitorEvent>(64);
+            let cancel = CancellationToken::new();
             self.live_rx = Some(rx);
+            self.monitor_cancel = Some(cancel.clone());
             self.monitor_task = Some(tokio::spawn(async move {
-                DeviceManager::stream_realtime(
-                    &conn,
-                    ReadingType::HeartRateBatch,
-                    Duration::from_secs(600),
-                    tx,
-                )
-                .await
+                DeviceManager::monitor_realtime(&device, conn, session, tx, cancel).await
             }));
         }
     }
 
+    /// Steps the Live tab through overlay, then each measured metric alone.
+    fn cycle_live_view(&mut self) {
+        if self.current_screen != Screen::Connected || self.live_types.is_empty() {
+            return;
+        }
+        self.live_view = match self.live_view {
+            None => self.live_types.first().copied(),
+            Some(current) => {
+                let index = self.live_types.iter().position(|&t| t == current);
+                index.and_then(|i| self.live_types.get(i + 1).copied())
+            }
+        };
+    }
+
     fn stop_monitoring(&mut self) {
-        if let Some(task) = &mut self.monitor_task {
-            task.abort();
+        // The task stays around until the ring has been told to stop.
+        if let Some(cancel) = &self.monitor_cancel {
+            cancel.cancel();
         }
-        self.monitor_task = None;
         self.live_rx = None;
         self.is_monitoring = false;
         self.monitor_started_at = None;
-        self.status_message = "Monitoring stopped".to_string();
+        self.status_message = "Stopping monitoring...".to_string();
+    }
+
+    fn toggle_workout(&mut self) {
+        if self.current_screen != Screen::Connected {
+            return;
+        }
+        if self.workout_task.is_some() {
+            self.stop_workout();
+        } else {
+            self.start_workout();
+        }
+    }
+
+    fn start_workout(&mut self) {
+        if self.is_monitoring {
+            self.stop_monitoring();
+        }
+        if let Some(conn) = &self.connection {
+            self.status_message = format!("Workout ({}) running...", self.workout_sport.label());
+            self.connected_tab = ConnectedTab::Workout;
+            self.workout_summary = None;
+            self.workout_session = Some(WorkoutSession::new(
+                self.workout_sport,
+                DEFAULT_MAX_HEART_RATE,
+            ));
+
+            let conn = conn.clone();
+            let sport_type = self.workout_sport;
+            let (tx, rx) = mpsc::channel::<WorkoutEvent>(64);
+            let (control_tx, control_rx) = mpsc::channel::<WorkoutAction>(8);
+            let cancel = CancellationToken::new();
+            self.workout_rx = Some(rx);
+            self.workout_control = Some(control_tx);
+            self.workout_cancel = Some(cancel.clone());
+            self.workout_task = Some(tokio::spawn(async move {
+                DeviceManager::stream_workout(&conn, sport_type, control_rx, tx, cancel).await
+            }));
+        }
+    }
+
+    fn stop_workout(&mut self) {
+        if let Some(cancel) = &self.workout_cancel {
+            cancel.cancel();
+        }
+        self.status_message = "Stopping workout...".to_string();
+    }
+
+    fn toggle_workout_pause(&mut self) {
+        if self.current_screen != Screen::Connected {
+            return;
+        }
+        if let (Some(control), Some(session)) = (&self.workout_control, &mut self.workout_session) {
+            let action = if session.paused {
+                session.resume();
+                self.status_message = "Workout resumed".to_string();
+                WorkoutAction::Resume
+            } else {
+                session.pause();
+                self.status_message = "Workout paused".to_string();
+                WorkoutAction::Pause
+            };
+            let _ = control.try_send(action);
+        }
+    }
+
+    fn cycle_workout_sport(&mut self) {
+        if self.current_screen == Screen::Connected
+            && self.connected_tab == ConnectedTab::Workout
+            && self.workout_task.is_none()
+        {
+            self.workout_sport = self.workout_sport.next();
+        }
+    }
+
+    fn fetch_today_totals(&mut self) {
+        if self.current_screen == Screen::Connected
+            && self.totals_task.is_none()
+            && let Some(conn) = &self.connection
+        {
+            let conn = conn.clone();
+            let cancel = self.request_cancel();
+            self.totals_task = Some(tokio::spawn(async move {
+                unless_cancelled(&cancel, DeviceManager::get_today_totals(&conn)).await
+            }));
+        }
     }
 
     fn fetch_history(&mut self) {
+        self.fetch_today_totals();
         if self.current_screen == Screen::Connected
             && self.history_task.is_none()
             && let Some(conn) = &self.connection
         {
             self.status_message = "Fetching today's data...".to_string();
             let conn = conn.clone();
+            let calendar = self.calendar;
+            let cancel = self.request_cancel();
             self.history_task = Some(tokio::spawn(async move {
-                let day = Utc::now();
-                let midnight = Utc
-                    .with_ymd_and_hms(day.year(), day.month(), day.day(), 0, 0, 0)
-                    .single()
-                    .ok_or(DeviceError::StreamEnded)?;
-                let heart_rate =
-                    DeviceManager::get_heart_rate_log(&conn, midnight.timestamp() as u32).await?;
-                let steps = DeviceManager::get_steps(&conn, 0).await?;
-                let sleep = DeviceManager::get_sleep(&conn).await?;
-                let oxygen = DeviceManager::get_oxygen(&conn).await?;
+                let today = calendar.today();
+                let heart_rate = unless_cancelled(
+                    &cancel,
+                    DeviceManager::get_day_heart_rate(&conn, &calendar, today),
+                )
+                .await?;
+                let steps = unless_cancelled(
+                    &cancel,
+                    DeviceManager::get_day_steps(&conn, &calendar, today),
+                )
+                .await?;
+                let sleep = unless_cancelled(&cancel, DeviceManager::get_sleep(&conn)).await?;
+                let oxygen = unless_cancelled(&cancel, DeviceManager::get_oxygen(&conn)).await?;
                 Ok((heart_rate, steps, sleep, oxygen))
             }));
         }
@@ -536,9 +856,10 @@ impl App {
         {
             self.status_message = "Blinking device...".to_string();
             let conn = conn.clone();
-            self.operation_task = Some(tokio::spawn(
-                async move { DeviceManager::blink(&conn).await },
-            ));
+            let cancel = self.request_cancel();
+            self.operation_task = Some(tokio::spawn(async move {
+                unless_cancelled(&cancel, DeviceManager::blink(&conn)).await
+            }));
         }
     }
 
@@ -548,9 +869,10 @@ impl App {
         {
             self.status_message = "Finding device...".to_string();
             let conn = conn.clone();
-            self.operation_task = Some(tokio::spawn(
-                async move { DeviceManager::find(&conn).await },
-            ));
+            let cancel = self.request_cancel();
+            self.operation_task = Some(tokio::spawn(async move {
+                unless_cancelled(&cancel, DeviceManager::find(&conn)).await
+            }));
         }
     }
 
@@ -560,9 +882,10 @@ impl App {
         {
             self.status_message = "Rebooting device...".to_string();
             let conn = conn.clone();
-            self.operation_task = Some(tokio::spawn(
-                async move { DeviceManager::reboot(&conn).await },
-            ));
+            let cancel = self.request_cancel();
+            self.operation_task = Some(tokio::spawn(async move {
+                unless_cancelled(&cancel, DeviceManager::reboot(&conn)).await
+            }));
         }
     }
 
@@ -577,11 +900,30 @@ impl App {
         {
             self.status_message = "Resetting device...".to_string();
             let conn = conn.clone();
-            self.operation_task = Some(tokio::spawn(
-                async move { DeviceManager::reset(&conn).await },
-            ));
+            let cancel = self.request_cancel();
+            self.operation_task = Some(tokio::spawn(async move {
+                unless_cancelled(&cancel, DeviceManager::reset(&conn)).await
+            }));
 
             self.current_screen = Screen::Idle;
         }
     }
 }
+
+/// Starts `request` unless `cancel` has fired. A started request is never
+/// interrupted, so cancelling cannot cut a write to the ring short.
+async fn unless_cancelled<T>(
+    cancel: &CancellationToken,
+    request: impl Future<Output = Result<T, DeviceError>>,
+) -> Result<T, DeviceError> {
+    if cancel.is_cancelled() {
+        return Err(DeviceError::Cancelled);
+    }
+    request.await
+}
+
+async fn settle<T>(task: Option<task::JoinHandle<T>>) {
+    if let Some(task) = task {
+        let _ = task.await;
+    }
+}
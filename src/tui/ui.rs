@@ -1,3 +1,8 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use chrono::Utc;
+
 use ratatui::{
     Frame,
     layout::{Alignment, Constraint, Direction, Layout, Rect},
@@ -7,7 +12,11 @@ use ratatui::{
 };
 
 use crate::{
-    protocol::{hr::HeartRateResult, steps::StepsResult},
+    protocol::{
+        hr::HeartRateResult,
+        realtime::{ReadingType, RealtimeReading},
+        steps::{StepsResult, detail_shortfall},
+    },
     tui::app::{App, ConnectedTab, Screen},
 };
 
@@ -21,18 +30,33 @@ pub fn render_app(f: &mut Frame, app: &App) {
         ])
         .split(f.area());
 
-    render_header(f, main_layout[0]);
+    render_header(f, main_layout[0], app);
     render_main_content(f, main_layout[1], app);
     render_footer(f, main_layout[2], app);
 }
 
-fn render_header(f: &mut Frame, area: Rect) {
-    let title = Line::from(vec![Span::styled(
+fn render_header(f: &mut Frame, area: Rect, app: &App) {
+    let mut spans = vec![Span::styled(
         "Colmi TUI",
         Style::default()
             .fg(Color::Cyan)
             .add_modifier(Modifier::BOLD),
-    )]);
+    )];
+
+    let today_steps = app
+        .live_activity
+        .as_ref()
+        .map(|a| a.steps)
+        .or(app.today_totals.as_ref().map(|t| t.steps));
+    if app.current_screen == Screen::Connected
+        && let Some(steps) = today_steps
+    {
+        spans.push(Span::styled(
+            format!("  |  👟 {steps} steps today"),
+            Style::default().fg(Color::Green),
+        ));
+    }
+    let title = Line::from(spans);
 
     let header = Paragraph::new(title).alignment(Alignment::Center).block(
         Block::default()
@@ -243,8 +267,14 @@ fn get_battery_color(charge_pct: u8) -> Style {
     })
 }
 
-fn render_sparkline(readings: &[crate::protocol::realtime::RealtimeReading]) -> String {
-    let window: Vec<u8> = readings.iter().rev().take(30).map(|r| r.value).collect();
+fn render_sparkline(readings: &VecDeque<RealtimeReading>, reading_type: ReadingType) -> String {
+    let window: Vec<u8> = readings
+        .iter()
+        .rev()
+        .filter(|r| r.reading_type == reading_type)
+        .take(30)
+        .map(|r| r.value)
+        .collect();
     let max = window.iter().copied().max().unwrap_or(1).max(1) as f32;
     let mut line = String::new();
     for &value in window.iter().rev() {
@@ -281,6 +311,10 @@ fn render_connected_screen(f: &mut Frame, area: Rect, app: &App) {
             " Today ",
             tab_style(app.connected_tab == ConnectedTab::Today),
         ),
+        Span::styled(
+            " Workout ",
+            tab_style(app.connected_tab == ConnectedTab::Workout),
+        ),
         Span::styled(
             " Controls ",
             tab_style(app.connected_tab == ConnectedTab::Controls),
@@ -297,6 +331,7 @@ fn render_connected_screen(f: &mut Frame, area: Rect, app: &App) {
     match app.connected_tab {
         ConnectedTab::Live => render_live_tab(f, layout[1], app),
         ConnectedTab::Today => render_today_tab(f, layout[1], app),
+        ConnectedTab::Workout => render_workout_tab(f, layout[1], app),
         ConnectedTab::Controls => render_controls_tab(f, layout[1], app),
     }
 }
@@ -373,30 +408,53 @@ fn render_live_tab(f: &mut Frame, area: Rect, app: &App) {
     if app.is_monitoring {
         let elapsed = app
             .monitor_started_at
-            .map(|t| t.elapsed().as_secs())
-            .unwrap_or(0);
+            .map(|t| t.elapsed())
+            .unwrap_or_default();
+        let total: u64 = app.live_stats.values().map(|stats| stats.count).sum();
         content.push(Line::from(format!(
-            "  ● LIVE  {}s elapsed  |  {} readings  |  [l] stop",
-            elapsed,
-            app.live_readings.len()
+            "  ● LIVE  {} elapsed  |  {} readings  |  [l] stop  [m] metric",
+            format_elapsed(elapsed),
+            total
         )));
-        if let Some(latest) = app.live_readings.last() {
+        content.push(Line::from(match app.live_view {
+            Some(reading_type) => format!("  Showing: {}", reading_type.label()),
+            None => "  Showing: all metrics".to_string(),
+        }));
+        if app.live_readings.is_empty() {
+            content.push(Line::from("  Warming up (~30s)..."));
+        }
+        let shown: Vec<ReadingType> = match app.live_view {
+            Some(reading_type) => vec![reading_type],
+            None => app.live_types.clone(),
+        };
+        for reading_type in shown {
+            let latest = app
+                .live_readings
+                .iter()
+                .rev()
+                .find(|r| r.reading_type == reading_type);
+            let (Some(latest), Some(stats)) = (latest, app.live_stats.get(&reading_type)) else {
+                continue;
+            };
+            content.push(Line::from(""));
             content.push(Line::from(format!(
-                "  Latest: {} {}",
+                "  {}: {} {}",
+                reading_type.label(),
                 latest.value,
-                latest.reading_type.unit()
+                reading_type.unit()
             )));
+            if let (Some(avg), Some(min), Some(max)) = (stats.average(), stats.min, stats.max) {
+                content.push(Line::from(format!(
+                    "  Session: avg {avg} | min {min} | max {max}"
+                )));
+            }
             content.push(Line::from(format!(
                 "  {}",
-                render_sparkline(&app.live_readings)
+                render_sparkline(&app.live_readings, reading_type)
             )));
-        } else {
-            content.push(Line::from("  Warming up (~30s)..."));
         }
     } else {
-        content.push(Line::from(
-            "  Press [l] to start live heart-rate monitoring",
-        ));
+        content.push(Line::from("  Press [l] to start live monitoring"));
     }
 
     render_status_and_footer(&mut content, app);
@@ -423,11 +481,21 @@ fn render_today_tab(f: &mut Frame, area: Rect, app: &App) {
         Line::from(""),
     ];
 
+    if let Some(activity) = &app.live_activity {
+        content.push(Line::from(vec![
+            Span::styled("  ● LIVE  ", Style::default().fg(Color::Green)),
+            Span::raw(format!(
+                "{} steps | {:.1} kcal | {} m",
+                activity.steps, activity.calories, activity.distance
+            )),
+        ]));
+        content.push(Line::from(""));
+    }
+
     if let Some((heart_rate, steps, sleep, oxygen)) = &app.history {
         match heart_rate {
             HeartRateResult::Log(log) => {
-                let readings: Vec<u8> =
-                    log.heart_rates.iter().copied().filter(|&r| r > 0).collect();
+                let readings: Vec<u8> = log.readings().map(|(_, bpm)| bpm).collect();
                 if readings.is_empty() {
                     content.push(Line::from("  🫀  Heart rate: no readings"));
                 } else {
@@ -455,20 +523,32 @@ fn render_today_tab(f: &mut Frame, area: Rect, app: &App) {
                 content.push(Line::from(format!(
                     "  👟  Steps: {total_steps} | {total_calories:.0} kcal | {total_distance} m"
                 )));
+                // Totals cover the ring's UTC day; only comparable when it is the local day.
+                if let Some(totals) = &app.today_totals
+                    && app.calendar.is_ring_day(app.calendar.today())
+                    && let Some(missing) = detail_shortfall(totals, details)
+                {
+                    content.push(Line::from(Span::styled(
+                        format!(
+                            "  ⚠ details incomplete: {missing} of {} steps missing",
+                            totals.steps
+                        ),
+                        Style::default().fg(Color::Yellow),
+                    )));
+                }
             }
             StepsResult::NoData => content.push(Line::from("  👟  Steps: no data")),
         }
 
-        if let Some(day) = sleep.days.first() {
-            let total: u16 = day.phases.iter().map(|p| p.minutes as u16).sum();
+        if let Some(session) = sleep.sessions(&Utc).last() {
+            let calendar = &app.calendar;
+            let total = session.total_minutes();
             content.push(Line::from(format!(
-                "  😴  Sleep: {}h {:02}m ({}:{:02} → {}:{:02})",
+                "  😴  Sleep: {}h {:02}m ({} → {})",
                 total / 60,
                 total % 60,
-                day.start_minutes / 60,
-                day.start_minutes % 60,
-                day.end_minutes / 60,
-                day.end_minutes % 60
+                calendar.format(session.start, "%H:%M"),
+                calendar.format(session.end, "%H:%M")
             )));
         } else {
             content.push(Line::from("  😴  Sleep: no data"));
@@ -513,6 +593,131 @@ fn render_today_tab(f: &mut Frame, area: Rect, app: &App) {
     f.render_widget(paragraph, area);
 }
 
+fn format_elapsed(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+fn render_zone_bars(content: &mut Vec<Line<'static>>, time_in_zones: &[Duration; 6]) {
+    let total = time_in_zones.iter().sum::<Duration>().as_secs().max(1);
+    for (zone, time) in time_in_zones.iter().enumerate().rev() {
+        let label = if zone == 0 {
+            "Rest  ".to_string()
+        } else {
+            format!("Zone {zone}")
+        };
+        let filled = (time.as_secs() * 20 / total) as usize;
+        content.push(Line::from(vec![
+            Span::raw(format!("  {label} ")),
+            Span::styled(
+                format!("{}{}", "▪".repeat(filled), "░".repeat(20 - filled)),
+                Style::default().fg(zone_color(zone)),
+            ),
+            Span::raw(format!(" {}", format_elapsed(*time))),
+        ]));
+    }
+}
+
+fn zone_color(zone: usize) -> Color {
+    match zone {
+        0 => Color::Gray,
+        1 => Color::Blue,
+        2 => Color::Green,
+        3 => Color::Yellow,
+        4 => Color::LightRed,
+        _ => Color::Red,
+    }
+}
+
+fn render_workout_tab(f: &mut Frame, area: Rect, app: &App) {
+    let mut content = vec![
+        Line::from(""),
+        Line::from(vec![Span::styled(
+            "Workout",
+            Style::default()
+                .fg(Color::Green)
+                .add_modifier(Modifier::BOLD),
+        )]),
+        Line::from(""),
+    ];
+
+    if let Some(session) = &app.workout_session {
+        let state = if session.paused {
+            "❚❚ PAUSED"
+        } else {
+            "● RUNNING"
+        };
+        content.push(Line::from(format!(
+            "  {}  {}  {}",
+            state,
+            session.sport_type.label(),
+            format_elapsed(session.elapsed())
+        )));
+        content.push(Line::from(""));
+        match session.heart_rates.last() {
+            Some(&(_, hr)) => {
+                let zone = session.zone(hr);
+                content.push(Line::from(vec![
+                    Span::raw("  🫀  "),
+                    Span::styled(
+                        format!("{hr} bpm (zone {zone})"),
+                        Style::default().fg(zone_color(zone)),
+                    ),
+                ]));
+            }
+            None => content.push(Line::from("  🫀  Warming up (~30s)...")),
+        }
+        if let Some(progress) = &session.latest {
+            content.push(Line::from(format!(
+                "  👟  {} steps | {:.1} kcal | {} m",
+                progress.steps, progress.calories, progress.distance
+            )));
+        }
+        content.push(Line::from(""));
+        content.push(Line::from("  [p] pause/resume  [w] stop"));
+    } else if let Some(summary) = &app.workout_summary {
+        content.push(Line::from(format!(
+            "  Last workout ({}): {}",
+            summary.sport_type.label(),
+            format_elapsed(summary.duration)
+        )));
+        match (summary.avg_heart_rate, summary.max_heart_rate) {
+            (Some(avg), Some(max)) => {
+                content.push(Line::from(format!("  🫀  avg {avg} bpm | max {max} bpm")))
+            }
+            _ => content.push(Line::from("  🫀  no heart-rate readings")),
+        }
+        content.push(Line::from(format!(
+            "  👟  {} steps | {:.1} kcal | {} m",
+            summary.steps, summary.calories, summary.distance
+        )));
+        content.push(Line::from(""));
+        render_zone_bars(&mut content, &summary.time_in_zones);
+        content.push(Line::from(""));
+        content.push(Line::from(format!(
+            "  [←/→] sport: {}  [w] start new workout",
+            app.workout_sport.label()
+        )));
+    } else {
+        content.push(Line::from(format!(
+            "  [←/→] sport: {}",
+            app.workout_sport.label()
+        )));
+        content.push(Line::from("  Press [w] to start a workout"));
+    }
+
+    render_status_and_footer(&mut content, app);
+
+    let paragraph = Paragraph::new(content).alignment(Alignment::Center).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .title("Workout"),
+    );
+
+    f.render_widget(paragraph, area);
+}
+
 fn render_controls_tab(f: &mut Frame, area: Rect, app: &App) {
     let mut content = vec![
         Line::from(""),
@@ -525,7 +730,8 @@ fn render_controls_tab(f: &mut Frame, area: Rect, app: &App) {
         Line::from(""),
         Line::from("[1] Blink  [2] Find  [3] Reboot  [4] Reset"),
         Line::from(""),
-        Line::from("[l] Live heart-rate monitoring"),
+        Line::from("[l] Live monitoring ([m] switch metric)"),
+        Line::from("[w] Start/stop workout  [p] Pause/resume"),
         Line::from("[h] Refresh today's data"),
         Line::from("[b] Refresh battery"),
     ];
@@ -590,7 +796,9 @@ fn render_footer(f: &mut Frame, area: Rect, app: &App) {
         Screen::DeviceList => "[↑/↓] Select | [ENTER] Choose | [ESC] Back | [s] Rescan",
         Screen::Error => "[ESC] Back",
         Screen::Connecting => "[ESC] Cancel | Connecting...",
-        Screen::Connected => "[l] Live HR | [h] Today | [b] Battery | [q] Quit",
+        Screen::Connected => {
+            "[l] Live | [m] Metric | [w] Workout | [h] Today | [b] Battery | [q] Quit"
+        }
         Screen::ConfirmReset => "[4] Confirm Reset | [ESC] Cancel",
     };
 